@@ -8,7 +8,7 @@
 pub mod test;
 
 use std::cmp::Ordering;
-use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 
 use nakamoto_common::bitcoin;
 use nakamoto_common::bitcoin::blockdata::block::BlockHeader;
@@ -18,7 +18,9 @@ use nakamoto_common::bitcoin::network::constants::Network;
 use nakamoto_common::bitcoin::util::BitArray;
 
 use nakamoto_common::bitcoin::util::uint::Uint256;
-use nakamoto_common::block::tree::{self, BlockReader, BlockTree, Branch, Error, ImportResult};
+use nakamoto_common::block::tree::{
+    self, BlockReader, BlockTree, Branch, ChainTip, ChainTipStatus, Error, ImportResult,
+};
 use nakamoto_common::block::{
     self,
     iter::Iter,
@@ -34,6 +36,8 @@ struct CachedBlock {
     pub height: Height,
     pub hash: BlockHash,
     pub header: BlockHeader,
+    /// Total proof-of-work of the chain up to and including this block.
+    pub work: Work,
 }
 
 impl std::ops::Deref for CachedBlock {
@@ -67,9 +71,15 @@ pub struct BlockCache<S: Store> {
     chain: NonEmpty<CachedBlock>,
     headers: HashMap<BlockHash, Height>,
     orphans: HashMap<BlockHash, BlockHeader>,
+    /// Orphans whose branch passed validation the last time it was checked.
+    valid_forks: HashSet<BlockHash>,
+    /// Tips of branches rejected for re-organizing the active chain too deeply, that were
+    /// already reported.
+    rejected: HashSet<BlockHash>,
     checkpoints: BTreeMap<Height, BlockHash>,
     params: Params,
     store: S,
+    max_reorg_depth: Option<usize>,
 }
 
 impl<S: Store<Header = BlockHeader>> BlockCache<S> {
@@ -89,6 +99,7 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
                 height: 0,
                 hash: genesis.block_hash(),
                 header: genesis,
+                work: genesis.work(),
             },
             Vec::with_capacity(length - 1),
         ));
@@ -100,12 +111,22 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
             chain,
             headers,
             orphans,
+            valid_forks: HashSet::new(),
+            rejected: HashSet::new(),
             params,
             checkpoints,
             store,
+            max_reorg_depth: None,
         })
     }
 
+    /// Set the maximum re-org depth accepted. Branches that would disconnect more than
+    /// this number of blocks from the active chain are rejected.
+    pub fn with_max_reorg_depth(mut self, max_reorg_depth: Option<usize>) -> Self {
+        self.max_reorg_depth = max_reorg_depth;
+        self
+    }
+
     /// Create a new `BlockCache` from a `Store`, consensus parameters, and checkpoints,
     /// and load all the blocks from the store.
     pub fn from(
@@ -118,19 +139,22 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
 
     /// Load the block headers from the store, into the cache.
     pub fn load(self) -> Result<Self, Error> {
-        self.load_with(|_| true)
+        self.load_with(|_, _| true)
     }
 
     /// Load the block headers from the store, into the cache.
-    /// Takes a function that is called for each block imported.
-    pub fn load_with(mut self, progress: impl Fn(Height) -> bool) -> Result<Self, Error> {
+    /// Takes a function that is called for each block imported, with the height of the
+    /// block and the height of the store.
+    pub fn load_with(mut self, progress: impl Fn(Height, Height) -> bool) -> Result<Self, Error> {
+        let tip = self.store.height()?;
+
         for result in self.store.iter().skip(1) {
             let (height, header) = result?;
             let hash = header.block_hash();
 
             self.extend_chain(height, hash, header);
 
-            if !progress(height) {
+            if !progress(height, tip) {
                 return Err(Error::Interrupted);
             }
         }
@@ -252,8 +276,12 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
         // Note that we can't compare candidates with each other directly, as they may have
         // different fork heights. So a candidate with less work than another may infact result
         // in a longer chain if selected, due to replacing less blocks on the active chain.
-        let candidates = self.chain_candidates(clock);
-        if candidates.is_empty() {
+        let (candidates, too_deep) = self.chain_candidates(clock);
+
+        // Remember which forks are valid, so that they can be classified without a clock.
+        self.valid_forks = candidates.iter().chain(&too_deep).map(|c| c.tip).collect();
+
+        if candidates.is_empty() && too_deep.is_empty() {
             return Ok(ImportResult::TipUnchanged);
         }
 
@@ -304,6 +332,8 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
             for h in hashes {
                 self.orphans.remove(&h);
             }
+            let orphans = &self.orphans;
+            self.rejected.retain(|h| orphans.contains_key(h));
         }
 
         if let Some(branch) = best_branch {
@@ -330,23 +360,42 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
             Ok(ImportResult::TipChanged(
                 header, hash, height, stale, connected,
             ))
+        } else if let Some(branch) = too_deep.iter().find(|b| {
+            // Report branches that would otherwise have been selected, once.
+            !self.rejected.contains(&b.tip)
+                && Branch(&b.headers).work() > Branch(self.chain_suffix(b.fork_height)).work()
+        }) {
+            self.rejected.insert(branch.tip);
+
+            Ok(ImportResult::ReorgRejected {
+                depth: (self.height() - branch.fork_height) as usize,
+                fork_point: (branch.fork_height, branch.fork_hash),
+            })
         } else {
             Ok(ImportResult::TipUnchanged)
         }
     }
 
-    /// Find all the potential forks off the main chain.
-    fn chain_candidates(&self, clock: &impl Clock) -> Vec<Candidate> {
+    /// Find all the potential forks off the main chain. Returns the forks that can be
+    /// selected, and the forks that would re-org the active chain deeper than allowed.
+    fn chain_candidates(&self, clock: &impl Clock) -> (Vec<Candidate>, Vec<Candidate>) {
         let mut branches = Vec::new();
+        let mut too_deep = Vec::new();
 
         for tip in self.orphans.keys() {
             if let Some(branch) = self.fork(tip) {
                 if self.validate_branch(&branch, clock).is_ok() {
-                    branches.push(branch);
+                    let depth = (self.height() - branch.fork_height) as usize;
+
+                    if self.max_reorg_depth.map_or(false, |max| depth > max) {
+                        too_deep.push(branch);
+                    } else {
+                        branches.push(branch);
+                    }
                 }
             }
         }
-        branches
+        (branches, too_deep)
     }
 
     /// Find a potential branch starting from the active chain and ending at the given tip.
@@ -388,11 +437,10 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
 
     /// Validate a candidate branch. This function is useful for chain selection.
     fn validate_branch(&self, candidate: &Candidate, clock: &impl Clock) -> Result<(), Error> {
-        let mut tip = CachedBlock {
-            height: candidate.fork_height,
-            hash: candidate.fork_hash,
-            header: candidate.fork_header,
-        };
+        let mut tip = *self
+            .chain
+            .get(candidate.fork_height as usize)
+            .expect("BlockCache::validate_branch: the fork block is in the active chain");
 
         for header in candidate.headers.iter() {
             self.validate(&tip, header, clock)?;
@@ -401,6 +449,7 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
                 height: tip.height + 1,
                 hash: header.block_hash(),
                 header: *header,
+                work: tip.work + header.work(),
             };
         }
         Ok(())
@@ -489,6 +538,8 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
 
             self.headers.remove(&block.hash);
             self.orphans.insert(block.hash, block.header);
+            // Blocks on the active chain were fully validated when they were imported.
+            self.valid_forks.insert(block.hash);
         }
         self.store.rollback(height)?;
 
@@ -517,10 +568,14 @@ impl<S: Store<Header = BlockHeader>> BlockCache<S> {
 
         self.headers.insert(hash, height);
         self.orphans.remove(&hash);
+        self.valid_forks.remove(&hash);
+
+        let work = self.chain.last().work + header.work();
         self.chain.push(CachedBlock {
             height,
             hash,
             header,
+            work,
         });
     }
 
@@ -543,6 +598,7 @@ impl<S: Store<Header = BlockHeader>> BlockTree for BlockCache<S> {
         let mut best_height = self.height();
         let mut best_hash = self.chain.last().hash;
         let mut best_header = self.chain.last().header;
+        let mut rejected = None;
 
         for (i, header) in chain.enumerate() {
             match self.import_block(header, context) {
@@ -556,6 +612,7 @@ impl<S: Store<Header = BlockHeader>> BlockTree for BlockCache<S> {
                     best_header = header;
                 }
                 Ok(ImportResult::TipUnchanged) => {}
+                Ok(result @ ImportResult::ReorgRejected { .. }) => rejected = Some(result),
                 Err(Error::DuplicateBlock(hash)) => log::trace!("Duplicate block {}", hash),
                 Err(Error::BlockMissing(hash)) => log::trace!("Missing block {}", hash),
                 Err(err) => return Err(Error::BlockImportAborted(err.into(), i, self.height())),
@@ -582,6 +639,8 @@ impl<S: Store<Header = BlockHeader>> BlockTree for BlockCache<S> {
                     "BlockCache::import_blocks: there is always at least one connected block",
                 ),
             ))
+        } else if let Some(result) = rejected {
+            Ok(result)
         } else {
             Ok(ImportResult::TipUnchanged)
         }
@@ -614,6 +673,11 @@ impl<S: Store<Header = BlockHeader>> BlockTree for BlockCache<S> {
             Ok(ImportResult::TipUnchanged)
         }
     }
+
+    /// Flush the header store to disk.
+    fn flush(&mut self) -> Result<(), Error> {
+        self.store.sync().map_err(Error::from)
+    }
 }
 
 impl<S: Store<Header = BlockHeader>> BlockReader for BlockCache<S> {
@@ -652,6 +716,44 @@ impl<S: Store<Header = BlockHeader>> BlockReader for BlockCache<S> {
         }
     }
 
+    /// Get all known chain tips. Forks are classified by the validity recorded when blocks
+    /// were last imported.
+    fn chain_tips(&self) -> Vec<ChainTip> {
+        let (hash, _) = self.tip();
+        let mut tips = vec![ChainTip {
+            height: self.height(),
+            hash,
+            status: ChainTipStatus::Active,
+            branch_length: 0,
+        }];
+        // Stale blocks that aren't the parent of any other stale block are fork tips.
+        let parents = self
+            .orphans
+            .values()
+            .map(|h| h.prev_blockhash)
+            .collect::<BTreeSet<_>>();
+
+        for tip in self.orphans.keys().filter(|h| !parents.contains(h)) {
+            if let Some(candidate) = self.fork(tip) {
+                let status = if self.valid_forks.contains(tip) {
+                    ChainTipStatus::ValidFork
+                } else {
+                    ChainTipStatus::HeadersOnly
+                };
+                tips.push(ChainTip {
+                    height: candidate.fork_height + candidate.headers.len() as Height,
+                    hash: candidate.tip,
+                    status,
+                    branch_length: candidate.headers.len(),
+                });
+            }
+        }
+        // Highest forks first.
+        tips[1..].sort_by(|a, b| b.height.cmp(&a.height));
+
+        tips
+    }
+
     /// Get the best block hash and header.
     fn tip(&self) -> (BlockHash, BlockHeader) {
         (self.chain.last().hash, self.chain.last().header)
@@ -686,6 +788,11 @@ impl<S: Store<Header = BlockHeader>> BlockReader for BlockCache<S> {
         self.chain.last().height
     }
 
+    /// Get the total proof-of-work carried by the longest chain.
+    fn chain_work(&self) -> Work {
+        self.chain.last().work
+    }
+
     /// Get the height of the last checkpoint block.
     fn last_checkpoint(&self) -> Height {
         let height = self.height();
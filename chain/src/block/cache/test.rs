@@ -2,7 +2,9 @@ use super::BlockCache;
 
 use nakamoto_common::bitcoin_hashes::Hash;
 use nakamoto_common::block::time::{AdjustedTime, Clock, LocalTime};
-use nakamoto_common::block::tree::{BlockReader, BlockTree, Error, ImportResult};
+use nakamoto_common::block::tree::{
+    BlockReader, BlockTree, ChainTip, ChainTipStatus, Error, ImportResult,
+};
 use nakamoto_common::block::{BlockTime, Height, Target};
 use nakamoto_common::nonempty::NonEmpty;
 
@@ -81,6 +83,10 @@ impl BlockTree for HeightCache {
     fn extend_tip<C>(&mut self, _header: BlockHeader, _context: &C) -> Result<ImportResult, Error> {
         unimplemented!()
     }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
 }
 
 impl BlockReader for HeightCache {
@@ -553,6 +559,28 @@ fn test_from_store() {
     }
 }
 
+#[test]
+fn test_load_with_progress() {
+    let genesis = constants::genesis_block(bitcoin::Network::Bitcoin).header;
+    let store = store::File::open(&*nakamoto_test::headers::PATH, genesis).unwrap();
+    let store_height = store.height().unwrap();
+    let progress = std::cell::RefCell::new(Vec::new());
+
+    let cache = BlockCache::new(store, Params::new(bitcoin::Network::Bitcoin), &[])
+        .unwrap()
+        .load_with(|height, tip| {
+            progress.borrow_mut().push((height, tip));
+            true
+        })
+        .unwrap();
+    let progress = progress.into_inner();
+
+    assert_eq!(cache.height(), store_height);
+    assert_eq!(progress.len() as Height, store_height);
+    assert!(progress.iter().all(|(_, tip)| *tip == store_height));
+    assert_eq!(progress.last(), Some(&(store_height, store_height)));
+}
+
 #[test]
 fn test_median_time_past() {
     let network = bitcoin::Network::Bitcoin;
@@ -1004,6 +1032,180 @@ fn test_cache_import_back_and_forth() {
     assert_matches!(r, ImportResult::TipChanged { .. });
 }
 
+#[test]
+fn test_cache_max_reorg_depth() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[])
+        .unwrap()
+        .with_max_reorg_depth(Some(1));
+
+    let g = &mut fastrand::Rng::new();
+
+    let a0 = Tree::new(genesis);
+
+    // a0 <- a1 <- a2
+    //           \
+    //            <- b2 <- b3 *
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let b2 = a1.next(g);
+    let b3 = b2.next(g);
+
+    cache.import_blocks(a0.branch([&a1, &a2]), &ctx).unwrap();
+    cache.import_blocks(a1.branch([&b2, &b3]), &ctx).unwrap();
+    assert_eq!(cache.tip().0, b3.hash, "A re-org of depth 1 is accepted");
+
+    // a0 <- a1 <- b2 <- b3 *
+    //  \
+    //   <- c1 <- c2 <- c3 <- c4
+    let c1 = a0.next(g);
+    let c2 = c1.next(g);
+    let c3 = c2.next(g);
+    let c4 = c3.next(g);
+
+    let r = cache.import_blocks(a0.branch([&c1, &c4]), &ctx).unwrap();
+
+    assert_matches!(
+        r,
+        ImportResult::ReorgRejected { depth: 3, fork_point } if fork_point == (0, a0.hash)
+    );
+    assert_eq!(cache.tip().0, b3.hash, "The active chain is kept");
+    assert_eq!(cache.height(), 3);
+
+    // The rejected branch is only reported once, when other blocks are imported.
+    let d2 = a1.next(g);
+
+    let r = cache.import_blocks(a1.branch([&d2, &d2]), &ctx).unwrap();
+    assert_matches!(r, ImportResult::TipUnchanged);
+
+    // a0 <- a1 <- b2 <- b3 <- b4 *
+    //      //   <- c1 <- c2 <- c3 <- c4
+    let b4 = b3.next(g);
+
+    let r = cache.import_blocks(b3.branch([&b4, &b4]), &ctx).unwrap();
+    assert_matches!(r, ImportResult::TipChanged(_, hash, 4, _, _) if hash == b4.hash);
+    assert_eq!(cache.tip().0, b4.hash, "The active chain is extended");
+}
+
+#[test]
+fn test_cache_chain_tips() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+
+    let g = &mut fastrand::Rng::new();
+
+    let a0 = Tree::new(genesis);
+
+    // a0 <- a1 <- a2 *
+    //           \
+    //            <- b2
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+    let b2 = a1.next(g);
+
+    cache.import_blocks(a0.branch([&a1, &a2]), &ctx).unwrap();
+    cache.import_blocks(a1.branch([&b2]), &ctx).unwrap();
+
+    assert_eq!(
+        cache.chain_tips(),
+        vec![
+            ChainTip {
+                height: 2,
+                hash: a2.hash,
+                status: ChainTipStatus::Active,
+                branch_length: 0,
+            },
+            ChainTip {
+                height: 2,
+                hash: b2.hash,
+                status: ChainTipStatus::ValidFork,
+                branch_length: 1,
+            }
+        ]
+    );
+
+    // a0 <- a1 <- a2
+    //  \        \
+    //   \        <- b2
+    //    \
+    //     <- c1 <- c2 <- c3 *
+    let c1 = a0.next(g);
+    let c2 = c1.next(g);
+    let c3 = c2.next(g);
+
+    cache
+        .import_blocks(a0.branch([&c1, &c2, &c3]), &ctx)
+        .unwrap();
+
+    let tips = cache.chain_tips();
+    assert_eq!(tips.len(), 3);
+    assert_eq!(
+        tips[0],
+        ChainTip {
+            height: 3,
+            hash: c3.hash,
+            status: ChainTipStatus::Active,
+            branch_length: 0,
+        }
+    );
+    for hash in [a2.hash, b2.hash] {
+        assert!(tips.contains(&ChainTip {
+            height: 2,
+            hash,
+            status: ChainTipStatus::ValidFork,
+            branch_length: 2,
+        }));
+    }
+}
+
+#[test]
+fn test_cache_chain_work() {
+    let network = bitcoin::Network::Regtest;
+    let genesis = constants::genesis_block(network).header;
+    let params = Params::new(network);
+    let store = store::Memory::new(NonEmpty::new(genesis));
+    let ctx = AdjustedTime::<net::SocketAddr>::new(LOCAL_TIME);
+    let mut cache = BlockCache::from(store, params, &[]).unwrap();
+
+    let g = &mut fastrand::Rng::new();
+    let a0 = Tree::new(genesis);
+    assert_eq!(cache.chain_work(), genesis.work());
+
+    // a0 <- a1 <- a2 *
+    let a1 = a0.next(g);
+    let a2 = a1.next(g);
+
+    cache.import_blocks(a0.branch([&a1, &a2]), &ctx).unwrap();
+
+    let expected = cache
+        .chain()
+        .fold(Uint256::zero(), |work, header| work + header.work());
+    assert_eq!(cache.chain_work(), expected);
+    assert!(cache.chain_work() > genesis.work());
+
+    // a0 <- a1 <- a2
+    //        \
+    //         <- b2 <- b3 *
+    let b2 = a1.next(g);
+    let b3 = b2.next(g);
+
+    cache.import_blocks(a0.branch([&b2, &b3]), &ctx).unwrap();
+    assert_eq!(cache.tip().0, b3.hash);
+
+    let expected = cache
+        .chain()
+        .fold(Uint256::zero(), |work, header| work + header.work());
+    assert_eq!(cache.chain_work(), expected);
+}
+
 #[test]
 fn test_cache_import_equal_difficulty_blocks() {
     let mut headers = vec![
@@ -175,20 +175,24 @@ impl<H: 'static + Copy + Encodable + Decodable> Store for File<H> {
         self.len().map(|_| ())
     }
 
-    /// Attempt to heal data corruption.
-    fn heal(&self) -> Result<(), Error> {
+    /// Attempt to heal data corruption, by removing any partially written header.
+    /// The height before healing includes the partial header.
+    fn heal(&self) -> Result<(Height, Height), Error> {
         let meta = self.file.metadata()?;
         let len = meta.len();
         let size = mem::size_of::<H>();
 
         assert!(len <= usize::MAX as u64);
 
+        let height = (len as usize / size) as Height;
         let extraneous = len as usize % size;
         if extraneous != 0 {
             self.file.set_len(len - extraneous as u64)?;
+
+            return Ok((height + 1, height));
         }
 
-        Ok(())
+        Ok((height, height))
     }
 }
 
@@ -349,7 +353,10 @@ mod test {
         let mut store = store("headers.db");
 
         store.check().expect("checking always works");
-        store.heal().expect("healing when there is no corruption");
+        assert_eq!(
+            store.heal().expect("healing when there is no corruption"),
+            (0, 0)
+        );
 
         let headers = &[
             BlockHeader {
@@ -399,7 +406,7 @@ mod test {
         store.len().expect_err("data is corrupted");
         store.check().expect_err("data is corrupted");
 
-        store.heal().unwrap();
+        assert_eq!(store.heal().unwrap(), (2, 1));
         store.check().unwrap();
 
         assert_eq!(
@@ -95,7 +95,9 @@ impl<H: 'static + Copy + Clone + Genesis> Store for Memory<H> {
     }
 
     /// Heal data corruption.
-    fn heal(&self) -> Result<(), Error> {
-        Ok(())
+    fn heal(&self) -> Result<(Height, Height), Error> {
+        let height = self.height()?;
+
+        Ok((height, height))
     }
 }
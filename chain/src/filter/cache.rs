@@ -2,6 +2,7 @@
 //! Compact block filter cache.
 
 use std::io;
+use std::iter;
 use std::ops::RangeInclusive;
 
 use nakamoto_common::bitcoin::consensus::{encode, Decodable, Encodable};
@@ -13,9 +14,10 @@ pub use nakamoto_common::block::filter::{
 pub use nakamoto_common::block::store::Store;
 
 use nakamoto_common::block::store::Genesis;
-use nakamoto_common::block::Height;
+use nakamoto_common::block::{Block, Height};
 use nakamoto_common::network::Network;
 use nakamoto_common::nonempty::NonEmpty;
+use nakamoto_common::source;
 
 use crate::filter::store;
 
@@ -54,6 +56,48 @@ impl Decodable for StoredHeader {
     }
 }
 
+impl StoredHeader {
+    /// Create the stored filter header of the given genesis block.
+    pub fn from_genesis(genesis: &Block) -> Self {
+        let filter = BlockFilter::new_script_filter(genesis, |_| {
+            panic!("{}: genesis block should have no inputs", source!())
+        })
+        .unwrap();
+
+        Self {
+            hash: FilterHash::hash(&filter.content),
+            header: filter.filter_header(&FilterHeader::all_zeros()),
+        }
+    }
+}
+
+impl StoredHeader {
+    /// Create the marker recording that the headers below the given height were pruned.
+    ///
+    /// The marker is stored right after the genesis header. Since its hash can't be the
+    /// hash of a real filter, it can't be confused with a filter header.
+    fn pruned(floor: Height) -> Self {
+        let mut header = [0; 32];
+        header[..8].copy_from_slice(&floor.to_le_bytes());
+
+        Self {
+            hash: FilterHash::from_inner([0xff; 32]),
+            header: FilterHeader::from_inner(header),
+        }
+    }
+
+    /// Get the height below which headers were pruned, if this is a pruning marker.
+    fn floor(&self) -> Option<Height> {
+        if self.hash != FilterHash::from_inner([0xff; 32]) {
+            return None;
+        }
+        let mut floor = [0; 8];
+        floor.copy_from_slice(&self.header.into_inner()[..8]);
+
+        Some(Height::from_le_bytes(floor))
+    }
+}
+
 impl Genesis for StoredHeader {
     fn genesis(network: Network) -> Self {
         Self {
@@ -63,56 +107,114 @@ impl Genesis for StoredHeader {
     }
 }
 
+/// Filter header cache.
+///
+/// When the headers below a certain height, the *floor*, are pruned, the backing store is
+/// rewritten to hold the genesis header, followed by a pruning marker and the headers
+/// starting at the floor.
 pub struct FilterCache<S> {
     headers: NonEmpty<StoredHeader>,
     header_store: S,
+    /// Height of the first header held in memory. Headers below it were pruned.
+    floor: Height,
 }
 
 impl<S: Store<Header = StoredHeader>> FilterCache<S> {
     pub fn load(header_store: S) -> Result<Self, nakamoto_common::block::store::Error> {
-        Self::load_with(header_store, |_| true)
+        Self::load_with(header_store, |_, _| true)
     }
 
+    /// Load the filter headers from the store. Takes a function that is called for each
+    /// header loaded, with the height of the header and the height of the store.
     pub fn load_with(
-        header_store: S,
-        progress: impl Fn(Height) -> bool,
+        mut header_store: S,
+        progress: impl Fn(Height, Height) -> bool,
     ) -> Result<Self, nakamoto_common::block::store::Error> {
         let mut headers = NonEmpty::new(header_store.genesis());
+        let mut floor = 0;
+        let tip = header_store.height()?;
 
         for (height, result) in header_store.iter().enumerate().skip(1) {
             let (_, header) = result?;
-            headers.push(header);
 
-            if !progress(height as Height) {
+            if height == 1 {
+                if let Some(f) = header.floor() {
+                    floor = f;
+                    continue;
+                }
+            }
+            if floor > 0 && height == 2 {
+                // The first header after the marker is the header at the floor.
+                headers = NonEmpty::new(header);
+            } else {
+                headers.push(header);
+            }
+            if !progress(height as Height, tip) {
                 return Err(nakamoto_common::block::store::Error::Interrupted);
             }
         }
 
+        if floor > 0 && tip < 2 {
+            // The store was interrupted while being pruned, and the header at the floor
+            // is missing. Start over, the headers will be fetched again.
+            header_store.rollback(0)?;
+            floor = 0;
+        }
+
         Ok(Self {
             header_store,
             headers,
+            floor,
         })
     }
+
+    /// Get the position in the store of the header at the given height.
+    fn position(&self, height: Height) -> Height {
+        if self.floor == 0 {
+            height
+        } else {
+            // Skip the genesis header and the pruning marker.
+            height - self.floor + 2
+        }
+    }
 }
 
 impl<S> FilterCache<S> {
     /// Verify the filter header chain. Returns `true` if the chain is valid.
     pub fn verify(&self, network: Network) -> Result<(), store::Error> {
-        self.verify_with(network, |_| true)
+        self.verify_with(network, |_, _| true)
     }
 
+    /// Verify the filter header chain. Takes a function that is called for each header
+    /// verified, with the height of the header and the height of the chain.
     pub fn verify_with(
         &self,
         network: Network,
-        progress: impl Fn(Height) -> bool,
+        progress: impl Fn(Height, Height) -> bool,
     ) -> Result<(), store::Error> {
-        let mut prev_header = FilterHeader::all_zeros();
+        self.verify_from(FilterHeader::genesis(network), progress)
+    }
 
-        if self.headers.first().header != FilterHeader::genesis(network) {
-            return Err(store::Error::Integrity);
-        }
+    /// Verify the filter header chain, given the genesis filter header.
+    /// Used for chains with a custom genesis block.
+    pub fn verify_from(
+        &self,
+        genesis: FilterHeader,
+        progress: impl Fn(Height, Height) -> bool,
+    ) -> Result<(), store::Error> {
+        let tip = self.floor + self.headers.tail.len() as Height;
+
+        // If the chain was pruned, we verify it starting from the first header we have.
+        let (mut prev_header, skip) = if self.floor == 0 {
+            if self.headers.first().header != genesis {
+                return Err(store::Error::Integrity);
+            }
+            (FilterHeader::all_zeros(), 0)
+        } else {
+            (self.headers.first().header, 1)
+        };
 
-        for (height, stored_header) in self.headers.iter().enumerate() {
+        for (i, stored_header) in self.headers.iter().enumerate().skip(skip) {
             let expected = stored_header.hash.filter_header(&prev_header);
             let actual = stored_header.header;
 
@@ -121,7 +223,7 @@ impl<S> FilterCache<S> {
             }
             prev_header = actual;
 
-            if !progress(height as Height) {
+            if !progress(self.floor + i as Height, tip) {
                 return Err(store::Error::Interrupted);
             }
         }
@@ -132,17 +234,22 @@ impl<S> FilterCache<S> {
 #[allow(unused_variables)]
 impl<S: Store<Header = StoredHeader>> Filters for FilterCache<S> {
     fn get_header(&self, height: Height) -> Option<(FilterHash, FilterHeader)> {
-        self.headers
-            .get(height as usize)
-            .map(|s| (s.hash, s.header))
+        let ix = height.checked_sub(self.floor)?;
+
+        self.headers.get(ix as usize).map(|s| (s.hash, s.header))
     }
 
     fn get_headers(&self, range: RangeInclusive<Height>) -> Vec<(FilterHash, FilterHeader)> {
-        let (start, end) = (*range.start(), *range.end());
+        // Pruned headers are not returned.
+        let (start, end) = (Height::max(*range.start(), self.floor), *range.end());
+
+        if start > end {
+            return vec![];
+        }
 
         self.headers
             .iter()
-            .skip(start as usize)
+            .skip((start - self.floor) as usize)
             .take(end as usize - start as usize + 1)
             .map(|h| (h.hash, h.header))
             .collect()
@@ -157,7 +264,9 @@ impl<S: Store<Header = StoredHeader>> Filters for FilterCache<S> {
             .map(|(hash, header)| StoredHeader { hash, header });
 
         self.headers.tail.extend(iter.clone());
-        self.header_store.put(iter).map_err(Error::from)
+        self.header_store.put(iter)?;
+
+        Ok(self.height())
     }
 
     fn tip(&self) -> (&FilterHash, &FilterHeader) {
@@ -166,20 +275,114 @@ impl<S: Store<Header = StoredHeader>> Filters for FilterCache<S> {
     }
 
     fn height(&self) -> Height {
-        self.headers.tail.len() as Height
+        self.floor + self.headers.tail.len() as Height
     }
 
     fn rollback(&mut self, height: Height) -> Result<(), Error> {
-        self.header_store.rollback(height)?;
-        self.headers.tail.truncate(height as usize);
+        if height < self.floor {
+            return Err(Error::Pruned(height));
+        }
+        self.header_store.rollback(self.position(height))?;
+        self.headers.tail.truncate((height - self.floor) as usize);
 
         Ok(())
     }
 
     fn clear(&mut self) -> Result<(), Error> {
         self.header_store.rollback(0)?;
-        self.headers.tail.clear();
+        self.headers = NonEmpty::new(self.header_store.genesis());
+        self.floor = 0;
 
         Ok(())
     }
+
+    fn prune(&mut self, before: Height) -> Result<(), Error> {
+        if before <= self.floor {
+            return Ok(());
+        }
+        if before > self.height() {
+            return Err(Error::NotFound(before));
+        }
+        let remaining = self
+            .headers
+            .iter()
+            .skip((before - self.floor) as usize)
+            .copied()
+            .collect::<Vec<_>>();
+
+        // Rewrite the store, starting with the marker recording the new floor.
+        self.header_store.rollback(0)?;
+        self.header_store
+            .put(iter::once(StoredHeader::pruned(before)).chain(remaining.iter().copied()))?;
+        self.header_store.sync()?;
+
+        self.headers = NonEmpty::from_vec(remaining)
+            .expect("FilterCache::prune: the header at the new floor is always kept");
+        self.floor = before;
+
+        Ok(())
+    }
+
+    fn floor(&self) -> Height {
+        self.floor
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.header_store.sync().map_err(Error::from)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::*;
+    use crate::store::io::File;
+    use nakamoto_test::block::gen;
+
+    #[test]
+    fn test_prune() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("filters.db");
+        let network = Network::Regtest;
+        let genesis = StoredHeader::genesis(network);
+        let mut rng = fastrand::Rng::new();
+        let chain = gen::blockchain(network.genesis_block(), 16, &mut rng);
+        let headers = gen::cfheaders_from_blocks(FilterHeader::genesis(network), chain.tail.iter());
+
+        let mut cache = FilterCache::load(File::create(&path, genesis).unwrap()).unwrap();
+        cache.import_headers(headers.clone()).unwrap();
+        let size = fs::metadata(&path).unwrap().len();
+
+        cache.prune(10).unwrap();
+        assert_eq!(cache.floor(), 10);
+        assert_eq!(cache.height(), 16);
+        assert!(fs::metadata(&path).unwrap().len() < size);
+        assert!(matches!(cache.rollback(9), Err(Error::Pruned(9))));
+
+        // The pruned chain is restored from the store.
+        drop(cache);
+        let mut cache = FilterCache::load(File::open(&path, genesis).unwrap()).unwrap();
+        assert_eq!(cache.floor(), 10);
+        assert_eq!(cache.height(), 16);
+        assert_eq!(cache.get_header(9), None);
+        assert_eq!(cache.get_header(12), Some(headers[11]));
+        cache.verify(network).unwrap();
+
+        // Rolling back and extending the pruned chain is persisted too.
+        cache.rollback(12).unwrap();
+        assert_eq!(cache.import_headers(headers[12..].to_vec()).unwrap(), 16);
+        drop(cache);
+        let mut cache = FilterCache::load(File::open(&path, genesis).unwrap()).unwrap();
+        assert_eq!(cache.height(), 16);
+        assert_eq!(cache.get_header(16), Some(headers[15]));
+        cache.verify(network).unwrap();
+
+        // Clearing the chain removes the pruning marker.
+        cache.clear().unwrap();
+        drop(cache);
+        let cache = FilterCache::load(File::open(&path, genesis).unwrap()).unwrap();
+        assert_eq!(cache.floor(), 0);
+        assert_eq!(cache.height(), 0);
+    }
 }
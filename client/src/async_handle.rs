@@ -0,0 +1,252 @@
+//! Asynchronous wrapper around a client [`Handle`].
+//!
+//! Calls are run on a small, fixed pool of worker threads, using the regular blocking
+//! [`Handle`] methods, and their results are delivered through a [`Blocking`] future. This
+//! lets async applications call the client without blocking their executor.
+//!
+//! Calls that wait on the network, eg. [`AsyncHandle::wait_for_height`], are run on their
+//! own thread instead, so that they can't starve the pool.
+use std::any::Any;
+use std::future::Future;
+use std::net;
+use std::ops::RangeInclusive;
+use std::panic;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use nakamoto_common::bitcoin::{Block, Script, Transaction};
+use nakamoto_common::block::{BlockHash, BlockHeader, Height};
+use nakamoto_common::nonempty::NonEmpty;
+use nakamoto_p2p::fsm::fees::FeeRate;
+use nakamoto_p2p::fsm::SyncStatus;
+
+use crate::client::chan;
+use crate::handle::{Error, FilterRequest, Handle};
+
+/// Default number of worker threads used to run handle calls.
+///
+/// Waiting calls don't count towards this limit, as they run on their own thread.
+pub const DEFAULT_WORKERS: usize = 4;
+
+/// A call to run on a worker thread.
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed pool of worker threads, shared by all clones of an [`AsyncHandle`].
+/// Workers exit once the last handle is dropped and the pending calls are done.
+#[derive(Debug, Clone)]
+struct Pool {
+    jobs: chan::Sender<Job>,
+}
+
+impl Pool {
+    /// Spawn a pool with the given number of workers.
+    fn new(workers: usize) -> Self {
+        let (jobs, queue) = chan::unbounded::<Job>();
+
+        for _ in 0..workers.max(1) {
+            let queue = queue.clone();
+
+            thread::spawn(move || {
+                while let Ok(job) = queue.recv() {
+                    job();
+                }
+            });
+        }
+        Self { jobs }
+    }
+}
+
+/// Shared state between a [`Blocking`] future and the thread computing its output.
+#[derive(Debug)]
+struct State<T> {
+    /// Output of the call once it completes, or its panic payload if it panicked.
+    output: Option<Result<T, Box<dyn Any + Send>>>,
+    /// Waker of the task awaiting the output, if it was polled.
+    waker: Option<Waker>,
+}
+
+/// A future resolving to the output of a blocking call running on another thread.
+#[derive(Debug)]
+pub struct Blocking<T> {
+    state: Arc<Mutex<State<T>>>,
+}
+
+impl<T: Send + 'static> Blocking<T> {
+    /// Run the given function on one of the pool's workers.
+    fn spawn(pool: &Pool, f: impl FnOnce() -> T + Send + 'static) -> Self {
+        let (future, job) = Self::job(f);
+
+        pool.jobs
+            .send(job)
+            .expect("Blocking::spawn: workers are alive while the pool exists");
+
+        future
+    }
+
+    /// Run the given function on a dedicated thread.
+    fn spawn_thread(f: impl FnOnce() -> T + Send + 'static) -> Self {
+        let (future, job) = Self::job(f);
+
+        thread::spawn(job);
+
+        future
+    }
+
+    /// Create a future along with the job that completes it. A panic in the given
+    /// function is caught by the job, and resumed when the future is polled.
+    fn job(f: impl FnOnce() -> T + Send + 'static) -> (Self, Job) {
+        let state = Arc::new(Mutex::new(State {
+            output: None,
+            waker: None,
+        }));
+        let shared = state.clone();
+
+        let job: Job = Box::new(move || {
+            let output = panic::catch_unwind(panic::AssertUnwindSafe(f));
+            let mut state = shared.lock().unwrap_or_else(|e| e.into_inner());
+
+            state.output = Some(output);
+
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        });
+
+        (Self { state }, job)
+    }
+}
+
+impl<T> Future for Blocking<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+        match state.output.take() {
+            Some(Ok(output)) => return Poll::Ready(output),
+            Some(Err(payload)) => {
+                drop(state);
+                panic::resume_unwind(payload);
+            }
+            None => {}
+        }
+        state.waker = Some(cx.waker().clone());
+
+        Poll::Pending
+    }
+}
+
+/// Asynchronous client handle. Wraps a [`Handle`], and exposes its most common
+/// operations as futures. Other operations can be run with [`AsyncHandle::call`].
+///
+/// Calls beyond the number of workers are queued. Calls that wait on the network, eg.
+/// [`AsyncHandle::wait_for_height`], run on their own thread and never occupy a worker.
+///
+/// If a call panics, the panic is resumed when its future is polled.
+#[derive(Debug, Clone)]
+pub struct AsyncHandle<H> {
+    handle: H,
+    pool: Pool,
+}
+
+impl<H: Handle + 'static> AsyncHandle<H> {
+    /// Create a new asynchronous handle, with [`DEFAULT_WORKERS`] worker threads.
+    pub fn new(handle: H) -> Self {
+        Self::with_workers(handle, DEFAULT_WORKERS)
+    }
+
+    /// Create a new asynchronous handle, with the given number of worker threads.
+    pub fn with_workers(handle: H, workers: usize) -> Self {
+        Self {
+            handle,
+            pool: Pool::new(workers),
+        }
+    }
+
+    /// Get the underlying blocking handle.
+    pub fn handle(&self) -> &H {
+        &self.handle
+    }
+
+    /// Run any blocking handle operation on a worker thread, and await its result.
+    pub fn call<T, F>(&self, f: F) -> Blocking<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&H) -> T + Send + 'static,
+    {
+        let handle = self.handle.clone();
+
+        Blocking::spawn(&self.pool, move || f(&handle))
+    }
+
+    /// Run a blocking handle operation that may wait indefinitely, on its own thread.
+    fn wait<T, F>(&self, f: F) -> Blocking<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&H) -> T + Send + 'static,
+    {
+        let handle = self.handle.clone();
+
+        Blocking::spawn_thread(move || f(&handle))
+    }
+
+    /// See [`Handle::get_tip`].
+    pub async fn get_tip(&self) -> Result<(Height, BlockHeader), Error> {
+        self.call(|h| h.get_tip()).await
+    }
+
+    /// See [`Handle::get_sync_status`].
+    pub async fn get_sync_status(&self) -> Result<SyncStatus, Error> {
+        self.call(|h| h.get_sync_status()).await
+    }
+
+    /// See [`Handle::get_block`].
+    pub async fn get_block(&self, hash: BlockHash) -> Result<(), Error> {
+        self.call(move |h| h.get_block(&hash)).await
+    }
+
+    /// See [`Handle::get_filters`].
+    pub async fn get_filters(&self, range: RangeInclusive<Height>) -> Result<FilterRequest, Error> {
+        self.call(move |h| h.get_filters(range)).await
+    }
+
+    /// See [`Handle::get_filters_matching`].
+    pub async fn get_filters_matching(
+        &self,
+        range: RangeInclusive<Height>,
+        scripts: Vec<Script>,
+    ) -> Result<Vec<(Height, Block)>, Error> {
+        self.call(move |h| h.get_filters_matching(range, scripts))
+            .await
+    }
+
+    /// See [`Handle::submit_transaction`].
+    pub async fn submit_transaction(
+        &self,
+        tx: Transaction,
+    ) -> Result<NonEmpty<net::SocketAddr>, Error> {
+        self.call(move |h| h.submit_transaction(tx)).await
+    }
+
+    /// See [`Handle::estimate_fee`].
+    pub async fn estimate_fee(&self, target_blocks: u16) -> Result<Option<FeeRate>, Error> {
+        self.call(move |h| h.estimate_fee(target_blocks)).await
+    }
+
+    /// See [`Handle::wait_for_height`].
+    pub async fn wait_for_height(&self, height: Height) -> Result<BlockHash, Error> {
+        self.wait(move |h| h.wait_for_height(height)).await
+    }
+
+    /// See [`Handle::wait_for_filters`].
+    pub async fn wait_for_filters(&self, range: RangeInclusive<Height>) -> Result<(), Error> {
+        self.wait(move |h| h.wait_for_filters(range)).await
+    }
+
+    /// See [`Handle::flush`].
+    pub async fn flush(&self) -> Result<(), Error> {
+        self.call(|h| h.flush()).await
+    }
+}
@@ -1,12 +1,15 @@
 //! Core nakamoto client functionality. Wraps all the other modules under a unified
 //! interface.
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::env;
 use std::fs;
 use std::io;
 use std::net;
 use std::ops::RangeInclusive;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{self, SystemTime};
 
 pub use crossbeam_channel as chan;
@@ -16,53 +19,204 @@ use nakamoto_chain::filter;
 use nakamoto_chain::filter::cache::FilterCache;
 use nakamoto_chain::{block::cache::BlockCache, filter::BlockFilter};
 
+use nakamoto_common::bitcoin::consensus::params::Params;
 use nakamoto_common::bitcoin::network::constants::ServiceFlags;
 use nakamoto_common::bitcoin::network::message::NetworkMessage;
 use nakamoto_common::bitcoin::network::Address;
-use nakamoto_common::block::store::{Genesis as _, Store as _};
-use nakamoto_common::block::time::{AdjustedTime, RefClock};
-use nakamoto_common::block::tree::{self, BlockReader, ImportResult};
-use nakamoto_common::block::{BlockHash, BlockHeader, Height, Transaction};
+use nakamoto_common::bitcoin::{Script, Txid};
+use nakamoto_common::block::filter::{FilterHash, FilterHeader, Filters};
+use nakamoto_common::block::store::Store as _;
+use nakamoto_common::block::time::{AdjustedTime, Clock as _, LocalDuration, LocalTime, RefClock};
+use nakamoto_common::block::tree::{self, BlockReader, ImportSummary};
+use nakamoto_common::block::{Bits, BlockHash, BlockHeader, Height, Transaction, Work};
 use nakamoto_common::nonempty::NonEmpty;
 use nakamoto_common::p2p::peer::{Source, Store as _};
 
+pub use nakamoto_common::block::tree::{ChainTip, ChainTipStatus};
 pub use nakamoto_common::network::{Network, Services};
 pub use nakamoto_common::p2p::Domain;
 
 use nakamoto_p2p::fsm;
+use nakamoto_p2p::fsm::fees::FeeRate;
 
 pub use nakamoto_net::event;
+pub use nakamoto_net::event::{BufferPolicy, Lossy, Overflow};
+use nakamoto_net::socks5;
 pub use nakamoto_net::{Reactor, Waker};
-pub use nakamoto_p2p::fsm::{Command, CommandError, Hooks, Limits, Link, Peer};
-
-pub use crate::error::Error;
-pub use crate::event::{Event, Loading};
+pub use nakamoto_p2p::fsm::{
+    AddressFilter, Backoff, Bandwidth, ByteRate, Command, CommandError, ConnectionCounts,
+    FlushError, Health, Hooks, Limits, Link, Peer, PeerInfo, SyncStatus, Tips, Traffic,
+    VersionInfo,
+};
+
+pub use crate::error::{ConfigError, ConfigProblem, Error};
+pub use crate::event::{Direction, Event, EventKind, Loading, StoreKind};
 pub use crate::handle;
+pub use crate::mempool;
 pub use crate::peer;
+pub use crate::rescan;
 pub use crate::service::Service;
 pub use crate::spv;
+use crate::spv::utxos::{Utxo, Utxos};
+use crate::spv::Trackers;
+
+/// Maximum time to wait for a host name to be resolved through a proxy.
+pub const PROXY_RESOLVE_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+
+/// Capacity of the client's command queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandQueue {
+    /// The queue grows as needed.
+    Unbounded,
+    /// The queue holds at most the given number of commands. Commands sent while the
+    /// queue is full fail with [`handle::Error::QueueFull`].
+    Bounded(usize),
+}
+
+impl Default for CommandQueue {
+    fn default() -> Self {
+        Self::Unbounded
+    }
+}
+
+/// Chain parameters of a custom network, eg. a private regtest or signet fork with
+/// non-standard rules.
+#[derive(Debug, Clone)]
+pub struct CustomParams {
+    /// Consensus parameters.
+    pub params: Params,
+    /// Genesis block.
+    pub genesis: Block,
+    /// Block header checkpoints.
+    pub checkpoints: Vec<(Height, BlockHash)>,
+}
+
+impl From<Network> for CustomParams {
+    fn from(network: Network) -> Self {
+        Self {
+            params: network.params(),
+            genesis: network.genesis_block(),
+            checkpoints: network.checkpoints().collect(),
+        }
+    }
+}
 
 /// Client configuration.
 #[derive(Debug, Clone)]
 pub struct Config {
     /// Bitcoin network.
     pub network: Network,
+    /// Chain parameters overriding the ones of [`Config::network`]. The network's
+    /// magic, port and seeds are still used.
+    pub custom_params: Option<CustomParams>,
     /// Connect via these network domains, eg. IPv4, IPv6.
     pub domains: Vec<Domain>,
     /// Peers to connect to instead of using the peer discovery mechanism.
     pub connect: Vec<net::SocketAddr>,
+    /// DNS seeds to query for peer addresses when the address book is empty, instead of the
+    /// network's built-in seeds. Seeds are resolved on the network's default port.
+    pub dns_seeds: Option<Vec<String>>,
     /// Client listen addresses.
     pub listen: Vec<net::SocketAddr>,
+    /// Whether to bind the listen address with `SO_REUSEADDR`, so that it can be bound
+    /// again right after a restart, while old connections linger. Enabled by default.
+    pub reuse_address: bool,
     /// Client home path, where runtime data is stored, eg. block headers and filters.
     pub root: PathBuf,
+    /// Name of the data directory under the client home path. Defaults to `.nakamoto`
+    /// when unset.
+    pub data_dir: Option<PathBuf>,
     /// User agent string.
-    pub user_agent: &'static str,
-    /// Client hooks.
+    pub user_agent: Cow<'static, str>,
+    /// Client hooks. Protocol hooks are passed to the state machine, and
+    /// [`Hooks::on_block_matched`] to the event mapper, when the client is run.
     pub hooks: Hooks,
     /// Services offered by this node.
     pub services: ServiceFlags,
+    /// Services outbound peers are required to offer. Peers that don't advertise them
+    /// during the handshake are disconnected, unless they are whitelisted. Set to include
+    /// [`ServiceFlags::COMPACT_FILTERS`] to only connect to peers serving filters.
+    /// Defaults to [`ServiceFlags::NETWORK`].
+    pub required_services: ServiceFlags,
+    /// Whether peers should announce their unconfirmed transactions to us, via the `relay`
+    /// field of our `version` message. Light clients usually don't need mempool
+    /// transactions, so this defaults to `false`, which saves bandwidth.
+    ///
+    /// This doesn't affect our own transactions: transactions submitted with
+    /// [`handle::Handle::submit_transaction`] are announced to peers either way.
+    pub relay_transactions: bool,
+    /// Minimum fee rate of the transactions peers should announce to us, sent in a
+    /// `feefilter` message after the handshake. This cuts down on announcements of
+    /// transactions we'd ignore. Has no effect unless
+    /// [`Config::relay_transactions`] is enabled. Not set by default.
+    pub min_relay_feerate: Option<FeeRate>,
+    /// Whether to ask peers to announce new blocks with `headers` messages, rather than
+    /// `inv` messages that need an extra round-trip to fetch the headers. Enabled by default.
+    pub prefer_headers_announcements: bool,
     /// Configured limits.
     pub limits: Limits,
+    /// How long without a new block header before [`Event::TipStale`] is emitted.
+    pub stale_tip_timeout: LocalDuration,
+    /// Maximum number of blocks a re-org may disconnect from the active chain. Branches
+    /// forking off deeper than this are rejected with an [`Event::ReorgRejected`], even
+    /// if they have more work. Not set by default.
+    pub max_reorg_depth: Option<usize>,
+    /// Backoff policy for reconnecting to the [`Config::connect`] peers when they are
+    /// unreachable or disconnect.
+    pub reconnect_backoff: Backoff,
+    /// Time to wait for an outbound connection to be established. Connections that aren't
+    /// established in time are dropped, with a timeout reason, and another peer is tried.
+    /// This is unrelated to the timeout of handle operations, see [`Handle::set_timeout`].
+    pub connect_timeout: LocalDuration,
+    /// Maximum number of blocks requested at the same time, across all peers. Raising this
+    /// speeds up rescans that match many blocks.
+    pub block_download_concurrency: usize,
+    /// Number of times a block request is retried, with a different peer when possible,
+    /// if the peer doesn't deliver the block in time or disconnects. Once exhausted, the
+    /// block is dropped and an [`Event::BlockDownloadFailed`] is emitted.
+    pub block_request_retries: u8,
+    /// Whether to download blocks as `BIP152` compact blocks from peers that support them.
+    /// Compact blocks are reconstructed from the client's own unconfirmed transactions, and
+    /// the transactions relayed by peers if [`Config::relay_transactions`] is enabled,
+    /// falling back to the full block when transactions are missing. Disabled by default.
+    pub compact_blocks: bool,
+    /// Number of blocks after which an acknowledged transaction that hasn't been confirmed
+    /// or acknowledged again is considered dropped, and removed from the mempool and
+    /// transaction cache. Defaults to [`fsm::DEFAULT_DROP_WINDOW`].
+    pub drop_window: Height,
+    /// Whether to sync compact block filters. When disabled, the client only follows the
+    /// block header chain: no filter headers are stored, and peers aren't asked for filters.
+    /// Enabled by default.
+    pub sync_filters: bool,
+    /// Height from which compact block filters are fetched and matched, eg. a wallet's
+    /// birthday height. Filter headers below it are still synced and verified, but their
+    /// filters are never downloaded.
+    pub filter_sync_start: Option<Height>,
+    /// Maximum rate at which compact block filters are downloaded, eg. to limit bandwidth
+    /// usage on metered connections. Unlimited if not set.
+    pub filter_download_rate: Option<ByteRate>,
+    /// Wallet birthday, ie. the height of the first block that can involve the watched
+    /// scripts. Filters and blocks below it aren't processed.
+    pub wallet_birthday: Option<Height>,
+    /// Whether to persist submitted transactions until they are confirmed, so that they are
+    /// re-announced after a restart. Transactions that are dropped, or older than
+    /// [`mempool::DEFAULT_MAX_AGE`], are no longer persisted.
+    pub persist_mempool: bool,
+    /// Whether to persist the progress of filter rescans, so that an interrupted rescan
+    /// automatically continues where it left off after a restart.
+    pub persist_rescan: bool,
+    /// Whether to capture all network messages sent and received, so that they can be
+    /// streamed via [`handle::Handle::subscribe_messages`]. Disabled by default.
+    pub capture_messages: bool,
+    /// Capacity of the command queue. Set when the client is created with
+    /// [`Client::with_config`]; running the client with a different queue fails with
+    /// [`ConfigProblem::CommandQueueMismatch`].
+    pub command_queue: CommandQueue,
+    /// Whether to include a merkle proof in [`spv::TxStatus::Confirmed`] statuses, which
+    /// can be verified independently of the client. Disabled by default.
+    pub include_merkle_proofs: bool,
+    /// SOCKS5 proxy to route outbound connections and DNS seed lookups through, eg. Tor.
+    pub proxy: Option<net::SocketAddr>,
 }
 
 impl Config {
@@ -74,6 +228,71 @@ impl Config {
         }
     }
 
+    /// Set the target number of outbound peer connections.
+    ///
+    /// Must be at least one, otherwise the configuration is rejected by [`Config::validate`].
+    pub fn with_max_outbound(mut self, n: usize) -> Self {
+        self.limits.max_outbound_peers = n;
+        self
+    }
+
+    /// Set the maximum number of inbound peer connections. Inbound connections past this
+    /// limit are rejected.
+    pub fn with_max_inbound(mut self, n: usize) -> Self {
+        self.limits.max_inbound_peers = n;
+        self
+    }
+
+    /// Set the services outbound peers are required to offer.
+    pub fn with_required_services(mut self, services: ServiceFlags) -> Self {
+        self.required_services = services;
+        self
+    }
+
+    /// Set whether peers should announce their unconfirmed transactions to us.
+    pub fn with_relay_transactions(mut self, relay: bool) -> Self {
+        self.relay_transactions = relay;
+        self
+    }
+
+    /// Set the minimum fee rate of the transactions peers should announce to us.
+    /// See [`Config::min_relay_feerate`].
+    pub fn with_min_relay_feerate(mut self, rate: FeeRate) -> Self {
+        self.min_relay_feerate = Some(rate);
+        self
+    }
+
+    /// Set the time to wait for outbound connections to be established.
+    pub fn with_connect_timeout(mut self, timeout: LocalDuration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Set the maximum re-org depth accepted. See [`Config::max_reorg_depth`].
+    pub fn with_max_reorg_depth(mut self, depth: usize) -> Self {
+        self.max_reorg_depth = Some(depth);
+        self
+    }
+
+    /// Set the number of blocks after which unacknowledged transactions are dropped.
+    /// See [`Config::drop_window`].
+    pub fn with_drop_window(mut self, blocks: Height) -> Self {
+        self.drop_window = blocks;
+        self
+    }
+
+    /// Limit the rate at which compact block filters are downloaded.
+    pub fn with_filter_download_rate(mut self, rate: ByteRate) -> Self {
+        self.filter_download_rate = Some(rate);
+        self
+    }
+
+    /// Set the user agent string sent to peers, eg. to include the application version.
+    pub fn with_user_agent(mut self, user_agent: impl Into<Cow<'static, str>>) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+
     /// Add seeds to connect to.
     pub fn seed<T: net::ToSocketAddrs + std::fmt::Debug>(&mut self, seeds: &[T]) -> io::Result<()> {
         let connect = seeds
@@ -88,41 +307,155 @@ impl Config {
 
         Ok(())
     }
+
+    /// Check the configuration for conflicting or unusable options.
+    ///
+    /// Returns an error listing every problem found.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        if self.domains.is_empty() {
+            problems.push(ConfigProblem::NoDomains);
+        }
+        if self.limits.max_outbound_peers == 0 {
+            problems.push(ConfigProblem::NoOutboundPeers);
+        } else if self.connect.len() > self.limits.max_outbound_peers {
+            problems.push(ConfigProblem::TooManyConnectPeers {
+                connect: self.connect.len(),
+                max: self.limits.max_outbound_peers,
+            });
+        }
+        if !self.connect.is_empty() && self.dns_seeds.is_some() {
+            problems.push(ConfigProblem::ConnectWithDnsSeeds);
+        }
+        if self.required_services.has(ServiceFlags::COMPACT_FILTERS) {
+            if self.limits.filter_cache_size == 0 {
+                problems.push(ConfigProblem::FilterCacheDisabled);
+            }
+            if !self.sync_filters {
+                problems.push(ConfigProblem::FilterSyncDisabled);
+            }
+        }
+        if let Some(seeds) = &self.dns_seeds {
+            if seeds.iter().any(|s| s.trim().is_empty()) {
+                problems.push(ConfigProblem::EmptyDnsSeed);
+            }
+        }
+        if self.reconnect_backoff.initial > self.reconnect_backoff.max
+            || self.reconnect_backoff.multiplier < 1.
+        {
+            problems.push(ConfigProblem::InvalidBackoff);
+        }
+        if self.block_download_concurrency == 0 {
+            problems.push(ConfigProblem::NoBlockDownloadConcurrency);
+        }
+        if self.command_queue == CommandQueue::Bounded(0) {
+            problems.push(ConfigProblem::EmptyCommandQueue);
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError(problems))
+        }
+    }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             network: Network::default(),
+            custom_params: None,
             connect: Vec::new(),
+            dns_seeds: None,
             domains: Domain::all(),
             listen: vec![([0, 0, 0, 0], 0).into()],
+            reuse_address: true,
             root: PathBuf::from(env::var("HOME").unwrap_or_default()),
-            user_agent: fsm::USER_AGENT,
+            data_dir: None,
+            user_agent: Cow::Borrowed(fsm::USER_AGENT),
             hooks: Hooks::default(),
             limits: Limits::default(),
+            stale_tip_timeout: fsm::DEFAULT_STALE_TIP_TIMEOUT,
+            max_reorg_depth: None,
+            reconnect_backoff: Backoff::default(),
+            connect_timeout: fsm::CONNECTION_TIMEOUT,
+            block_download_concurrency: fsm::DEFAULT_BLOCK_DOWNLOAD_CONCURRENCY,
+            block_request_retries: fsm::DEFAULT_BLOCK_REQUEST_RETRIES,
+            compact_blocks: false,
+            drop_window: fsm::DEFAULT_DROP_WINDOW,
+            sync_filters: true,
+            filter_sync_start: None,
+            filter_download_rate: None,
+            wallet_birthday: None,
             services: ServiceFlags::NONE,
+            required_services: ServiceFlags::NETWORK,
+            relay_transactions: false,
+            min_relay_feerate: None,
+            prefer_headers_announcements: true,
+            persist_mempool: true,
+            persist_rescan: true,
+            capture_messages: false,
+            command_queue: CommandQueue::default(),
+            include_merkle_proofs: false,
+            proxy: None,
         }
     }
 }
 
-/// The client's event publisher.
+/// Identifies a publisher registered with [`Publisher::register`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SubscriptionId(u64);
+
+/// A registered publisher.
+type Registered<E> = (SubscriptionId, Box<dyn nakamoto_net::Publisher<E>>);
+
+/// The client's event publisher. Clones share the registered publishers, so that
+/// publishers can be registered and unregistered from handles while the client runs.
 pub struct Publisher<E> {
-    publishers: Vec<Box<dyn nakamoto_net::Publisher<E>>>,
+    publishers: Arc<Mutex<Vec<Registered<E>>>>,
+    /// Identifier of the next registered publisher.
+    next_id: Arc<AtomicU64>,
 }
 
 impl<E> Publisher<E> {
-    /// Register a publisher.
-    pub fn register(mut self, publisher: impl nakamoto_net::Publisher<E> + 'static) -> Self {
-        self.publishers.push(Box::new(publisher));
-        self
+    /// Register a publisher. Returns an identifier that can be used to unregister it.
+    pub fn register(&self, publisher: impl nakamoto_net::Publisher<E> + 'static) -> SubscriptionId {
+        let id = SubscriptionId(self.next_id.fetch_add(1, Ordering::Relaxed));
+
+        self.publishers
+            .lock()
+            .unwrap()
+            .push((id, Box::new(publisher)));
+
+        id
+    }
+
+    /// Unregister a publisher, so that it no longer receives events.
+    /// Returns `false` if no publisher with the given identifier was registered.
+    pub fn unregister(&self, id: SubscriptionId) -> bool {
+        let mut publishers = self.publishers.lock().unwrap();
+        let len = publishers.len();
+        publishers.retain(|(i, _)| *i != id);
+
+        publishers.len() < len
+    }
+}
+
+impl<E> Clone for Publisher<E> {
+    fn clone(&self) -> Self {
+        Self {
+            publishers: self.publishers.clone(),
+            next_id: self.next_id.clone(),
+        }
     }
 }
 
 impl<E> Default for Publisher<E> {
     fn default() -> Self {
         Self {
-            publishers: Vec::new(),
+            publishers: Arc::new(Mutex::new(Vec::new())),
+            next_id: Arc::new(AtomicU64::new(0)),
         }
     }
 }
@@ -132,24 +465,83 @@ where
     E: Clone,
 {
     fn publish(&mut self, e: E) {
-        for p in self.publishers.iter_mut() {
+        for (_, p) in self.publishers.lock().unwrap().iter_mut() {
             p.publish(e.clone());
         }
     }
 }
 
+/// DNS seeds of a running client.
+#[derive(Debug, Clone)]
+struct DnsSeeds {
+    /// Seed host names.
+    hosts: Vec<String>,
+    /// Port of the seeded peers.
+    port: u16,
+    /// Proxy to resolve seeds through, if any.
+    proxy: Option<net::SocketAddr>,
+}
+
+impl DnsSeeds {
+    /// Resolve the seeds to peer addresses. If a proxy is set, seeds are resolved
+    /// through it, to avoid leaking DNS requests. Fails if *none* of the seeds resolve.
+    fn resolve(&self) -> io::Result<Vec<net::SocketAddr>> {
+        use std::net::ToSocketAddrs as _;
+
+        let mut addrs = Vec::new();
+        let mut error = None;
+
+        for host in &self.hosts {
+            let result = match self.proxy {
+                Some(proxy) => socks5::resolve(&proxy, host, PROXY_RESOLVE_TIMEOUT)
+                    .map(|ip| vec![net::SocketAddr::new(ip, self.port)]),
+                None => (host.as_str(), self.port)
+                    .to_socket_addrs()
+                    .map(|a| a.collect()),
+            };
+            match result {
+                Ok(resolved) => addrs.extend(resolved),
+                Err(err) => {
+                    log::warn!("Failed to resolve seed {}: {}", host, err);
+                    error = Some(err);
+                }
+            }
+        }
+
+        match error {
+            Some(err) if addrs.is_empty() => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("seeds failed to resolve: {}", err),
+            )),
+            _ => Ok(addrs),
+        }
+    }
+}
+
 /// A light-client process.
 pub struct Client<R: Reactor> {
     handle: chan::Sender<Command>,
     commands: chan::Receiver<Command>,
+    command_queue: CommandQueue,
     events: event::Subscriber<fsm::Event>,
     blocks: event::Subscriber<(Block, Height)>,
     filters: event::Subscriber<(BlockFilter, BlockHash, Height)>,
+    messages: event::Subscriber<(net::SocketAddr, Direction, NetworkMessage)>,
     loading: event::Subscriber<Loading>,
     subscriber: event::Subscriber<Event>,
+    /// Maps protocol events to client events. Configured once the client is run.
+    spv: Arc<Mutex<spv::Mapper>>,
+    utxos: Arc<RwLock<Utxos>>,
+    statuses: Arc<RwLock<HashMap<Txid, spv::TxStatus>>>,
+    trackers: Trackers,
     shutdown: chan::Sender<()>,
+    /// Dropped once the client has stopped, to signal handles waiting on shutdown.
+    stopped: chan::Sender<()>,
+    stopped_recv: chan::Receiver<()>,
     listening: chan::Receiver<net::SocketAddr>,
-    seeds: Vec<net::SocketAddr>,
+    /// DNS seeds, set once the client is run.
+    dns_seeds: Arc<RwLock<Option<DnsSeeds>>>,
+    seeds: Vec<(net::SocketAddr, ServiceFlags)>,
     publisher: Publisher<fsm::Event>,
 
     reactor: R,
@@ -161,7 +553,16 @@ where
 {
     /// Create a new client.
     pub fn new() -> Result<Self, Error> {
-        let (handle, commands) = chan::unbounded::<Command>();
+        Self::with_config(&Config::default())
+    }
+
+    /// Create a new client, using the options of the given configuration that apply before
+    /// the client is run, eg. [`Config::command_queue`].
+    pub fn with_config(config: &Config) -> Result<Self, Error> {
+        let (handle, commands) = match config.command_queue {
+            CommandQueue::Unbounded => chan::unbounded::<Command>(),
+            CommandQueue::Bounded(cap) => chan::bounded::<Command>(cap),
+        };
         let (event_pub, events) = event::broadcast(|e, p| p.emit(e));
         let (blocks_pub, blocks) = event::broadcast(|e, p| {
             if let fsm::Event::Inventory(fsm::InventoryEvent::BlockProcessed {
@@ -184,20 +585,32 @@ where
                 p.emit((filter, block_hash, height));
             }
         });
-        let (publisher, subscriber) = event::broadcast({
-            let mut spv = spv::Mapper::new();
-            move |e, p| spv.process(e, p)
+        let (messages_pub, messages) = event::broadcast(|e, p| match e {
+            fsm::Event::Received(addr, msg) => p.emit((addr, Direction::Inbound, msg)),
+            fsm::Event::Sent(addr, msg) => p.emit((addr, Direction::Outbound, msg)),
+            _ => {}
+        });
+        let spv = spv::Mapper::new();
+        let utxos = spv.utxos();
+        let statuses = spv.statuses();
+        let trackers = spv.trackers();
+        let spv = Arc::new(Mutex::new(spv));
+        let (spv_pub, subscriber) = event::broadcast({
+            let spv = spv.clone();
+            move |e, p| spv.lock().unwrap().process(e, p)
         });
 
-        let publisher = Publisher::default()
-            .register(event_pub)
-            .register(blocks_pub)
-            .register(filters_pub)
-            .register(publisher);
+        let publisher = Publisher::default();
+        publisher.register(event_pub);
+        publisher.register(blocks_pub);
+        publisher.register(filters_pub);
+        publisher.register(messages_pub);
+        publisher.register(spv_pub);
 
         let seeds = Vec::new();
         let loading = event::Subscriber::default();
         let (shutdown, shutdown_recv) = chan::bounded(1);
+        let (stopped, stopped_recv) = chan::bounded(0);
         let (listening_send, listening) = chan::bounded(1);
         let reactor = R::new(shutdown_recv, listening_send)?;
 
@@ -206,13 +619,22 @@ where
             loading,
             handle,
             commands,
+            command_queue: config.command_queue,
             reactor,
             blocks,
             filters,
+            messages,
             subscriber,
+            spv,
+            utxos,
+            statuses,
+            trackers,
             publisher,
             seeds,
+            dns_seeds: Arc::new(RwLock::new(None)),
             shutdown,
+            stopped,
+            stopped_recv,
             listening,
         })
     }
@@ -221,25 +643,100 @@ where
     pub fn seed<S: net::ToSocketAddrs>(&mut self, seeds: Vec<S>) -> Result<(), Error> {
         for seed in seeds.into_iter() {
             let addrs = seed.to_socket_addrs()?;
-            self.seeds.extend(addrs);
+            self.seeds
+                .extend(addrs.map(|addr| (addr, ServiceFlags::NONE)));
         }
         Ok(())
     }
 
+    /// Seed the client's address book with peer addresses and their expected services.
+    /// This allows peers with the required services, eg. compact filters, to be
+    /// preferred from the first connection attempt.
+    pub fn seed_with_services(&mut self, seeds: Vec<(net::SocketAddr, ServiceFlags)>) {
+        self.seeds.extend(seeds);
+    }
+
     /// Start the client process. This function is meant to be run in its own thread.
-    pub fn run(mut self, config: Config) -> Result<(), Error> {
-        let home = config.root.join(".nakamoto");
+    pub fn run(self, config: Config) -> Result<(), Error> {
+        let local_time = SystemTime::now().into();
+        let clock = AdjustedTime::<net::SocketAddr>::new(local_time);
+
+        self.start(config, clock.into())
+    }
+
+    /// Start the client process with the given clock, instead of the system clock. This
+    /// allows time-dependent behavior, eg. timeouts and backoffs, to be reproduced in tests.
+    /// The reactor takes its time from the clock too, so timeouts fire as the clock is
+    /// advanced, eg. from another thread via a clone of the clock.
+    /// This function is meant to be run in its own thread.
+    pub fn run_with_clock(
+        mut self,
+        config: Config,
+        clock: impl Into<RefClock<AdjustedTime<net::SocketAddr>>>,
+    ) -> Result<(), Error> {
+        let clock = clock.into();
+
+        self.reactor.set_clock(Arc::new({
+            let clock = clock.clone();
+            move || clock.local_time()
+        }))?;
+        self.start(config, clock)
+    }
+
+    /// Start the client process with the given clock.
+    fn start(
+        mut self,
+        config: Config,
+        clock: RefClock<AdjustedTime<net::SocketAddr>>,
+    ) -> Result<(), Error> {
+        config.validate()?;
+
+        if config.command_queue != self.command_queue {
+            return Err(ConfigError(vec![ConfigProblem::CommandQueueMismatch {
+                created: self.command_queue,
+                configured: config.command_queue,
+            }])
+            .into());
+        }
+
+        {
+            // The mapper is built from the configuration the client is run with, and keeps
+            // the state shared with handles.
+            let mut spv = self.spv.lock().unwrap();
+            let mapper = spv::Mapper::new()
+                .with_merkle_proofs(config.include_merkle_proofs)
+                .with_filter_sync_start(config.filter_sync_start.unwrap_or_default())
+                .with_birthday(config.wallet_birthday.unwrap_or_default())
+                .with_max_reorg_depth(config.max_reorg_depth.map(|d| d as Height))
+                .with_on_block_matched(config.hooks.on_block_matched.clone())
+                .with_shared(&spv);
+
+            *spv = mapper;
+        }
+
+        let data_dir = config
+            .data_dir
+            .as_deref()
+            .unwrap_or_else(|| Path::new(".nakamoto"));
+        let home = config.root.join(data_dir);
         let network = config.network;
         let dir = home.join(network.as_str());
-        let listen = config.listen.clone();
 
         fs::create_dir_all(&dir)?;
 
-        let genesis = network.genesis();
-        let params = network.params();
+        let CustomParams {
+            params,
+            genesis,
+            checkpoints,
+        } = config
+            .custom_params
+            .clone()
+            .unwrap_or_else(|| CustomParams::from(network));
+        let cfheaders_genesis = filter::cache::StoredHeader::from_genesis(&genesis);
+        let genesis = genesis.header;
 
         log::info!("Initializing client ({:?})..", network);
-        log::info!("Genesis block hash is {}", network.genesis_hash());
+        log::info!("Genesis block hash is {}", genesis.block_hash());
 
         let path = dir.join("headers.db");
         let store = match store::File::create(&path, genesis) {
@@ -253,7 +750,14 @@ where
 
                 if store.check().is_err() {
                     log::warn!("Corruption detected in header store, healing..");
-                    store.heal()?; // Rollback store to the last valid header.
+
+                    // Rollback store to the last valid header.
+                    let (from, to) = store.heal()?;
+                    self.subscriber.publish(Event::StoreHealed {
+                        kind: StoreKind::BlockHeaders,
+                        from,
+                        to,
+                    });
                 }
                 log::info!("Store height = {}", store.height()?);
 
@@ -262,19 +766,32 @@ where
             Err(err) => return Err(err.into()),
         };
 
-        let local_time = SystemTime::now().into();
-        let checkpoints = network.checkpoints().collect::<Vec<_>>();
-        let clock = AdjustedTime::<net::SocketAddr>::new(local_time);
-        let rng = fastrand::Rng::new();
-
         log::info!("Loading block headers from store..");
 
         let cache = BlockCache::new(store, params, &checkpoints)?
-            .load_with(|height| self.loading.publish(Loading::BlockHeaderLoaded { height }))?;
+            .with_max_reorg_depth(config.max_reorg_depth)
+            .load_with(|height, tip| {
+                self.loading
+                    .publish(Loading::BlockHeaderLoaded { height, tip })
+            })?;
+
+        if !config.sync_filters {
+            log::info!("Filter sync is disabled, skipping block filters..");
+
+            let filters = FilterCache::load(store::Memory::new(NonEmpty::new(cfheaders_genesis)))?;
+
+            // Loading is done, close all channels.
+            self.loading.publish(Loading::Done {
+                tip: cache.height(),
+                filter_tip: cache.height(),
+            });
+            self.loading.close();
+
+            return self.run_service(&dir, cache, filters, clock, config);
+        }
 
         log::info!("Initializing block filters..");
 
-        let cfheaders_genesis = filter::cache::StoredHeader::genesis(network);
         let cfheaders_path = dir.join("filters.db");
         let cfheaders_store = match store::File::create(&cfheaders_path, cfheaders_genesis) {
             Ok(store) => {
@@ -287,7 +804,14 @@ where
 
                 if store.check().is_err() {
                     log::warn!("Corruption detected in filter store, healing..");
-                    store.heal()?; // Rollback store to the last valid header.
+
+                    // Rollback store to the last valid header.
+                    let (from, to) = store.heal()?;
+                    self.subscriber.publish(Event::StoreHealed {
+                        kind: StoreKind::FilterHeaders,
+                        from,
+                        to,
+                    });
                 }
                 log::info!("Filters height = {}", store.height()?);
 
@@ -297,19 +821,41 @@ where
         };
         log::info!("Loading filter headers from store..");
 
-        let filters = FilterCache::load_with(cfheaders_store, |height| {
-            self.loading.publish(Loading::FilterHeaderLoaded { height })
+        let filters = FilterCache::load_with(cfheaders_store, |height, tip| {
+            self.loading
+                .publish(Loading::FilterHeaderLoaded { height, tip })
         })?;
         log::info!("Verifying filter headers..");
 
-        filters.verify_with(network, |height| {
+        filters.verify_from(cfheaders_genesis.header, |height, tip| {
             self.loading
-                .publish(Loading::FilterHeaderVerified { height })
+                .publish(Loading::FilterHeaderVerified { height, tip })
         })?; // Verify store integrity.
 
         // Loading is done, close all channels.
+        self.loading.publish(Loading::Done {
+            tip: cache.height(),
+            filter_tip: filters.height(),
+        });
         self.loading.close();
 
+        self.run_service(&dir, cache, filters, clock, config)
+    }
+
+    /// Load the peer address book and run the client service with the given
+    /// block headers and filters, until the client is shut down.
+    fn run_service<F: Filters>(
+        mut self,
+        dir: &Path,
+        cache: BlockCache<store::File<BlockHeader>>,
+        filters: F,
+        clock: RefClock<AdjustedTime<net::SocketAddr>>,
+        config: Config,
+    ) -> Result<(), Error> {
+        let network = config.network;
+        let listen = config.listen.clone();
+        let rng = fastrand::Rng::new();
+
         log::info!("Loading peer addresses..");
 
         let peers_path = dir.join("peers.json");
@@ -340,23 +886,89 @@ where
 
         log::trace!("{:#?}", peers);
 
+        let dns_seeds = DnsSeeds {
+            hosts: match &config.dns_seeds {
+                Some(seeds) => seeds.clone(),
+                None => network.seeds().iter().map(|s| s.to_string()).collect(),
+            },
+            port: network.port(),
+            proxy: config.proxy,
+        };
+        // Shared with handles, to re-seed the address book.
+        *self.dns_seeds.write().unwrap() = Some(dns_seeds.clone());
+
         if config.connect.is_empty() && peers.is_empty() {
-            log::info!("Address book is empty. Trying DNS seeds..");
-            peers.seed(
-                network.seeds().iter().map(|s| (*s, network.port())),
-                Source::Dns,
-            )?;
+            if let Some(proxy) = config.proxy {
+                log::info!("Address book is empty. Trying DNS seeds via {}..", proxy);
+            } else {
+                log::info!("Address book is empty. Trying DNS seeds..");
+            }
+            peers.seed(dns_seeds.resolve()?.into_iter(), Source::Dns)?;
             peers.flush()?;
 
             log::info!("{} seeds added to address book", peers.len());
         }
 
-        self.reactor.run(
-            &listen,
-            Service::new(cache, filters, peers, RefClock::from(clock), rng, config),
-            self.publisher,
-            self.commands,
-        )?;
+        if !self.seeds.is_empty() {
+            log::info!("Adding {} seed(s) to address book", self.seeds.len());
+
+            peers.seed_with_services(self.seeds.drain(..), Source::Imported);
+            peers.flush()?;
+        }
+
+        if let Some(proxy) = config.proxy {
+            log::info!("Connecting to peers via proxy {}..", proxy);
+            self.reactor.set_proxy(proxy)?;
+        }
+        self.reactor.set_reuse_address(config.reuse_address)?;
+
+        let persist_mempool = config.persist_mempool;
+        let persist_rescan = config.persist_rescan;
+        let mut service = Service::new(cache, filters, peers, clock, rng, config);
+
+        if persist_mempool {
+            let txs_path = dir.join("txs.db");
+            let txs = match mempool::Cache::create(&txs_path) {
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    log::info!("Found existing transaction cache {:?}", txs_path);
+                    let mut cache = mempool::Cache::open(&txs_path)?;
+                    let expired = cache.expire(mempool::DEFAULT_MAX_AGE, LocalTime::now())?;
+
+                    log::info!("{} unconfirmed transaction(s) found", cache.len());
+
+                    if !expired.is_empty() {
+                        log::info!("{} expired transaction(s) removed", expired.len());
+                    }
+
+                    cache
+                }
+                Err(err) => return Err(err.into()),
+                Ok(cache) => {
+                    log::info!("Initializing new transaction cache {:?}", txs_path);
+                    cache
+                }
+            };
+            service = service.with_mempool(txs);
+        }
+
+        if persist_rescan {
+            let rescan_path = dir.join("rescan.json");
+            let rescan = match rescan::Cache::create(&rescan_path) {
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    log::info!("Found existing rescan state {:?}", rescan_path);
+                    rescan::Cache::open(&rescan_path)?
+                }
+                Err(err) => return Err(err.into()),
+                Ok(cache) => {
+                    log::info!("Initializing new rescan state {:?}", rescan_path);
+                    cache
+                }
+            };
+            service = service.with_rescan(rescan);
+        }
+
+        self.reactor
+            .run(&listen, service, self.publisher, self.commands)?;
 
         Ok(())
     }
@@ -377,6 +989,21 @@ where
         Ok(())
     }
 
+    /// Register a publisher to receive the protocol events, eg. an [`event::Broadcast`].
+    /// Returns an identifier that can be used to unregister it.
+    pub fn register_publisher(
+        &self,
+        publisher: impl event::Publisher<fsm::Event> + 'static,
+    ) -> SubscriptionId {
+        self.publisher.register(publisher)
+    }
+
+    /// Unregister a publisher, so that it no longer receives events.
+    /// Returns `false` if no publisher with the given identifier was registered.
+    pub fn unregister_publisher(&self, id: SubscriptionId) -> bool {
+        self.publisher.unregister(id)
+    }
+
     /// Create a new handle to communicate with the client.
     pub fn handle(&self) -> Handle<R::Waker> {
         Handle {
@@ -387,9 +1014,16 @@ where
             loading: self.loading.clone(),
             blocks: self.blocks.clone(),
             filters: self.filters.clone(),
+            messages: self.messages.clone(),
             subscriber: self.subscriber.clone(),
+            utxos: self.utxos.clone(),
+            statuses: self.statuses.clone(),
+            trackers: self.trackers.clone(),
             shutdown: self.shutdown.clone(),
+            stopped: self.stopped_recv.clone(),
             listening: self.listening.clone(),
+            dns_seeds: self.dns_seeds.clone(),
+            publisher: self.publisher.clone(),
         }
     }
 }
@@ -400,12 +1034,19 @@ pub struct Handle<W: Waker> {
     events: event::Subscriber<fsm::Event>,
     blocks: event::Subscriber<(Block, Height)>,
     filters: event::Subscriber<(BlockFilter, BlockHash, Height)>,
+    messages: event::Subscriber<(net::SocketAddr, Direction, NetworkMessage)>,
     loading: event::Subscriber<Loading>,
     subscriber: event::Subscriber<Event>,
+    utxos: Arc<RwLock<Utxos>>,
+    statuses: Arc<RwLock<HashMap<Txid, spv::TxStatus>>>,
+    trackers: Trackers,
     waker: W,
     timeout: time::Duration,
     shutdown: chan::Sender<()>,
+    stopped: chan::Receiver<()>,
     listening: chan::Receiver<net::SocketAddr>,
+    dns_seeds: Arc<RwLock<Option<DnsSeeds>>>,
+    publisher: Publisher<fsm::Event>,
 }
 
 impl<W: Waker> Clone for Handle<W> {
@@ -415,12 +1056,19 @@ impl<W: Waker> Clone for Handle<W> {
             commands: self.commands.clone(),
             events: self.events.clone(),
             filters: self.filters.clone(),
+            messages: self.messages.clone(),
             subscriber: self.subscriber.clone(),
+            utxos: self.utxos.clone(),
+            statuses: self.statuses.clone(),
+            trackers: self.trackers.clone(),
             loading: self.loading.clone(),
             timeout: self.timeout,
             waker: self.waker.clone(),
             shutdown: self.shutdown.clone(),
+            stopped: self.stopped.clone(),
             listening: self.listening.clone(),
+            dns_seeds: self.dns_seeds.clone(),
+            publisher: self.publisher.clone(),
         }
     }
 }
@@ -444,6 +1092,19 @@ impl<W: Waker> Handle<W> {
         Ok(recvr.recv()?)
     }
 
+    /// Get the tip of the chain, without blocking indefinitely on the event loop.
+    /// Returns `None` if no reply is received within the configured timeout.
+    pub fn try_get_tip(&self) -> Result<Option<(Height, BlockHeader)>, handle::Error> {
+        let (transmit, receive) = chan::bounded::<(Height, BlockHeader)>(1);
+        self._command(Command::GetTip(transmit))?;
+
+        match receive.recv_timeout(self.timeout) {
+            Ok(tip) => Ok(Some(tip)),
+            Err(chan::RecvTimeoutError::Timeout) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
     /// Get block by height.
     pub fn get_block_by_height(
         &self,
@@ -455,9 +1116,28 @@ impl<W: Waker> Handle<W> {
         Ok(recvr.recv()?)
     }
 
+    /// Register a publisher to receive the protocol events, eg. an [`event::Broadcast`].
+    /// This can be done while the client is running. Returns an identifier that can be
+    /// used to unregister it.
+    pub fn register_publisher(
+        &self,
+        publisher: impl event::Publisher<fsm::Event> + 'static,
+    ) -> SubscriptionId {
+        self.publisher.register(publisher)
+    }
+
+    /// Unregister a publisher, so that it no longer receives events, eg. once a consumer
+    /// is done. Returns `false` if no publisher with the given identifier was registered.
+    pub fn unregister_publisher(&self, id: SubscriptionId) -> bool {
+        self.publisher.unregister(id)
+    }
+
     /// Send a command to the command channel, and wake up the event loop.
     fn _command(&self, cmd: Command) -> Result<(), handle::Error> {
-        self.commands.send(cmd)?;
+        self.commands.try_send(cmd).map_err(|err| match err {
+            chan::TrySendError::Full(_) => handle::Error::QueueFull,
+            chan::TrySendError::Disconnected(_) => handle::Error::Disconnected,
+        })?;
         self.waker.wake()?;
 
         Ok(())
@@ -466,18 +1146,13 @@ impl<W: Waker> Handle<W> {
 
 impl<W: Waker> handle::Handle for Handle<W> {
     fn get_tip(&self) -> Result<(Height, BlockHeader), handle::Error> {
-        let (transmit, receive) = chan::bounded::<(Height, BlockHeader)>(1);
-        self.command(Command::GetTip(transmit))?;
-
-        Ok(receive.recv()?)
+        self.try_get_tip()?.ok_or(handle::Error::Timeout)
     }
 
     fn query_tree(
         &self,
         query: impl Fn(&dyn BlockReader) + Send + Sync + 'static,
     ) -> Result<(), handle::Error> {
-        use std::sync::Arc;
-
         self.command(Command::QueryTree(Arc::new(query)))?;
 
         Ok(())
@@ -497,13 +1172,103 @@ impl<W: Waker> handle::Handle for Handle<W> {
         Ok(receive.recv()?)
     }
 
+    fn get_header_by_hash(
+        &self,
+        hash: &BlockHash,
+    ) -> Result<Option<(Height, BlockHeader)>, handle::Error> {
+        let hash = *hash;
+        let (transmit, receive) = chan::bounded(1);
+
+        self.query_tree(move |t| {
+            transmit
+                .send(t.get_block(&hash).map(|(height, header)| (height, *header)))
+                .ok();
+        })?;
+
+        Ok(receive.recv()?)
+    }
+
+    fn get_block_height(&self, hash: &BlockHash) -> Result<Option<Height>, handle::Error> {
+        let hash = *hash;
+        let (transmit, receive) = chan::bounded(1);
+
+        self.query_tree(move |t| {
+            let height = t.get_block(&hash).and_then(|(height, _)| {
+                // Make sure the block is still on the active chain.
+                t.get_block_by_height(height)
+                    .filter(|h| h.block_hash() == hash)
+                    .map(|_| height)
+            });
+            transmit.send(height).ok();
+        })?;
+
+        Ok(receive.recv()?)
+    }
+
+    fn get_chain_tips(&self) -> Result<Vec<ChainTip>, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+
+        self.query_tree(move |t| {
+            transmit.send(t.chain_tips()).ok();
+        })?;
+
+        Ok(receive.recv()?)
+    }
+
+    fn get_block_hash_range(
+        &self,
+        range: RangeInclusive<Height>,
+    ) -> Result<Vec<(Height, BlockHeader)>, handle::Error> {
+        if range.is_empty() {
+            return Err(handle::Error::InvalidRange(range));
+        }
+        let (sender, recvr) = chan::bounded(1);
+        self._command(Command::GetBlockByHeightRange(range, sender))?;
+
+        Ok(recvr.recv()?)
+    }
+
+    fn get_recent_blocks(&self, n: usize) -> Result<Vec<(Height, BlockHeader)>, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+
+        self.query_tree(move |t| {
+            transmit.send(t.iter().rev().take(n).collect()).ok();
+        })?;
+
+        Ok(receive.recv()?)
+    }
+
+    fn block_locator(&self) -> Result<Vec<BlockHash>, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+
+        self.query_tree(move |t| {
+            transmit.send(t.locator_hashes(t.height())).ok();
+        })?;
+
+        Ok(receive.recv()?)
+    }
+
+    fn get_tip_work(&self) -> Result<(Work, Bits), handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+
+        self.query_tree(move |t| {
+            let (_, tip) = t.tip();
+            transmit.send((t.chain_work(), tip.bits)).ok();
+        })?;
+
+        Ok(receive.recv()?)
+    }
+
     fn get_block(&self, hash: &BlockHash) -> Result<(), handle::Error> {
         self.command(Command::GetBlock(*hash))?;
 
         Ok(())
     }
 
-    fn get_filters(&self, range: RangeInclusive<Height>) -> Result<(), handle::Error> {
+    fn get_filters(
+        &self,
+        range: RangeInclusive<Height>,
+    ) -> Result<handle::FilterRequest, handle::Error> {
         assert!(
             !range.is_empty(),
             "client::Handle::get_filters: range cannot be empty"
@@ -511,13 +1276,256 @@ impl<W: Waker> handle::Handle for Handle<W> {
         let (transmit, receive) = chan::bounded(1);
         self.command(Command::GetFilters(range, transmit))?;
 
-        receive.recv()?.map_err(handle::Error::GetFilters)
+        let id = receive.recv()?.map_err(handle::Error::GetFilters)?;
+        let handle = self.clone();
+
+        Ok(handle::FilterRequest::new(id, move |id| {
+            handle.command(Command::CancelFilterRequest(id))
+        }))
+    }
+
+    fn get_filters_matching(
+        &self,
+        range: RangeInclusive<Height>,
+        scripts: Vec<Script>,
+    ) -> Result<Vec<(Height, Block)>, handle::Error> {
+        if range.is_empty() {
+            return Err(handle::Error::InvalidRange(range));
+        }
+        // Subscribe before requesting, so that no filter or block can be missed.
+        let filters = self.filters();
+        let blocks = self.blocks();
+        let mut missing = range.clone().collect::<BTreeSet<_>>();
+        let mut matched = HashMap::new();
+
+        let request = self.get_filters(range)?;
+        let result = event::wait(
+            &filters,
+            |(filter, hash, height)| {
+                if !missing.remove(&height) {
+                    return None;
+                }
+                match filter.match_any(&hash, &mut scripts.iter().map(|s| s.as_bytes())) {
+                    Ok(true) => {
+                        matched.insert(hash, height);
+                    }
+                    Ok(false) => {}
+                    Err(err) => {
+                        // If we can't rule out a match, fetch the block to be safe.
+                        log::warn!("Error matching filter for block {}: {}", hash, err);
+                        matched.insert(hash, height);
+                    }
+                }
+                if missing.is_empty() {
+                    Some(())
+                } else {
+                    None
+                }
+            },
+            self.timeout,
+        );
+
+        match result {
+            Ok(()) => {}
+            Err(chan::RecvTimeoutError::Timeout) => {
+                request.cancel()?;
+
+                return Err(handle::Error::FiltersIncomplete(
+                    missing.into_iter().collect(),
+                ));
+            }
+            Err(err) => return Err(err.into()),
+        }
+
+        let mut found = Vec::with_capacity(matched.len());
+        for hash in matched.keys() {
+            self.get_block(hash)?;
+        }
+        if !matched.is_empty() {
+            let result = event::wait(
+                &blocks,
+                |(block, height)| {
+                    if matched.remove(&block.block_hash()).is_some() {
+                        found.push((height, block));
+                    }
+                    if matched.is_empty() {
+                        Some(())
+                    } else {
+                        None
+                    }
+                },
+                self.timeout,
+            );
+
+            if let Err(err) = result {
+                // Don't keep downloading blocks nobody is waiting for.
+                for hash in matched.keys() {
+                    self.command(Command::CancelBlock(*hash))?;
+                }
+                return Err(err.into());
+            }
+        }
+        found.sort_by_key(|(height, _)| *height);
+
+        Ok(found)
+    }
+
+    fn get_sync_status(&self) -> Result<SyncStatus, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::GetSyncStatus(transmit))?;
+
+        Ok(receive.recv()?)
+    }
+
+    fn get_peers_where(
+        &self,
+        predicate: impl Fn(&Peer) -> bool + Send + Sync + 'static,
+    ) -> Result<Vec<Peer>, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::GetPeersWhere(Arc::new(predicate), transmit))?;
+
+        Ok(receive.recv()?)
+    }
+
+    fn get_peer_info(&self, addr: net::SocketAddr) -> Result<Option<PeerInfo>, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::GetPeerInfo(addr, transmit))?;
+
+        Ok(receive.recv()?)
+    }
+
+    fn ping(&self, addr: net::SocketAddr) -> Result<time::Duration, handle::Error> {
+        // Subscribe before pinging, so that the `pong` can't be missed.
+        let events = self.events();
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::Ping(addr, transmit))?;
+
+        let nonce = receive.recv()??;
+        let latency = event::wait(
+            &events,
+            |e| match e {
+                fsm::Event::Ping(fsm::PingEvent::PongReceived {
+                    addr: a,
+                    nonce: n,
+                    latency,
+                }) if a == addr && n == nonce => Some(latency),
+                _ => None,
+            },
+            self.timeout,
+        )?;
+
+        Ok(latency.into())
+    }
+
+    fn bandwidth(&self) -> Result<Bandwidth, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::GetBandwidth(transmit))?;
+
+        Ok(receive.recv()?)
+    }
+
+    fn connection_counts(&self) -> Result<ConnectionCounts, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::GetConnectionCounts(transmit))?;
+
+        Ok(receive.recv()?)
+    }
+
+    fn version_info(&self) -> Result<VersionInfo, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::GetVersionInfo(transmit))?;
+
+        Ok(receive.recv()?)
+    }
+
+    fn get_known_addresses(
+        &self,
+        filter: AddressFilter,
+    ) -> Result<Vec<(net::SocketAddr, Source, ServiceFlags)>, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::GetAddresses(filter, transmit))?;
+
+        Ok(receive.recv()?)
+    }
+
+    fn get_tips(&self) -> Result<Tips, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::GetTips(transmit))?;
+
+        Ok(receive.recv()?)
+    }
+
+    fn health(&self) -> Result<Health, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::GetHealth(transmit))?;
+
+        Ok(receive.recv()?)
+    }
+
+    fn network_time(&self) -> Result<time::SystemTime, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::GetNetworkTime(transmit))?;
+
+        let secs = receive.recv()?;
+
+        Ok(time::UNIX_EPOCH + time::Duration::from_secs(secs as u64))
+    }
+
+    fn get_filter(&self, hash: &BlockHash) -> Result<Option<BlockFilter>, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::GetCachedFilter(*hash, transmit))?;
+
+        Ok(receive.recv()?)
+    }
+
+    fn get_filter_header(
+        &self,
+        height: Height,
+    ) -> Result<Option<(FilterHash, FilterHeader)>, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::GetFilterHeader(height, transmit))?;
+
+        Ok(receive.recv()?)
+    }
+
+    fn get_filter_checkpoints(&self) -> Result<Vec<(Height, FilterHeader)>, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::GetFilterCheckpoints(transmit))?;
+
+        Ok(receive.recv()?)
+    }
+
+    fn prune_filters(&self, before: Height) -> Result<(), handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::PruneFilters {
+            before,
+            reply: transmit,
+        })?;
+
+        receive.recv()?.map_err(handle::Error::Filters)
+    }
+
+    fn get_utxos(&self, spendable_at: Height) -> Result<Vec<Utxo>, handle::Error> {
+        let utxos = self.utxos.read().map_err(|_| handle::Error::Disconnected)?;
+
+        Ok(utxos.list(spendable_at))
+    }
+
+    fn flush(&self) -> Result<(), handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::Flush(transmit))?;
+
+        receive.recv()?.map_err(handle::Error::Flush)
     }
 
     fn blocks(&self) -> chan::Receiver<(Block, Height)> {
         self.blocks.subscribe()
     }
 
+    fn blocks_bounded(&self, capacity: usize) -> Lossy<(Block, Height)> {
+        self.blocks.subscribe_bounded(capacity)
+    }
+
     fn filters(&self) -> chan::Receiver<(BlockFilter, BlockHash, Height)> {
         self.filters.subscribe()
     }
@@ -526,6 +1534,23 @@ impl<W: Waker> handle::Handle for Handle<W> {
         self.subscriber.subscribe()
     }
 
+    fn subscribe_filtered(&self, kinds: EventKind) -> chan::Receiver<Event> {
+        self.subscriber
+            .subscribe_filtered(move |e| kinds.contains(e.kind()))
+    }
+
+    fn subscribe_with(&self, policy: BufferPolicy) -> chan::Receiver<Event> {
+        self.subscriber.subscribe_with(policy)
+    }
+
+    fn track_txids(&self, txids: Vec<Txid>) -> chan::Receiver<(Txid, spv::TxStatus)> {
+        self.trackers.track(txids)
+    }
+
+    fn subscribe_messages(&self) -> chan::Receiver<(net::SocketAddr, Direction, NetworkMessage)> {
+        self.messages.subscribe()
+    }
+
     fn loading(&self) -> chan::Receiver<Loading> {
         self.loading.subscribe()
     }
@@ -537,10 +1562,10 @@ impl<W: Waker> handle::Handle for Handle<W> {
     fn broadcast(
         &self,
         msg: NetworkMessage,
-        predicate: fn(Peer) -> bool,
+        predicate: impl Fn(&Peer) -> bool + Send + Sync + 'static,
     ) -> Result<Vec<net::SocketAddr>, handle::Error> {
         let (transmit, receive) = chan::bounded(1);
-        self.command(Command::Broadcast(msg, predicate, transmit))?;
+        self.command(Command::Broadcast(msg, Arc::new(predicate), transmit))?;
 
         Ok(receive.recv()?)
     }
@@ -591,11 +1616,46 @@ impl<W: Waker> handle::Handle for Handle<W> {
         Ok(())
     }
 
+    fn disconnect_all(&self) -> Result<usize, handle::Error> {
+        let events = self.events();
+        let mut remaining = self
+            .get_peers(ServiceFlags::NONE)?
+            .into_iter()
+            .map(|p| p.addr)
+            .collect::<HashSet<_>>();
+        let count = remaining.len();
+
+        if remaining.is_empty() {
+            return Ok(0);
+        }
+        for addr in remaining.iter() {
+            self.command(Command::Disconnect(*addr))?;
+        }
+        event::wait(
+            &events,
+            |e| match e {
+                fsm::Event::Peer(fsm::PeerEvent::Disconnected(addr, _)) => {
+                    remaining.remove(&addr);
+
+                    if remaining.is_empty() {
+                        Some(())
+                    } else {
+                        None
+                    }
+                }
+                _ => None,
+            },
+            self.timeout,
+        )?;
+
+        Ok(count)
+    }
+
     fn import_headers(
         &self,
         headers: Vec<BlockHeader>,
-    ) -> Result<Result<ImportResult, tree::Error>, handle::Error> {
-        let (transmit, receive) = chan::bounded::<Result<ImportResult, tree::Error>>(1);
+    ) -> Result<Result<ImportSummary, tree::Error>, handle::Error> {
+        let (transmit, receive) = chan::bounded::<Result<ImportSummary, tree::Error>>(1);
         self.command(Command::ImportHeaders(headers, transmit))?;
 
         Ok(receive.recv()?)
@@ -607,6 +1667,28 @@ impl<W: Waker> handle::Handle for Handle<W> {
         Ok(())
     }
 
+    fn reset_peers(&self) -> Result<(), handle::Error> {
+        let seeds = self.dns_seeds.read().unwrap().clone().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotConnected, "the client is not running")
+        })?;
+        // Seeds are resolved here, so that the client isn't blocked on DNS requests.
+        let addrs = seeds.resolve()?;
+
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "seeds didn't resolve to any address",
+            )
+            .into());
+        }
+        let (transmit, receive) = chan::bounded(1);
+
+        self.command(Command::ResetPeers(addrs, transmit))?;
+        receive.recv()??;
+
+        Ok(())
+    }
+
     fn submit_transaction(
         &self,
         tx: Transaction,
@@ -617,6 +1699,47 @@ impl<W: Waker> handle::Handle for Handle<W> {
         receive.recv()?.map_err(handle::Error::Command)
     }
 
+    fn submit_transactions(
+        &self,
+        txs: Vec<Transaction>,
+    ) -> Result<Vec<Result<NonEmpty<net::SocketAddr>, CommandError>>, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::SubmitTransactions(txs, transmit))?;
+
+        Ok(receive.recv()?)
+    }
+
+    fn get_mempool(&self) -> Result<Vec<(Txid, spv::TxStatus)>, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::GetMempool(transmit))?;
+
+        let mempool = receive.recv()?;
+        let statuses = self
+            .statuses
+            .read()
+            .map_err(|_| handle::Error::Disconnected)?;
+
+        Ok(mempool
+            .into_iter()
+            .map(|(txid, peer)| {
+                // Prefer the latest status emitted for the transaction, if any.
+                let status = match (statuses.get(&txid), peer) {
+                    (Some(status), _) => status.clone(),
+                    (None, Some(peer)) => spv::TxStatus::Acknowledged { peer },
+                    (None, None) => spv::TxStatus::Unconfirmed,
+                };
+                (txid, status)
+            })
+            .collect())
+    }
+
+    fn estimate_fee(&self, target_blocks: u16) -> Result<Option<FeeRate>, handle::Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::EstimateFee(target_blocks, transmit))?;
+
+        Ok(receive.recv()?)
+    }
+
     fn wait<F, T>(&self, f: F) -> Result<T, handle::Error>
     where
         F: FnMut(fsm::Event) -> Option<T>,
@@ -635,18 +1758,17 @@ impl<W: Waker> handle::Handle for Handle<W> {
         let events = self.events();
         let required_services = required_services.into();
 
-        let negotiated = self.get_peers(required_services)?;
-        if negotiated.len() == count {
-            return Ok(negotiated
-                .into_iter()
-                .map(|p| (p.addr, p.height, p.services))
-                .collect());
-        }
-
-        let mut negotiated = negotiated
+        // Get already connected peers.
+        let mut negotiated = self
+            .get_peers(required_services)?
             .into_iter()
-            .map(|p| (p.addr, (p.height, p.services)))
-            .collect::<HashMap<_, _>>(); // Get already connected peers.
+            .map(|p| (p.addr, p.height, p.services))
+            .collect::<Vec<_>>();
+
+        if negotiated.len() >= count {
+            negotiated.truncate(count);
+            return Ok(negotiated);
+        }
 
         event::wait(
             &events,
@@ -657,12 +1779,14 @@ impl<W: Waker> handle::Handle for Handle<W> {
                     services,
                     ..
                 }) => {
-                    if services.has(required_services) {
-                        negotiated.insert(addr, (height, services));
+                    if services.has(required_services)
+                        && !negotiated.iter().any(|(a, _, _)| *a == addr)
+                    {
+                        negotiated.push((addr, height, services));
                     }
 
-                    if negotiated.len() == count {
-                        Some(negotiated.iter().map(|(a, (h, s))| (*a, *h, *s)).collect())
+                    if negotiated.len() >= count {
+                        Some(negotiated.iter().take(count).cloned().collect())
                     } else {
                         None
                     }
@@ -693,6 +1817,48 @@ impl<W: Waker> handle::Handle for Handle<W> {
         }
     }
 
+    fn wait_for_filters(&self, range: RangeInclusive<Height>) -> Result<(), handle::Error> {
+        if range.is_empty() {
+            return Err(handle::Error::InvalidRange(range));
+        }
+        // Subscribe before checking which filters were already processed, so that filters
+        // processed in the meantime aren't missed.
+        let events = self.events();
+        let mut missing = range.collect::<BTreeSet<_>>();
+
+        if let Some((start, end)) = self.get_sync_status()?.filters_processed {
+            missing.retain(|h| !(start..=end).contains(h));
+        }
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        let result = event::wait(
+            &events,
+            |e| match e {
+                fsm::Event::Filter(fsm::FilterEvent::FilterProcessed { height, .. }) => {
+                    missing.remove(&height);
+
+                    if missing.is_empty() {
+                        Some(())
+                    } else {
+                        None
+                    }
+                }
+                _ => None,
+            },
+            self.timeout,
+        );
+
+        match result {
+            Ok(()) => Ok(()),
+            Err(chan::RecvTimeoutError::Timeout) => Err(handle::Error::FiltersIncomplete(
+                missing.into_iter().collect(),
+            )),
+            Err(err) => Err(err.into()),
+        }
+    }
+
     fn events(&self) -> chan::Receiver<fsm::Event> {
         self.events.subscribe()
     }
@@ -703,4 +1869,21 @@ impl<W: Waker> handle::Handle for Handle<W> {
 
         Ok(())
     }
+
+    fn shutdown_timeout(self, timeout: time::Duration) -> Result<(), handle::Error> {
+        let stopped = self.stopped.clone();
+
+        match self.shutdown() {
+            // The client has already stopped.
+            Err(handle::Error::Disconnected) => return Ok(()),
+            Err(err) => return Err(err),
+            Ok(()) => {}
+        }
+        // Nothing is ever sent on this channel: it disconnects once the client has
+        // stopped, ie. after the stores were closed and peer connections were shut down.
+        match stopped.recv_timeout(timeout) {
+            Ok(()) | Err(chan::RecvTimeoutError::Disconnected) => Ok(()),
+            Err(chan::RecvTimeoutError::Timeout) => Err(handle::Error::Timeout),
+        }
+    }
 }
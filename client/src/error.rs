@@ -31,14 +31,78 @@ pub enum Error {
     /// An error coming from the filter store.
     #[error(transparent)]
     FilterStore(#[from] chain::filter::store::Error),
+    /// An error coming from the filter cache.
+    #[error(transparent)]
+    Filters(#[from] common::block::filter::Error),
     /// An error coming from the peer store.
     #[error("error loading peers: {0}")]
     PeerStore(io::Error),
+    /// The client configuration is invalid.
+    #[error(transparent)]
+    Config(#[from] ConfigError),
     /// A communication channel error.
     #[error("command channel disconnected")]
     Channel,
 }
 
+/// A client configuration error. Lists every problem found in the configuration.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error(
+    "invalid configuration: {}",
+    .0.iter().map(|p| p.to_string()).collect::<Vec<_>>().join("; ")
+)]
+pub struct ConfigError(pub Vec<ConfigProblem>);
+
+/// A single problem found in a client configuration.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ConfigProblem {
+    /// No network domains are enabled, so no peer can be connected to.
+    #[error("no network domains are enabled")]
+    NoDomains,
+    /// The outbound peer limit is zero.
+    #[error("the maximum number of outbound peers must be at least one")]
+    NoOutboundPeers,
+    /// More peers were given to connect to than the outbound peer limit allows.
+    #[error("{connect} peer(s) to connect to, but at most {max} outbound peer(s) allowed")]
+    TooManyConnectPeers {
+        /// Number of peers to connect to.
+        connect: usize,
+        /// Maximum number of outbound peers.
+        max: usize,
+    },
+    /// Peers to connect to are set, along with DNS seeds. Seeds are only used to find
+    /// peers when no peers to connect to are set, so they would be ignored.
+    #[error("DNS seeds are ignored when peers to connect to are set")]
+    ConnectWithDnsSeeds,
+    /// Compact filters are required from peers, but the filter cache is disabled.
+    #[error("compact filters required from peers, but the filter cache size is zero")]
+    FilterCacheDisabled,
+    /// Compact filters are required from peers, but filter sync is disabled.
+    #[error("compact filters required from peers, but filter sync is disabled")]
+    FilterSyncDisabled,
+    /// A DNS seed hostname is empty.
+    #[error("DNS seed hostnames must not be empty")]
+    EmptyDnsSeed,
+    /// The reconnection backoff delay would shrink, or starts above its maximum.
+    #[error("the reconnection backoff delay must not shrink or start above its maximum")]
+    InvalidBackoff,
+    /// The block download concurrency is zero, so blocks would never be fetched.
+    #[error("the block download concurrency must be at least one")]
+    NoBlockDownloadConcurrency,
+    /// The command queue is bounded with a capacity of zero.
+    #[error("the command queue capacity must be at least one")]
+    EmptyCommandQueue,
+    /// The command queue differs from the one the client was created with. The queue
+    /// can't be changed once handles to it exist.
+    #[error("command queue {configured:?} differs from the client's command queue {created:?}")]
+    CommandQueueMismatch {
+        /// Command queue the client was created with.
+        created: crate::client::CommandQueue,
+        /// Command queue the client was run with.
+        configured: crate::client::CommandQueue,
+    },
+}
+
 impl From<chan::SendError<Command>> for Error {
     fn from(_: chan::SendError<Command>) -> Self {
         Self::Channel
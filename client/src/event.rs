@@ -1,10 +1,12 @@
 //! Client events.
 use std::fmt;
 use std::io;
+use std::ops::BitOr;
 use std::sync::Arc;
+use std::time::SystemTime;
 
 use nakamoto_common::bitcoin::network::constants::ServiceFlags;
-use nakamoto_common::bitcoin::{Transaction, Txid};
+use nakamoto_common::bitcoin::{OutPoint, Script, Transaction, Txid};
 use nakamoto_common::block::{BlockHash, BlockHeader, Height};
 use nakamoto_net::DisconnectReason;
 use nakamoto_p2p::fsm;
@@ -13,6 +15,24 @@ use nakamoto_p2p::fsm::{Link, PeerId};
 
 use crate::spv::TxStatus;
 
+/// Direction of a captured network message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Message received from a peer.
+    Inbound,
+    /// Message sent to a peer.
+    Outbound,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Inbound => write!(fmt, "inbound"),
+            Self::Outbound => write!(fmt, "outbound"),
+        }
+    }
+}
+
 /// Event emitted by the client during the "loading" phase.
 #[derive(Clone, Debug)]
 pub enum Loading {
@@ -21,32 +41,70 @@ pub enum Loading {
     BlockHeaderLoaded {
         /// Height of loaded block.
         height: Height,
+        /// Height of the block header store, ie. the last header to be loaded.
+        tip: Height,
     },
     /// A filter header was loaded from the store.
     /// This event only fires during startup.
     FilterHeaderLoaded {
         /// Height of loaded filter header.
         height: Height,
+        /// Height of the filter header store, ie. the last header to be loaded.
+        tip: Height,
     },
     /// A filter header was verified.
     /// This event only fires during startup.
     FilterHeaderVerified {
         /// Height of verified filter header.
         height: Height,
+        /// Height of the filter header chain, ie. the last header to be verified.
+        tip: Height,
     },
+    /// Loading is done. This is the last event fired before the loading channel is closed.
+    Done {
+        /// The tip of the block header chain.
+        tip: Height,
+        /// The tip of the filter header chain.
+        filter_tip: Height,
+    },
+}
+
+/// A header store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreKind {
+    /// The block header store.
+    BlockHeaders,
+    /// The filter header store.
+    FilterHeaders,
+}
+
+impl fmt::Display for StoreKind {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BlockHeaders => write!(fmt, "block header store"),
+            Self::FilterHeaders => write!(fmt, "filter header store"),
+        }
+    }
 }
 
 impl fmt::Display for Loading {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::BlockHeaderLoaded { height } => {
-                write!(fmt, "block header #{} loaded", height)
+            Self::BlockHeaderLoaded { height, tip } => {
+                write!(fmt, "block header #{}/{} loaded", height, tip)
             }
-            Self::FilterHeaderLoaded { height } => {
-                write!(fmt, "filter header #{} loaded", height)
+            Self::FilterHeaderLoaded { height, tip } => {
+                write!(fmt, "filter header #{}/{} loaded", height, tip)
             }
-            Self::FilterHeaderVerified { height } => {
-                write!(fmt, "filter header #{} verified", height)
+            Self::FilterHeaderVerified { height, tip } => {
+                write!(fmt, "filter header #{}/{} verified", height, tip)
+            }
+            Self::Done { tip, filter_tip } => {
+                write!(
+                    fmt,
+                    "loading done (tip = {}, filter tip = {})",
+                    tip, filter_tip
+                )
             }
         }
     }
@@ -63,6 +121,22 @@ pub enum Event {
         /// The tip of the filter header chain.
         filter_tip: Height,
     },
+    /// Filter and block syncing was paused, eg. via
+    /// [`crate::handle::Handle::pause_sync`]. Peers stay connected.
+    SyncPaused,
+    /// Filter and block syncing was resumed, eg. via
+    /// [`crate::handle::Handle::resume_sync`].
+    SyncResumed,
+    /// A corrupted store was healed on startup, by rolling it back to its last valid
+    /// header. Headers above the new height have to be synced again.
+    StoreHealed {
+        /// The healed store.
+        kind: StoreKind,
+        /// Height of the store before it was healed.
+        from: Height,
+        /// Height of the store after it was healed.
+        to: Height,
+    },
     /// Peer connected. This is fired when the physical TCP/IP connection
     /// is established. Use [`Event::PeerNegotiated`] to know when the P2P handshake
     /// has completed.
@@ -86,6 +160,18 @@ pub enum Event {
         /// Connection error.
         error: Arc<io::Error>,
     },
+    /// Peer was disconnected for misbehaving. Peers whose address misbehaves repeatedly
+    /// are banned.
+    PeerMisbehaved {
+        /// Peer address.
+        addr: PeerId,
+        /// Accumulated misbehavior score of the peer's address.
+        score: u32,
+        /// Reason for the misbehavior.
+        reason: &'static str,
+        /// Whether the peer's address was banned.
+        banned: bool,
+    },
     /// Peer handshake completed. The peer connection is fully functional from this point.
     PeerNegotiated {
         /// Peer address.
@@ -109,6 +195,15 @@ pub enum Event {
         /// Best block height known.
         height: Height,
     },
+    /// Peer addresses were gossiped to us by a peer, via an `addr` message.
+    /// Addresses that aren't usable, eg. because they lack the required services,
+    /// are still counted.
+    PeerAddressesReceived {
+        /// Peer the addresses were received from.
+        from: PeerId,
+        /// Number of addresses received.
+        count: usize,
+    },
     /// A block was added to the main chain.
     BlockConnected {
         /// Block header.
@@ -128,6 +223,74 @@ pub enum Event {
         hash: BlockHash,
         /// Height of the block when it was part of the main chain.
         height: Height,
+        /// Total number of blocks disconnected by the re-org this block is part of.
+        reorg_depth: usize,
+    },
+    /// The main chain was re-organized. This event summarizes the
+    /// [`Event::BlockDisconnected`] and [`Event::BlockConnected`] events that precede it,
+    /// and fires once the chain is in sync again.
+    ChainReorg {
+        /// Last block shared by the old and new chain.
+        common_ancestor: (Height, BlockHash),
+        /// Blocks removed from the main chain, starting from the old tip.
+        disconnected: Vec<BlockHash>,
+        /// Blocks added to the main chain, starting from the child of the common ancestor.
+        connected: Vec<BlockHash>,
+    },
+    /// Block header sync progress, for display during initial sync.
+    /// Emitted at most once per second, as new headers are imported.
+    SyncProgress {
+        /// Percentage of headers synced, between `0` and `100`.
+        percent: f64,
+        /// Current block header height.
+        height: Height,
+        /// Best block header height known, out of all connected peers.
+        target: Height,
+    },
+    /// A branch with more work was found, but switching to it would disconnect more than
+    /// [`crate::Config::max_reorg_depth`] blocks. The current chain is kept.
+    ReorgRejected {
+        /// Number of blocks that would have been disconnected.
+        depth: usize,
+        /// Height and hash of the block the rejected branch forks from.
+        fork_point: (Height, BlockHash),
+    },
+    /// No new block header was received within [`crate::Config::stale_tip_timeout`].
+    /// This usually means that our peers stopped relaying blocks, and the client is stuck.
+    /// Fires once, until [`Event::TipFresh`].
+    TipStale {
+        /// Height of our tip.
+        height: Height,
+        /// Last time our tip was updated.
+        last_update: SystemTime,
+    },
+    /// A new block header was received after an [`Event::TipStale`].
+    TipFresh {
+        /// Height of our new tip.
+        height: Height,
+    },
+    /// A block was requested from a peer, eg. via [`crate::handle::Handle::get_block`].
+    /// The block is delivered once it is received and processed.
+    BlockDownloadStarted {
+        /// Hash of the requested block.
+        hash: BlockHash,
+        /// Peer the block was requested from.
+        peer: PeerId,
+    },
+    /// A block request was retried, because the peer didn't deliver the block in time
+    /// or disconnected.
+    BlockDownloadRetried {
+        /// Hash of the requested block.
+        hash: BlockHash,
+        /// Retry attempt, starting at one.
+        attempt: u8,
+    },
+    /// A block couldn't be downloaded after [`crate::Config::block_request_retries`]
+    /// retries, and is no longer requested. If the block matched a filter, its
+    /// transactions are missed.
+    BlockDownloadFailed {
+        /// Hash of the requested block.
+        hash: BlockHash,
     },
     /// A block has matched one of the filters and is ready to be processed.
     /// This event usually precedes [`Event::TxStatusChanged`] events.
@@ -141,6 +304,18 @@ pub enum Event {
         /// Transactions in this block.
         transactions: Vec<Transaction>,
     },
+    /// One of our unspent outputs was spent by a transaction in a matching block.
+    /// This event is fired before the corresponding [`Event::BlockMatched`] event.
+    OutputSpent {
+        /// The output that was spent.
+        outpoint: OutPoint,
+        /// The spending transaction.
+        txid: Txid,
+        /// Height of the block including the spending transaction.
+        height: Height,
+        /// Hash of the block including the spending transaction.
+        block: BlockHash,
+    },
     /// Transaction fee rate estimated for a block.
     FeeEstimated {
         /// Block hash of the estimate.
@@ -160,6 +335,9 @@ pub enum Event {
         height: Height,
         /// Whether or not this filter matched any of the watched scripts.
         matched: bool,
+        /// Watched scripts matched by this filter. Only the transactions of the block
+        /// involving these scripts need to be scanned.
+        matched_scripts: Vec<Script>,
         /// Whether or not this filter is valid.
         valid: bool,
     },
@@ -170,11 +348,17 @@ pub enum Event {
         /// The new transaction status.
         status: TxStatus,
     },
+    /// A rescan was aborted, eg. via [`crate::handle::Handle::abort_rescan`].
+    RescanAborted {
+        /// Height at which the rescan was aborted. Filters from this height onward
+        /// were not processed.
+        at_height: Height,
+    },
     /// Compact filters have been synced and processed up to this point and matching blocks have
     /// been fetched.
     ///
-    /// If filters have been processed up to the last block in the client's header chain, `height`
-    /// and `tip` will be equal.
+    /// Emitted once per newly reached tip of the client's header chain, when filters have been
+    /// processed up to it. Re-processing filters, eg. during a rescan, doesn't emit it again.
     Synced {
         /// Height up to which we are synced.
         height: Height,
@@ -183,18 +367,120 @@ pub enum Event {
     },
 }
 
+impl Event {
+    /// Get the kind of this event.
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Self::Ready { .. }
+            | Self::StoreHealed { .. }
+            | Self::SyncPaused
+            | Self::SyncResumed => EventKind::NODE,
+            Self::PeerConnected { .. }
+            | Self::PeerDisconnected { .. }
+            | Self::PeerConnectionFailed { .. }
+            | Self::PeerMisbehaved { .. }
+            | Self::PeerNegotiated { .. }
+            | Self::PeerHeightUpdated { .. }
+            | Self::PeerAddressesReceived { .. } => EventKind::PEERS,
+            Self::BlockConnected { .. }
+            | Self::BlockDisconnected { .. }
+            | Self::ChainReorg { .. }
+            | Self::ReorgRejected { .. }
+            | Self::SyncProgress { .. }
+            | Self::TipStale { .. }
+            | Self::TipFresh { .. }
+            | Self::BlockDownloadStarted { .. }
+            | Self::BlockDownloadRetried { .. }
+            | Self::BlockDownloadFailed { .. }
+            | Self::BlockMatched { .. } => EventKind::BLOCKS,
+            Self::FilterProcessed { .. } | Self::RescanAborted { .. } | Self::Synced { .. } => {
+                EventKind::FILTERS
+            }
+            Self::TxStatusChanged { .. } | Self::OutputSpent { .. } | Self::FeeEstimated { .. } => {
+                EventKind::TX
+            }
+        }
+    }
+}
+
+/// A set of [`Event`] kinds, used to only subscribe to some events.
+/// Kinds can be combined with `|`, eg. `EventKind::BLOCKS | EventKind::TX`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EventKind(u8);
+
+impl EventKind {
+    /// No events.
+    pub const NONE: Self = Self(0);
+    /// Node events, eg. [`Event::Ready`].
+    pub const NODE: Self = Self(1 << 0);
+    /// Peer events, eg. [`Event::PeerConnected`].
+    pub const PEERS: Self = Self(1 << 1);
+    /// Block events, eg. [`Event::BlockConnected`] and [`Event::BlockMatched`].
+    pub const BLOCKS: Self = Self(1 << 2);
+    /// Filter events, eg. [`Event::FilterProcessed`] and [`Event::Synced`].
+    pub const FILTERS: Self = Self(1 << 3);
+    /// Transaction events, eg. [`Event::TxStatusChanged`] and [`Event::OutputSpent`].
+    pub const TX: Self = Self(1 << 4);
+    /// All events.
+    pub const ALL: Self = Self(0b11111);
+
+    /// Check whether this set includes all the given kinds.
+    pub fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for EventKind {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
 impl fmt::Display for Event {
     fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Ready { .. } => {
                 write!(fmt, "ready to process events and commands")
             }
+            Self::SyncPaused => write!(fmt, "filter and block syncing paused"),
+            Self::SyncResumed => write!(fmt, "filter and block syncing resumed"),
+            Self::StoreHealed { kind, from, to } => {
+                write!(fmt, "{} healed from height {} to {}", kind, from, to)
+            }
             Self::BlockConnected { hash, height, .. } => {
                 write!(fmt, "block {} connected at height {}", hash, height)
             }
+            Self::ChainReorg {
+                common_ancestor: (height, _),
+                disconnected,
+                connected,
+            } => {
+                write!(
+                    fmt,
+                    "chain re-organized above height {}: {} block(s) disconnected, {} connected",
+                    height,
+                    disconnected.len(),
+                    connected.len()
+                )
+            }
             Self::BlockDisconnected { hash, height, .. } => {
                 write!(fmt, "block {} disconnected at height {}", hash, height)
             }
+            Self::BlockDownloadStarted { hash, peer } => {
+                write!(fmt, "downloading block {} from {}", hash, peer)
+            }
+            Self::BlockDownloadRetried { hash, attempt } => {
+                write!(
+                    fmt,
+                    "retrying download of block {} (attempt {})",
+                    hash, attempt
+                )
+            }
+            Self::BlockDownloadFailed { hash } => {
+                write!(fmt, "failed to download block {}", hash)
+            }
             Self::BlockMatched { hash, height, .. } => {
                 write!(
                     fmt,
@@ -202,10 +488,22 @@ impl fmt::Display for Event {
                     hash, height
                 )
             }
+            Self::OutputSpent {
+                outpoint,
+                txid,
+                height,
+                ..
+            } => {
+                write!(
+                    fmt,
+                    "output {} spent by transaction {} at height {}",
+                    outpoint, txid, height
+                )
+            }
             Self::FeeEstimated { fees, height, .. } => {
                 write!(
                     fmt,
-                    "transaction median fee rate for block #{} is {} sat/vB",
+                    "transaction median fee rate for block #{} is {}",
                     height, fees.median,
                 )
             }
@@ -221,6 +519,9 @@ impl fmt::Display for Event {
             Self::TxStatusChanged { txid, status } => {
                 write!(fmt, "transaction {} status changed: {}", txid, status)
             }
+            Self::RescanAborted { at_height } => {
+                write!(fmt, "rescan aborted at height {}", at_height)
+            }
             Self::Synced { height, .. } => write!(fmt, "filters synced up to height {}", height),
             Self::PeerConnected { addr, link } => {
                 write!(fmt, "peer {} connected ({:?})", &addr, link)
@@ -235,9 +536,52 @@ impl fmt::Display for Event {
             Self::PeerHeightUpdated { height } => {
                 write!(fmt, "peer height updated to {}", height)
             }
+            Self::PeerAddressesReceived { from, count } => {
+                write!(fmt, "received {} address(es) from {}", count, from)
+            }
+            Self::SyncProgress {
+                percent,
+                height,
+                target,
+            } => {
+                write!(
+                    fmt,
+                    "block headers synced to height {}/{} ({:.2}%)",
+                    height, target, percent
+                )
+            }
+            Self::ReorgRejected { depth, fork_point } => {
+                write!(
+                    fmt,
+                    "re-org of depth {} from block {} at height {} rejected",
+                    depth, fork_point.1, fork_point.0
+                )
+            }
+            Self::TipStale { height, .. } => {
+                write!(fmt, "tip at height {} is stale", height)
+            }
+            Self::TipFresh { height } => {
+                write!(fmt, "tip is fresh again at height {}", height)
+            }
             Self::PeerDisconnected { addr, reason } => {
                 write!(fmt, "disconnected from {} ({})", &addr, reason)
             }
+            Self::PeerMisbehaved {
+                addr,
+                score,
+                reason,
+                banned,
+            } => {
+                write!(
+                    fmt,
+                    "peer {} misbehaved (score = {}): {}",
+                    addr, score, reason
+                )?;
+                if *banned {
+                    write!(fmt, ", banned")?;
+                }
+                Ok(())
+            }
             Self::PeerNegotiated {
                 addr,
                 height,
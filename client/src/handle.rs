@@ -1,24 +1,37 @@
 //! Node handles are created from nodes by users of the library, to communicate with the underlying
 //! protocol instance.
-use std::net;
-use std::ops::{RangeBounds, RangeInclusive};
+use std::ops::{Bound, RangeBounds, RangeInclusive};
+use std::{io, net, time};
 
 use crossbeam_channel as chan;
 use thiserror::Error;
 
 use nakamoto_common::bitcoin::network::constants::ServiceFlags;
 use nakamoto_common::bitcoin::network::Address;
-use nakamoto_common::bitcoin::Script;
+use nakamoto_common::bitcoin::{Script, Txid};
 
+use nakamoto_common::bitcoin::consensus::encode;
 use nakamoto_common::bitcoin::network::message::NetworkMessage;
-use nakamoto_common::block::filter::BlockFilter;
-use nakamoto_common::block::tree::{BlockReader, ImportResult};
-use nakamoto_common::block::{self, Block, BlockHash, BlockHeader, Height, Transaction};
+use nakamoto_common::block::filter::{self, BlockFilter, FilterHash, FilterHeader};
+use nakamoto_common::block::tree::{BlockReader, ChainTip, ImportResult, ImportSummary};
+use nakamoto_common::block::{
+    self, Bits, Block, BlockHash, BlockHeader, Height, Transaction, Work,
+};
 use nakamoto_common::nonempty::NonEmpty;
+use nakamoto_common::p2p::peer::Source;
+use nakamoto_p2p::fsm::fees::FeeRate;
 use nakamoto_p2p::fsm::Link;
-use nakamoto_p2p::fsm::{self, Command, CommandError, GetFiltersError, Peer};
+use nakamoto_p2p::fsm::{
+    self, AddressFilter, Bandwidth, Command, CommandError, ConnectionCounts, FlushError,
+    GetFiltersError, Health, Peer, PeerInfo, RequestId, SyncStatus, Tips, VersionInfo,
+};
 
-use crate::client::{Event, Loading};
+use crate::client::{BufferPolicy, Direction, Event, EventKind, Loading, Lossy};
+use crate::spv::utxos::Utxo;
+use crate::spv::TxStatus;
+
+/// Number of headers imported at a time by [`Handle::import_headers_from_reader`].
+pub const IMPORT_CHUNK_SIZE: usize = 1000;
 
 /// An error resulting from a handle method.
 #[derive(Error, Debug)]
@@ -32,9 +45,25 @@ pub enum Error {
     /// Failed to fetch filters.
     #[error("failed to get filters: {0}")]
     GetFilters(#[from] GetFiltersError),
+    /// A filter cache error.
+    #[error("filter error: {0}")]
+    Filters(#[from] filter::Error),
+    /// Failed to flush the stores to disk.
+    #[error("flush failed: {0}")]
+    Flush(#[from] FlushError),
     /// The operation timed out.
     #[error("the operation timed out")]
     Timeout,
+    /// The command queue is full.
+    #[error("the command queue is full")]
+    QueueFull,
+    /// The given height range is empty or reversed.
+    #[error("invalid height range {0:?}")]
+    InvalidRange(RangeInclusive<Height>),
+    /// Timed out before all filters in a range were processed. Holds the heights of the
+    /// filters that weren't.
+    #[error("timed out waiting for {} filter(s) to be processed", .0.len())]
+    FiltersIncomplete(Vec<Height>),
     /// An I/O error occured.
     #[error(transparent)]
     Io(#[from] std::io::Error),
@@ -61,14 +90,146 @@ impl<T> From<chan::SendError<T>> for Error {
     }
 }
 
+/// An in-flight filter request, returned by [`Handle::get_filters`].
+///
+/// Filters are delivered via [`Handle::filters`] as they are received.
+/// Dropping the request does *not* cancel it; use [`FilterRequest::cancel`] for that.
+pub struct FilterRequest {
+    id: RequestId,
+    cancel: Box<dyn FnOnce(RequestId) -> Result<(), Error> + Send + Sync>,
+}
+
+impl FilterRequest {
+    /// Create a new filter request, given a function that cancels it.
+    pub fn new(
+        id: RequestId,
+        cancel: impl FnOnce(RequestId) -> Result<(), Error> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            id,
+            cancel: Box::new(cancel),
+        }
+    }
+
+    /// Get the request identifier.
+    pub fn id(&self) -> RequestId {
+        self.id
+    }
+
+    /// Cancel the request. Filters that were already requested may still be received,
+    /// but the remaining filters in the range will not be requested.
+    pub fn cancel(self) -> Result<(), Error> {
+        (self.cancel)(self.id)
+    }
+}
+
+impl std::fmt::Debug for FilterRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("FilterRequest")
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
 /// A handle for communicating with a node process.
 pub trait Handle: Sized + Send + Sync + Clone {
     /// Get the tip of the chain.
     fn get_tip(&self) -> Result<(Height, BlockHeader), Error>;
+    /// Get the synchronization status of the node.
+    fn get_sync_status(&self) -> Result<SyncStatus, Error>;
+    /// Check whether the node is in initial block download, ie. whether its tip
+    /// is still far behind the best height known amongst its peers.
+    fn is_ibd(&self) -> Result<bool, Error> {
+        self.get_sync_status().map(|status| status.in_ibd)
+    }
+    /// Get the connected peers matching the predicate, eg. peers at or above a certain
+    /// height or protocol version. The predicate is evaluated in the service thread.
+    fn get_peers_where(
+        &self,
+        predicate: impl Fn(&Peer) -> bool + Send + Sync + 'static,
+    ) -> Result<Vec<Peer>, Error>;
+    /// Get detailed information about a connected peer, eg. traffic and latency.
+    /// Returns [`None`] if the peer isn't connected, or hasn't finished its handshake.
+    fn get_peer_info(&self, addr: net::SocketAddr) -> Result<Option<PeerInfo>, Error>;
+    /// Ping a connected peer and wait for its `pong`, returning the round-trip latency.
+    /// Returns [`Error::Timeout`] if no `pong` is received in time.
+    fn ping(&self, addr: net::SocketAddr) -> Result<time::Duration, Error>;
+    /// Get the known peer addresses matching the given filter, along with their source
+    /// and services. At most [`AddressFilter::limit`] addresses are returned.
+    fn get_known_addresses(
+        &self,
+        filter: AddressFilter,
+    ) -> Result<Vec<(net::SocketAddr, Source, ServiceFlags)>, Error>;
+    /// Get the number of bytes sent and received since startup, across all peers,
+    /// broken down by message type.
+    fn bandwidth(&self) -> Result<Bandwidth, Error>;
+    /// Get the number of inbound and outbound peer connections, along with the configured
+    /// connection limits.
+    fn connection_counts(&self) -> Result<ConnectionCounts, Error>;
+    /// Get the protocol version we advertise, and the version negotiated with each
+    /// connected peer.
+    fn version_info(&self) -> Result<VersionInfo, Error>;
+    /// Get the tips of the block header and filter header chains.
+    fn get_tips(&self) -> Result<Tips, Error>;
+    /// Get a summary of the node's health: its number of peers, whether it is in initial
+    /// block download, the age of its tip and whether its stores are writable. This is cheap
+    /// to call, eg. from a liveness or readiness probe. A node can be considered ready
+    /// once it is out of initial block download and has at least one peer.
+    fn health(&self) -> Result<Health, Error>;
+    /// Get the network-adjusted time, ie. our local time adjusted by the median offset of
+    /// our peers' clocks, with a precision of one second.
+    fn network_time(&self) -> Result<time::SystemTime, Error>;
+    /// Get the height and hash of the best block header.
+    fn best_block_header(&self) -> Result<(Height, BlockHash), Error> {
+        self.get_tips().map(|tips| tips.header)
+    }
+    /// Get the height and block hash of the best filter header. During initial sync,
+    /// this usually lags behind [`Handle::best_block_header`].
+    fn best_filter_header(&self) -> Result<(Height, BlockHash), Error> {
+        self.get_tips().map(|tips| tips.filter)
+    }
     /// Get a full block from the network.
     fn get_block(&self, hash: &BlockHash) -> Result<(), Error>;
-    /// Get compact filters from the network.
-    fn get_filters(&self, range: RangeInclusive<Height>) -> Result<(), Error>;
+    /// Get compact filters from the network. Returns a [`FilterRequest`] which can be used
+    /// to cancel the request before the whole range is fetched.
+    fn get_filters(&self, range: RangeInclusive<Height>) -> Result<FilterRequest, Error>;
+    /// Get the compact filters in the given range from the network, match them against the
+    /// given scripts, and download the matching blocks. Blocks until all matching blocks are
+    /// received, and returns them in height order.
+    ///
+    /// Returns [`Error::FiltersIncomplete`] with the missing heights if not all filters were
+    /// received in time, and [`Error::Timeout`] if not all matching blocks were. In both
+    /// cases, the outstanding requests are cancelled.
+    fn get_filters_matching(
+        &self,
+        range: RangeInclusive<Height>,
+        scripts: Vec<Script>,
+    ) -> Result<Vec<(Height, Block)>, Error>;
+    /// Get the compact filter of a block in the active chain, if it was already downloaded
+    /// and is still in the filter cache. This doesn't make any network request.
+    fn get_filter(&self, hash: &BlockHash) -> Result<Option<BlockFilter>, Error>;
+    /// Get the stored filter header at the given height, along with its filter hash.
+    /// Returns [`None`] if the height is beyond the filter header tip, or was pruned.
+    fn get_filter_header(
+        &self,
+        height: Height,
+    ) -> Result<Option<(FilterHash, FilterHeader)>, Error>;
+    /// Get the filter header checkpoints computed from our filter header chain, ie. the
+    /// filter headers at every [`fsm::FILTER_CHECKPOINT_INTERVAL`] blocks, in height order.
+    /// These can be compared against the filter headers of a peer's `cfcheckpt` message,
+    /// to find where the filter chains disagree. Pruned checkpoints are skipped.
+    fn get_filter_checkpoints(&self) -> Result<Vec<(Height, FilterHeader)>, Error>;
+    /// Prune stored filter headers below the given height.
+    ///
+    /// Once pruned, filters below this height can no longer be fetched, and rescans
+    /// starting below it will fail with [`GetFiltersError::Pruned`].
+    fn prune_filters(&self, before: Height) -> Result<(), Error>;
+    /// Get the unspent outputs paying to watched scripts. Coinbase outputs that can't be
+    /// spent in a block at the given height are marked as immature.
+    fn get_utxos(&self, spendable_at: Height) -> Result<Vec<Utxo>, Error>;
+    /// Flush the block header store, filter header store and peer store to disk.
+    /// Blocks until all writes are complete.
+    fn flush(&self) -> Result<(), Error>;
     /// Query the block tree using the given function. To return results from
     /// the query function, a [channel](`crate::chan`) may be used.
     fn query_tree(
@@ -80,35 +241,151 @@ pub trait Handle: Sized + Send + Sync + Clone {
     /// See [BlockReader::find_branch](`nakamoto_common::block::tree::BlockReader::find_branch`).
     fn find_branch(&self, to: &BlockHash)
         -> Result<Option<(Height, NonEmpty<BlockHeader>)>, Error>;
+    /// Get a block header from the block tree, by hash, along with its height.
+    /// Returns [`None`] if the block isn't known. This doesn't perform any network request.
+    fn get_header_by_hash(&self, hash: &BlockHash) -> Result<Option<(Height, BlockHeader)>, Error>;
+    /// Get the height of a block on the active chain, by hash. Returns [`None`] if the block
+    /// isn't on the active chain, eg. if it is unknown or was orphaned by a re-org.
+    fn get_block_height(&self, hash: &BlockHash) -> Result<Option<Height>, Error>;
+    /// Get all known chain tips, like Bitcoin Core's `getchaintips`. The tip of the active
+    /// chain comes first, followed by the tips of the forks branching off the active chain.
+    fn get_chain_tips(&self) -> Result<Vec<ChainTip>, Error>;
+    /// Get the block headers in the given height range, in ascending height order, in a
+    /// single round-trip. Heights above the current tip are skipped.
+    ///
+    /// Returns [`Error::InvalidRange`] if the range is empty or reversed.
+    fn get_block_hash_range(
+        &self,
+        range: RangeInclusive<Height>,
+    ) -> Result<Vec<(Height, BlockHeader)>, Error>;
+    /// Get the last `n` block headers of the active chain, along with their heights,
+    /// starting from the tip. If `n` exceeds the length of the chain, all headers down to
+    /// and including genesis are returned.
+    fn get_recent_blocks(&self, n: usize) -> Result<Vec<(Height, BlockHeader)>, Error>;
+    /// Get the block locator hashes of the active chain, from the tip back to genesis, as
+    /// used in `getheaders` messages.
+    ///
+    /// See [BlockReader::locator_hashes](`nakamoto_common::block::tree::BlockReader::locator_hashes`).
+    fn block_locator(&self) -> Result<Vec<BlockHash>, Error>;
+    /// Get the total proof-of-work of the active chain, along with the difficulty target of
+    /// the tip, in compact form.
+    fn get_tip_work(&self) -> Result<(Work, Bits), Error>;
     /// Subscribe to blocks received.
     fn blocks(&self) -> chan::Receiver<(Block, Height)>;
+    /// Like [`Handle::blocks`], but buffers at most `capacity` blocks.
+    ///
+    /// Delivery is *lossy*: if the consumer falls behind and the buffer is full, the
+    /// oldest buffered block is dropped to make room for the new one, and a warning is
+    /// logged. Consumers should therefore expect gaps in the received heights, and
+    /// refetch missing blocks by height if needed. In exchange, memory usage stays bounded
+    /// regardless of how slow the consumer is. Dropping the receiver ends the subscription.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    fn blocks_bounded(&self, capacity: usize) -> Lossy<(Block, Height)>;
     /// Subscribe to compact filters received.
     fn filters(&self) -> chan::Receiver<(BlockFilter, BlockHash, Height)>;
     /// Subscribe to SPV events.
     fn subscribe(&self) -> chan::Receiver<Event>;
+    /// Subscribe to SPV events of the given kinds only. Other events are filtered out
+    /// before they reach the returned channel.
+    fn subscribe_filtered(&self, kinds: EventKind) -> chan::Receiver<Event>;
+    /// Subscribe to SPV events, buffered according to the given policy. Each subscription
+    /// is buffered independently, so that eg. a slow consumer using a bounded policy
+    /// doesn't hold up the others.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the policy is bounded with a capacity of zero.
+    fn subscribe_with(&self, policy: BufferPolicy) -> chan::Receiver<Event>;
+    /// Subscribe to the status changes of the given transactions only. This is equivalent
+    /// to filtering the [`Event::TxStatusChanged`] events by transaction id.
+    fn track_txids(&self, txids: Vec<Txid>) -> chan::Receiver<(Txid, TxStatus)>;
+    /// Subscribe to all network messages sent and received, along with the peer and
+    /// direction of each message. Only available if the client was configured with
+    /// [`crate::client::Config::capture_messages`], otherwise no messages are received.
+    fn subscribe_messages(&self) -> chan::Receiver<(net::SocketAddr, Direction, NetworkMessage)>;
     /// Subscribe to client loading events.
     fn loading(&self) -> chan::Receiver<Loading>;
     /// Send a command to the client.
     fn command(&self, cmd: Command) -> Result<(), Error>;
     /// Rescan the blockchain for matching scripts.
     ///
+    /// Filters in the given range are re-fetched and matched against the given scripts.
     /// If a "reorg" takes place, filters up to the start of the provided range
     /// will be re-fetched and scanned.
+    ///
+    /// Returns [`Error::InvalidRange`] if the range is empty or reversed, and
+    /// [`GetFiltersError::Pruned`] if the range starts below the pruned filter height.
     fn rescan(
         &self,
         range: impl RangeBounds<Height>,
         watch: impl Iterator<Item = Script>,
     ) -> Result<(), Error> {
-        // TODO: Handle invalid/empty ranges.
-
         let from = range.start_bound().cloned();
         let to = range.end_bound().cloned();
 
+        // Reject empty or reversed ranges. An exclusive start at the maximum height
+        // always yields an empty range.
+        let start = match from {
+            Bound::Included(h) => h,
+            Bound::Excluded(h) => h
+                .checked_add(1)
+                .ok_or_else(|| Error::InvalidRange(RangeInclusive::new(1, 0)))?,
+            Bound::Unbounded => 0,
+        };
+        let end = match to {
+            Bound::Included(h) => Some(h),
+            // An exclusive end of zero always yields an empty range.
+            Bound::Excluded(h) => Some(
+                h.checked_sub(1)
+                    .ok_or_else(|| Error::InvalidRange(RangeInclusive::new(1, 0)))?,
+            ),
+            Bound::Unbounded => None,
+        };
+        if let Some(end) = end {
+            if end < start {
+                return Err(Error::InvalidRange(start..=end));
+            }
+        }
+
+        let (transmit, receive) = chan::bounded(1);
         self.command(Command::Rescan {
             from,
             to,
             watch: watch.collect(),
+            reply: transmit,
         })?;
+        receive.recv()??;
+
+        Ok(())
+    }
+    /// Abort the active rescan, if any. Outstanding filter and block requests of the
+    /// rescan are canceled, and an [`crate::Event::RescanAborted`] event is emitted.
+    ///
+    /// Scripts stay on the watchlist, but new blocks aren't scanned until a new rescan
+    /// is started.
+    fn abort_rescan(&self) -> Result<(), Error> {
+        self.command(Command::AbortRescan)?;
+
+        Ok(())
+    }
+    /// Pause filter and block syncing, eg. when the application goes to the background.
+    /// Peer connections are kept alive, and block headers are still synced, but no new
+    /// filter or block requests are issued until [`Handle::resume_sync`] is called.
+    ///
+    /// Emits [`Event::SyncPaused`].
+    fn pause_sync(&self) -> Result<(), Error> {
+        self.command(Command::PauseSync)?;
+
+        Ok(())
+    }
+    /// Resume filter and block syncing after [`Handle::pause_sync`].
+    ///
+    /// Emits [`Event::SyncResumed`].
+    fn resume_sync(&self) -> Result<(), Error> {
+        self.command(Command::ResumeSync)?;
 
         Ok(())
     }
@@ -117,6 +394,11 @@ pub trait Handle: Sized + Send + Sync + Clone {
     /// Note that this won't trigger a rescan of any existing blocks. To avoid
     /// missing matching blocks, always watch scripts before sharing their
     /// corresponding address.
+    ///
+    /// Only exact scripts can be watched: BIP 158 filters store a hash of each script,
+    /// so they can be tested for the presence of a given script, but not for scripts
+    /// matching a prefix or pattern. Wallets scanning a range of derived addresses should
+    /// watch every script in the range, eg. up to their gap limit.
     fn watch(&self, watch: impl Iterator<Item = Script>) -> Result<(), Error> {
         self.command(Command::Watch {
             watch: watch.collect(),
@@ -124,12 +406,40 @@ pub trait Handle: Sized + Send + Sync + Clone {
 
         Ok(())
     }
+    /// Watch the given script, and match new blocks against it going forward.
+    ///
+    /// If `rescan_from` is set, blocks starting from that height are rescanned for
+    /// the script as well. Fails with [`GetFiltersError::Pruned`] if `rescan_from` is below
+    /// the pruned filter height.
+    fn watch_address(&self, script: Script, rescan_from: Option<Height>) -> Result<(), Error> {
+        let (transmit, receive) = chan::bounded(1);
+        self.command(Command::WatchAddress {
+            script,
+            rescan_from,
+            reply: transmit,
+        })?;
+        receive.recv()??;
+
+        Ok(())
+    }
+    /// Stop watching the given script. Blocks processed from now on are no longer
+    /// matched against it.
+    ///
+    /// Returns whether the script was being watched.
+    fn remove_watch(&self, script: Script) -> Result<bool, Error> {
+        let (reply, receive) = chan::bounded(1);
+        self.command(Command::RemoveWatch { script, reply })?;
+
+        Ok(receive.recv()?)
+    }
     /// Broadcast a message to peers matching the predicate.
     /// To only broadcast to outbound peers, use [`Peer::is_outbound`].
+    ///
+    /// The predicate may capture state, eg. a set of peers to skip.
     fn broadcast(
         &self,
         msg: NetworkMessage,
-        predicate: fn(Peer) -> bool,
+        predicate: impl Fn(&Peer) -> bool + Send + Sync + 'static,
     ) -> Result<Vec<net::SocketAddr>, Error>;
     /// Send a message to a random *outbound* peer. Return the chosen
     /// peer or nothing if no peer was available.
@@ -138,21 +448,156 @@ pub trait Handle: Sized + Send + Sync + Clone {
     fn connect(&self, addr: net::SocketAddr) -> Result<Link, Error>;
     /// Disconnect from the designated peer address.
     fn disconnect(&self, addr: net::SocketAddr) -> Result<(), Error>;
+    /// Disconnect from all negotiated peers, and wait for them to be disconnected.
+    /// Returns the number of peers disconnected.
+    ///
+    /// Note that the node will keep trying to maintain its target number of outbound
+    /// peers, and will connect to new peers after this call.
+    fn disconnect_all(&self) -> Result<usize, Error>;
     /// Submit a transaction to the network.
     ///
     /// Returns the peer(s) the transaction was announced to, or an error if no peers were found.
     fn submit_transaction(&self, tx: Transaction) -> Result<NonEmpty<net::SocketAddr>, Error>;
+    /// Submit a batch of transactions to the network, in a single command.
+    ///
+    /// Returns, for each transaction in the given order, the peer(s) it was announced to, or
+    /// an error. A failure to submit one transaction doesn't prevent the others from being
+    /// submitted.
+    fn submit_transactions(
+        &self,
+        txs: Vec<Transaction>,
+    ) -> Result<Vec<Result<NonEmpty<net::SocketAddr>, CommandError>>, Error>;
+    /// Get the unconfirmed transactions submitted to the network, along with their status.
+    fn get_mempool(&self) -> Result<Vec<(Txid, TxStatus)>, Error>;
+    /// Estimate the fee rate, in satoshis/vByte, required for a transaction to be confirmed
+    /// within the given number of blocks. The estimate is based on the fee rates of the
+    /// most recently processed blocks.
+    ///
+    /// Returns [`None`] if not enough blocks have been processed to produce an estimate.
+    fn estimate_fee(&self, target_blocks: u16) -> Result<Option<FeeRate>, Error>;
     /// Import block headers into the node.
     /// This may cause the node to broadcast header or inventory messages to its peers.
+    ///
+    /// Returns a summary of the import, with the number of headers accepted and the number
+    /// of headers that were already known.
     fn import_headers(
         &self,
         headers: Vec<BlockHeader>,
-    ) -> Result<Result<ImportResult, block::tree::Error>, Error>;
+    ) -> Result<Result<ImportSummary, block::tree::Error>, Error>;
+    /// Import block headers from a reader, eg. a file of consecutive 80-byte serialized
+    /// headers. Headers are read and imported in chunks of [`IMPORT_CHUNK_SIZE`], so
+    /// that the whole set is never held in memory.
+    ///
+    /// Each chunk is checked to be a connected chain, connecting to the previous chunk,
+    /// before it is imported. If a check or an import fails, the chunks imported so far
+    /// are kept. The returned summary covers all chunks; its `result` is the result of
+    /// the last chunk that changed the tip.
+    fn import_headers_from_reader<R: io::Read>(
+        &self,
+        mut reader: R,
+    ) -> Result<Result<ImportSummary, block::tree::Error>, Error> {
+        let mut summary = ImportSummary {
+            accepted: 0,
+            duplicates: 0,
+            new_tip: None,
+            result: ImportResult::TipUnchanged,
+        };
+        let mut last: Option<BlockHash> = None;
+
+        loop {
+            let mut chunk = Vec::with_capacity(IMPORT_CHUNK_SIZE);
+
+            while chunk.len() < IMPORT_CHUNK_SIZE {
+                match read_header(&mut reader)? {
+                    Some(header) => chunk.push(header),
+                    None => break,
+                }
+            }
+            if chunk.is_empty() {
+                break;
+            }
+            for header in &chunk {
+                if let Some(prev) = last {
+                    if header.prev_blockhash != prev {
+                        return Ok(Err(block::tree::Error::BlockMissing(header.prev_blockhash)));
+                    }
+                }
+                last = Some(header.block_hash());
+            }
+
+            let imported = match self.import_headers(chunk)? {
+                Ok(imported) => imported,
+                Err(err) => return Ok(Err(err)),
+            };
+            summary.accepted += imported.accepted;
+            summary.duplicates += imported.duplicates;
+
+            if imported.new_tip.is_some() {
+                summary.new_tip = imported.new_tip;
+                summary.result = imported.result;
+            }
+        }
+        Ok(Ok(summary))
+    }
+    /// Export the block headers of the active chain in the given height range to a writer,
+    /// as consecutive 80-byte serialized headers. This is the format expected by
+    /// [`Handle::import_headers_from_reader`].
+    ///
+    /// Headers are queried and written in chunks of [`IMPORT_CHUNK_SIZE`]. Heights above
+    /// the current tip are skipped. Returns the number of headers written.
+    fn export_headers<W: io::Write>(
+        &self,
+        mut writer: W,
+        range: RangeInclusive<Height>,
+    ) -> Result<usize, Error> {
+        if range.is_empty() {
+            return Err(Error::InvalidRange(range));
+        }
+        let (mut start, end) = range.into_inner();
+        let mut count = 0;
+
+        loop {
+            let last = start
+                .saturating_add(IMPORT_CHUNK_SIZE as Height - 1)
+                .min(end);
+            let (transmit, receive) = chan::bounded(1);
+
+            self.query_tree(move |t| {
+                let headers = (start..=last)
+                    .map_while(|h| t.get_block_by_height(h).copied())
+                    .collect::<Vec<_>>();
+                transmit.send(headers).ok();
+            })?;
+
+            let headers: Vec<BlockHeader> = receive.recv()?;
+            for header in &headers {
+                writer.write_all(&encode::serialize(header))?;
+            }
+            count += headers.len();
+
+            // Stop when we've reached the end of the range, or the tip.
+            if last == end || (headers.len() as Height) < last - start + 1 {
+                break;
+            }
+            start = last + 1;
+        }
+        writer.flush()?;
+
+        Ok(count)
+    }
     /// Import peer addresses into the node's address book.
     fn import_addresses(&self, addrs: Vec<Address>) -> Result<(), Error>;
+    /// Clear the node's address book and re-seed it from the DNS seeds, ie.
+    /// [`crate::Config::dns_seeds`] or the network's built-in seeds. Useful
+    /// to recover connectivity when all known addresses are stale, eg. after a long time
+    /// offline. Seeds are resolved through [`crate::Config::proxy`], if set. The address
+    /// book is left untouched, and an error is returned, if the seeds don't resolve to any
+    /// address.
+    fn reset_peers(&self) -> Result<(), Error>;
     /// Wait for the given predicate to be fulfilled.
     fn wait<F: FnMut(fsm::Event) -> Option<T>, T>(&self, f: F) -> Result<T, Error>;
-    /// Wait for a given number of peers to be connected with the given services.
+    /// Wait for at least the given number of peers to be connected with the given services.
+    /// Exactly `count` peers are returned.
     fn wait_for_peers(
         &self,
         count: usize,
@@ -161,8 +606,38 @@ pub trait Handle: Sized + Send + Sync + Clone {
     /// Wait for the node's active chain to reach a certain height. The hash at that height
     /// is returned.
     fn wait_for_height(&self, h: Height) -> Result<BlockHash, Error>;
+    /// Wait for the filters in the given height range to be processed, ie. for a
+    /// [`fsm::FilterEvent::FilterProcessed`] event to be emitted for every height in the range.
+    /// Filters already processed by the current or last rescan are taken into account.
+    ///
+    /// Returns [`Error::FiltersIncomplete`] with the missing heights on timeout.
+    fn wait_for_filters(&self, range: RangeInclusive<Height>) -> Result<(), Error>;
     /// Listen on events.
     fn events(&self) -> chan::Receiver<fsm::Event>;
     /// Shutdown the node process.
     fn shutdown(self) -> Result<(), Error>;
+    /// Shutdown the node process, and wait for it to stop. The node is stopped once its
+    /// stores are closed and its peer connections are shut down.
+    ///
+    /// Returns [`Error::Timeout`] if the node didn't stop within the given timeout.
+    fn shutdown_timeout(self, timeout: time::Duration) -> Result<(), Error>;
+}
+
+/// Read a serialized block header. Returns [`None`] if the reader is exhausted.
+fn read_header<R: io::Read>(reader: &mut R) -> io::Result<Option<BlockHeader>> {
+    let mut buf = [0; 80];
+    let mut read = 0;
+
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    encode::deserialize(&buf)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
 }
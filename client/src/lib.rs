@@ -2,15 +2,22 @@
 #![allow(clippy::inconsistent_struct_constructor)]
 #![allow(clippy::type_complexity)]
 #![deny(missing_docs, unsafe_code)]
+#[cfg(feature = "async")]
+pub mod async_handle;
 pub mod client;
 pub mod error;
 pub mod event;
 pub mod handle;
+pub mod mempool;
 pub mod peer;
+pub mod rescan;
 pub mod service;
 pub mod spv;
 
 pub use client::*;
 
+#[cfg(feature = "async")]
+pub use async_handle::AsyncHandle;
+
 #[cfg(test)]
 mod tests;
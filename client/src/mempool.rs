@@ -0,0 +1,322 @@
+//! Client-related mempool functionality.
+//!
+//! Transactions submitted to the network are persisted until they are confirmed, so that they
+//! can be re-announced after a restart. Transactions that never confirm are expired after
+//! some time, see [`Cache::expire`].
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+use nakamoto_common::bitcoin::consensus::encode::{self, Decodable, Encodable};
+use nakamoto_common::bitcoin::{Transaction, Txid};
+use nakamoto_common::block::time::{LocalDuration, LocalTime};
+
+/// Age after which unconfirmed transactions are expired from the cache. Matches the default
+/// mempool expiry of most nodes, after which peers have evicted the transaction anyway.
+pub const DEFAULT_MAX_AGE: LocalDuration = LocalDuration::from_mins(14 * 24 * 60);
+
+/// Record tag for a removed transaction.
+const TAG_REMOVE: u8 = 0;
+/// Record tag for an inserted transaction.
+const TAG_INSERT: u8 = 1;
+
+/// A record in the cache file.
+enum Record {
+    /// A transaction was inserted at the given time.
+    Insert(LocalTime, Transaction),
+    /// A transaction was removed.
+    Remove(Txid),
+}
+
+impl Decodable for Record {
+    fn consensus_decode<R: io::Read + ?Sized>(r: &mut R) -> Result<Self, encode::Error> {
+        match u8::consensus_decode(r)? {
+            TAG_INSERT => {
+                let time = LocalTime::from_secs(u64::consensus_decode(r)?);
+                let tx = Transaction::consensus_decode(r)?;
+
+                Ok(Record::Insert(time, tx))
+            }
+            TAG_REMOVE => Txid::consensus_decode(r).map(Record::Remove),
+            _ => Err(encode::Error::ParseFailed("unknown mempool record tag")),
+        }
+    }
+}
+
+/// A file-backed store of unconfirmed transactions.
+///
+/// The file is an append-only log of insert and remove records. It is compacted down to
+/// the live transactions every time the cache is opened.
+#[derive(Debug)]
+pub struct Cache {
+    txs: BTreeMap<Txid, (LocalTime, Transaction)>,
+    file: fs::File,
+}
+
+impl Cache {
+    /// Open an existing cache, compacting its file.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let txs = Self::load(fs::File::open(path)?)?;
+        let file = Self::compact(path, &txs)?;
+
+        Ok(Self { txs, file })
+    }
+
+    /// Create a new cache.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = fs::OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(path)?;
+
+        Ok(Self {
+            file,
+            txs: BTreeMap::new(),
+        })
+    }
+
+    /// Insert a transaction and persist the cache.
+    /// Returns `false` if the transaction was already present.
+    pub fn insert(&mut self, tx: Transaction) -> io::Result<bool> {
+        let txid = tx.txid();
+
+        if self.txs.contains_key(&txid) {
+            return Ok(false);
+        }
+        let time = LocalTime::now();
+        let mut record = vec![TAG_INSERT];
+        time.as_secs().consensus_encode(&mut record)?;
+        tx.consensus_encode(&mut record)?;
+
+        self.append(&record)?;
+        self.txs.insert(txid, (time, tx));
+
+        Ok(true)
+    }
+
+    /// Remove a transaction and persist the cache.
+    /// Returns `false` if the transaction wasn't present.
+    pub fn remove(&mut self, txid: &Txid) -> io::Result<bool> {
+        if !self.txs.contains_key(txid) {
+            return Ok(false);
+        }
+        let mut record = vec![TAG_REMOVE];
+        txid.consensus_encode(&mut record)?;
+
+        self.append(&record)?;
+        self.txs.remove(txid);
+
+        Ok(true)
+    }
+
+    /// Remove the transactions that were inserted at least `max_age` ago, and persist the
+    /// cache. Returns the expired transaction ids.
+    pub fn expire(&mut self, max_age: LocalDuration, now: LocalTime) -> io::Result<Vec<Txid>> {
+        let expired = self
+            .txs
+            .iter()
+            .filter(|(_, (time, _))| now - *time >= max_age)
+            .map(|(txid, _)| *txid)
+            .collect::<Vec<_>>();
+
+        for txid in &expired {
+            self.remove(txid)?;
+        }
+        Ok(expired)
+    }
+
+    /// Iterate over the cached transactions.
+    pub fn iter(&self) -> impl Iterator<Item = &Transaction> {
+        self.txs.values().map(|(_, tx)| tx)
+    }
+
+    /// Number of cached transactions.
+    pub fn len(&self) -> usize {
+        self.txs.len()
+    }
+
+    /// Whether the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.txs.is_empty()
+    }
+
+    /// Append a record to the file.
+    fn append(&mut self, record: &[u8]) -> io::Result<()> {
+        self.file.write_all(record)?;
+        self.file.sync_data()
+    }
+
+    /// Replay the records of a cache file.
+    ///
+    /// A truncated record at the end of the file, eg. due to a crash during a write,
+    /// is ignored.
+    fn load(mut file: fs::File) -> io::Result<BTreeMap<Txid, (LocalTime, Transaction)>> {
+        use io::Read;
+
+        let mut bytes = Vec::new();
+        let mut txs = BTreeMap::new();
+
+        file.read_to_end(&mut bytes)?;
+
+        let mut cursor = io::Cursor::new(bytes.as_slice());
+
+        while (cursor.position() as usize) < bytes.len() {
+            match Record::consensus_decode(&mut cursor) {
+                Ok(Record::Insert(time, tx)) => {
+                    txs.insert(tx.txid(), (time, tx));
+                }
+                Ok(Record::Remove(txid)) => {
+                    txs.remove(&txid);
+                }
+                Err(encode::Error::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(_) => return Err(io::ErrorKind::InvalidData.into()),
+            }
+        }
+        Ok(txs)
+    }
+
+    /// Rewrite the cache file with only the given transactions, and return it opened
+    /// for appending. The new file is written next to the old one and renamed over it,
+    /// so that a crash can't leave a truncated cache behind.
+    fn compact(
+        path: &Path,
+        txs: &BTreeMap<Txid, (LocalTime, Transaction)>,
+    ) -> io::Result<fs::File> {
+        let mut tmp = PathBuf::from(path);
+        tmp.set_extension("tmp");
+
+        {
+            let mut file = fs::File::create(&tmp)?;
+            let mut bytes = Vec::new();
+
+            for (time, tx) in txs.values() {
+                bytes.push(TAG_INSERT);
+                time.as_secs().consensus_encode(&mut bytes)?;
+                tx.consensus_encode(&mut bytes)?;
+            }
+            file.write_all(&bytes)?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, path)?;
+
+        fs::OpenOptions::new().append(true).open(path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use nakamoto_test::block::gen;
+
+    #[test]
+    fn test_save_and_load() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("txs.db");
+        let mut rng = fastrand::Rng::new();
+        let txs = (0..8)
+            .map(|_| gen::transaction(&mut rng))
+            .collect::<Vec<_>>();
+        let (first, rest) = txs.split_first().unwrap();
+
+        {
+            let mut cache = Cache::create(&path).unwrap();
+
+            assert!(cache.insert(first.clone()).unwrap());
+            assert!(!cache.insert(first.clone()).unwrap());
+
+            for tx in rest {
+                cache.insert(tx.clone()).unwrap();
+            }
+            assert!(cache.remove(&first.txid()).unwrap());
+            assert!(!cache.remove(&first.txid()).unwrap());
+        }
+
+        let cache = Cache::open(&path).unwrap();
+
+        assert_eq!(cache.len(), rest.len());
+        for tx in rest {
+            assert!(cache.iter().any(|t| t == tx));
+        }
+    }
+
+    #[test]
+    fn test_compact_and_truncated() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("txs.db");
+        let mut rng = fastrand::Rng::new();
+        let txs = (0..4)
+            .map(|_| gen::transaction(&mut rng))
+            .collect::<Vec<_>>();
+
+        {
+            let mut cache = Cache::create(&path).unwrap();
+
+            for tx in &txs {
+                cache.insert(tx.clone()).unwrap();
+            }
+            cache.remove(&txs[0].txid()).unwrap();
+        }
+        let size = fs::metadata(&path).unwrap().len();
+
+        // Simulate a crash in the middle of appending a record.
+        {
+            let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+            file.write_all(&[TAG_INSERT, 0x01, 0x00]).unwrap();
+        }
+
+        let mut cache = Cache::open(&path).unwrap();
+        assert_eq!(cache.len(), txs.len() - 1);
+        assert!(fs::metadata(&path).unwrap().len() < size);
+
+        // Records appended after compaction are still readable.
+        cache.remove(&txs[1].txid()).unwrap();
+        drop(cache);
+
+        let cache = Cache::open(&path).unwrap();
+        assert_eq!(cache.len(), txs.len() - 2);
+        assert!(cache.iter().all(|t| t != &txs[0] && t != &txs[1]));
+    }
+
+    #[test]
+    fn test_expire() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("txs.db");
+        let mut rng = fastrand::Rng::new();
+        let (old, new) = (gen::transaction(&mut rng), gen::transaction(&mut rng));
+        let day = LocalDuration::from_mins(24 * 60);
+
+        {
+            let mut cache = Cache::create(&path).unwrap();
+            cache.insert(old.clone()).unwrap();
+        }
+        // Backdate the first transaction by rewriting the file.
+        {
+            let mut txs = Cache::load(fs::File::open(&path).unwrap()).unwrap();
+            txs.get_mut(&old.txid()).unwrap().0 = LocalTime::now() - day * 15;
+            Cache::compact(&path, &txs).unwrap();
+        }
+        let mut cache = Cache::open(&path).unwrap();
+        cache.insert(new.clone()).unwrap();
+
+        assert_eq!(
+            cache.expire(DEFAULT_MAX_AGE, LocalTime::now()).unwrap(),
+            vec![old.txid()]
+        );
+        assert_eq!(cache.iter().collect::<Vec<_>>(), vec![&new]);
+        drop(cache);
+
+        let mut cache = Cache::open(&path).unwrap();
+        assert_eq!(
+            cache.len(),
+            1,
+            "Expired transactions are removed from the file"
+        );
+        assert!(cache
+            .expire(DEFAULT_MAX_AGE, LocalTime::now() + DEFAULT_MAX_AGE)
+            .unwrap()
+            .contains(&new.txid()));
+        assert!(cache.is_empty());
+    }
+}
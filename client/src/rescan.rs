@@ -0,0 +1,331 @@
+//! Client-related rescan functionality.
+//!
+//! The progress of a filter rescan is persisted, so that an interrupted rescan can continue
+//! where it left off after a restart, instead of starting over.
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::str::FromStr;
+use std::{fs, io};
+
+use microserde as serde;
+
+use nakamoto_common::bitcoin::Script;
+use nakamoto_common::block::Height;
+
+/// Interval, in blocks, at which the rescan state is persisted while processing filters.
+/// The state is always persisted when a block is matched or processed.
+pub const FLUSH_INTERVAL: Height = 1000;
+
+/// Progress of a filter rescan.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct State {
+    /// Height the rescan was started from.
+    pub start: Height,
+    /// Height the rescan stops at, if any.
+    pub end: Option<Height>,
+    /// Scripts being matched.
+    pub watch: Vec<Script>,
+    /// Height up to which filters were processed.
+    pub filter_height: Height,
+    /// Height up to which filters and matching blocks were processed.
+    pub sync_height: Height,
+    /// Height up to which matching blocks were processed.
+    pub block_height: Height,
+    /// Heights of matching blocks that haven't been processed yet.
+    pub pending: BTreeSet<Height>,
+}
+
+impl State {
+    /// Create a new rescan state, starting at the given height.
+    pub fn new(start: Height) -> Self {
+        Self {
+            start,
+            end: None,
+            watch: Vec::new(),
+            filter_height: start,
+            sync_height: start,
+            block_height: start,
+            pending: BTreeSet::new(),
+        }
+    }
+
+    /// Height from which an interrupted rescan should resume, ie. the lowest height
+    /// that wasn't fully processed.
+    pub fn resume_height(&self) -> Height {
+        match self.pending.iter().next() {
+            Some(height) => *height,
+            None if self.filter_height > self.start => self.filter_height + 1,
+            None => self.start,
+        }
+    }
+
+    /// Record a processed filter. Returns `true` if the state should be persisted.
+    pub fn filter_processed(&mut self, height: Height, matched: bool) -> bool {
+        let flush = matched || height % FLUSH_INTERVAL == 0;
+
+        if matched {
+            self.pending.insert(height);
+        }
+        self.filter_height = height;
+        self.update();
+
+        flush
+    }
+
+    /// Record a processed block. Returns `true` if the block was pending.
+    pub fn block_processed(&mut self, height: Height) -> bool {
+        if !self.pending.remove(&height) {
+            return false;
+        }
+        self.block_height = height;
+        self.update();
+
+        true
+    }
+
+    /// Convert to JSON.
+    pub fn to_json(&self) -> serde::json::Value {
+        use serde::json::{Array, Number, Object, Value};
+
+        let mut obj = Object::new();
+
+        obj.insert("start".to_owned(), Value::Number(Number::U64(self.start)));
+        obj.insert(
+            "end".to_owned(),
+            self.end
+                .map_or(Value::Null, |h| Value::Number(Number::U64(h))),
+        );
+        obj.insert(
+            "watch".to_owned(),
+            Value::Array(
+                self.watch
+                    .iter()
+                    .map(|s| Value::String(format!("{:x}", s)))
+                    .collect::<Array>(),
+            ),
+        );
+        obj.insert(
+            "filter_height".to_owned(),
+            Value::Number(Number::U64(self.filter_height)),
+        );
+        obj.insert(
+            "sync_height".to_owned(),
+            Value::Number(Number::U64(self.sync_height)),
+        );
+        obj.insert(
+            "block_height".to_owned(),
+            Value::Number(Number::U64(self.block_height)),
+        );
+        obj.insert(
+            "pending".to_owned(),
+            Value::Array(
+                self.pending
+                    .iter()
+                    .map(|h| Value::Number(Number::U64(*h)))
+                    .collect::<Array>(),
+            ),
+        );
+
+        Value::Object(obj)
+    }
+
+    /// Convert from JSON.
+    pub fn from_json(v: serde::json::Value) -> Result<Self, serde::Error> {
+        use serde::json::{Number, Value};
+
+        let obj = match v {
+            Value::Object(obj) => obj,
+            _ => return Err(serde::Error),
+        };
+        let height = |key: &str| match obj.get(key) {
+            Some(Value::Number(Number::U64(n))) => Ok(*n),
+            _ => Err(serde::Error),
+        };
+        let pending = match obj.get("pending") {
+            Some(Value::Array(ary)) => ary
+                .iter()
+                .map(|v| match v {
+                    Value::Number(Number::U64(n)) => Ok(*n),
+                    _ => Err(serde::Error),
+                })
+                .collect::<Result<_, _>>()?,
+            _ => return Err(serde::Error),
+        };
+        let end = match obj.get("end") {
+            Some(Value::Number(Number::U64(n))) => Some(*n),
+            Some(Value::Null) => None,
+            _ => return Err(serde::Error),
+        };
+        let watch = match obj.get("watch") {
+            Some(Value::Array(ary)) => ary
+                .iter()
+                .map(|v| match v {
+                    Value::String(s) => Script::from_str(s).map_err(|_| serde::Error),
+                    _ => Err(serde::Error),
+                })
+                .collect::<Result<_, _>>()?,
+            _ => return Err(serde::Error),
+        };
+
+        Ok(Self {
+            start: height("start")?,
+            end,
+            watch,
+            filter_height: height("filter_height")?,
+            sync_height: height("sync_height")?,
+            block_height: height("block_height")?,
+            pending,
+        })
+    }
+
+    /// Update the sync height. If we have no blocks left to process, we are synced
+    /// to the height of the last processed filter. Otherwise, we're synced up to the
+    /// last processed block.
+    fn update(&mut self) {
+        self.sync_height = if self.pending.is_empty() {
+            self.filter_height
+        } else {
+            self.block_height
+        };
+    }
+}
+
+/// A file-backed rescan state.
+#[derive(Debug)]
+pub struct Cache {
+    state: Option<State>,
+    file: fs::File,
+}
+
+impl Cache {
+    /// Open an existing cache.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .and_then(Self::from)
+    }
+
+    /// Create a new cache.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = fs::OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(path)?;
+
+        Ok(Self { file, state: None })
+    }
+
+    /// Create a new cache from a file.
+    pub fn from(mut file: fs::File) -> io::Result<Self> {
+        use io::Read;
+
+        let mut s = String::new();
+        let mut state = None;
+
+        file.read_to_string(&mut s)?;
+
+        if !s.trim().is_empty() {
+            let val = serde::json::from_str(&s)
+                .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+
+            if !matches!(val, serde::json::Value::Null) {
+                state = Some(
+                    State::from_json(val)
+                        .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?,
+                );
+            }
+        }
+
+        Ok(Self { file, state })
+    }
+
+    /// Get the persisted rescan state, if any.
+    pub fn state(&self) -> Option<&State> {
+        self.state.as_ref()
+    }
+
+    /// Get the rescan state mutably, if any. Changes are persisted on the next flush.
+    pub fn state_mut(&mut self) -> Option<&mut State> {
+        self.state.as_mut()
+    }
+
+    /// Set the rescan state and persist the cache.
+    /// Setting the state to `None` clears it.
+    pub fn set(&mut self, state: Option<State>) -> io::Result<()> {
+        self.state = state;
+        self.flush()
+    }
+
+    /// Flush the cache to disk.
+    pub fn flush(&mut self) -> io::Result<()> {
+        use io::{Seek, Write};
+
+        let s = match &self.state {
+            Some(state) => serde::json::to_string(&state.to_json()),
+            None => serde::json::to_string(&serde::json::Value::Null),
+        };
+
+        self.file.set_len(0)?;
+        self.file.seek(io::SeekFrom::Start(0))?;
+        self.file.write_all(s.as_bytes())?;
+        self.file.write_all(&[b'\n'])?;
+        self.file.sync_data()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_resume_height() {
+        let mut state = State::new(100);
+
+        assert_eq!(state.resume_height(), 100);
+
+        state.filter_processed(100, false);
+        state.filter_processed(101, true);
+        state.filter_processed(102, false);
+        assert_eq!(state.sync_height, 100);
+        assert_eq!(state.resume_height(), 101);
+
+        assert!(state.block_processed(101));
+        assert!(!state.block_processed(101));
+        assert_eq!(state.sync_height, 102);
+        assert_eq!(state.resume_height(), 103);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("rescan.json");
+        let mut state = State {
+            end: Some(144),
+            watch: vec![Script::from(vec![0x51]), Script::from(vec![0x00, 0x14])],
+            ..State::new(42)
+        };
+
+        state.filter_processed(43, true);
+        state.filter_processed(44, true);
+        state.filter_processed(45, false);
+        state.block_processed(43);
+
+        {
+            let mut cache = Cache::create(&path).unwrap();
+            assert_eq!(cache.state(), None);
+
+            cache.set(Some(state.clone())).unwrap();
+        }
+        {
+            let mut cache = Cache::open(&path).unwrap();
+            assert_eq!(cache.state(), Some(&state));
+
+            cache.set(None).unwrap();
+        }
+        assert_eq!(Cache::open(&path).unwrap().state(), None);
+    }
+}
@@ -1,23 +1,56 @@
 //! TODO
 use std::borrow::{Borrow, Cow};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::net;
+use std::ops::Bound;
 use std::sync::Arc;
 
 use nakamoto_chain::BlockTree;
 use nakamoto_common::bitcoin::consensus::Encodable;
+use nakamoto_common::bitcoin::network::message::RawNetworkMessage;
+use nakamoto_common::bitcoin::Script;
 use nakamoto_common::block::time::{AdjustedClock, LocalTime};
+use nakamoto_common::block::Height;
 use nakamoto_net::{DisconnectReason, Io, Link, StateMachine};
 use nakamoto_p2p as p2p;
 
-use crate::client::Config;
+use crate::client::{chan, Config};
+use crate::mempool;
 use crate::peer;
+use crate::rescan;
 use nakamoto_common::block::filter;
 
+/// Traffic statistics of a peer connection.
+#[derive(Debug, Default, Clone, Copy)]
+struct Stats {
+    /// Number of bytes sent.
+    bytes_sent: u64,
+    /// Number of bytes received.
+    bytes_received: u64,
+    /// Time at which the last message was received.
+    last_message: Option<LocalTime>,
+}
+
 /// Client service. Wraps a state machine and handles decoding and encoding of network messages.
 pub struct Service<T, F, P, C> {
     inboxes: HashMap<net::SocketAddr, p2p::stream::Decoder>,
+    /// Traffic statistics, per peer connection.
+    stats: HashMap<net::SocketAddr, Stats>,
+    /// Bandwidth usage since startup, across all connections.
+    bandwidth: p2p::fsm::Bandwidth,
+    /// Local time, as of the last tick.
+    local_time: LocalTime,
     machine: p2p::StateMachine<T, F, P, C>,
+    mempool: Option<mempool::Cache>,
+    rescan: Option<rescan::Cache>,
+    /// Start height of a rescan that was resumed from a persisted state.
+    resumed: Option<Height>,
+    /// Scripts watched by the state machine, persisted with the rescan state.
+    watch: Vec<Script>,
+    /// Whether to emit an event for every message sent or received.
+    capture_messages: bool,
+    /// Captured message events waiting to be returned.
+    captured: VecDeque<p2p::Event>,
 }
 
 impl<T: BlockTree, F: filter::Filters, P: peer::Store, C: AdjustedClock<net::SocketAddr>>
@@ -32,8 +65,17 @@ impl<T: BlockTree, F: filter::Filters, P: peer::Store, C: AdjustedClock<net::Soc
         rng: fastrand::Rng,
         config: Config,
     ) -> Self {
+        let capture_messages = config.capture_messages;
+        let params = match config.custom_params {
+            Some(custom) => custom.params,
+            None => config.network.params(),
+        };
+
         Self {
             inboxes: HashMap::new(),
+            stats: HashMap::new(),
+            bandwidth: p2p::fsm::Bandwidth::default(),
+            local_time: LocalTime::default(),
             machine: p2p::StateMachine::new(
                 tree,
                 filters,
@@ -42,16 +84,150 @@ impl<T: BlockTree, F: filter::Filters, P: peer::Store, C: AdjustedClock<net::Soc
                 rng,
                 p2p::Config {
                     network: config.network,
+                    params,
                     domains: config.domains,
                     connect: config.connect,
                     user_agent: config.user_agent,
                     hooks: config.hooks,
                     limits: config.limits,
+                    reconnect_backoff: config.reconnect_backoff,
+                    connect_timeout: config.connect_timeout,
+                    stale_tip_timeout: config.stale_tip_timeout,
+                    block_download_concurrency: config.block_download_concurrency,
+                    block_request_retries: config.block_request_retries,
+                    compact_blocks: config.compact_blocks,
+                    drop_window: config.drop_window,
+                    sync_filters: config.sync_filters,
+                    filter_sync_start: config.filter_sync_start,
+                    filter_download_rate: config.filter_download_rate,
                     services: config.services,
+                    required_services: config.required_services,
+                    relay_transactions: config.relay_transactions,
+                    min_relay_feerate: config.min_relay_feerate,
+                    prefer_headers_announcements: config.prefer_headers_announcements,
 
                     ..p2p::Config::default()
                 },
             ),
+            mempool: None,
+            rescan: None,
+            resumed: None,
+            watch: Vec::new(),
+            capture_messages,
+            captured: VecDeque::new(),
+        }
+    }
+
+    /// Persist submitted transactions to the given cache until they are confirmed.
+    /// Transactions already in the cache are re-announced when the service is initialized.
+    pub fn with_mempool(mut self, mempool: mempool::Cache) -> Self {
+        self.mempool = Some(mempool);
+        self
+    }
+
+    /// Persist the progress of filter rescans to the given cache. A rescan that was
+    /// interrupted is resumed where it left off when the service is initialized.
+    pub fn with_rescan(mut self, rescan: rescan::Cache) -> Self {
+        self.rescan = Some(rescan);
+        self
+    }
+}
+
+impl<T, F, P, C> Service<T, F, P, C> {
+    /// Update the transaction cache based on a state machine event.
+    fn persist(&mut self, event: &p2p::Event) {
+        let mempool = if let Some(mempool) = &mut self.mempool {
+            mempool
+        } else {
+            return;
+        };
+        let result = match event {
+            p2p::Event::Inventory(p2p::fsm::InventoryEvent::Confirmed { transaction, .. }) => {
+                mempool.remove(&transaction.txid())
+            }
+            p2p::Event::Inventory(p2p::fsm::InventoryEvent::Reverted { transaction }) => {
+                mempool.insert(transaction.clone())
+            }
+            p2p::Event::Inventory(p2p::fsm::InventoryEvent::Dropped { txid, .. })
+            | p2p::Event::Inventory(p2p::fsm::InventoryEvent::Replaced { txid, .. }) => {
+                mempool.remove(txid)
+            }
+            _ => return,
+        };
+        if let Err(err) = result {
+            log::error!("Failed to update transaction cache: {}", err);
+        }
+    }
+
+    /// Update the rescan state based on a state machine event.
+    fn persist_rescan(&mut self, event: &p2p::Event) {
+        let cache = if let Some(cache) = &mut self.rescan {
+            cache
+        } else {
+            return;
+        };
+        let result = match event {
+            p2p::Event::Filter(p2p::fsm::FilterEvent::RescanStarted { start, end }) => {
+                // Keep the existing progress if this rescan was resumed.
+                if self.resumed.take() == Some(*start) {
+                    return;
+                }
+                cache.set(Some(rescan::State {
+                    end: *end,
+                    watch: self.watch.clone(),
+                    ..rescan::State::new(*start)
+                }))
+            }
+            p2p::Event::Filter(p2p::fsm::FilterEvent::Watching { scripts }) => {
+                for script in scripts {
+                    if !self.watch.contains(script) {
+                        self.watch.push(script.clone());
+                    }
+                }
+                match cache.state_mut() {
+                    Some(state) => state.watch = self.watch.clone(),
+                    None => return,
+                }
+                cache.flush()
+            }
+            p2p::Event::Filter(p2p::fsm::FilterEvent::Unwatched { script }) => {
+                self.watch.retain(|s| s != script);
+
+                match cache.state_mut() {
+                    Some(state) => state.watch = self.watch.clone(),
+                    None => return,
+                }
+                cache.flush()
+            }
+            p2p::Event::Filter(p2p::fsm::FilterEvent::RescanCompleted { .. }) => cache.set(None),
+            // An aborted rescan isn't resumed.
+            p2p::Event::Filter(p2p::fsm::FilterEvent::RescanAborted { .. }) => cache.set(None),
+            p2p::Event::Filter(p2p::fsm::FilterEvent::FilterProcessed {
+                height, matched, ..
+            }) => {
+                let flush = match cache.state_mut() {
+                    Some(state) => state.filter_processed(*height, *matched),
+                    None => false,
+                };
+                if !flush {
+                    return;
+                }
+                cache.flush()
+            }
+            p2p::Event::Inventory(p2p::fsm::InventoryEvent::BlockProcessed { height, .. }) => {
+                let flush = match cache.state_mut() {
+                    Some(state) => state.block_processed(*height),
+                    None => false,
+                };
+                if !flush {
+                    return;
+                }
+                cache.flush()
+            }
+            _ => return,
+        };
+        if let Err(err) = result {
+            log::error!("Failed to persist rescan state: {}", err);
         }
     }
 }
@@ -66,6 +242,41 @@ where
     type Command = p2p::Command;
 
     fn command(&mut self, cmd: Self::Command) {
+        if let (p2p::Command::SubmitTransaction(tx, _), Some(mempool)) = (&cmd, &mut self.mempool) {
+            if let Err(err) = mempool.insert(tx.clone()) {
+                log::error!("Failed to persist transaction {}: {}", tx.txid(), err);
+            }
+        }
+        if let (p2p::Command::SubmitTransactions(txs, _), Some(mempool)) = (&cmd, &mut self.mempool)
+        {
+            for tx in txs {
+                if let Err(err) = mempool.insert(tx.clone()) {
+                    log::error!("Failed to persist transaction {}: {}", tx.txid(), err);
+                }
+            }
+        }
+        if let p2p::Command::Rescan { watch, .. } = &cmd {
+            // A rescan replaces the watch list of the state machine.
+            self.watch = watch.clone();
+        }
+        if let p2p::Command::GetPeerInfo(addr, reply) = &cmd {
+            let info = self.machine.peer_info(addr).map(|mut info| {
+                if let Some(stats) = self.stats.get(addr) {
+                    info.bytes_sent = stats.bytes_sent;
+                    info.bytes_received = stats.bytes_received;
+                    info.last_message = stats.last_message;
+                }
+                info
+            });
+            reply.send(info).ok();
+
+            return;
+        }
+        if let p2p::Command::GetBandwidth(reply) = &cmd {
+            reply.send(self.bandwidth).ok();
+
+            return;
+        }
         // TODO: Commands shouldn't be handled by the inner state machine.
         self.machine.command(cmd)
     }
@@ -83,10 +294,49 @@ where
     type DisconnectReason = p2p::DisconnectReason;
 
     fn initialize(&mut self, time: LocalTime) {
+        self.local_time = time;
         self.machine.initialize(time);
+
+        if let Some(state) = self.rescan.as_ref().and_then(|cache| cache.state()) {
+            // Resume an interrupted rescan from the lowest height not fully processed.
+            // Since this is the lowest pending height, the blocks that were matched but not
+            // processed are matched again, requested, and reported to the client.
+            let height = state.resume_height();
+            let watch = state.watch.clone();
+            let to = state.end.map_or(Bound::Unbounded, Bound::Included);
+
+            log::info!(
+                "Resuming rescan started at height {} from height {}",
+                state.start,
+                height
+            );
+            self.resumed = Some(height);
+
+            let (reply, result) = chan::bounded(1);
+            self.command(p2p::Command::Rescan {
+                from: Bound::Included(height),
+                to,
+                watch,
+                reply,
+            });
+            if let Ok(Err(err)) = result.try_recv() {
+                log::error!("Unable to resume rescan: {}", err);
+            }
+        }
+
+        if let Some(mempool) = &self.mempool {
+            // Re-announce transactions that were not confirmed before the last shutdown.
+            // They are kept in the state machine's mempool until peers are available.
+            for tx in mempool.iter().cloned().collect::<Vec<_>>() {
+                let (reply, _) = chan::bounded(1);
+                self.machine
+                    .command(p2p::Command::SubmitTransaction(tx, reply));
+            }
+        }
     }
 
     fn tick(&mut self, local_time: LocalTime) {
+        self.local_time = local_time;
         self.machine.tick(local_time);
     }
 
@@ -96,11 +346,27 @@ where
 
     fn received(&mut self, addr: &net::SocketAddr, bytes: Cow<[u8]>) {
         if let Some(inbox) = self.inboxes.get_mut(addr) {
+            let stats = self.stats.entry(*addr).or_default();
+
+            stats.bytes_received += bytes.len() as u64;
             inbox.input(bytes.borrow());
 
             loop {
-                match inbox.decode_next() {
-                    Ok(Some(msg)) => self.machine.received(addr, Cow::Owned(msg)),
+                let buffered = inbox.buffered();
+
+                match inbox.decode_next::<RawNetworkMessage>() {
+                    Ok(Some(msg)) => {
+                        let size = buffered - inbox.buffered();
+
+                        stats.last_message = Some(self.local_time);
+                        self.bandwidth.received.record(&msg.payload, size as u64);
+
+                        if self.capture_messages {
+                            self.captured
+                                .push_back(p2p::Event::Received(*addr, msg.payload.clone()));
+                        }
+                        self.machine.received(addr, Cow::Owned(msg))
+                    }
                     Ok(None) => break,
 
                     Err(err) => {
@@ -124,6 +390,7 @@ where
 
     fn connected(&mut self, addr: net::SocketAddr, local_addr: &net::SocketAddr, link: Link) {
         self.inboxes.insert(addr, p2p::stream::Decoder::new(1024));
+        self.stats.insert(addr, Stats::default());
         self.machine.connected(addr, local_addr, link)
     }
 
@@ -133,6 +400,7 @@ where
         reason: DisconnectReason<Self::DisconnectReason>,
     ) {
         self.inboxes.remove(addr);
+        self.stats.remove(addr);
         self.machine.disconnected(addr, reason)
     }
 }
@@ -141,6 +409,9 @@ impl<T, F, P, C> Iterator for Service<T, F, P, C> {
     type Item = Io<Vec<u8>, p2p::Event, p2p::DisconnectReason>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.captured.pop_front() {
+            return Some(Io::Event(e));
+        }
         match self.machine.next() {
             Some(Io::Write(addr, msg)) => {
                 log::debug!("Write {:?} to {}", &msg, addr.ip());
@@ -149,9 +420,21 @@ impl<T, F, P, C> Iterator for Service<T, F, P, C> {
                 msg.consensus_encode(&mut buf)
                     .expect("writing to an in-memory buffer doesn't fail");
 
+                if let Some(stats) = self.stats.get_mut(&addr) {
+                    stats.bytes_sent += buf.len() as u64;
+                }
+                self.bandwidth.sent.record(&msg.payload, buf.len() as u64);
+                if self.capture_messages {
+                    self.captured.push_back(p2p::Event::Sent(addr, msg.payload));
+                }
+
                 Some(Io::Write(addr, buf))
             }
-            Some(Io::Event(e)) => Some(Io::Event(e)),
+            Some(Io::Event(e)) => {
+                self.persist(&e);
+                self.persist_rescan(&e);
+                Some(Io::Event(e))
+            }
             Some(Io::Connect(a)) => Some(Io::Connect(a)),
             Some(Io::Disconnect(a, r)) => Some(Io::Disconnect(a, r)),
             Some(Io::Wakeup(d)) => Some(Io::Wakeup(d)),
@@ -6,17 +6,121 @@ pub mod utxos;
 #[cfg(test)]
 mod tests;
 
-use std::collections::HashSet;
-use std::{fmt, net};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use std::{fmt, net, time};
 
-use nakamoto_common::bitcoin::{Block, Txid};
-use nakamoto_common::block::{BlockHash, Height};
+use crossbeam_channel as chan;
+use nakamoto_common::bitcoin::consensus::encode;
+use nakamoto_common::bitcoin::util::merkleblock::MerkleBlock;
+use nakamoto_common::bitcoin::{Block, Script, Txid};
+use nakamoto_common::block::{BlockHash, BlockHeader, Height};
+use nakamoto_common::p2p::peer::Source;
 use nakamoto_net::event::Emitter;
 use nakamoto_p2p as p2p;
 use p2p::fsm;
 
 use crate::client::Event;
 
+use self::utxos::Utxos;
+
+/// Subscriptions to the status changes of specific transactions.
+/// Shared between the [`Mapper`] and client handles.
+#[derive(Debug, Clone, Default)]
+pub struct Trackers {
+    #[allow(clippy::type_complexity)]
+    trackers: Arc<Mutex<Vec<(HashSet<Txid>, chan::Sender<(Txid, TxStatus)>)>>>,
+}
+
+impl Trackers {
+    /// Track the given transactions. Their status changes are sent on the returned channel,
+    /// until it is dropped.
+    pub fn track(&self, txids: impl IntoIterator<Item = Txid>) -> chan::Receiver<(Txid, TxStatus)> {
+        let (sender, receiver) = chan::unbounded();
+
+        self.trackers
+            .lock()
+            .unwrap()
+            .push((txids.into_iter().collect(), sender));
+
+        receiver
+    }
+
+    /// Send a status change to the trackers of the given transaction. Trackers whose
+    /// channel was dropped are removed.
+    fn notify(&self, txid: Txid, status: &TxStatus) {
+        self.trackers.lock().unwrap().retain(|(txids, sender)| {
+            !txids.contains(&txid) || sender.send((txid, status.clone())).is_ok()
+        });
+    }
+}
+
+pub use fsm::{DEFAULT_DROP_WINDOW, TRANSACTION_PRUNE_DEPTH};
+
+/// Reason for a transaction being dropped.
+#[derive(Debug, Clone, PartialOrd, Ord, PartialEq, Eq)]
+pub enum DropReason {
+    /// The transaction wasn't confirmed or acknowledged again by any peer for the given
+    /// number of blocks. It was likely evicted from peer mempools, eg. due to expiry or
+    /// a minimum fee increase.
+    Expired {
+        /// Number of blocks since the transaction was last acknowledged.
+        blocks: Height,
+    },
+}
+
+impl fmt::Display for DropReason {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Expired { blocks } => write!(
+                fmt,
+                "not confirmed or acknowledged by any peer in the last {} block(s)",
+                blocks
+            ),
+        }
+    }
+}
+
+/// Proof of inclusion of a transaction in a block. Consists of the block header and a
+/// partial merkle tree committing to the transaction, and can be verified independently
+/// of the client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleProof(pub MerkleBlock);
+
+impl MerkleProof {
+    /// Compute the proof of inclusion of a transaction in the given block.
+    pub fn new(block: &Block, txid: &Txid) -> Self {
+        Self(MerkleBlock::from_block_with_predicate(block, |t| t == txid))
+    }
+
+    /// Header of the block the transaction was included in.
+    pub fn header(&self) -> &BlockHeader {
+        &self.0.header
+    }
+
+    /// Check that the proof commits to the given transaction, under the merkle root of
+    /// the block header.
+    pub fn verify(&self, txid: &Txid) -> bool {
+        let mut matches = Vec::new();
+        let mut indexes = Vec::new();
+
+        self.0.extract_matches(&mut matches, &mut indexes).is_ok() && matches == [*txid]
+    }
+}
+
+impl PartialOrd for MerkleProof {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for MerkleProof {
+    fn cmp(&self, other: &Self) -> Ordering {
+        encode::serialize(&self.0).cmp(&encode::serialize(&other.0))
+    }
+}
+
 /// Transaction status of a given transaction.
 #[derive(Debug, Clone, PartialOrd, Ord, PartialEq, Eq)]
 pub enum TxStatus {
@@ -31,6 +135,14 @@ pub enum TxStatus {
         /// Peer acknowledging the transaction.
         peer: net::SocketAddr,
     },
+    /// Transaction was announced to us by a peer, meaning it is in the peer's mempool.
+    ///
+    /// This is a stronger signal of propagation than [`TxStatus::Acknowledged`], and is
+    /// reported once for every peer announcing the transaction.
+    InMempool {
+        /// Peer announcing the transaction.
+        peer: net::SocketAddr,
+    },
     /// Transaction was included in a block. This event is fired after
     /// a block from the main chain is scanned.
     Confirmed {
@@ -38,6 +150,9 @@ pub enum TxStatus {
         height: Height,
         /// Hash of the block in which it was included.
         block: BlockHash,
+        /// Proof of inclusion of the transaction in the block. Only included when merkle
+        /// proofs are enabled on the [`Mapper`].
+        merkle_proof: Option<MerkleProof>,
     },
     /// A transaction that was previously confirmed, and is now reverted due to a
     /// re-org. Note that this event can only fire if the originally confirmed tx
@@ -53,6 +168,12 @@ pub enum TxStatus {
         /// Block of the included transaction.
         block: BlockHash,
     },
+    /// Transaction was dropped from peer mempools without being replaced, and will
+    /// probably never be included in a block unless it is re-broadcast.
+    Dropped {
+        /// Reason the transaction was dropped.
+        reason: DropReason,
+    },
 }
 
 impl fmt::Display for TxStatus {
@@ -62,7 +183,10 @@ impl fmt::Display for TxStatus {
             Self::Acknowledged { peer } => {
                 write!(fmt, "transaction was acknowledged by peer {}", peer)
             }
-            Self::Confirmed { height, block } => write!(
+            Self::InMempool { peer } => {
+                write!(fmt, "transaction was announced by peer {}", peer)
+            }
+            Self::Confirmed { height, block, .. } => write!(
                 fmt,
                 "transaction was included in block {} at height {}",
                 block, height
@@ -73,15 +197,30 @@ impl fmt::Display for TxStatus {
                 "transaction was replaced by {} in block {}",
                 replaced_by, block
             ),
+            Self::Dropped { reason } => write!(fmt, "transaction was dropped: {}", reason),
         }
     }
 }
 
+/// A re-org in progress, ie. blocks disconnected and connected since the
+/// chain was last in sync.
+#[derive(Debug)]
+struct Reorg {
+    /// Last block shared by the old and new chain.
+    common_ancestor: (Height, BlockHash),
+    /// Blocks disconnected, starting from the old tip.
+    disconnected: Vec<BlockHash>,
+    /// Blocks connected, starting from the common ancestor.
+    connected: Vec<BlockHash>,
+}
+
 /// Event mapper for SPV and client events.
 /// Consumes protocol events and emits [`Event`].
 pub struct Mapper {
     /// Best height known.
     tip: Height,
+    /// Hash of the best block known, if any.
+    tip_hash: Option<BlockHash>,
     /// The height up to which we've processed filters and matching blocks.
     sync_height: Height,
     /// The height up to which we've processed filters.
@@ -90,28 +229,156 @@ pub struct Mapper {
     /// The height up to which we've processed matching blocks.
     /// This is always going to be lesser or equal to `filter_height`.
     block_height: Height,
-    /// Filter heights that have been matched, and for which we are awaiting a block to process.
-    pending: HashSet<Height>,
+    /// Filter heights that have been matched, and for which we are awaiting a block to process,
+    /// along with the block hash.
+    pending: HashMap<Height, BlockHash>,
+    /// Scripts being watched.
+    watch: Vec<Script>,
+    /// Unspent outputs paying to watched scripts. Shared with client handles.
+    utxos: Arc<RwLock<Utxos>>,
+    /// Re-org in progress, if any.
+    reorg: Option<Reorg>,
+    /// Whether to include merkle proofs in transaction confirmations.
+    merkle_proofs: bool,
+    /// Confirmed transactions awaiting their block, to compute their merkle proofs.
+    unproven: HashMap<BlockHash, Vec<(Txid, Height)>>,
+    /// Height below which filters are not processed.
+    filter_sync_start: Height,
+    /// Wallet birthday. Filters and blocks below this height are not processed.
+    birthday: Height,
+    /// Last status emitted for each transaction, until the status is final. Shared with
+    /// client handles.
+    statuses: Arc<RwLock<HashMap<Txid, TxStatus>>>,
+    /// Maximum depth of a re-org, if any. Confirmations deeper than this are final.
+    max_reorg_depth: Option<Height>,
+    /// Subscriptions to the status of specific transactions.
+    trackers: Trackers,
+    /// Tip we last emitted a synced event for, if any.
+    last_synced_tip: Option<BlockHash>,
+    /// Called with each matched block, before it is reported.
+    on_block_matched: Option<Arc<dyn Fn(&Block, Height) + Send + Sync>>,
 }
 
 impl Mapper {
     /// Create a new SPV event mapper.
     pub fn new() -> Self {
         let tip = 0;
+        let tip_hash = None;
         let sync_height = 0;
         let filter_height = 0;
         let block_height = 0;
-        let pending = HashSet::new();
+        let pending = HashMap::new();
+        let watch = Vec::new();
+        let utxos = Arc::new(RwLock::new(Utxos::new()));
+        let reorg = None;
+        let merkle_proofs = false;
+        let unproven = HashMap::new();
+        let filter_sync_start = 0;
+        let birthday = 0;
+        let statuses = Arc::new(RwLock::new(HashMap::new()));
+        let max_reorg_depth = None;
+        let trackers = Trackers::default();
+        let last_synced_tip = None;
+        let on_block_matched = None;
 
         Self {
             tip,
+            tip_hash,
             sync_height,
             filter_height,
             block_height,
             pending,
+            watch,
+            utxos,
+            reorg,
+            merkle_proofs,
+            unproven,
+            filter_sync_start,
+            birthday,
+            statuses,
+            max_reorg_depth,
+            trackers,
+            last_synced_tip,
+            on_block_matched,
         }
     }
 
+    /// Set the maximum depth of a re-org. Transaction confirmations deeper than this are
+    /// considered final. If not set, the drop window is used. Confirmations are never
+    /// considered final before [`TRANSACTION_PRUNE_DEPTH`].
+    pub fn with_max_reorg_depth(mut self, depth: Option<Height>) -> Self {
+        self.max_reorg_depth = depth;
+        self
+    }
+
+    /// Track the given unspent outputs, eg. when restoring a wallet. An
+    /// [`Event::OutputSpent`] event is emitted when any of them is spent.
+    pub fn with_utxos(mut self, utxos: Utxos) -> Self {
+        self.utxos = Arc::new(RwLock::new(utxos));
+        self
+    }
+
+    /// Include a [`MerkleProof`] in [`TxStatus::Confirmed`] statuses. When enabled, the
+    /// status change is emitted once the block is processed, since the proof is computed
+    /// from the block's transactions.
+    pub fn with_merkle_proofs(mut self, enabled: bool) -> Self {
+        self.merkle_proofs = enabled;
+        self
+    }
+
+    /// Only process filters from the given height onward. Filters below it are ignored.
+    pub fn with_filter_sync_start(mut self, height: Height) -> Self {
+        self.filter_sync_start = height;
+        self
+    }
+
+    /// Set the wallet birthday, ie. the height of the first block that can involve the
+    /// watched scripts. Filters and blocks below this height are skipped.
+    pub fn with_birthday(mut self, height: Height) -> Self {
+        self.birthday = height;
+        self.filter_height = height;
+        self.sync_height = height;
+        self.block_height = height;
+        self
+    }
+
+    /// Call the given function with each matched block, before [`Event::BlockMatched`] is
+    /// emitted. The function runs on the mapper's thread, and must not block.
+    pub fn with_on_block_matched(
+        mut self,
+        hook: Option<Arc<dyn Fn(&Block, Height) + Send + Sync>>,
+    ) -> Self {
+        self.on_block_matched = hook;
+        self
+    }
+
+    /// Share the state exposed to client handles with the given mapper, ie. the unspent
+    /// outputs, transaction statuses and trackers.
+    pub fn with_shared(mut self, other: &Self) -> Self {
+        self.utxos = other.utxos();
+        self.statuses = other.statuses();
+        self.trackers = other.trackers();
+        self
+    }
+
+    /// Get the unspent outputs tracked by the mapper. The set is shared, and kept up to date
+    /// as blocks are processed.
+    pub fn utxos(&self) -> Arc<RwLock<Utxos>> {
+        self.utxos.clone()
+    }
+
+    /// Get the last status of the transactions tracked by the mapper, until their status
+    /// is final. The map is shared, and kept up to date as events are processed.
+    pub fn statuses(&self) -> Arc<RwLock<HashMap<Txid, TxStatus>>> {
+        self.statuses.clone()
+    }
+
+    /// Get the transaction status subscriptions. New subscriptions can be added through
+    /// the returned handle, which is shared with the mapper.
+    pub fn trackers(&self) -> Trackers {
+        self.trackers.clone()
+    }
+
     /// Process protocol event and map it to client event(s).
     pub fn process(&mut self, event: fsm::Event, emitter: &Emitter<Event>) {
         match event {
@@ -125,6 +392,12 @@ impl Mapper {
                     filter_tip: filter_height,
                 });
             }
+            fsm::Event::SyncPaused => {
+                emitter.emit(Event::SyncPaused);
+            }
+            fsm::Event::SyncResumed => {
+                emitter.emit(Event::SyncResumed);
+            }
             fsm::Event::Peer(fsm::PeerEvent::Connected(addr, link)) => {
                 emitter.emit(Event::PeerConnected { addr, link });
             }
@@ -151,31 +424,126 @@ impl Mapper {
             fsm::Event::Peer(fsm::PeerEvent::Disconnected(addr, reason)) => {
                 emitter.emit(Event::PeerDisconnected { addr, reason });
             }
+            fsm::Event::Peer(fsm::PeerEvent::Misbehaved {
+                addr,
+                score,
+                reason,
+                banned,
+            }) => {
+                emitter.emit(Event::PeerMisbehaved {
+                    addr,
+                    score,
+                    reason,
+                    banned,
+                });
+            }
             fsm::Event::Chain(fsm::ChainEvent::PeerHeightUpdated { height }) => {
                 emitter.emit(Event::PeerHeightUpdated { height });
             }
-            fsm::Event::Chain(fsm::ChainEvent::Synced(_, height)) => {
+            fsm::Event::Address(fsm::AddressEvent::AddressesReceived {
+                count,
+                source: Source::Peer(from),
+            }) => {
+                emitter.emit(Event::PeerAddressesReceived { from, count });
+            }
+            fsm::Event::Chain(fsm::ChainEvent::TipStale {
+                height,
+                last_update,
+            }) => {
+                emitter.emit(Event::TipStale {
+                    height,
+                    last_update: time::UNIX_EPOCH
+                        + time::Duration::from_secs(last_update.as_secs()),
+                });
+            }
+            fsm::Event::Chain(fsm::ChainEvent::TipFresh { height }) => {
+                emitter.emit(Event::TipFresh { height });
+            }
+            fsm::Event::Chain(fsm::ChainEvent::ReorgRejected { depth, fork_point }) => {
+                emitter.emit(Event::ReorgRejected { depth, fork_point });
+            }
+            fsm::Event::Chain(fsm::ChainEvent::SyncProgress { height, target }) => {
+                let percent = if target == 0 {
+                    100.
+                } else {
+                    height.min(target) as f64 / target as f64 * 100.
+                };
+                emitter.emit(Event::SyncProgress {
+                    percent,
+                    height,
+                    target,
+                });
+            }
+            fsm::Event::Chain(fsm::ChainEvent::Synced(hash, height)) => {
                 self.tip = height;
+                self.tip_hash = Some(hash);
+
+                // The chain is stable again, summarize the re-org, if any.
+                if let Some(reorg) = self.reorg.take() {
+                    emitter.emit(Event::ChainReorg {
+                        common_ancestor: reorg.common_ancestor,
+                        disconnected: reorg.disconnected,
+                        connected: reorg.connected,
+                    });
+                }
             }
             fsm::Event::Chain(fsm::ChainEvent::BlockConnected { header, height }) => {
+                let hash = header.block_hash();
+
+                if let Some(reorg) = &mut self.reorg {
+                    reorg.connected.push(hash);
+                }
                 emitter.emit(Event::BlockConnected {
                     header,
-                    hash: header.block_hash(),
+                    hash,
                     height,
                 });
+                self.prune(height);
             }
-            fsm::Event::Chain(fsm::ChainEvent::BlockDisconnected { header, height }) => {
+            fsm::Event::Chain(fsm::ChainEvent::BlockDisconnected {
+                header,
+                height,
+                reorg_depth,
+            }) => {
+                let hash = header.block_hash();
+                let reorg = self.reorg.get_or_insert_with(|| Reorg {
+                    common_ancestor: (height - 1, header.prev_blockhash),
+                    disconnected: Vec::with_capacity(reorg_depth),
+                    connected: Vec::new(),
+                });
+                // Blocks are disconnected from the tip down, so the common ancestor is
+                // the parent of the last block disconnected.
+                reorg.common_ancestor = (height - 1, header.prev_blockhash);
+                reorg.disconnected.push(hash);
+
+                self.utxos.write().unwrap().revert(height);
+
                 emitter.emit(Event::BlockDisconnected {
                     header,
-                    hash: header.block_hash(),
+                    hash,
                     height,
+                    reorg_depth,
                 });
             }
+            fsm::Event::Inventory(fsm::InventoryEvent::BlockDownloadStarted { hash, peer }) => {
+                emitter.emit(Event::BlockDownloadStarted { hash, peer });
+            }
+            fsm::Event::Inventory(fsm::InventoryEvent::BlockDownloadRetried { hash, attempt }) => {
+                emitter.emit(Event::BlockDownloadRetried { hash, attempt });
+            }
+            fsm::Event::Inventory(fsm::InventoryEvent::BlockDownloadFailed { hash }) => {
+                // Don't wait for a block that won't arrive, or we'd never be synced again.
+                self.pending.retain(|_, h| *h != hash);
+
+                emitter.emit(Event::BlockDownloadFailed { hash });
+            }
             fsm::Event::Inventory(fsm::InventoryEvent::BlockProcessed {
                 block,
                 height,
                 fees,
             }) => {
+                self.process_proofs(&block, emitter);
+
                 let hash = self.process_block(block, height, emitter);
 
                 if let Some(fees) = fees {
@@ -191,32 +559,94 @@ impl Mapper {
                 height,
                 block,
             }) => {
-                emitter.emit(Event::TxStatusChanged {
-                    txid: transaction.txid(),
-                    status: TxStatus::Confirmed { height, block },
-                });
+                let txid = transaction.txid();
+
+                if self.merkle_proofs {
+                    self.unproven.entry(block).or_default().push((txid, height));
+                } else {
+                    self.transition(
+                        txid,
+                        TxStatus::Confirmed {
+                            height,
+                            block,
+                            merkle_proof: None,
+                        },
+                        emitter,
+                    );
+                }
+            }
+            fsm::Event::Inventory(fsm::InventoryEvent::Reverted { transaction }) => {
+                let txid = transaction.txid();
+                let mut unproven = false;
+
+                // If the confirmation is still awaiting its proof, it was never reported,
+                // hence there is nothing to revert.
+                for txs in self.unproven.values_mut() {
+                    let len = txs.len();
+                    txs.retain(|(t, _)| *t != txid);
+                    unproven |= txs.len() != len;
+                }
+                self.unproven.retain(|_, txs| !txs.is_empty());
+
+                if !unproven {
+                    self.transition(txid, TxStatus::Reverted, emitter);
+                }
             }
             fsm::Event::Inventory(fsm::InventoryEvent::Acknowledged { txid, peer }) => {
-                emitter.emit(Event::TxStatusChanged {
+                self.transition(txid, TxStatus::Acknowledged { peer }, emitter);
+            }
+            fsm::Event::Inventory(fsm::InventoryEvent::TxAnnounced { txid, peer }) => {
+                self.transition(txid, TxStatus::InMempool { peer }, emitter);
+            }
+            fsm::Event::Inventory(fsm::InventoryEvent::Dropped { txid, blocks }) => {
+                self.transition(
                     txid,
-                    status: TxStatus::Acknowledged { peer },
-                });
+                    TxStatus::Dropped {
+                        reason: DropReason::Expired { blocks },
+                    },
+                    emitter,
+                );
+            }
+            fsm::Event::Inventory(fsm::InventoryEvent::Replaced {
+                txid,
+                replaced_by,
+                block,
+            }) => {
+                self.transition(txid, TxStatus::Stale { replaced_by, block }, emitter);
+            }
+            fsm::Event::Filter(fsm::FilterEvent::Watching { scripts }) => {
+                for script in scripts {
+                    if !self.watch.contains(&script) {
+                        self.watch.push(script);
+                    }
+                }
+            }
+            fsm::Event::Filter(fsm::FilterEvent::Unwatched { script }) => {
+                self.watch.retain(|s| s != &script);
             }
             fsm::Event::Filter(fsm::FilterEvent::RescanStarted { start, .. }) => {
+                let start = Height::max(start, self.birthday);
                 self.pending.clear();
 
                 self.filter_height = start;
                 self.sync_height = start;
                 self.block_height = start;
             }
+            fsm::Event::Filter(fsm::FilterEvent::RescanAborted { height }) => {
+                // Matched blocks of the rescan are no longer downloaded.
+                self.pending.clear();
+
+                emitter.emit(Event::RescanAborted { at_height: height });
+            }
             fsm::Event::Filter(fsm::FilterEvent::FilterProcessed {
                 block,
                 height,
                 matched,
+                matched_scripts,
                 valid,
                 ..
             }) => {
-                self.process_filter(block, height, matched, valid, emitter);
+                self.process_filter(block, height, matched, matched_scripts, valid, emitter);
             }
             _ => {}
         }
@@ -241,15 +671,109 @@ impl Mapper {
         if height > self.sync_height {
             self.sync_height = height;
 
-            emitter.emit(Event::Synced {
-                height,
-                tip: self.tip,
-            });
+            // Only broadcast a sync event once per newly reached tip. Intermediate heights,
+            // and filters re-processed during a rescan, don't trigger one.
+            if height >= self.tip && self.last_synced_tip != self.tip_hash {
+                self.last_synced_tip = self.tip_hash;
+
+                emitter.emit(Event::Synced {
+                    height,
+                    tip: self.tip,
+                });
+            }
         }
     }
 
     // PRIVATE METHODS /////////////////////////////////////////////////////////
 
+    /// Forget the status of transactions, and the UTXO set changes, deep enough that they
+    /// can no longer be reverted.
+    fn prune(&mut self, tip: Height) {
+        // Transactions can be reverted as long as the inventory manager keeps them.
+        let depth = Height::max(
+            self.max_reorg_depth.unwrap_or(DEFAULT_DROP_WINDOW),
+            TRANSACTION_PRUNE_DEPTH,
+        );
+
+        self.utxos
+            .write()
+            .unwrap()
+            .prune(tip.saturating_sub(depth) + 1);
+
+        self.statuses
+            .write()
+            .unwrap()
+            .retain(|_, status| match status {
+                TxStatus::Confirmed { height, .. } => tip.saturating_sub(*height) < depth,
+                _ => true,
+            });
+    }
+
+    fn process_proofs(&mut self, block: &Block, emitter: &Emitter<Event>) {
+        let hash = block.block_hash();
+
+        for (txid, height) in self.unproven.remove(&hash).unwrap_or_default() {
+            self.transition(
+                txid,
+                TxStatus::Confirmed {
+                    height,
+                    block: hash,
+                    merkle_proof: Some(MerkleProof::new(block, &txid)),
+                },
+                emitter,
+            );
+        }
+    }
+
+    /// Emit a transaction status change, making sure it is a legal transition from the
+    /// previous status of the transaction.
+    ///
+    /// A confirmed transaction can only change status once it is reverted, hence a
+    /// [`TxStatus::Reverted`] status is emitted first if it's missing. Reverting a transaction
+    /// that isn't confirmed is ignored.
+    fn transition(&mut self, txid: Txid, status: TxStatus, emitter: &Emitter<Event>) {
+        let prev = self.statuses.read().unwrap().get(&txid).cloned();
+        let revert = match (prev.as_ref(), &status) {
+            (Some(TxStatus::Confirmed { block: prev, .. }), TxStatus::Confirmed { block, .. })
+                if prev == block =>
+            {
+                // Already confirmed in this block.
+                return;
+            }
+            (Some(TxStatus::Confirmed { .. }), TxStatus::Reverted) => false,
+            (Some(TxStatus::Confirmed { .. }), _) => true,
+            (_, TxStatus::Reverted) => {
+                log::warn!("Ignoring revert of unconfirmed transaction {}", txid);
+                return;
+            }
+            _ => false,
+        };
+
+        if revert {
+            log::warn!(
+                "Transaction {} status changed without being reverted first: {}",
+                txid,
+                status
+            );
+            self.trackers.notify(txid, &TxStatus::Reverted);
+            emitter.emit(Event::TxStatusChanged {
+                txid,
+                status: TxStatus::Reverted,
+            });
+        }
+        match status {
+            // These statuses are final, there is no transition left to check.
+            TxStatus::Stale { .. } | TxStatus::Dropped { .. } => {
+                self.statuses.write().unwrap().remove(&txid);
+            }
+            _ => {
+                self.statuses.write().unwrap().insert(txid, status.clone());
+            }
+        }
+        self.trackers.notify(txid, &status);
+        emitter.emit(Event::TxStatusChanged { txid, status });
+    }
+
     // TODO: Instead of receiving the block, fetch it if matched.
     fn process_block(
         &mut self,
@@ -259,7 +783,10 @@ impl Mapper {
     ) -> BlockHash {
         let hash = block.block_hash();
 
-        if !self.pending.remove(&height) {
+        if height < self.birthday {
+            return hash;
+        }
+        if self.pending.remove(&height).is_none() {
             // Received unexpected block.
             return hash;
         }
@@ -269,6 +796,26 @@ impl Mapper {
 
         self.block_height = height;
 
+        let mut utxos = self.utxos.write().unwrap();
+
+        for tx in &block.txdata {
+            let txid = tx.txid();
+
+            for (outpoint, _) in utxos.apply(tx, height, &self.watch) {
+                emitter.emit(Event::OutputSpent {
+                    outpoint,
+                    txid,
+                    height,
+                    block: hash,
+                });
+            }
+        }
+        drop(utxos);
+
+        if let Some(hook) = &self.on_block_matched {
+            hook(&block, height);
+        }
+
         emitter.emit(Event::BlockMatched {
             height,
             hash,
@@ -284,20 +831,26 @@ impl Mapper {
         block: BlockHash,
         height: Height,
         matched: bool,
+        matched_scripts: Vec<Script>,
         valid: bool,
         emitter: &Emitter<Event>,
     ) {
+        if height < self.birthday {
+            return;
+        }
         debug_assert!(height >= self.filter_height);
 
-        if matched {
+        // Blocks below the filter sync start height aren't fetched, even if matched.
+        if matched && height >= self.filter_sync_start {
             log::debug!("Filter matched for block #{}", height);
-            self.pending.insert(height);
+            self.pending.insert(height, block);
         }
         self.filter_height = height;
 
         emitter.emit(Event::FilterProcessed {
             height,
             matched,
+            matched_scripts,
             valid,
             block,
         });
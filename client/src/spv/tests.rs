@@ -59,6 +59,7 @@ use super::utxos::Utxos;
 use super::Event;
 use super::*;
 
+use crate::client::{chan, EventKind};
 use crate::handle::Handle as _;
 use crate::tests::mock;
 
@@ -236,6 +237,61 @@ fn test_peer_negotiated() {
     );
 }
 
+#[test]
+fn test_peer_addresses_received() {
+    use nakamoto_common::bitcoin::network::address::Address;
+    use nakamoto_common::bitcoin::network::constants::ServiceFlags;
+    use nakamoto_common::bitcoin::network::message::NetworkMessage;
+    use nakamoto_common::bitcoin::network::message_network::VersionMessage;
+
+    let network = Network::default();
+    let mut client = mock::Client::new(network);
+    let handle = client.handle();
+    let remote = ([44, 44, 44, 44], 8333).into();
+    let local_time = LocalTime::now();
+    let local_addr = ([0, 0, 0, 0], 16333).into();
+    let events = handle.subscribe();
+
+    client
+        .protocol
+        .connected(remote, &local_addr, Link::Inbound);
+    client.received(
+        &remote,
+        NetworkMessage::Version(VersionMessage {
+            version: fsm::MIN_PROTOCOL_VERSION,
+            services: ServiceFlags::NETWORK,
+            timestamp: local_time.block_time() as i64,
+            receiver: Address::new(&remote, ServiceFlags::NONE),
+            sender: Address::new(&local_addr, ServiceFlags::NONE),
+            nonce: 42,
+            user_agent: "?".to_owned(),
+            start_height: 42,
+            relay: false,
+        }),
+    );
+    client.received(&remote, NetworkMessage::Verack);
+    client.step();
+
+    let addrs = (1..=3)
+        .map(|i| {
+            let addr: net::SocketAddr = ([88, 88, 88, i], 8333).into();
+            (
+                local_time.block_time(),
+                Address::new(&addr, ServiceFlags::NETWORK),
+            )
+        })
+        .collect();
+    client.received(&remote, NetworkMessage::Addr(addrs));
+    client.step();
+
+    events
+        .try_iter()
+        .find(|e| {
+            matches!(e, Event::PeerAddressesReceived { from, count } if *from == remote && *count == 3)
+        })
+        .expect("We receive an event for the gossiped addresses");
+}
+
 #[quickcheck]
 fn prop_client_side_filtering(birth: Height, height: Height, seed: u64) -> TestResult {
     if height < 1 || height > 24 || birth >= height {
@@ -276,6 +332,7 @@ fn prop_client_side_filtering(birth: Height, height: Height, seed: u64) -> TestR
                 block: block.block_hash(),
                 height: h,
                 matched,
+                matched_scripts: vec![],
                 cached: false,
                 valid: true,
             }));
@@ -320,6 +377,667 @@ fn prop_client_side_filtering(birth: Height, height: Height, seed: u64) -> TestR
     TestResult::passed()
 }
 
+#[test]
+fn test_subscribe_filtered() {
+    let mut rng = fastrand::Rng::new();
+    let network = Network::Regtest;
+    let mut mock = mock::Client::new(network);
+    let client = mock.handle();
+    let events = client.subscribe_filtered(EventKind::TX | EventKind::NODE);
+    let txid = gen::transaction(&mut rng).txid();
+    let peer = ([88, 88, 88, 88], 8333).into();
+
+    mock.subscriber
+        .broadcast(fsm::Event::Filter(fsm::FilterEvent::FilterProcessed {
+            block: network.genesis_hash(),
+            height: 1,
+            matched: false,
+            matched_scripts: vec![],
+            cached: false,
+            valid: true,
+        }));
+    mock.subscriber
+        .broadcast(fsm::Event::Inventory(fsm::InventoryEvent::TxAnnounced {
+            txid,
+            peer,
+        }));
+
+    let kinds = events.try_iter().map(|e| e.kind()).collect::<Vec<_>>();
+    assert_eq!(kinds, vec![EventKind::TX]);
+}
+
+#[test]
+fn test_output_spent() {
+    let mut rng = fastrand::Rng::new();
+    let network = Network::Regtest;
+    let genesis = network.genesis_block();
+    let mut mock = mock::Client::new(network);
+    let client = mock.handle();
+    let events = client.subscribe();
+    let script = gen::script(&mut rng);
+
+    let mut tx = gen::transaction(&mut rng);
+    tx.output[0].script_pubkey = script.clone();
+
+    let outpoint = OutPoint {
+        txid: tx.txid(),
+        vout: 0,
+    };
+    let spend = gen::transaction_with(outpoint, tx.output[0].value, &mut rng);
+    let funding = gen::block_with(&genesis.header, vec![gen::coinbase(&mut rng), tx], &mut rng);
+    let spending = gen::block_with(
+        &funding.header,
+        vec![gen::coinbase(&mut rng), spend.clone()],
+        &mut rng,
+    );
+
+    mock.subscriber
+        .broadcast(fsm::Event::Filter(fsm::FilterEvent::Watching {
+            scripts: vec![script.clone()],
+        }));
+
+    for (height, block) in [(1, funding), (2, spending.clone())] {
+        mock.subscriber
+            .broadcast(fsm::Event::Filter(fsm::FilterEvent::FilterProcessed {
+                block: block.block_hash(),
+                height,
+                matched: true,
+                matched_scripts: vec![script.clone()],
+                cached: false,
+                valid: true,
+            }));
+        mock.subscriber
+            .broadcast(fsm::Event::Inventory(fsm::InventoryEvent::BlockProcessed {
+                block,
+                height,
+                fees: None,
+            }));
+    }
+
+    let spent = events
+        .try_iter()
+        .filter_map(|e| match e {
+            Event::OutputSpent {
+                outpoint,
+                txid,
+                height,
+                block,
+            } => Some((outpoint, txid, height, block)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        spent,
+        vec![(outpoint, spend.txid(), 2, spending.block_hash())]
+    );
+}
+
+#[test]
+fn test_utxos_reverted() {
+    let mut rng = fastrand::Rng::new();
+    let network = Network::Regtest;
+    let genesis = network.genesis_block();
+    let script = gen::script(&mut rng);
+
+    let mut tx = gen::transaction(&mut rng);
+    tx.output[0].script_pubkey = script.clone();
+
+    let outpoint = OutPoint {
+        txid: tx.txid(),
+        vout: 0,
+    };
+    let spend = gen::transaction_with(outpoint, tx.output[0].value, &mut rng);
+    let funding = gen::block_with(&genesis.header, vec![gen::coinbase(&mut rng), tx], &mut rng);
+    let spending = gen::block_with(
+        &funding.header,
+        vec![gen::coinbase(&mut rng), spend],
+        &mut rng,
+    );
+
+    let spv = Mapper::new();
+    let utxos = spv.utxos();
+    let (mut publisher, _) = nakamoto_net::event::broadcast({
+        let mut spv = spv;
+        move |e, p| spv.process(e, p)
+    });
+
+    publisher.broadcast(fsm::Event::Filter(fsm::FilterEvent::Watching {
+        scripts: vec![script.clone()],
+    }));
+
+    for (height, block) in [(1, funding.clone()), (2, spending.clone())] {
+        publisher.broadcast(fsm::Event::Filter(fsm::FilterEvent::FilterProcessed {
+            block: block.block_hash(),
+            height,
+            matched: true,
+            matched_scripts: vec![script.clone()],
+            cached: false,
+            valid: true,
+        }));
+        publisher.broadcast(fsm::Event::Inventory(fsm::InventoryEvent::BlockProcessed {
+            block,
+            height,
+            fees: None,
+        }));
+    }
+    assert!(utxos.read().unwrap().is_empty());
+
+    // Disconnecting the spending block restores the output.
+    publisher.broadcast(fsm::Event::Chain(fsm::ChainEvent::BlockDisconnected {
+        header: spending.header,
+        height: 2,
+        reorg_depth: 2,
+    }));
+    assert!(utxos.read().unwrap().contains_key(&outpoint));
+
+    // Disconnecting the funding block removes it.
+    publisher.broadcast(fsm::Event::Chain(fsm::ChainEvent::BlockDisconnected {
+        header: funding.header,
+        height: 1,
+        reorg_depth: 2,
+    }));
+    assert!(utxos.read().unwrap().is_empty());
+}
+
+#[test]
+fn test_chain_reorg() {
+    let mut rng = fastrand::Rng::new();
+    let network = Network::Regtest;
+    let genesis = network.genesis_block();
+    let mut mock = mock::Client::new(network);
+    let client = mock.handle();
+    let events = client.subscribe();
+
+    let main = gen::blockchain(genesis, 3, &mut rng)
+        .into_iter()
+        .collect::<Vec<_>>();
+    let fork = gen::fork(&main[1].header, 3, &mut rng);
+    let ancestor = main[1].block_hash();
+
+    for (height, block) in main.iter().enumerate().skip(1) {
+        mock.subscriber
+            .broadcast(fsm::Event::Chain(fsm::ChainEvent::BlockConnected {
+                header: block.header,
+                height: height as Height,
+            }));
+    }
+    mock.subscriber
+        .broadcast(fsm::Event::Chain(fsm::ChainEvent::Synced(
+            main[3].block_hash(),
+            3,
+        )));
+
+    for (height, block) in main.iter().enumerate().skip(2).rev() {
+        mock.subscriber
+            .broadcast(fsm::Event::Chain(fsm::ChainEvent::BlockDisconnected {
+                header: block.header,
+                height: height as Height,
+                reorg_depth: 2,
+            }));
+    }
+    for (i, block) in fork.iter().enumerate() {
+        mock.subscriber
+            .broadcast(fsm::Event::Chain(fsm::ChainEvent::BlockConnected {
+                header: block.header,
+                height: i as Height + 2,
+            }));
+    }
+    mock.subscriber
+        .broadcast(fsm::Event::Chain(fsm::ChainEvent::Synced(
+            fork.last().unwrap().block_hash(),
+            4,
+        )));
+
+    let reorgs = events
+        .try_iter()
+        .filter_map(|e| match e {
+            Event::ChainReorg {
+                common_ancestor,
+                disconnected,
+                connected,
+            } => Some((common_ancestor, disconnected, connected)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        reorgs,
+        vec![(
+            (1, ancestor),
+            vec![main[3].block_hash(), main[2].block_hash()],
+            fork.iter().map(|b| b.block_hash()).collect::<Vec<_>>(),
+        )]
+    );
+}
+
+#[test]
+fn test_tx_confirmed_merkle_proof() {
+    let mut rng = fastrand::Rng::new();
+    let network = Network::Regtest;
+    let genesis = network.genesis_block();
+    let tx = gen::transaction(&mut rng);
+    let txid = tx.txid();
+    let block = gen::block_with(
+        &genesis.header,
+        vec![
+            gen::coinbase(&mut rng),
+            tx.clone(),
+            gen::transaction(&mut rng),
+        ],
+        &mut rng,
+    );
+    let hash = block.block_hash();
+
+    let (mut publisher, subscriber) = nakamoto_net::event::broadcast({
+        let mut spv = Mapper::new().with_merkle_proofs(true);
+        move |e, p| spv.process(e, p)
+    });
+    let events = subscriber.subscribe();
+
+    publisher.broadcast(fsm::Event::Inventory(fsm::InventoryEvent::Confirmed {
+        transaction: tx,
+        height: 1,
+        block: hash,
+    }));
+    // The status is only reported once the block is processed.
+    assert!(events.try_recv().is_err());
+
+    publisher.broadcast(fsm::Event::Inventory(fsm::InventoryEvent::BlockProcessed {
+        block,
+        height: 1,
+        fees: None,
+    }));
+
+    let proof = events
+        .try_iter()
+        .find_map(|e| match e {
+            Event::TxStatusChanged {
+                txid: t,
+                status:
+                    TxStatus::Confirmed {
+                        height: 1,
+                        block,
+                        merkle_proof: Some(proof),
+                    },
+            } if t == txid && block == hash => Some(proof),
+            _ => None,
+        })
+        .expect("the confirmation includes a merkle proof");
+
+    assert_eq!(proof.header().block_hash(), hash);
+    assert!(proof.verify(&txid));
+    assert!(!proof.verify(&Txid::all_zeros()));
+}
+
+#[test]
+fn test_birthday() {
+    let birthday = 10;
+    let (mut publisher, subscriber) = nakamoto_net::event::broadcast({
+        let mut spv = Mapper::new().with_birthday(birthday);
+        move |e, p| spv.process(e, p)
+    });
+    let events = subscriber.subscribe();
+
+    for height in [birthday - 5, birthday + 2] {
+        publisher.broadcast(fsm::Event::Filter(fsm::FilterEvent::FilterProcessed {
+            block: BlockHash::hash(&height.to_le_bytes()),
+            height,
+            matched: false,
+            matched_scripts: vec![],
+            cached: false,
+            valid: true,
+        }));
+    }
+    let processed = events
+        .try_iter()
+        .filter_map(|e| match e {
+            Event::FilterProcessed { height, .. } => Some(height),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        processed,
+        vec![birthday + 2],
+        "Filters below the birthday are skipped"
+    );
+}
+
+#[test]
+fn test_filter_sync_start() {
+    let sync_start = 10;
+    let (mut publisher, subscriber) = nakamoto_net::event::broadcast({
+        let mut spv = Mapper::new().with_filter_sync_start(sync_start);
+        move |e, p| spv.process(e, p)
+    });
+    let events = subscriber.subscribe();
+
+    for height in [sync_start - 5, sync_start + 2] {
+        publisher.broadcast(fsm::Event::Filter(fsm::FilterEvent::FilterProcessed {
+            block: BlockHash::hash(&height.to_le_bytes()),
+            height,
+            matched: true,
+            matched_scripts: vec![],
+            cached: false,
+            valid: true,
+        }));
+    }
+    let processed = events
+        .try_iter()
+        .filter_map(|e| match e {
+            Event::FilterProcessed { height, .. } => Some(height),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        processed,
+        vec![sync_start - 5, sync_start + 2],
+        "Filters below the sync start height are still reported"
+    );
+}
+
+#[test]
+fn test_on_block_matched_hook() {
+    let mut rng = fastrand::Rng::new();
+    let network = Network::Regtest;
+    let block = gen::block(&network.genesis_block().header, &mut rng);
+    let (matched_tx, matched_rx) = chan::unbounded();
+
+    let (mut publisher, subscriber) = nakamoto_net::event::broadcast({
+        let mut spv =
+            Mapper::new().with_on_block_matched(Some(Arc::new(move |block: &Block, height| {
+                matched_tx.send((block.block_hash(), height)).unwrap();
+            })));
+        move |e, p| spv.process(e, p)
+    });
+    let events = subscriber.subscribe();
+
+    publisher.broadcast(fsm::Event::Filter(fsm::FilterEvent::FilterProcessed {
+        block: block.block_hash(),
+        height: 1,
+        matched: true,
+        matched_scripts: vec![],
+        cached: false,
+        valid: true,
+    }));
+    assert!(matched_rx.try_recv().is_err());
+
+    publisher.broadcast(fsm::Event::Inventory(fsm::InventoryEvent::BlockProcessed {
+        block: block.clone(),
+        height: 1,
+        fees: None,
+    }));
+    assert_eq!(matched_rx.try_recv(), Ok((block.block_hash(), 1)));
+    assert!(events
+        .try_iter()
+        .any(|e| matches!(e, Event::BlockMatched { height: 1, .. })));
+}
+
+#[test]
+fn test_utxos_coinbase_maturity() {
+    let mut rng = fastrand::Rng::new();
+    let mut utxos = Utxos::new();
+    let coinbase = gen::coinbase(&mut rng);
+    let tx = gen::transaction(&mut rng);
+    let scripts = coinbase
+        .output
+        .iter()
+        .chain(tx.output.iter())
+        .map(|o| o.script_pubkey.clone())
+        .collect::<Vec<_>>();
+
+    utxos.apply(&coinbase, 10, &scripts);
+    utxos.apply(&tx, 10, &scripts);
+
+    let maturity = |at| {
+        let mut coinbase = utxos
+            .list(at)
+            .into_iter()
+            .filter(|u| u.is_coinbase)
+            .map(|u| u.is_mature)
+            .collect::<Vec<_>>();
+        coinbase.dedup();
+        coinbase
+    };
+    assert!(utxos.list(10).iter().all(|u| u.is_coinbase || u.is_mature));
+    assert_eq!(maturity(10 + utxos::COINBASE_MATURITY - 1), vec![false]);
+    assert_eq!(maturity(10 + utxos::COINBASE_MATURITY), vec![true]);
+}
+
+#[test]
+fn test_synced_once_per_tip() {
+    let tip = 3;
+    let (mut publisher, subscriber) = nakamoto_net::event::broadcast({
+        let mut spv = Mapper::new();
+        move |e, p| spv.process(e, p)
+    });
+    let events = subscriber.subscribe();
+    let process = |publisher: &mut nakamoto_net::event::Broadcast<_, _>| {
+        for height in 1..=tip {
+            publisher.broadcast(fsm::Event::Filter(fsm::FilterEvent::FilterProcessed {
+                block: BlockHash::hash(&height.to_le_bytes()),
+                height,
+                matched: false,
+                matched_scripts: vec![],
+                cached: false,
+                valid: true,
+            }));
+        }
+    };
+    let synced = || {
+        events
+            .try_iter()
+            .filter_map(|e| match e {
+                Event::Synced { height, tip } => Some((height, tip)),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    publisher.broadcast(fsm::Event::Chain(fsm::ChainEvent::Synced(
+        BlockHash::hash(&tip.to_le_bytes()),
+        tip,
+    )));
+    process(&mut publisher);
+    assert_eq!(synced(), vec![(tip, tip)]);
+
+    // Re-processing filters up to the same tip doesn't emit sync events.
+    publisher.broadcast(fsm::Event::Filter(fsm::FilterEvent::RescanStarted {
+        start: 1,
+        end: None,
+    }));
+    process(&mut publisher);
+    assert_eq!(synced(), vec![]);
+
+    // Reaching a new tip emits a sync event again.
+    publisher.broadcast(fsm::Event::Chain(fsm::ChainEvent::Synced(
+        BlockHash::hash(&(tip + 1).to_le_bytes()),
+        tip + 1,
+    )));
+    publisher.broadcast(fsm::Event::Filter(fsm::FilterEvent::FilterProcessed {
+        block: BlockHash::hash(&(tip + 1).to_le_bytes()),
+        height: tip + 1,
+        matched: false,
+        matched_scripts: vec![],
+        cached: false,
+        valid: true,
+    }));
+    assert_eq!(synced(), vec![(tip + 1, tip + 1)]);
+}
+
+#[test]
+fn test_tx_status_transitions() {
+    let mut rng = fastrand::Rng::new();
+    let tx = gen::transaction(&mut rng);
+    let txid = tx.txid();
+    let peer: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
+    let (block1, block2) = (BlockHash::hash(&[1]), BlockHash::hash(&[2]));
+
+    let (mut publisher, subscriber) = nakamoto_net::event::broadcast({
+        let mut spv = Mapper::new();
+        move |e, p| spv.process(e, p)
+    });
+    let events = subscriber.subscribe();
+    let statuses = || {
+        events
+            .try_iter()
+            .filter_map(|e| match e {
+                Event::TxStatusChanged { txid: t, status } if t == txid => Some(status),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // Reverting a transaction that was never confirmed is ignored.
+    publisher.broadcast(fsm::Event::Inventory(fsm::InventoryEvent::Reverted {
+        transaction: tx.clone(),
+    }));
+    assert_eq!(statuses(), vec![]);
+
+    publisher.broadcast(fsm::Event::Inventory(fsm::InventoryEvent::Confirmed {
+        transaction: tx.clone(),
+        height: 1,
+        block: block1,
+    }));
+    assert_matches!(&statuses()[..], [TxStatus::Confirmed { .. }]);
+
+    // A confirmed transaction announced by a peer must have been reverted first.
+    publisher.broadcast(fsm::Event::Inventory(fsm::InventoryEvent::TxAnnounced {
+        txid,
+        peer,
+    }));
+    assert_eq!(
+        statuses(),
+        vec![TxStatus::Reverted, TxStatus::InMempool { peer }]
+    );
+
+    publisher.broadcast(fsm::Event::Inventory(fsm::InventoryEvent::Confirmed {
+        transaction: tx.clone(),
+        height: 1,
+        block: block1,
+    }));
+    assert_matches!(&statuses()[..], [TxStatus::Confirmed { .. }]);
+
+    // A confirmation in another block must be preceded by a revert.
+    publisher.broadcast(fsm::Event::Inventory(fsm::InventoryEvent::Confirmed {
+        transaction: tx.clone(),
+        height: 2,
+        block: block2,
+    }));
+    assert_matches!(
+        &statuses()[..],
+        [TxStatus::Reverted, TxStatus::Confirmed { block, .. }] if *block == block2
+    );
+
+    publisher.broadcast(fsm::Event::Inventory(fsm::InventoryEvent::Reverted {
+        transaction: tx,
+    }));
+    assert_eq!(statuses(), vec![TxStatus::Reverted]);
+
+    // A reverted transaction can be replaced by a conflicting one.
+    let replaced_by = gen::transaction(&mut rng).txid();
+    publisher.broadcast(fsm::Event::Inventory(fsm::InventoryEvent::Replaced {
+        txid,
+        replaced_by,
+        block: block2,
+    }));
+    assert_eq!(
+        statuses(),
+        vec![TxStatus::Stale {
+            replaced_by,
+            block: block2
+        }]
+    );
+}
+
+#[test]
+fn test_tx_statuses_pruned() {
+    let mut rng = fastrand::Rng::new();
+    let network = Network::Regtest;
+    let genesis = network.genesis_block();
+    let chain = gen::blockchain(genesis, TRANSACTION_PRUNE_DEPTH + 1, &mut rng);
+    let (tx1, tx2) = (gen::transaction(&mut rng), gen::transaction(&mut rng));
+
+    let spv = Arc::new(Mutex::new(Mapper::new().with_max_reorg_depth(Some(2))));
+    let (mut publisher, _) = nakamoto_net::event::broadcast({
+        let spv = spv.clone();
+        move |e, p| spv.lock().unwrap().process(e, p)
+    });
+
+    publisher.broadcast(fsm::Event::Inventory(fsm::InventoryEvent::Confirmed {
+        transaction: tx1.clone(),
+        height: 1,
+        block: chain[1].block_hash(),
+    }));
+    publisher.broadcast(fsm::Event::Inventory(fsm::InventoryEvent::TxAnnounced {
+        txid: tx2.txid(),
+        peer: ([88, 88, 88, 88], 8333).into(),
+    }));
+    assert_eq!(spv.lock().unwrap().statuses.read().unwrap().len(), 2);
+
+    for (height, block) in chain.iter().enumerate().skip(1) {
+        if height == 3 {
+            publisher.broadcast(fsm::Event::Inventory(fsm::InventoryEvent::Dropped {
+                txid: tx2.txid(),
+                blocks: 3,
+            }));
+        }
+        publisher.broadcast(fsm::Event::Chain(fsm::ChainEvent::BlockConnected {
+            header: block.header,
+            height: height as Height,
+        }));
+        let spv = spv.lock().unwrap();
+
+        // Confirmations deeper than the maximum re-org depth are final, but are kept as
+        // long as the transaction can be reverted.
+        assert_eq!(
+            spv.statuses.read().unwrap().contains_key(&tx1.txid()),
+            height < TRANSACTION_PRUNE_DEPTH as usize + 1
+        );
+        // Dropped transactions are final.
+        assert_eq!(
+            spv.statuses.read().unwrap().contains_key(&tx2.txid()),
+            height < 3
+        );
+    }
+}
+
+#[test]
+fn test_track_txids() {
+    let mut rng = fastrand::Rng::new();
+    let (tx1, tx2) = (gen::transaction(&mut rng), gen::transaction(&mut rng));
+    let peer: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
+
+    let spv = Mapper::new();
+    let trackers = spv.trackers();
+    let tracked = trackers.track([tx1.txid()]);
+    let (mut publisher, _) = nakamoto_net::event::broadcast({
+        let mut spv = spv;
+        move |e, p| spv.process(e, p)
+    });
+
+    for tx in [&tx1, &tx2] {
+        publisher.broadcast(fsm::Event::Inventory(fsm::InventoryEvent::TxAnnounced {
+            txid: tx.txid(),
+            peer,
+        }));
+    }
+    assert_eq!(
+        tracked.try_iter().collect::<Vec<_>>(),
+        vec![(tx1.txid(), TxStatus::InMempool { peer })]
+    );
+
+    // Dropped trackers are removed.
+    drop(tracked);
+    publisher.broadcast(fsm::Event::Inventory(fsm::InventoryEvent::TxAnnounced {
+        txid: tx1.txid(),
+        peer,
+    }));
+    assert!(trackers.trackers.lock().unwrap().is_empty());
+}
+
 #[test]
 fn test_tx_status_ordering() {
     assert!(
@@ -331,15 +1049,24 @@ fn test_tx_status_ordering() {
     assert!(
         TxStatus::Acknowledged {
             peer: ([0, 0, 0, 0], 0).into()
+        } < TxStatus::InMempool {
+            peer: ([0, 0, 0, 0], 0).into()
+        }
+    );
+    assert!(
+        TxStatus::InMempool {
+            peer: ([0, 0, 0, 0], 0).into()
         } < TxStatus::Confirmed {
             height: 0,
             block: BlockHash::all_zeros(),
+            merkle_proof: None,
         }
     );
     assert!(
         TxStatus::Confirmed {
             height: 0,
             block: BlockHash::all_zeros(),
+            merkle_proof: None,
         } < TxStatus::Reverted
     );
     assert!(
@@ -349,4 +1076,12 @@ fn test_tx_status_ordering() {
                 block: BlockHash::all_zeros()
             }
     );
+    assert!(
+        TxStatus::Stale {
+            replaced_by: Txid::all_zeros(),
+            block: BlockHash::all_zeros()
+        } < TxStatus::Dropped {
+            reason: DropReason::Expired { blocks: 1 }
+        }
+    );
 }
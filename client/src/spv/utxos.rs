@@ -1,13 +1,44 @@
 //! A simple UTXO set.
-use std::collections::HashMap;
-use std::ops::{Deref, DerefMut};
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Deref;
 
 use nakamoto_common::bitcoin::{OutPoint, Script, Transaction, TxOut};
+use nakamoto_common::block::Height;
+
+/// Number of blocks a coinbase output must be buried under before it can be spent.
+pub const COINBASE_MATURITY: Height = 100;
+
+/// An unspent output, as listed by [`Utxos::list`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Utxo {
+    /// The output's outpoint.
+    pub outpoint: OutPoint,
+    /// The output itself.
+    pub output: TxOut,
+    /// Whether the output was created by a coinbase transaction.
+    pub is_coinbase: bool,
+    /// Whether the output can be spent at the requested height. Always `true` for
+    /// outputs not created by a coinbase transaction.
+    pub is_mature: bool,
+}
+
+/// A change applied to the UTXO set by a transaction, kept so that it can be reverted.
+#[derive(Debug, Clone)]
+enum Change {
+    /// An output was created.
+    Created(OutPoint),
+    /// An output was spent. Includes the height of its coinbase transaction, if any.
+    Spent(OutPoint, TxOut, Option<Height>),
+}
 
 /// A simple UTXO set.
 #[derive(Debug, Clone)]
 pub struct Utxos {
     map: HashMap<OutPoint, TxOut>,
+    /// Heights of the coinbase transactions that created outputs in the set.
+    coinbase: HashMap<OutPoint, Height>,
+    /// Changes applied at each height, in order, to revert disconnected blocks.
+    changes: BTreeMap<Height, Vec<Change>>,
 }
 
 impl Utxos {
@@ -15,6 +46,8 @@ impl Utxos {
     pub fn new() -> Self {
         Self {
             map: HashMap::new(),
+            coinbase: HashMap::new(),
+            changes: BTreeMap::new(),
         }
     }
 
@@ -23,8 +56,46 @@ impl Utxos {
         self.map.values().map(|u| u.value).sum()
     }
 
-    /// Apply a transaction to the UTXO set.
-    pub fn apply(&mut self, tx: &Transaction, scripts: &[Script]) {
+    /// List all UTXOs, marking coinbase outputs that can't be spent in a block at the given
+    /// height as immature.
+    pub fn list(&self, spendable_at: Height) -> Vec<Utxo> {
+        self.map
+            .iter()
+            .map(|(outpoint, output)| {
+                let height = self.coinbase.get(outpoint);
+
+                Utxo {
+                    outpoint: *outpoint,
+                    output: output.clone(),
+                    is_coinbase: height.is_some(),
+                    is_mature: height.map_or(true, |h| h + COINBASE_MATURITY <= spendable_at),
+                }
+            })
+            .collect()
+    }
+
+    /// Track an unspent output, eg. when restoring a wallet. Returns the output previously
+    /// tracked under the same outpoint, if any.
+    pub fn insert(&mut self, outpoint: OutPoint, output: TxOut) -> Option<TxOut> {
+        self.map.insert(outpoint, output)
+    }
+
+    /// Stop tracking an unspent output. Returns the output, if it was tracked.
+    pub fn remove(&mut self, outpoint: &OutPoint) -> Option<TxOut> {
+        self.coinbase.remove(outpoint);
+        self.map.remove(outpoint)
+    }
+
+    /// Apply a transaction, included in a block at the given height, to the UTXO set.
+    /// Returns the outputs that were spent by the transaction.
+    pub fn apply(
+        &mut self,
+        tx: &Transaction,
+        height: Height,
+        scripts: &[Script],
+    ) -> Vec<(OutPoint, TxOut)> {
+        let mut spent = Vec::new();
+
         // Look for outputs.
         for (vout, output) in tx.output.iter().enumerate() {
             // Received coin.
@@ -34,18 +105,61 @@ impl Utxos {
                     txid,
                     vout: vout as u32,
                 };
-                self.insert(outpoint, output.clone());
+                if tx.is_coin_base() {
+                    self.coinbase.insert(outpoint, height);
+                }
+                self.map.insert(outpoint, output.clone());
+                self.changes
+                    .entry(height)
+                    .or_default()
+                    .push(Change::Created(outpoint));
                 log::info!("Unspent output found (balance={})", self.balance());
             }
         }
         // Look for inputs.
         for input in tx.input.iter() {
             // Spent coin.
-            if self.remove(&input.previous_output).is_some() {
-                log::info!("Spent output found (balance={})", self.balance())
+            if let Some(output) = self.map.remove(&input.previous_output) {
+                let coinbase = self.coinbase.remove(&input.previous_output);
+
+                self.changes.entry(height).or_default().push(Change::Spent(
+                    input.previous_output,
+                    output.clone(),
+                    coinbase,
+                ));
+
+                log::info!("Spent output found (balance={})", self.balance());
+                spent.push((input.previous_output, output));
+            }
+        }
+        spent
+    }
+
+    /// Revert the transactions applied at the given height and above, eg. because their
+    /// blocks were disconnected. Spent outputs are restored, and created outputs removed.
+    pub fn revert(&mut self, height: Height) {
+        for (_, changes) in self.changes.split_off(&height).into_iter().rev() {
+            for change in changes.into_iter().rev() {
+                match change {
+                    Change::Created(outpoint) => {
+                        self.map.remove(&outpoint);
+                        self.coinbase.remove(&outpoint);
+                    }
+                    Change::Spent(outpoint, output, coinbase) => {
+                        if let Some(h) = coinbase {
+                            self.coinbase.insert(outpoint, h);
+                        }
+                        self.map.insert(outpoint, output);
+                    }
+                }
             }
         }
     }
+
+    /// Forget the changes applied below the given height, which can no longer be reverted.
+    pub fn prune(&mut self, height: Height) {
+        self.changes = self.changes.split_off(&height);
+    }
 }
 
 impl Deref for Utxos {
@@ -55,9 +169,3 @@ impl Deref for Utxos {
         &self.map
     }
 }
-
-impl DerefMut for Utxos {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.map
-    }
-}
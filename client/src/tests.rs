@@ -10,16 +10,20 @@ use nakamoto_chain::block::cache::BlockCache;
 use nakamoto_chain::block::store;
 use nakamoto_chain::filter::cache::FilterCache;
 use nakamoto_common::bitcoin::network::constants::ServiceFlags;
-use nakamoto_common::block::time::AdjustedTime;
-use nakamoto_common::block::Height;
+use nakamoto_common::bitcoin_hashes::Hash as _;
+use nakamoto_common::block::time::{AdjustedTime, Clock as _, LocalTime, RefClock};
+use nakamoto_common::block::{BlockHash, Height};
 use nakamoto_common::network::Services;
 use nakamoto_net::event;
 use nakamoto_p2p::fsm;
-use nakamoto_test::{logger, BITCOIN_HEADERS};
+use nakamoto_test::block::gen;
+use nakamoto_test::{assert_matches, logger, BITCOIN_HEADERS};
 
-use crate::client::{self, Client, Config};
+use crate::client::{
+    self, ChainTip, ChainTipStatus, Client, Command, CommandQueue, Config, CustomParams, Network,
+};
 use crate::error;
-use crate::handle::Handle as _;
+use crate::handle::{self, Handle as _};
 use crate::service::Service;
 
 type Reactor = nakamoto_net_poll::Reactor<net::TcpStream>;
@@ -101,11 +105,15 @@ fn test_full_sync() {
     // all peers on time.
     handle.wait_for_peers(2, Services::Chain).unwrap();
 
-    handle
+    let summary = handle
         .import_headers(headers)
         .expect("command is successful")
         .expect("chain is valid");
 
+    assert_eq!(summary.accepted, height as usize);
+    assert_eq!(summary.duplicates, 0);
+    assert_eq!(summary.new_tip, Some((height, hash)));
+
     for (mut node, _, thread) in nodes.into_iter() {
         node.set_timeout(std::time::Duration::from_secs(5));
         assert_eq!(node.wait_for_height(height).unwrap(), hash);
@@ -115,6 +123,82 @@ fn test_full_sync() {
     }
 }
 
+#[test]
+fn test_import_headers_from_reader() {
+    use std::fs;
+    use std::path::Path;
+
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../test/data/headers.bin");
+    let nodes = network(&[Config::default()]).unwrap();
+    let (handle, _, _) = nodes.first().unwrap();
+    let height = BITCOIN_HEADERS.tail.len() as Height;
+    let hash = BITCOIN_HEADERS.last().block_hash();
+
+    // The file starts with the genesis header, which is already known.
+    let file = fs::File::open(&path).unwrap();
+    let summary = handle
+        .import_headers_from_reader(file)
+        .expect("command is successful")
+        .expect("chain is valid");
+
+    assert_eq!(summary.accepted, height as usize);
+    assert_eq!(summary.duplicates, 1);
+    assert_eq!(summary.new_tip, Some((height, hash)));
+    assert_eq!(handle.get_tip().unwrap(), (height, *BITCOIN_HEADERS.last()));
+
+    // A truncated header fails.
+    let mut bytes = fs::read(&path).unwrap();
+    bytes.truncate(bytes.len() - 1);
+    assert_matches!(
+        handle.import_headers_from_reader(bytes.as_slice()),
+        Err(handle::Error::Io(_))
+    );
+
+    // A gap in the headers fails.
+    let mut bytes = fs::read(&path).unwrap();
+    bytes.drain(80..160);
+    assert_matches!(
+        handle.import_headers_from_reader(bytes.as_slice()),
+        Ok(Err(_))
+    );
+}
+
+#[test]
+fn test_export_headers() {
+    use std::fs;
+    use std::path::Path;
+
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../test/data/headers.bin");
+    let nodes = network(&[Config::default()]).unwrap();
+    let (handle, _, _) = nodes.first().unwrap();
+    let height = BITCOIN_HEADERS.tail.len() as Height;
+
+    handle
+        .import_headers_from_reader(fs::File::open(&path).unwrap())
+        .unwrap()
+        .unwrap();
+
+    // Exporting the whole chain yields the original file.
+    let mut bytes = Vec::new();
+    let count = handle.export_headers(&mut bytes, 0..=height).unwrap();
+    assert_eq!(count, height as usize + 1);
+    assert_eq!(bytes, fs::read(&path).unwrap());
+
+    // Heights above the tip are skipped.
+    let mut bytes = Vec::new();
+    let count = handle
+        .export_headers(&mut bytes, height - 1..=height + 10)
+        .unwrap();
+    assert_eq!(count, 2);
+    assert_eq!(bytes.len(), 160);
+
+    // Empty ranges are invalid.
+    assert_matches!(
+        handle.export_headers(Vec::new(), height..=height - 1),
+        Err(handle::Error::InvalidRange(_))
+    );
+}
+
 #[test]
 fn test_wait_for_peers() {
     logger::init(log::Level::Debug);
@@ -137,6 +221,81 @@ fn test_wait_for_peers() {
     assert_eq!(peers.len(), nodes.len() - 1);
 }
 
+#[test]
+fn test_disconnect_all() {
+    logger::init(log::Level::Debug);
+
+    let cfgs = vec![
+        Config {
+            services: ServiceFlags::NETWORK,
+            ..Default::default()
+        };
+        3
+    ];
+
+    let nodes = network(&cfgs).unwrap();
+    let (handle, _, _) = nodes.first().unwrap();
+
+    handle
+        .wait_for_peers(nodes.len() - 1, Services::Chain)
+        .unwrap();
+
+    assert_eq!(handle.disconnect_all().unwrap(), nodes.len() - 1);
+}
+
+#[test]
+fn test_get_peer_info() {
+    let cfgs = vec![
+        Config {
+            services: ServiceFlags::NETWORK,
+            ..Default::default()
+        };
+        2
+    ];
+
+    let nodes = network(&cfgs).unwrap();
+    let (handle, _, _) = nodes.first().unwrap();
+    let (_, addr, _) = nodes.last().unwrap();
+
+    handle.wait_for_peers(1, Services::Chain).unwrap();
+
+    let info = handle.get_peer_info(*addr).unwrap().unwrap();
+
+    assert_eq!(info.peer.addr, *addr);
+    assert!(info.bytes_sent > 0);
+    assert!(info.bytes_received > 0);
+    assert!(info.last_message.is_some());
+
+    assert!(handle
+        .get_peer_info(([1, 1, 1, 1], 8333).into())
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn test_bandwidth() {
+    let cfgs = vec![
+        Config {
+            services: ServiceFlags::NETWORK,
+            ..Default::default()
+        };
+        2
+    ];
+
+    let nodes = network(&cfgs).unwrap();
+    let (handle, _, _) = nodes.first().unwrap();
+
+    handle.wait_for_peers(1, Services::Chain).unwrap();
+
+    let bandwidth = handle.bandwidth().unwrap();
+
+    // At least the handshake was exchanged.
+    assert!(bandwidth.sent.other > 0);
+    assert!(bandwidth.received.other > 0);
+    assert_eq!(bandwidth.sent.blocks, 0);
+    assert_eq!(bandwidth.received.blocks, 0);
+}
+
 #[test]
 fn test_send_handle() {
     let client: Client<Reactor> = Client::new().unwrap();
@@ -223,6 +382,38 @@ fn test_handle_shutdown() {
     th.join().unwrap().unwrap();
 }
 
+#[test]
+fn test_handle_shutdown_timeout() {
+    let cfg = Config::default();
+    let genesis = cfg.network.genesis();
+    let params = cfg.network.params();
+    let client: Client<Reactor> = Client::new().unwrap();
+    let handle = client.handle();
+    let store = store::Memory::new((genesis, vec![]).into());
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+    let filters = FilterCache::load(store::Memory::default()).unwrap();
+    let peers = HashMap::new();
+
+    let th = thread::spawn(|| {
+        let local_time = time::SystemTime::now().into();
+        let clock = AdjustedTime::<net::SocketAddr>::new(local_time);
+        let rng = fastrand::Rng::new();
+
+        client.run_with(vec![], Service::new(cache, filters, peers, clock, rng, cfg))
+    });
+
+    handle
+        .clone()
+        .shutdown_timeout(time::Duration::from_secs(6))
+        .unwrap();
+    th.join().unwrap().unwrap();
+
+    // Shutting down a stopped client is a no-op.
+    handle
+        .shutdown_timeout(time::Duration::from_secs(1))
+        .unwrap();
+}
+
 #[test]
 fn test_client_dropped() {
     let client: Client<Reactor> = Client::new().unwrap();
@@ -236,6 +427,26 @@ fn test_client_dropped() {
     ));
 }
 
+#[test]
+fn test_rescan_invalid_bounds() {
+    use std::ops::Bound;
+
+    let client: Client<Reactor> = Client::new().unwrap();
+    let handle = client.handle();
+
+    assert_matches!(
+        handle.rescan(
+            (Bound::Excluded(Height::MAX), Bound::Unbounded),
+            std::iter::empty()
+        ),
+        Err(client::handle::Error::InvalidRange(_))
+    );
+    assert_matches!(
+        handle.rescan((Bound::Unbounded, Bound::Excluded(0)), std::iter::empty()),
+        Err(client::handle::Error::InvalidRange(_))
+    );
+}
+
 #[test]
 fn test_query_headers() {
     let cfg = Config::default();
@@ -273,3 +484,694 @@ fn test_query_headers() {
     assert_eq!(header, BITCOIN_HEADERS.tail.first().cloned());
     assert!(found);
 }
+
+#[test]
+fn test_wait_for_filters() {
+    let cfg = Config::default();
+    let genesis = cfg.network.genesis();
+    let params = cfg.network.params();
+    let client: Client<Reactor> = Client::new().unwrap();
+    let mut handle = client.handle();
+    let store = store::Memory::new((genesis, BITCOIN_HEADERS.tail.clone()).into());
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+    let filters = FilterCache::load(store::Memory::default()).unwrap();
+
+    thread::spawn(|| {
+        let local_time = time::SystemTime::now().into();
+        let clock = AdjustedTime::<net::SocketAddr>::new(local_time);
+        let rng = fastrand::Rng::new();
+
+        client.run_with(
+            vec![],
+            Service::new(cache, filters, HashMap::new(), clock, rng, cfg),
+        )
+    });
+    handle.set_timeout(time::Duration::from_millis(100));
+
+    assert_matches!(
+        handle.wait_for_filters(1..=3),
+        Err(client::handle::Error::FiltersIncomplete(missing)) if missing == vec![1, 2, 3]
+    );
+}
+
+#[test]
+fn test_get_header_by_hash() {
+    let cfg = Config::default();
+    let genesis = cfg.network.genesis();
+    let params = cfg.network.params();
+    let client: Client<Reactor> = Client::new().unwrap();
+    let handle = client.handle();
+    let store = store::Memory::new((genesis, BITCOIN_HEADERS.tail.clone()).into());
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+    let filters = FilterCache::load(store::Memory::default()).unwrap();
+
+    thread::spawn(|| {
+        let local_time = time::SystemTime::now().into();
+        let clock = AdjustedTime::<net::SocketAddr>::new(local_time);
+        let rng = fastrand::Rng::new();
+
+        client.run_with(
+            vec![],
+            Service::new(cache, filters, HashMap::new(), clock, rng, cfg),
+        )
+    });
+
+    let header = BITCOIN_HEADERS.tail[1];
+
+    assert_eq!(
+        handle.get_header_by_hash(&header.block_hash()).unwrap(),
+        Some((2, header))
+    );
+    assert_eq!(
+        handle.get_header_by_hash(&BlockHash::all_zeros()).unwrap(),
+        None
+    );
+}
+
+#[test]
+fn test_get_block_height() {
+    let cfg = Config::default();
+    let genesis = cfg.network.genesis();
+    let params = cfg.network.params();
+    let client: Client<Reactor> = Client::new().unwrap();
+    let handle = client.handle();
+    let store = store::Memory::new((genesis, BITCOIN_HEADERS.tail.clone()).into());
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+    let filters = FilterCache::load(store::Memory::default()).unwrap();
+
+    thread::spawn(|| {
+        let local_time = time::SystemTime::now().into();
+        let clock = AdjustedTime::<net::SocketAddr>::new(local_time);
+        let rng = fastrand::Rng::new();
+
+        client.run_with(
+            vec![],
+            Service::new(cache, filters, HashMap::new(), clock, rng, cfg),
+        )
+    });
+
+    let header = BITCOIN_HEADERS.tail[1];
+
+    assert_eq!(
+        handle.get_block_height(&genesis.block_hash()).unwrap(),
+        Some(0)
+    );
+    assert_eq!(
+        handle.get_block_height(&header.block_hash()).unwrap(),
+        Some(2)
+    );
+    assert_eq!(
+        handle.get_block_height(&BlockHash::all_zeros()).unwrap(),
+        None
+    );
+}
+
+#[test]
+fn test_get_chain_tips() {
+    let cfg = Config::default();
+    let genesis = cfg.network.genesis();
+    let params = cfg.network.params();
+    let client: Client<Reactor> = Client::new().unwrap();
+    let handle = client.handle();
+    let store = store::Memory::new((genesis, BITCOIN_HEADERS.tail.clone()).into());
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+    let filters = FilterCache::load(store::Memory::default()).unwrap();
+    let tip = *BITCOIN_HEADERS.last();
+
+    thread::spawn(|| {
+        let local_time = time::SystemTime::now().into();
+        let clock = AdjustedTime::<net::SocketAddr>::new(local_time);
+        let rng = fastrand::Rng::new();
+
+        client.run_with(
+            vec![],
+            Service::new(cache, filters, HashMap::new(), clock, rng, cfg),
+        )
+    });
+
+    assert_eq!(
+        handle.get_chain_tips().unwrap(),
+        vec![ChainTip {
+            height: BITCOIN_HEADERS.tail.len() as Height,
+            hash: tip.block_hash(),
+            status: ChainTipStatus::Active,
+            branch_length: 0,
+        }]
+    );
+}
+
+#[test]
+fn test_get_recent_blocks() {
+    let cfg = Config::default();
+    let genesis = cfg.network.genesis();
+    let params = cfg.network.params();
+    let client: Client<Reactor> = Client::new().unwrap();
+    let handle = client.handle();
+    let store = store::Memory::new((genesis, BITCOIN_HEADERS.tail.clone()).into());
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+    let filters = FilterCache::load(store::Memory::default()).unwrap();
+    let height = BITCOIN_HEADERS.tail.len() as Height;
+
+    thread::spawn(|| {
+        let local_time = time::SystemTime::now().into();
+        let clock = AdjustedTime::<net::SocketAddr>::new(local_time);
+        let rng = fastrand::Rng::new();
+
+        client.run_with(
+            vec![],
+            Service::new(cache, filters, HashMap::new(), clock, rng, cfg),
+        )
+    });
+
+    let recent = handle.get_recent_blocks(3).unwrap();
+    assert_eq!(
+        recent,
+        (height - 2..=height)
+            .rev()
+            .map(|h| (h, *BITCOIN_HEADERS.get(h as usize).unwrap()))
+            .collect::<Vec<_>>()
+    );
+
+    // Requesting more blocks than there are stops at genesis.
+    let recent = handle.get_recent_blocks(height as usize + 10).unwrap();
+    assert_eq!(recent.len(), height as usize + 1);
+    assert_eq!(recent.last(), Some(&(0, genesis)));
+
+    assert!(handle.get_recent_blocks(0).unwrap().is_empty());
+}
+
+#[test]
+fn test_get_block_hash_range() {
+    let cfg = Config::default();
+    let genesis = cfg.network.genesis();
+    let params = cfg.network.params();
+    let client: Client<Reactor> = Client::new().unwrap();
+    let handle = client.handle();
+    let store = store::Memory::new((genesis, BITCOIN_HEADERS.tail.clone()).into());
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+    let filters = FilterCache::load(store::Memory::default()).unwrap();
+    let height = BITCOIN_HEADERS.tail.len() as Height;
+
+    thread::spawn(|| {
+        let local_time = time::SystemTime::now().into();
+        let clock = AdjustedTime::<net::SocketAddr>::new(local_time);
+        let rng = fastrand::Rng::new();
+
+        client.run_with(
+            vec![],
+            Service::new(cache, filters, HashMap::new(), clock, rng, cfg),
+        )
+    });
+
+    // Heights above the tip are skipped.
+    let range = handle
+        .get_block_hash_range(height - 2..=height + 2)
+        .unwrap();
+    assert_eq!(
+        range,
+        (height - 2..=height)
+            .map(|h| (h, *BITCOIN_HEADERS.get(h as usize).unwrap()))
+            .collect::<Vec<_>>()
+    );
+
+    #[allow(clippy::reversed_empty_ranges)]
+    let reversed = 2..=1;
+    assert!(matches!(
+        handle.get_block_hash_range(reversed),
+        Err(handle::Error::InvalidRange(_))
+    ));
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_async_handle() {
+    use std::future::Future;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake};
+
+    use crate::AsyncHandle;
+
+    struct Unpark(thread::Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker = Arc::new(Unpark(thread::current())).into();
+        let mut cx = Context::from_waker(&waker);
+
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    let cfg = Config::default();
+    let genesis = cfg.network.genesis();
+    let params = cfg.network.params();
+    let client: Client<Reactor> = Client::new().unwrap();
+    let handle = AsyncHandle::with_workers(client.handle(), 1);
+    let store = store::Memory::new((genesis, BITCOIN_HEADERS.tail.clone()).into());
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+    let filters = FilterCache::load(store::Memory::default()).unwrap();
+
+    thread::spawn(|| {
+        let local_time = time::SystemTime::now().into();
+        let clock = AdjustedTime::<net::SocketAddr>::new(local_time);
+        let rng = fastrand::Rng::new();
+
+        client.run_with(
+            vec![],
+            Service::new(cache, filters, HashMap::new(), clock, rng, cfg),
+        )
+    });
+
+    let (height, header) = block_on(handle.get_tip()).unwrap();
+
+    assert_eq!(height, BITCOIN_HEADERS.tail.len() as Height);
+    assert_eq!(header, *BITCOIN_HEADERS.last());
+    assert_eq!(
+        block_on(handle.call(|h| h.get_block_height(&genesis.block_hash()))).unwrap(),
+        Some(0)
+    );
+
+    // A panicking call is resumed when its future is polled, instead of never completing.
+    let result = std::panic::catch_unwind(|| block_on(handle.call::<(), _>(|_| panic!("call"))));
+    assert!(result.is_err());
+
+    // A call waiting on the network doesn't occupy the only worker.
+    let mut waiting = Box::pin(handle.wait_for_height(Height::MAX));
+    let waker = Arc::new(Unpark(thread::current())).into();
+    assert!(waiting
+        .as_mut()
+        .poll(&mut Context::from_waker(&waker))
+        .is_pending());
+    assert_eq!(
+        block_on(handle.get_tip()).unwrap().0,
+        BITCOIN_HEADERS.tail.len() as Height
+    );
+}
+
+#[test]
+fn test_custom_params() {
+    let tmp = tempfile::tempdir().unwrap();
+    let custom = CustomParams::from(Network::Regtest);
+    let genesis = custom.genesis.header;
+    let cfg = Config {
+        network: Network::Testnet,
+        custom_params: Some(custom),
+        root: tmp.path().to_path_buf(),
+        connect: vec![([127, 0, 0, 1], 18333).into()],
+        ..Config::default()
+    };
+    let client: Client<Reactor> = Client::new().unwrap();
+    let handle = client.handle();
+    let t = thread::spawn(move || client.run(cfg).unwrap());
+
+    assert_eq!(handle.get_tip().unwrap(), (0, genesis));
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
+
+#[test]
+fn test_run_with_clock() {
+    let tmp = tempfile::tempdir().unwrap();
+    // A peer that accepts connections, but never completes the handshake.
+    let listener = net::TcpListener::bind(([127, 0, 0, 1], 0)).unwrap();
+    let remote = listener.local_addr().unwrap();
+    let cfg = Config {
+        network: Network::Regtest,
+        root: tmp.path().to_path_buf(),
+        connect: vec![remote],
+        ..Config::default()
+    };
+    let clock = RefClock::from(AdjustedTime::<net::SocketAddr>::new(LocalTime::now()));
+    let client: Client<Reactor> = Client::new().unwrap();
+    let handle = client.handle();
+    let events = handle.events();
+    let t = thread::spawn({
+        let clock = clock.clone();
+        move || client.run_with_clock(cfg, clock).unwrap()
+    });
+
+    event::wait(
+        &events,
+        |e| match e {
+            fsm::Event::Peer(fsm::PeerEvent::Connected(addr, _)) if addr == remote => Some(()),
+            _ => None,
+        },
+        time::Duration::from_secs(6),
+    )
+    .unwrap();
+
+    // Advance the clock past the handshake timeout, and wake the client up.
+    let now = clock.local_time();
+    clock
+        .lock()
+        .unwrap()
+        .set_local_time(now + fsm::HANDSHAKE_TIMEOUT);
+    handle.get_tip().unwrap();
+
+    event::wait(
+        &events,
+        |e| match e {
+            fsm::Event::Peer(fsm::PeerEvent::Disconnected(
+                addr,
+                nakamoto_net::DisconnectReason::StateMachine(fsm::DisconnectReason::PeerTimeout(
+                    "handshake",
+                )),
+            )) if addr == remote => Some(()),
+            _ => None,
+        },
+        time::Duration::from_secs(6),
+    )
+    .expect("The peer times out once the clock is advanced");
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
+
+#[test]
+fn test_config_validate() {
+    use crate::client::ConfigProblem;
+
+    assert!(Config::default().validate().is_ok());
+    assert!(Config::default().with_max_outbound(1).validate().is_ok());
+    assert!(Config::default().with_max_outbound(0).validate().is_err());
+
+    let mut cfg = Config {
+        domains: vec![],
+        required_services: ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS,
+        ..Config::default()
+    };
+    cfg.limits.max_outbound_peers = 0;
+    cfg.limits.filter_cache_size = 0;
+    cfg.sync_filters = false;
+    cfg.command_queue = CommandQueue::Bounded(0);
+
+    let err = cfg.validate().unwrap_err();
+
+    assert_eq!(
+        err.0,
+        vec![
+            ConfigProblem::NoDomains,
+            ConfigProblem::NoOutboundPeers,
+            ConfigProblem::FilterCacheDisabled,
+            ConfigProblem::FilterSyncDisabled,
+            ConfigProblem::EmptyCommandQueue,
+        ]
+    );
+
+    let mut cfg = Config {
+        proxy: Some(([127, 0, 0, 1], 9050).into()),
+        ..Config::default()
+    };
+    assert!(cfg.validate().is_ok());
+
+    cfg.reconnect_backoff.multiplier = 0.5;
+    assert_eq!(
+        cfg.validate().unwrap_err().0,
+        vec![ConfigProblem::InvalidBackoff]
+    );
+
+    cfg.reconnect_backoff = client::Backoff::default();
+    cfg.dns_seeds = Some(vec!["seed.example.com".to_owned(), " ".to_owned()]);
+    assert_eq!(
+        cfg.validate().unwrap_err().0,
+        vec![ConfigProblem::EmptyDnsSeed]
+    );
+
+    cfg.dns_seeds = Some(vec!["seed.example.com".to_owned()]);
+    cfg.block_download_concurrency = 0;
+    assert_eq!(
+        cfg.validate().unwrap_err().0,
+        vec![ConfigProblem::NoBlockDownloadConcurrency]
+    );
+
+    cfg.block_download_concurrency = 1;
+    cfg.connect = vec![([88, 88, 88, 88], 8333).into()];
+    assert_eq!(
+        cfg.validate().unwrap_err().0,
+        vec![ConfigProblem::ConnectWithDnsSeeds]
+    );
+
+    // Offering filters ourselves doesn't require syncing them.
+    let cfg = Config {
+        services: ServiceFlags::COMPACT_FILTERS,
+        sync_filters: false,
+        ..Config::default()
+    };
+    assert!(cfg.validate().is_ok());
+}
+
+#[test]
+fn test_command_queue_full() {
+    let cfg = Config {
+        command_queue: CommandQueue::Bounded(1),
+        ..Config::default()
+    };
+    let client: Client<Reactor> = Client::with_config(&cfg).unwrap();
+    let handle = client.handle();
+
+    // The client isn't running, so commands aren't drained from the queue.
+    handle
+        .command(Command::Connect(([0, 0, 0, 0], 0).into()))
+        .unwrap();
+
+    assert_matches!(
+        handle.command(Command::Connect(([0, 0, 0, 0], 0).into())),
+        Err(handle::Error::QueueFull)
+    );
+}
+
+#[test]
+fn test_command_queue_mismatch() {
+    use crate::client::ConfigProblem;
+
+    let tmp = tempfile::tempdir().unwrap();
+    let client: Client<Reactor> = Client::new().unwrap();
+    let cfg = Config {
+        root: tmp.path().to_path_buf(),
+        command_queue: CommandQueue::Bounded(1),
+        ..Config::default()
+    };
+
+    assert_matches!(
+        client.run(cfg),
+        Err(error::Error::Config(err))
+        if err.0 == vec![ConfigProblem::CommandQueueMismatch {
+            created: CommandQueue::Unbounded,
+            configured: CommandQueue::Bounded(1),
+        }]
+    );
+}
+
+/// A service serving the given filters and blocks in response to commands, in place of
+/// the network. Block requests that are cancelled are sent on the given channel.
+struct FilterService {
+    chain: Vec<nakamoto_common::block::Block>,
+    served: Vec<Height>,
+    cancelled: client::chan::Sender<BlockHash>,
+    outbox: std::collections::VecDeque<fsm::Event>,
+}
+
+impl Iterator for FilterService {
+    type Item = nakamoto_net::Io<Vec<u8>, fsm::Event, fsm::DisconnectReason>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.outbox.pop_front().map(nakamoto_net::Io::Event)
+    }
+}
+
+impl nakamoto_net::StateMachine for FilterService {
+    type Message = [u8];
+    type Event = fsm::Event;
+    type DisconnectReason = fsm::DisconnectReason;
+
+    fn received(&mut self, _addr: &net::SocketAddr, _message: std::borrow::Cow<[u8]>) {}
+    fn attempted(&mut self, _addr: &net::SocketAddr) {}
+    fn connected(
+        &mut self,
+        _addr: net::SocketAddr,
+        _local_addr: &net::SocketAddr,
+        _link: nakamoto_net::Link,
+    ) {
+    }
+    fn disconnected(
+        &mut self,
+        _addr: &net::SocketAddr,
+        _reason: nakamoto_net::DisconnectReason<fsm::DisconnectReason>,
+    ) {
+    }
+    fn tick(&mut self, _local_time: LocalTime) {}
+    fn wake(&mut self) {}
+}
+
+impl nakamoto_net::Service for FilterService {
+    type Command = Command;
+
+    fn command(&mut self, cmd: Command) {
+        match cmd {
+            Command::GetFilters(range, reply) => {
+                for height in range.clone() {
+                    let block = &self.chain[height as usize];
+
+                    self.outbox
+                        .push_back(fsm::Event::Filter(fsm::FilterEvent::FilterReceived {
+                            from: ([88, 88, 88, 88], 8333).into(),
+                            filter: gen::cfilter(block),
+                            height,
+                            block_hash: block.block_hash(),
+                        }));
+                }
+                reply.send(Ok(fsm::RequestId::from(1))).ok();
+            }
+            Command::GetBlock(hash) => {
+                if let Some(height) = self
+                    .served
+                    .iter()
+                    .copied()
+                    .find(|h| self.chain[*h as usize].block_hash() == hash)
+                {
+                    self.outbox.push_back(fsm::Event::Inventory(
+                        fsm::InventoryEvent::BlockProcessed {
+                            block: self.chain[height as usize].clone(),
+                            height,
+                            fees: None,
+                        },
+                    ));
+                }
+            }
+            Command::CancelBlock(hash) => {
+                self.cancelled.send(hash).ok();
+            }
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn test_get_filters_matching() {
+    let mut rng = fastrand::Rng::new();
+    let chain = gen::blockchain(Network::Regtest.genesis_block(), 4, &mut rng);
+    let scripts = [2, 3]
+        .iter()
+        .map(|h: &usize| chain[*h].txdata[0].output[0].script_pubkey.clone())
+        .collect::<Vec<_>>();
+
+    // Only some of the matching blocks are served: the others are cancelled on timeout.
+    for served in [vec![2, 3], vec![2]] {
+        let client: Client<Reactor> = Client::new().unwrap();
+        let mut handle = client.handle();
+        let (cancelled, cancelled_recv) = client::chan::unbounded();
+        let service = FilterService {
+            chain: chain.iter().cloned().collect(),
+            served: served.clone(),
+            cancelled,
+            outbox: Default::default(),
+        };
+        thread::spawn(|| client.run_with(vec![], service));
+        handle.set_timeout(time::Duration::from_millis(500));
+
+        let result = handle.get_filters_matching(1..=4, scripts.clone());
+
+        if served.len() == 2 {
+            let blocks = result.unwrap();
+            assert_eq!(
+                blocks
+                    .into_iter()
+                    .map(|(h, b)| (h, b.block_hash()))
+                    .collect::<Vec<_>>(),
+                vec![(2, chain[2].block_hash()), (3, chain[3].block_hash())]
+            );
+        } else {
+            assert_matches!(result, Err(client::handle::Error::Timeout));
+            assert_eq!(
+                cancelled_recv
+                    .recv_timeout(time::Duration::from_secs(3))
+                    .unwrap(),
+                chain[3].block_hash()
+            );
+        }
+        handle.shutdown().unwrap();
+    }
+}
+
+#[test]
+fn test_publisher_unregister() {
+    use nakamoto_net::Publisher as _;
+
+    let mut publisher = client::Publisher::default();
+    let (a_pub, a) = event::broadcast(|e: u8, p| p.emit(e));
+    let (b_pub, b) = event::broadcast(|e: u8, p| p.emit(e));
+    let (a, b) = (a.subscribe(), b.subscribe());
+
+    let a_id = publisher.register(a_pub);
+    let b_id = publisher.register(b_pub);
+    assert_ne!(a_id, b_id);
+
+    publisher.publish(1);
+    assert_eq!(a.try_iter().collect::<Vec<_>>(), vec![1]);
+    assert_eq!(b.try_iter().collect::<Vec<_>>(), vec![1]);
+
+    assert!(publisher.unregister(a_id));
+    assert!(!publisher.unregister(a_id));
+
+    publisher.publish(2);
+    assert_eq!(a.try_iter().collect::<Vec<_>>(), vec![]);
+    assert_eq!(b.try_iter().collect::<Vec<_>>(), vec![2]);
+}
+
+#[test]
+fn test_handle_unregister_publisher() {
+    let cfg = Config::default();
+    let genesis = cfg.network.genesis();
+    let params = cfg.network.params();
+    let client: Client<Reactor> = Client::new().unwrap();
+    let handle = client.handle();
+    let store = store::Memory::new((genesis, BITCOIN_HEADERS.tail.clone()).into());
+    let cache = BlockCache::from(store, params, &[]).unwrap();
+    let filters = FilterCache::load(store::Memory::default()).unwrap();
+
+    let (publisher, subscriber) = event::broadcast(|e: fsm::Event, p| {
+        if matches!(e, fsm::Event::SyncPaused | fsm::Event::SyncResumed) {
+            p.emit(e);
+        }
+    });
+    let events = subscriber.subscribe();
+    let id = handle.register_publisher(publisher);
+
+    thread::spawn(|| {
+        let local_time = time::SystemTime::now().into();
+        let clock = AdjustedTime::<net::SocketAddr>::new(local_time);
+        let rng = fastrand::Rng::new();
+
+        client.run_with(
+            vec![],
+            Service::new(cache, filters, HashMap::new(), clock, rng, cfg),
+        )
+    });
+
+    handle.pause_sync().unwrap();
+    assert_matches!(
+        events.recv_timeout(time::Duration::from_secs(3)),
+        Ok(fsm::Event::SyncPaused)
+    );
+
+    assert!(handle.unregister_publisher(id));
+    assert!(!handle.unregister_publisher(id));
+
+    // Once unregistered, the publisher no longer receives events.
+    handle.resume_sync().unwrap();
+    handle.get_tip().unwrap();
+    assert!(events
+        .recv_timeout(time::Duration::from_millis(100))
+        .is_err());
+}
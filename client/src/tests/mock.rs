@@ -9,27 +9,38 @@ use nakamoto_chain::filter::BlockFilter;
 use nakamoto_common::bitcoin::network::constants::ServiceFlags;
 use nakamoto_common::bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
 use nakamoto_common::bitcoin::network::Address;
-use nakamoto_common::block::filter::FilterHeader;
+use nakamoto_common::bitcoin::{Script, Txid};
+use nakamoto_common::block::filter::{FilterHash, FilterHeader};
 use nakamoto_common::block::store::Genesis as _;
 use nakamoto_common::block::time::{AdjustedTime, LocalTime};
-use nakamoto_common::block::tree::{self, ImportResult};
-use nakamoto_common::block::{BlockHash, BlockHeader, Height, Transaction};
+use nakamoto_common::block::tree::{self, ChainTip, ImportSummary};
+use nakamoto_common::block::{Bits, BlockHash, BlockHeader, Height, Transaction, Work};
 use nakamoto_common::network::Network;
 use nakamoto_common::nonempty::NonEmpty;
-use nakamoto_common::p2p::peer::KnownAddress;
+use nakamoto_common::p2p::peer::{KnownAddress, Source};
 use nakamoto_test::block::cache::model;
 
-use nakamoto_net::event;
+use nakamoto_net::event::{self, BufferPolicy};
 use nakamoto_net::StateMachine as _;
 use nakamoto_p2p::fsm;
+use nakamoto_p2p::fsm::fees::FeeRate;
+use nakamoto_p2p::fsm::AddressFilter;
+use nakamoto_p2p::fsm::Bandwidth;
 use nakamoto_p2p::fsm::Command;
+use nakamoto_p2p::fsm::ConnectionCounts;
+use nakamoto_p2p::fsm::Health;
 use nakamoto_p2p::fsm::Link;
 use nakamoto_p2p::fsm::Peer;
+use nakamoto_p2p::fsm::PeerInfo;
 use nakamoto_p2p::fsm::StateMachine;
+use nakamoto_p2p::fsm::SyncStatus;
+use nakamoto_p2p::fsm::Tips;
+use nakamoto_p2p::fsm::VersionInfo;
 
-use crate::client::{chan, Event, Loading};
+use crate::client::{chan, Direction, Event, EventKind, Loading};
 use crate::handle::{self, Handle};
 use crate::spv;
+use crate::spv::utxos::Utxo;
 
 pub struct Client {
     // Used by tests.
@@ -166,11 +177,109 @@ impl Handle for TestHandle {
         Ok(())
     }
 
-    fn get_filters(&self, range: RangeInclusive<Height>) -> Result<(), handle::Error> {
+    fn get_filters(
+        &self,
+        range: RangeInclusive<Height>,
+    ) -> Result<handle::FilterRequest, handle::Error> {
         let (transmit, receive) = chan::bounded(1);
         self.command(Command::GetFilters(range, transmit))?;
 
-        receive.recv()?.map_err(handle::Error::GetFilters)
+        let id = receive.recv()?.map_err(handle::Error::GetFilters)?;
+        let commands = self.commands.clone();
+
+        Ok(handle::FilterRequest::new(id, move |id| {
+            commands.send(Command::CancelFilterRequest(id))?;
+            Ok(())
+        }))
+    }
+
+    fn get_sync_status(&self) -> Result<SyncStatus, handle::Error> {
+        unimplemented!()
+    }
+
+    fn get_peers_where(
+        &self,
+        _predicate: impl Fn(&Peer) -> bool + Send + Sync + 'static,
+    ) -> Result<Vec<Peer>, handle::Error> {
+        unimplemented!()
+    }
+
+    fn get_peer_info(&self, _addr: net::SocketAddr) -> Result<Option<PeerInfo>, handle::Error> {
+        unimplemented!()
+    }
+
+    fn ping(&self, _addr: net::SocketAddr) -> Result<std::time::Duration, handle::Error> {
+        unimplemented!()
+    }
+
+    fn bandwidth(&self) -> Result<Bandwidth, handle::Error> {
+        unimplemented!()
+    }
+
+    fn connection_counts(&self) -> Result<ConnectionCounts, handle::Error> {
+        unimplemented!()
+    }
+
+    fn version_info(&self) -> Result<VersionInfo, handle::Error> {
+        unimplemented!()
+    }
+
+    fn get_known_addresses(
+        &self,
+        _filter: AddressFilter,
+    ) -> Result<Vec<(net::SocketAddr, Source, ServiceFlags)>, handle::Error> {
+        unimplemented!()
+    }
+
+    fn get_tips(&self) -> Result<Tips, handle::Error> {
+        unimplemented!()
+    }
+
+    fn health(&self) -> Result<Health, handle::Error> {
+        unimplemented!()
+    }
+
+    fn network_time(&self) -> Result<std::time::SystemTime, handle::Error> {
+        unimplemented!()
+    }
+
+    fn get_filters_matching(
+        &self,
+        _range: RangeInclusive<Height>,
+        _scripts: Vec<Script>,
+    ) -> Result<Vec<(Height, Block)>, handle::Error> {
+        unimplemented!()
+    }
+
+    fn get_filter(&self, _hash: &BlockHash) -> Result<Option<BlockFilter>, handle::Error> {
+        unimplemented!()
+    }
+
+    fn get_filter_header(
+        &self,
+        _height: Height,
+    ) -> Result<Option<(FilterHash, FilterHeader)>, handle::Error> {
+        unimplemented!()
+    }
+
+    fn get_filter_checkpoints(&self) -> Result<Vec<(Height, FilterHeader)>, handle::Error> {
+        unimplemented!()
+    }
+
+    fn prune_filters(&self, _before: Height) -> Result<(), handle::Error> {
+        unimplemented!()
+    }
+
+    fn get_utxos(&self, _spendable_at: Height) -> Result<Vec<Utxo>, handle::Error> {
+        unimplemented!()
+    }
+
+    fn track_txids(&self, _txids: Vec<Txid>) -> chan::Receiver<(Txid, spv::TxStatus)> {
+        unimplemented!()
+    }
+
+    fn flush(&self) -> Result<(), handle::Error> {
+        unimplemented!()
     }
 
     fn find_branch(
@@ -180,10 +289,48 @@ impl Handle for TestHandle {
         unimplemented!()
     }
 
+    fn get_header_by_hash(
+        &self,
+        _hash: &BlockHash,
+    ) -> Result<Option<(Height, BlockHeader)>, handle::Error> {
+        unimplemented!()
+    }
+
+    fn get_block_height(&self, _hash: &BlockHash) -> Result<Option<Height>, handle::Error> {
+        unimplemented!()
+    }
+
+    fn get_chain_tips(&self) -> Result<Vec<ChainTip>, handle::Error> {
+        unimplemented!()
+    }
+
+    fn get_block_hash_range(
+        &self,
+        _range: RangeInclusive<Height>,
+    ) -> Result<Vec<(Height, BlockHeader)>, handle::Error> {
+        unimplemented!()
+    }
+
+    fn get_recent_blocks(&self, _n: usize) -> Result<Vec<(Height, BlockHeader)>, handle::Error> {
+        unimplemented!()
+    }
+
+    fn block_locator(&self) -> Result<Vec<BlockHash>, handle::Error> {
+        unimplemented!()
+    }
+
+    fn get_tip_work(&self) -> Result<(Work, Bits), handle::Error> {
+        unimplemented!()
+    }
+
     fn blocks(&self) -> chan::Receiver<(Block, Height)> {
         self.blocks.clone()
     }
 
+    fn blocks_bounded(&self, capacity: usize) -> event::Lossy<(Block, Height)> {
+        event::Subscriber::default().subscribe_bounded(capacity)
+    }
+
     fn filters(&self) -> chan::Receiver<(BlockFilter, BlockHash, Height)> {
         self.filters.clone()
     }
@@ -192,6 +339,19 @@ impl Handle for TestHandle {
         self.subscriber.subscribe()
     }
 
+    fn subscribe_filtered(&self, kinds: EventKind) -> chan::Receiver<Event> {
+        self.subscriber
+            .subscribe_filtered(move |e| kinds.contains(e.kind()))
+    }
+
+    fn subscribe_with(&self, policy: BufferPolicy) -> chan::Receiver<Event> {
+        self.subscriber.subscribe_with(policy)
+    }
+
+    fn subscribe_messages(&self) -> chan::Receiver<(net::SocketAddr, Direction, NetworkMessage)> {
+        unimplemented!()
+    }
+
     fn loading(&self) -> chan::Receiver<Loading> {
         self.loading.subscribe()
     }
@@ -204,11 +364,15 @@ impl Handle for TestHandle {
     fn broadcast(
         &self,
         _msg: NetworkMessage,
-        _predicate: fn(Peer) -> bool,
+        _predicate: impl Fn(&Peer) -> bool + Send + Sync + 'static,
     ) -> Result<Vec<net::SocketAddr>, handle::Error> {
         unimplemented!()
     }
 
+    fn disconnect_all(&self) -> Result<usize, handle::Error> {
+        unimplemented!()
+    }
+
     fn query(&self, _msg: NetworkMessage) -> Result<Option<net::SocketAddr>, handle::Error> {
         unimplemented!()
     }
@@ -231,7 +395,7 @@ impl Handle for TestHandle {
     fn import_headers(
         &self,
         _headers: Vec<BlockHeader>,
-    ) -> Result<Result<ImportResult, tree::Error>, handle::Error> {
+    ) -> Result<Result<ImportSummary, tree::Error>, handle::Error> {
         unimplemented!()
     }
 
@@ -239,6 +403,10 @@ impl Handle for TestHandle {
         unimplemented!()
     }
 
+    fn reset_peers(&self) -> Result<(), handle::Error> {
+        unimplemented!()
+    }
+
     fn submit_transaction(
         &self,
         _tx: Transaction,
@@ -246,6 +414,21 @@ impl Handle for TestHandle {
         unimplemented!()
     }
 
+    fn submit_transactions(
+        &self,
+        _txs: Vec<Transaction>,
+    ) -> Result<Vec<Result<NonEmpty<net::SocketAddr>, fsm::CommandError>>, handle::Error> {
+        unimplemented!()
+    }
+
+    fn get_mempool(&self) -> Result<Vec<(Txid, spv::TxStatus)>, handle::Error> {
+        unimplemented!()
+    }
+
+    fn estimate_fee(&self, _target_blocks: u16) -> Result<Option<FeeRate>, handle::Error> {
+        unimplemented!()
+    }
+
     fn wait<F, T>(&self, _f: F) -> Result<T, handle::Error>
     where
         F: FnMut(fsm::Event) -> Option<T>,
@@ -265,6 +448,10 @@ impl Handle for TestHandle {
         unimplemented!()
     }
 
+    fn wait_for_filters(&self, _range: RangeInclusive<Height>) -> Result<(), handle::Error> {
+        unimplemented!()
+    }
+
     fn events(&self) -> chan::Receiver<fsm::Event> {
         self.events.clone()
     }
@@ -272,4 +459,8 @@ impl Handle for TestHandle {
     fn shutdown(self) -> Result<(), handle::Error> {
         Ok(())
     }
+
+    fn shutdown_timeout(self, _timeout: std::time::Duration) -> Result<(), handle::Error> {
+        Ok(())
+    }
 }
@@ -43,6 +43,9 @@ pub enum Error {
     /// Filter or header at given height not found.
     #[error("filter at height {0} not found")]
     NotFound(Height),
+    /// Filter or header at given height was pruned.
+    #[error("filter at height {0} was pruned")]
+    Pruned(Height),
     /// A storage error occured.
     #[error("storage error: {0}")]
     Store(#[from] store::Error),
@@ -74,4 +77,11 @@ pub trait Filters {
     fn rollback(&mut self, height: Height) -> Result<(), Error>;
     /// Truncate the filter header chain to zero.
     fn clear(&mut self) -> Result<(), Error>;
+    /// Prune filter headers below the given height. The header at the given height is kept,
+    /// so that the remaining chain can still be verified and extended.
+    fn prune(&mut self, before: Height) -> Result<(), Error>;
+    /// Get the height of the lowest filter header that hasn't been pruned.
+    fn floor(&self) -> Height;
+    /// Flush any pending writes to the underlying storage.
+    fn flush(&mut self) -> Result<(), Error>;
 }
@@ -91,6 +91,6 @@ pub trait Store {
     fn height(&self) -> Result<Height, Error>;
     /// Check the store integrity.
     fn check(&self) -> Result<(), Error>;
-    /// Heal data corruption.
-    fn heal(&self) -> Result<(), Error>;
+    /// Heal data corruption. Returns the store height before and after healing.
+    fn heal(&self) -> Result<(Height, Height), Error>;
 }
@@ -1,8 +1,7 @@
 //! Block time and other time-related types.
-use std::cell::RefCell;
 use std::collections::HashSet;
 use std::hash::Hash;
-use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 
 use super::{BlockTime, Height};
 
@@ -57,14 +56,15 @@ impl<K: Eq + Clone + Hash> AdjustedClock<K> for AdjustedTime<K> {
     }
 }
 
-/// Clock with interior mutability.
+/// Clock with interior mutability. Clones share the same clock, and can be sent to other
+/// threads, eg. to drive the clock of a running client.
 #[derive(Debug, Clone)]
 pub struct RefClock<T: Clock> {
-    inner: Rc<RefCell<T>>,
+    inner: Arc<Mutex<T>>,
 }
 
 impl<T: Clock> std::ops::Deref for RefClock<T> {
-    type Target = Rc<RefCell<T>>;
+    type Target = Arc<Mutex<T>>;
 
     fn deref(&self) -> &Self::Target {
         &self.inner
@@ -74,35 +74,35 @@ impl<T: Clock> std::ops::Deref for RefClock<T> {
 impl RefClock<LocalTime> {
     /// Elapse time.
     pub fn elapse(&self, duration: LocalDuration) {
-        self.inner.borrow_mut().elapse(duration)
+        self.inner.lock().unwrap().elapse(duration)
     }
 }
 
 impl<K: Eq + Clone + Hash> AdjustedClock<K> for RefClock<AdjustedTime<K>> {
     fn record_offset(&mut self, source: K, sample: TimeOffset) {
-        self.inner.borrow_mut().record_offset(source, sample);
+        self.inner.lock().unwrap().record_offset(source, sample);
     }
 
     fn set(&mut self, local_time: LocalTime) {
-        self.inner.borrow_mut().set_local_time(local_time);
+        self.inner.lock().unwrap().set_local_time(local_time);
     }
 }
 
 impl<T: Clock> From<T> for RefClock<T> {
     fn from(other: T) -> Self {
         Self {
-            inner: Rc::new(RefCell::new(other)),
+            inner: Arc::new(Mutex::new(other)),
         }
     }
 }
 
 impl<T: Clock> Clock for RefClock<T> {
     fn block_time(&self) -> BlockTime {
-        self.inner.borrow().block_time()
+        self.inner.lock().unwrap().block_time()
     }
 
     fn local_time(&self) -> LocalTime {
-        self.inner.borrow().local_time()
+        self.inner.lock().unwrap().local_time()
     }
 
     fn from_block_time(t: BlockTime) -> Self {
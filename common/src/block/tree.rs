@@ -1,6 +1,6 @@
 //! Types and functions relating to block trees.
 #![warn(missing_docs)]
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 
 use bitcoin::blockdata::block::BlockHeader;
 use bitcoin::consensus::params::Params;
@@ -20,6 +20,13 @@ pub enum Error {
     #[error("invalid block proof-of-work")]
     InvalidBlockPoW,
 
+    /// The proof-of-work of the block at the given height doesn't meet the expected target.
+    #[error("invalid proof-of-work for block at height {height}")]
+    InvalidProofOfWork {
+        /// Height of the offending block.
+        height: Height,
+    },
+
     /// The block's difficulty target is invalid.
     #[error("invalid block difficulty target: {0}, expected {1}")]
     InvalidBlockTarget(Target, Target),
@@ -88,6 +95,53 @@ pub enum ImportResult {
     /// The block headers were imported successfully, but our best block hasn't changed.
     /// This will happen if we imported a duplicate, orphan or stale block.
     TipUnchanged, // TODO: We could add a parameter eg. BlockMissing or DuplicateBlock.
+    /// A branch with more work than the active chain was found, but switching to it would
+    /// re-organize the active chain deeper than allowed, so our best block hasn't changed.
+    /// Each rejected branch is only reported once.
+    ReorgRejected {
+        /// Number of blocks that would have been disconnected.
+        depth: usize,
+        /// Height and hash of the block the rejected branch forks from.
+        fork_point: (Height, BlockHash),
+    },
+}
+
+/// Summary of a block header import, including how many of the headers were new.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportSummary {
+    /// Number of headers that weren't known to the tree before the import, and now are.
+    pub accepted: usize,
+    /// Number of headers that were already known to the tree, or repeated in the import.
+    pub duplicates: usize,
+    /// The new tip, if the import changed it.
+    pub new_tip: Option<(Height, BlockHash)>,
+    /// The underlying import result.
+    pub result: ImportResult,
+}
+
+/// Status of a chain tip. See [`ChainTip`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChainTipStatus {
+    /// The tip of the active chain.
+    Active,
+    /// The tip of a fork off the active chain, whose headers are valid.
+    ValidFork,
+    /// The tip of a fork off the active chain, whose headers couldn't be validated,
+    /// eg. because they are too far in the future, or invalid.
+    HeadersOnly,
+}
+
+/// A known chain tip, as returned by Bitcoin Core's `getchaintips`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainTip {
+    /// Height of the tip.
+    pub height: Height,
+    /// Block hash of the tip.
+    pub hash: BlockHash,
+    /// Status of the branch ending at this tip.
+    pub status: ChainTipStatus,
+    /// Length of the branch connecting the tip to the active chain. Zero for the active tip.
+    pub branch_length: usize,
 }
 
 /// A chain of block headers that may or may not lead back to genesis.
@@ -113,6 +167,38 @@ pub trait BlockTree: BlockReader {
         chain: I,
         context: &C,
     ) -> Result<ImportResult, Error>;
+    /// Import a chain of block headers into the block tree, like [`BlockTree::import_blocks`],
+    /// and count how many of the headers were accepted versus already known.
+    fn import_blocks_with_summary<I: Iterator<Item = BlockHeader>, C: Clock>(
+        &mut self,
+        chain: I,
+        context: &C,
+    ) -> Result<ImportSummary, Error> {
+        let chain = chain.collect::<Vec<_>>();
+        let mut unknown = BTreeSet::new();
+        let mut duplicates = 0;
+
+        for header in &chain {
+            let hash = header.block_hash();
+
+            if self.is_known(&hash) || !unknown.insert(hash) {
+                duplicates += 1;
+            }
+        }
+        let result = self.import_blocks(chain.into_iter(), context)?;
+        let accepted = unknown.iter().filter(|h| self.is_known(h)).count();
+        let new_tip = match &result {
+            ImportResult::TipChanged(_, hash, height, _, _) => Some((*height, *hash)),
+            ImportResult::TipUnchanged | ImportResult::ReorgRejected { .. } => None,
+        };
+
+        Ok(ImportSummary {
+            accepted,
+            duplicates,
+            new_tip,
+            result,
+        })
+    }
     /// Attempts to extend the active chain. Returns `Ok` with `ImportResult::TipUnchanged` if
     /// the block didn't connect, and `Err` if the block was invalid.
     fn extend_tip<C: Clock>(
@@ -120,6 +206,8 @@ pub trait BlockTree: BlockReader {
         header: BlockHeader,
         context: &C,
     ) -> Result<ImportResult, Error>;
+    /// Flush any pending writes to the underlying storage.
+    fn flush(&mut self) -> Result<(), Error>;
 }
 
 /// Read block header state.
@@ -157,6 +245,16 @@ pub trait BlockReader {
     fn height(&self) -> Height;
     /// Get the tip of the longest chain.
     fn tip(&self) -> (BlockHash, BlockHeader);
+    /// Get all known chain tips, starting with the tip of the active chain.
+    /// Only forks that branch off the active chain are included.
+    fn chain_tips(&self) -> Vec<ChainTip> {
+        vec![ChainTip {
+            height: self.height(),
+            hash: self.tip().0,
+            status: ChainTipStatus::Active,
+            branch_length: 0,
+        }]
+    }
     /// Get the last block of the longest chain.
     fn best_block(&self) -> (Height, &BlockHeader) {
         let height = self.height();
@@ -166,6 +264,11 @@ pub trait BlockReader {
                 .expect("the best block is always present"),
         )
     }
+    /// Get the total proof-of-work carried by the longest chain.
+    fn chain_work(&self) -> Work {
+        self.iter()
+            .fold(Work::default(), |work, (_, header)| work + header.work())
+    }
     /// Get the height of the last checkpoint block.
     fn last_checkpoint(&self) -> Height;
     /// Known checkpoints.
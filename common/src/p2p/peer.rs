@@ -80,6 +80,28 @@ pub trait Store {
         Ok(())
     }
 
+    /// Seed the peer store with addresses for which the services are known in advance.
+    /// If an address is already known without any services, its services are updated.
+    fn seed_with_services(
+        &mut self,
+        seeds: impl Iterator<Item = (net::SocketAddr, ServiceFlags)>,
+        source: Source,
+    ) {
+        for (addr, services) in seeds {
+            let inserted = self.insert(
+                addr.ip(),
+                KnownAddress::new(Address::new(&addr, services), source, None),
+            );
+            if !inserted {
+                if let Some(ka) = self.get_mut(&addr.ip()) {
+                    if ka.addr.services == ServiceFlags::NONE {
+                        ka.addr.services = services;
+                    }
+                }
+            }
+        }
+    }
+
     /// Clears the store of all addresses.
     fn clear(&mut self);
 
@@ -350,6 +372,17 @@ impl KnownAddress {
 pub trait AddressSource {
     /// Sample a random peer address. Returns `None` if there are no addresses left.
     fn sample(&mut self, services: ServiceFlags) -> Option<(Address, Source)>;
+    /// Select a peer address with the given function, out of the addresses that could be
+    /// sampled with the given services. Returns `None` if no address was selected.
+    ///
+    /// By default, the function isn't called, and an address is sampled instead.
+    fn select(
+        &mut self,
+        services: ServiceFlags,
+        _select: &dyn Fn(&[KnownAddress]) -> Option<net::SocketAddr>,
+    ) -> Option<(Address, Source)> {
+        self.sample(services)
+    }
     /// Record an address of ours as seen by a remote peer.
     fn record_local_address(&mut self, addr: net::SocketAddr);
     /// Return an iterator over random peer addresses.
@@ -365,6 +398,21 @@ pub mod test {
             self.pop_front()
         }
 
+        fn select(
+            &mut self,
+            _services: ServiceFlags,
+            select: &dyn Fn(&[KnownAddress]) -> Option<net::SocketAddr>,
+        ) -> Option<(Address, Source)> {
+            let candidates = std::collections::VecDeque::iter(self)
+                .map(|(addr, source)| KnownAddress::new(addr.clone(), *source, None))
+                .collect::<Vec<_>>();
+            let selected = select(&candidates)?;
+            let ix = std::collections::VecDeque::iter(self)
+                .position(|(addr, _)| addr.socket_addr().ok() == Some(selected))?;
+
+            self.remove(ix)
+        }
+
         fn record_local_address(&mut self, _addr: net::SocketAddr) {
             // Do nothing.
         }
@@ -400,4 +448,33 @@ mod tests {
 
         assert_eq!(ka, deserialized);
     }
+
+    #[test]
+    fn test_seed_with_services() {
+        let mut store: std::collections::HashMap<net::IpAddr, KnownAddress> =
+            std::collections::HashMap::new();
+        let known = net::SocketAddr::from(([1, 2, 3, 4], 8333));
+        let new = net::SocketAddr::from(([5, 6, 7, 8], 8333));
+        let services = ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS;
+
+        store.seed(std::iter::once(known), Source::Dns).unwrap();
+        store.seed_with_services(
+            vec![(known, services), (new, services)].into_iter(),
+            Source::Imported,
+        );
+
+        assert_eq!(store.len(), 2);
+        assert_eq!(
+            Store::get(&store, &known.ip()).unwrap().addr.services,
+            services
+        );
+        assert_eq!(
+            Store::get(&store, &new.ip()).unwrap().addr.services,
+            services
+        );
+        assert_eq!(
+            Store::get(&store, &new.ip()).unwrap().source,
+            Source::Imported
+        );
+    }
 }
@@ -3,6 +3,7 @@ use crossbeam_channel as chan;
 
 use nakamoto_net::error::Error;
 use nakamoto_net::event::Publisher;
+use nakamoto_net::socks5;
 use nakamoto_net::time::{LocalDuration, LocalTime};
 use nakamoto_net::{DisconnectReason, Io, PeerId};
 use nakamoto_net::{Link, Service};
@@ -32,6 +33,8 @@ const WRITE_TIMEOUT: time::Duration = time::Duration::from_secs(3);
 const WAIT_TIMEOUT: LocalDuration = LocalDuration::from_mins(60);
 /// Socket read buffer size.
 const READ_BUFFER_SIZE: usize = 1024 * 192;
+/// Maximum number of pending connections on the listener. Same as the standard library's.
+const LISTEN_BACKLOG: i32 = 128;
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 enum Source<Id: PeerId> {
@@ -61,6 +64,14 @@ impl nakamoto_net::Waker for Waker {
 pub struct Reactor<R: Write + Read, Id: PeerId = net::SocketAddr> {
     peers: HashMap<Id, Socket<R>>,
     connecting: HashSet<Id>,
+    /// Proxy handshakes in progress, for connections established through a proxy.
+    handshakes: HashMap<Id, socks5::Handshake>,
+    /// SOCKS5 proxy to connect to peers through, if any.
+    proxy: Option<net::SocketAddr>,
+    /// Whether to bind the listener with `SO_REUSEADDR`.
+    reuse_address: bool,
+    /// Time source, if not the system clock.
+    clock: Option<Arc<dyn Fn() -> LocalTime + Send + Sync>>,
     sources: popol::Sources<Source<Id>>,
     waker: Waker,
     timeouts: TimeoutManager<()>,
@@ -89,11 +100,30 @@ impl<R: Write + Read + AsRawFd, Id: PeerId> Reactor<R, Id> {
         S: Service<Id>,
     {
         self.connecting.remove(&addr);
+        self.handshakes.remove(&addr);
         self.peers.remove(&addr);
         self.sources.unregister(&Source::Peer(addr.clone()));
 
         service.disconnected(&addr, reason);
     }
+
+    /// Get the current time from the time source.
+    fn now(&self) -> LocalTime {
+        match &self.clock {
+            Some(now) => now(),
+            None => SystemTime::now().into(),
+        }
+    }
+
+    /// Close all peer connections. Used when shutting down.
+    fn close(&mut self) {
+        for addr in self.peers.keys().cloned().collect::<Vec<_>>() {
+            self.sources.unregister(&Source::Peer(addr));
+        }
+        self.peers.clear();
+        self.connecting.clear();
+        self.handshakes.clear();
+    }
 }
 
 impl<Id: PeerId> nakamoto_net::Reactor<Id> for Reactor<net::TcpStream, Id> {
@@ -110,10 +140,15 @@ impl<Id: PeerId> nakamoto_net::Reactor<Id> for Reactor<net::TcpStream, Id> {
         let waker = Waker::new(&mut sources)?;
         let timeouts = TimeoutManager::new(LocalDuration::from_secs(1));
         let connecting = HashSet::new();
+        let handshakes = HashMap::new();
 
         Ok(Self {
             peers,
             connecting,
+            handshakes,
+            proxy: None,
+            reuse_address: false,
+            clock: None,
             sources,
             waker,
             timeouts,
@@ -138,7 +173,7 @@ impl<Id: PeerId> nakamoto_net::Reactor<Id> for Reactor<net::TcpStream, Id> {
         let listener = if listen_addrs.is_empty() {
             None
         } else {
-            let listener = self::listen(listen_addrs)?;
+            let listener = self::listen(listen_addrs, self.reuse_address)?;
             let local_addr = listener.local_addr()?;
 
             self.sources
@@ -152,7 +187,7 @@ impl<Id: PeerId> nakamoto_net::Reactor<Id> for Reactor<net::TcpStream, Id> {
 
         info!("Initializing service..");
 
-        let local_time = SystemTime::now().into();
+        let local_time = self.now();
         service.initialize(local_time);
 
         self.process(&mut service, &mut publisher, local_time);
@@ -165,7 +200,7 @@ impl<Id: PeerId> nakamoto_net::Reactor<Id> for Reactor<net::TcpStream, Id> {
         loop {
             let timeout = self
                 .timeouts
-                .next(SystemTime::now())
+                .next(self.now())
                 .unwrap_or(WAIT_TIMEOUT)
                 .into();
 
@@ -177,7 +212,7 @@ impl<Id: PeerId> nakamoto_net::Reactor<Id> for Reactor<net::TcpStream, Id> {
             );
 
             let result = self.sources.wait_timeout(&mut events, timeout); // Blocking.
-            let local_time = SystemTime::now().into();
+            let local_time = self.now();
 
             service.tick(local_time);
 
@@ -240,6 +275,8 @@ impl<Id: PeerId> nakamoto_net::Reactor<Id> for Reactor<net::TcpStream, Id> {
 
                                 // Exit reactor loop if a shutdown was received.
                                 if let Ok(()) = self.shutdown.try_recv() {
+                                    self.close();
+
                                     return Ok(());
                                 }
                                 popol::Waker::reset(ev.source).ok();
@@ -255,18 +292,20 @@ impl<Id: PeerId> nakamoto_net::Reactor<Id> for Reactor<net::TcpStream, Id> {
                         }
                     }
                 }
-                Err(err) if err.kind() == io::ErrorKind::TimedOut => {
-                    // Nb. The way this is currently used basically ignores which keys have
-                    // timed out. So as long as *something* timed out, we wake the service.
-                    self.timeouts.wake(local_time, &mut timeouts);
-
-                    if !timeouts.is_empty() {
-                        timeouts.clear();
-                        service.wake();
-                    }
-                }
+                Err(err) if err.kind() == io::ErrorKind::TimedOut => {}
                 Err(err) => return Err(err.into()),
             }
+            // Timeouts are checked on every wake up, and not only when waiting timed out,
+            // since the time source may have moved past them in the meantime.
+            //
+            // Nb. The way this is currently used basically ignores which keys have
+            // timed out. So as long as *something* timed out, we wake the service.
+            self.timeouts.wake(local_time, &mut timeouts);
+
+            if !timeouts.is_empty() {
+                timeouts.clear();
+                service.wake();
+            }
             self.process(&mut service, &mut publisher, local_time);
         }
     }
@@ -277,6 +316,30 @@ impl<Id: PeerId> nakamoto_net::Reactor<Id> for Reactor<net::TcpStream, Id> {
     fn waker(&self) -> Self::Waker {
         self.waker.clone()
     }
+
+    /// Route outbound connections through a SOCKS5 proxy.
+    fn set_proxy(&mut self, proxy: net::SocketAddr) -> Result<(), io::Error> {
+        self.proxy = Some(proxy);
+
+        Ok(())
+    }
+
+    /// Bind the listener with `SO_REUSEADDR`.
+    fn set_reuse_address(&mut self, reuse: bool) -> Result<(), io::Error> {
+        self.reuse_address = reuse;
+
+        Ok(())
+    }
+
+    /// Use the given time source instead of the system clock.
+    fn set_clock(
+        &mut self,
+        now: Arc<dyn Fn() -> LocalTime + Send + Sync>,
+    ) -> Result<(), io::Error> {
+        self.clock = Some(now);
+
+        Ok(())
+    }
 }
 
 impl<Id: PeerId> Reactor<net::TcpStream, Id> {
@@ -303,10 +366,17 @@ impl<Id: PeerId> Reactor<net::TcpStream, Id> {
                     let socket_addr = addr.to_socket_addr();
                     trace!("Connecting to {}...", socket_addr);
 
-                    match self::dial(&socket_addr) {
+                    // When using a proxy, we connect to the proxy, and once connected,
+                    // ask it to connect to the peer.
+                    match self::dial(self.proxy.as_ref().unwrap_or(&socket_addr)) {
                         Ok(stream) => {
                             trace!("{:#?}", stream);
 
+                            if self.proxy.is_some() {
+                                self.handshakes
+                                    .insert(addr.clone(), socks5::Handshake::connect(socket_addr));
+                            }
+
                             self.register_peer(addr.clone(), stream, Link::Outbound);
                             self.connecting.insert(addr.clone());
 
@@ -370,7 +440,58 @@ impl<Id: PeerId> Reactor<net::TcpStream, Id> {
                     if count > 0 {
                         trace!("{}: Read {} bytes", socket_addr, count);
 
-                        service.received(&addr, Cow::Borrowed(&buffer[..count]));
+                        let handshake = if let Some(handshake) = self.handshakes.get_mut(&addr) {
+                            handshake
+                        } else {
+                            service.received(&addr, Cow::Borrowed(&buffer[..count]));
+                            return;
+                        };
+
+                        match handshake.input(&buffer[..count]) {
+                            Ok(socks5::Step::Pending) => {}
+                            Ok(socks5::Step::Write(bytes)) => {
+                                socket.push(&bytes);
+
+                                if let Some(source) =
+                                    self.sources.get_mut(&Source::Peer(addr.clone()))
+                                {
+                                    source.set(popol::interest::WRITE);
+                                }
+                            }
+                            Ok(socks5::Step::Done(rest)) => {
+                                trace!("{}: Proxy handshake complete", socket_addr);
+
+                                self.handshakes.remove(&addr);
+
+                                match socket.local_address() {
+                                    Ok(local_addr) => {
+                                        service.connected(addr.clone(), &local_addr, socket.link);
+
+                                        if !rest.is_empty() {
+                                            service.received(&addr, Cow::Owned(rest));
+                                        }
+                                    }
+                                    Err(err) => {
+                                        socket.disconnect().ok();
+                                        self.unregister_peer(
+                                            addr,
+                                            DisconnectReason::ConnectionError(Arc::new(err)),
+                                            service,
+                                        );
+                                    }
+                                }
+                            }
+                            Err(err) => {
+                                trace!("{}: Proxy handshake error: {}", socket_addr, err);
+
+                                socket.disconnect().ok();
+                                self.unregister_peer(
+                                    addr,
+                                    DisconnectReason::DialError(Arc::new(err)),
+                                    service,
+                                );
+                            }
+                        }
                     } else {
                         trace!("{}: Read 0 bytes", socket_addr);
                         // If we get zero bytes read as a return value, it means the peer has
@@ -422,9 +543,15 @@ impl<Id: PeerId> Reactor<net::TcpStream, Id> {
         // Since we perform a non-blocking connect, we're only really connected once the socket
         // is writable.
         if self.connecting.remove(&addr) {
-            let local_addr = socket.local_address()?;
-
-            service.connected(addr.clone(), &local_addr, socket.link);
+            if let Some(handshake) = self.handshakes.get_mut(&addr) {
+                // We're connected to the proxy. The peer is only connected once the proxy
+                // handshake is complete.
+                socket.push(&handshake.start());
+            } else {
+                let local_addr = socket.local_address()?;
+
+                service.connected(addr.clone(), &local_addr, socket.link);
+            }
         }
 
         match socket.flush() {
@@ -485,11 +612,33 @@ fn dial(addr: &net::SocketAddr) -> Result<net::TcpStream, io::Error> {
     Ok(sock.into())
 }
 
-// Listen for connections on the given address.
-fn listen<A: net::ToSocketAddrs>(addr: A) -> Result<net::TcpListener, Error> {
-    let sock = net::TcpListener::bind(addr)?;
+// Listen for connections on the first of the given addresses that can be bound.
+fn listen(addrs: &[net::SocketAddr], reuse_address: bool) -> Result<net::TcpListener, Error> {
+    let mut result = Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "no listen address given",
+    ));
+
+    for addr in addrs {
+        result = bind(addr, reuse_address);
 
+        if result.is_ok() {
+            break;
+        }
+    }
+    Ok(result?)
+}
+
+// Bind a non-blocking listener to the given address.
+fn bind(addr: &net::SocketAddr, reuse_address: bool) -> Result<net::TcpListener, io::Error> {
+    use socket2::{Domain, Socket, Type};
+
+    let sock = Socket::new(Domain::for_address(*addr), Type::STREAM, None)?;
+
+    sock.set_reuse_address(reuse_address)?;
+    sock.bind(&(*addr).into())?;
+    sock.listen(LISTEN_BACKLOG)?;
     sock.set_nonblocking(true)?;
 
-    Ok(sock)
+    Ok(sock.into())
 }
@@ -1,5 +1,5 @@
 //! Events generated by the peer-to-peer system.
-use std::ops::Not;
+use std::collections::VecDeque;
 use std::sync::{Arc, Mutex};
 use std::time;
 
@@ -7,9 +7,260 @@ use crossbeam_channel as chan;
 
 pub use chan::RecvTimeoutError;
 
+/// What to do with new events when a bounded subscription is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Drop the new event.
+    DropNewest,
+    /// Block until the subscriber makes room for the new event. Note that this blocks
+    /// the publisher, and thus delays the next events for all other subscribers, until then.
+    Block,
+}
+
+/// How events are buffered for a subscriber that hasn't received them yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BufferPolicy {
+    /// Buffer all events. Memory usage grows for as long as the subscriber lags behind.
+    Unbounded,
+    /// Buffer at most the given number of events, and handle new events as specified
+    /// once the buffer is full.
+    Bounded(usize, Overflow),
+}
+
+/// Outcome of sending an event to a subscription.
+enum Delivery<T> {
+    /// The event was sent, or deliberately dropped.
+    Done,
+    /// The subscriber is gone.
+    Gone,
+    /// The subscription is full, and the event must be sent by blocking on the given sender.
+    Blocked(chan::Sender<T>, T),
+}
+
+/// Where the events of a subscription are sent.
+enum Sink<T> {
+    /// A channel, and what to do with new events once it is full. Unbounded channels are
+    /// never full.
+    Channel(chan::Sender<T>, Overflow),
+    /// A queue shared with a [`Lossy`] receiver, which drops its oldest event once full.
+    Lossy {
+        queue: Arc<Mutex<VecDeque<T>>>,
+        capacity: usize,
+        notify: chan::Sender<()>,
+    },
+}
+
+/// A subscription to events.
+struct Subscription<T> {
+    sink: Sink<T>,
+    /// Only events matching this predicate are sent, if set.
+    filter: Option<Box<dyn Fn(&T) -> bool + Send + Sync>>,
+}
+
+impl<T> Subscription<T> {
+    /// Create a new subscription with the given buffering policy, and return it along with
+    /// the receiving end of its channel.
+    fn new(
+        policy: BufferPolicy,
+        filter: Option<Box<dyn Fn(&T) -> bool + Send + Sync>>,
+    ) -> (Self, chan::Receiver<T>) {
+        let (sender, receiver, overflow) = match policy {
+            BufferPolicy::Unbounded => {
+                let (sender, receiver) = chan::unbounded();
+                (sender, receiver, Overflow::Block)
+            }
+            BufferPolicy::Bounded(capacity, overflow) => {
+                assert!(capacity > 0, "subscription capacity must not be zero");
+
+                let (sender, receiver) = chan::bounded(capacity);
+                (sender, receiver, overflow)
+            }
+        };
+
+        (
+            Self {
+                sink: Sink::Channel(sender, overflow),
+                filter,
+            },
+            receiver,
+        )
+    }
+
+    /// Create a new lossy subscription holding at most `capacity` events, and return it
+    /// along with its receiver.
+    fn lossy(capacity: usize) -> (Self, Lossy<T>) {
+        assert!(capacity > 0, "subscription capacity must not be zero");
+
+        let queue = Arc::new(Mutex::new(VecDeque::with_capacity(capacity)));
+        // Never holds more notifications than the queue holds events.
+        let (notify, notified) = chan::bounded(capacity);
+
+        (
+            Self {
+                sink: Sink::Lossy {
+                    queue: queue.clone(),
+                    capacity,
+                    notify,
+                },
+                filter: None,
+            },
+            Lossy {
+                queue,
+                notify: notified,
+            },
+        )
+    }
+
+    /// Send an event to the subscriber, without blocking.
+    fn send(&self, event: T) -> Delivery<T> {
+        if let Some(filter) = &self.filter {
+            if !filter(&event) {
+                // Dropped subscribers are only detected on the next matching event.
+                return Delivery::Done;
+            }
+        }
+        match &self.sink {
+            Sink::Channel(sender, overflow) => match sender.try_send(event) {
+                Ok(()) => Delivery::Done,
+                Err(chan::TrySendError::Full(event)) => match overflow {
+                    Overflow::DropNewest => {
+                        log::warn!("Subscriber is lagging behind, dropping newest event..");
+
+                        Delivery::Done
+                    }
+                    Overflow::Block => Delivery::Blocked(sender.clone(), event),
+                },
+                Err(chan::TrySendError::Disconnected(_)) => Delivery::Gone,
+            },
+            Sink::Lossy {
+                queue,
+                capacity,
+                notify,
+            } => {
+                {
+                    let mut queue = queue.lock().unwrap();
+
+                    if queue.len() >= *capacity {
+                        log::warn!("Subscriber is lagging behind, dropping oldest event..");
+
+                        queue.pop_front();
+                    }
+                    queue.push_back(event);
+                }
+                // If the notifications are full, the receiver will be notified of the
+                // new event anyway, since the oldest event was dropped without its
+                // notification.
+                match notify.try_send(()) {
+                    Ok(()) | Err(chan::TrySendError::Full(())) => Delivery::Done,
+                    Err(chan::TrySendError::Disconnected(())) => Delivery::Gone,
+                }
+            }
+        }
+    }
+}
+
+/// Receiving end of a lossy subscription, see [`Subscriber::subscribe_bounded`].
+///
+/// Dropping the receiver ends the subscription.
+pub struct Lossy<T> {
+    queue: Arc<Mutex<VecDeque<T>>>,
+    /// Notified of every event queued. Holds at least as many notifications as there are
+    /// events in the queue, since dropped events leave their notification behind.
+    notify: chan::Receiver<()>,
+}
+
+impl<T> Lossy<T> {
+    /// Wait for the next event. Fails once the subscriber is closed and all events
+    /// were received.
+    pub fn recv(&self) -> Result<T, chan::RecvError> {
+        loop {
+            self.notify.recv()?;
+
+            if let Some(event) = self.pop() {
+                return Ok(event);
+            }
+        }
+    }
+
+    /// Wait for the next event, for at most the given duration.
+    pub fn recv_timeout(&self, timeout: time::Duration) -> Result<T, chan::RecvTimeoutError> {
+        let deadline = time::Instant::now() + timeout;
+
+        loop {
+            self.notify.recv_deadline(deadline)?;
+
+            if let Some(event) = self.pop() {
+                return Ok(event);
+            }
+        }
+    }
+
+    /// Get the next event, if any, without waiting.
+    pub fn try_recv(&self) -> Result<T, chan::TryRecvError> {
+        loop {
+            self.notify.try_recv()?;
+
+            if let Some(event) = self.pop() {
+                return Ok(event);
+            }
+        }
+    }
+
+    /// Iterate over the events, waiting for each, until the subscriber is closed.
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        std::iter::from_fn(move || self.recv().ok())
+    }
+
+    /// Iterate over the events received so far, without waiting.
+    pub fn try_iter(&self) -> impl Iterator<Item = T> + '_ {
+        std::iter::from_fn(move || self.try_recv().ok())
+    }
+
+    /// Number of events waiting to be received.
+    pub fn len(&self) -> usize {
+        self.queue.lock().unwrap().len()
+    }
+
+    /// Whether there are no events waiting to be received.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn pop(&self) -> Option<T> {
+        self.queue.lock().unwrap().pop_front()
+    }
+}
+
+/// Send an event to all subscriptions, and return the number of subscriptions left.
+///
+/// Subscriptions that are full and block are sent the event after the lock is released,
+/// so that a slow subscriber doesn't hold up new subscriptions. If such a subscriber is
+/// gone by then, its subscription is removed on the next event.
+fn deliver<T: Clone>(subscribers: &Mutex<Vec<Subscription<T>>>, event: &T) -> usize {
+    let mut blocked = Vec::new();
+    let remaining = {
+        let mut subs = subscribers.lock().unwrap();
+
+        subs.retain(|s| match s.send(event.clone()) {
+            Delivery::Done => true,
+            Delivery::Gone => false,
+            Delivery::Blocked(sender, event) => {
+                blocked.push((sender, event));
+                true
+            }
+        });
+        subs.len()
+    };
+
+    for (sender, event) in blocked {
+        sender.send(event).ok();
+    }
+    remaining
+}
+
 /// An event publish/subscribe channel.
 pub struct Broadcast<E, T> {
-    subscribers: Arc<Mutex<Vec<chan::Sender<T>>>>,
+    subscribers: Arc<Mutex<Vec<Subscription<T>>>>,
     broadcast: Box<dyn FnMut(E, &Emitter<T>) + Send + Sync>,
 }
 
@@ -27,23 +278,20 @@ impl<E, T: Clone> Broadcast<E, T> {
 
 /// Publishes an event to all subscribers.
 pub struct Emitter<T> {
-    subscribers: Arc<Mutex<Vec<chan::Sender<T>>>>,
+    subscribers: Arc<Mutex<Vec<Subscription<T>>>>,
 }
 
 impl<T: Clone> Emitter<T> {
     /// Publish an event to all subscribers.
     pub fn emit(&self, event: T) {
-        self.subscribers
-            .lock()
-            .unwrap()
-            .retain(|s| s.try_send(event.clone()).is_ok());
+        deliver(&self.subscribers, &event);
     }
 }
 
 /// An event subscriber.
 #[derive(Clone)]
 pub struct Subscriber<T> {
-    subscribers: Arc<Mutex<Vec<chan::Sender<T>>>>,
+    subscribers: Arc<Mutex<Vec<Subscription<T>>>>,
 }
 
 impl<T> Default for Subscriber<T> {
@@ -57,17 +305,54 @@ impl<T> Default for Subscriber<T> {
 impl<T: Clone> Subscriber<T> {
     /// Add a subscription to receive broadcast events.
     pub fn subscribe(&self) -> chan::Receiver<T> {
-        let (sender, receiver) = chan::unbounded();
-        let mut subs = self.subscribers.lock().unwrap();
-        subs.push(sender);
+        self.subscribe_with(BufferPolicy::Unbounded)
+    }
+
+    /// Add a subscription to receive broadcast events, buffered according to the given
+    /// policy. Each subscription is buffered independently of the others.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the policy is bounded with a capacity of zero.
+    pub fn subscribe_with(&self, policy: BufferPolicy) -> chan::Receiver<T> {
+        let (subscription, receiver) = Subscription::new(policy, None);
+        self.subscribers.lock().unwrap().push(subscription);
+
+        receiver
+    }
+
+    /// Add a subscription that only receives the broadcast events matching the given
+    /// predicate. Other events are never sent on the returned channel.
+    pub fn subscribe_filtered(
+        &self,
+        filter: impl Fn(&T) -> bool + Send + Sync + 'static,
+    ) -> chan::Receiver<T> {
+        let (subscription, receiver) =
+            Subscription::new(BufferPolicy::Unbounded, Some(Box::new(filter)));
+        self.subscribers.lock().unwrap().push(subscription);
+
+        receiver
+    }
+
+    /// Add a lossy subscription holding at most `capacity` events.
+    ///
+    /// When the subscription is full, the oldest event is dropped to make room for the
+    /// new one, and a warning is logged. Events are thus never blocked or accumulated
+    /// on behalf of a slow consumer, but may be missed by it. The subscription is removed
+    /// once the returned receiver is dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn subscribe_bounded(&self, capacity: usize) -> Lossy<T> {
+        let (subscription, receiver) = Subscription::lossy(capacity);
+        self.subscribers.lock().unwrap().push(subscription);
 
         receiver
     }
 
     pub fn publish(&self, event: T) -> bool {
-        let mut subs = self.subscribers.lock().unwrap();
-        subs.retain(|s| s.try_send(event.clone()).is_ok());
-        subs.is_empty().not()
+        deliver(&self.subscribers, &event) > 0
     }
 
     pub fn close(self) {
@@ -135,3 +420,90 @@ impl<E, T: Clone + Send + Sync> Publisher<E> for Broadcast<E, T> {
         self.broadcast(event)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_subscribe_bounded() {
+        let (mut broadcast, subscriber) = broadcast(|e, p| p.emit(e));
+        let bounded = subscriber.subscribe_bounded(2);
+        let unbounded = subscriber.subscribe();
+
+        for i in 0..5 {
+            broadcast.broadcast(i);
+        }
+        assert_eq!(bounded.try_iter().collect::<Vec<_>>(), vec![3, 4]);
+        assert_eq!(
+            unbounded.try_iter().collect::<Vec<_>>(),
+            vec![0, 1, 2, 3, 4]
+        );
+
+        subscriber.publish(5);
+        assert_eq!(bounded.try_iter().collect::<Vec<_>>(), vec![5]);
+
+        // Dropping the receiver removes the subscription.
+        drop(bounded);
+        broadcast.broadcast(6);
+        assert_eq!(subscriber.subscribers.lock().unwrap().len(), 1);
+
+        // Events are received in order, even once some were dropped.
+        let bounded = subscriber.subscribe_bounded(2);
+        for i in 0..3 {
+            broadcast.broadcast(i);
+        }
+        assert_eq!(bounded.len(), 2);
+        assert_eq!(bounded.recv().unwrap(), 1);
+        assert_eq!(bounded.recv().unwrap(), 2);
+        assert!(bounded.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_subscribe_with() {
+        let (mut broadcast, subscriber) = broadcast(|e, p| p.emit(e));
+        let newest = subscriber.subscribe_with(BufferPolicy::Bounded(2, Overflow::DropNewest));
+        let unbounded = subscriber.subscribe_with(BufferPolicy::Unbounded);
+
+        for i in 0..5 {
+            broadcast.broadcast(i);
+        }
+        assert_eq!(newest.try_iter().collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(
+            unbounded.try_iter().collect::<Vec<_>>(),
+            vec![0, 1, 2, 3, 4]
+        );
+
+        // A slow, blocking subscriber holds up the publisher until it makes room.
+        let blocking = subscriber.subscribe_with(BufferPolicy::Bounded(1, Overflow::Block));
+        let consumer = std::thread::spawn(move || blocking.iter().take(3).collect::<Vec<_>>());
+
+        for i in 0..3 {
+            broadcast.broadcast(i);
+        }
+        assert_eq!(consumer.join().unwrap(), vec![0, 1, 2]);
+        assert_eq!(newest.try_iter().collect::<Vec<_>>(), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_subscribe_blocking() {
+        let (mut broadcast, subscriber) = broadcast(|e, p| p.emit(e));
+        let blocking = subscriber.subscribe_with(BufferPolicy::Bounded(1, Overflow::Block));
+
+        broadcast.broadcast(0);
+
+        let publisher = std::thread::spawn(move || broadcast.broadcast(1));
+
+        // Subscribing doesn't wait for the blocked publisher.
+        let unbounded = subscriber.subscribe();
+
+        assert_eq!(blocking.recv().unwrap(), 0);
+        assert_eq!(blocking.recv().unwrap(), 1);
+
+        publisher.join().unwrap();
+        subscriber.publish(2);
+
+        assert_eq!(blocking.recv().unwrap(), 2);
+        assert_eq!(unbounded.try_iter().last(), Some(2));
+    }
+}
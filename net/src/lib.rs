@@ -10,6 +10,7 @@ use crossbeam_channel as chan;
 pub mod error;
 pub mod event;
 pub mod simulator;
+pub mod socks5;
 pub mod time;
 
 pub use event::Publisher;
@@ -189,4 +190,33 @@ pub trait Reactor<Id: PeerId = net::SocketAddr> {
 
     /// Return a new waker.
     fn waker(&self) -> Self::Waker;
+
+    /// Route outbound connections through the given SOCKS5 proxy.
+    /// Returns an error if the reactor doesn't support proxies.
+    fn set_proxy(&mut self, proxy: net::SocketAddr) -> Result<(), io::Error> {
+        let _ = proxy;
+
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Set whether listening sockets are bound with `SO_REUSEADDR`, allowing the listen
+    /// address to be bound again right after a restart. Reactors that don't control socket
+    /// options ignore this.
+    fn set_reuse_address(&mut self, reuse: bool) -> Result<(), io::Error> {
+        let _ = reuse;
+
+        Ok(())
+    }
+
+    /// Use the given function as the reactor's time source instead of the system clock,
+    /// eg. to drive timeouts from a mock clock in tests.
+    /// Returns an error if the reactor doesn't support custom time sources.
+    fn set_clock(
+        &mut self,
+        now: Arc<dyn Fn() -> LocalTime + Send + Sync>,
+    ) -> Result<(), io::Error> {
+        let _ = now;
+
+        Err(io::ErrorKind::Unsupported.into())
+    }
 }
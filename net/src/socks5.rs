@@ -0,0 +1,296 @@
+//! SOCKS5 proxy protocol, as used to connect to peers over Tor.
+//!
+//! The handshake is implemented as a state machine that doesn't perform any I/O, so that it
+//! can be driven by a non-blocking reactor.
+use std::io::{self, Read, Write};
+use std::net;
+use std::time;
+
+/// Protocol version.
+const VERSION: u8 = 0x05;
+/// "No authentication required" method.
+const NO_AUTH: u8 = 0x00;
+/// Connect command.
+const CMD_CONNECT: u8 = 0x01;
+/// Resolve command. This is a Tor extension to the protocol.
+const CMD_RESOLVE: u8 = 0xf0;
+/// IPv4 address type.
+const ATYP_IPV4: u8 = 0x01;
+/// Domain name address type.
+const ATYP_DOMAIN: u8 = 0x03;
+/// IPv6 address type.
+const ATYP_IPV6: u8 = 0x04;
+
+/// Destination of a proxied connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    /// An IP address, resolved locally.
+    Addr(net::SocketAddr),
+    /// A host name, resolved by the proxy.
+    Domain(String, u16),
+}
+
+impl From<net::SocketAddr> for Target {
+    fn from(addr: net::SocketAddr) -> Self {
+        Self::Addr(addr)
+    }
+}
+
+/// Progress of a SOCKS5 handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    /// More input is needed from the proxy.
+    Pending,
+    /// The given bytes should be sent to the proxy.
+    Write(Vec<u8>),
+    /// The handshake is complete. Any bytes received past the end of the handshake are
+    /// returned, and belong to the proxied connection.
+    Done(Vec<u8>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Initial,
+    Method,
+    Reply,
+    Done,
+}
+
+/// A SOCKS5 client handshake.
+#[derive(Debug, Clone)]
+pub struct Handshake {
+    target: Target,
+    command: u8,
+    state: State,
+    buffer: Vec<u8>,
+    bound: Option<net::SocketAddr>,
+}
+
+impl Handshake {
+    /// Create a handshake for connecting to the given target through the proxy.
+    pub fn connect(target: impl Into<Target>) -> Self {
+        Self::new(target.into(), CMD_CONNECT)
+    }
+
+    /// Create a handshake for resolving the given host name through the proxy.
+    /// Only supported by Tor.
+    pub fn resolve(host: impl Into<String>) -> Self {
+        Self::new(Target::Domain(host.into(), 0), CMD_RESOLVE)
+    }
+
+    /// Start the handshake. Returns the bytes to send to the proxy once connected.
+    pub fn start(&mut self) -> Vec<u8> {
+        self.state = State::Method;
+
+        vec![VERSION, 1, NO_AUTH]
+    }
+
+    /// Address returned by the proxy once the handshake is done. For a resolve
+    /// handshake, this is the resolved address.
+    pub fn bound(&self) -> Option<net::SocketAddr> {
+        self.bound
+    }
+
+    /// Process bytes received from the proxy.
+    pub fn input(&mut self, bytes: &[u8]) -> io::Result<Step> {
+        self.buffer.extend_from_slice(bytes);
+
+        match self.state {
+            State::Method => {
+                if self.buffer.len() < 2 {
+                    return Ok(Step::Pending);
+                }
+                let reply = self.buffer.drain(..2).collect::<Vec<_>>();
+
+                if reply[0] != VERSION {
+                    return Err(invalid_data("invalid protocol version"));
+                }
+                if reply[1] != NO_AUTH {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "proxy requires authentication",
+                    ));
+                }
+                self.state = State::Reply;
+
+                Ok(Step::Write(self.request()?))
+            }
+            State::Reply => match parse_reply(&self.buffer)? {
+                Some((bound, len)) => {
+                    self.buffer.drain(..len);
+                    self.bound = Some(bound);
+                    self.state = State::Done;
+
+                    Ok(Step::Done(std::mem::take(&mut self.buffer)))
+                }
+                None => Ok(Step::Pending),
+            },
+            State::Initial | State::Done => Err(invalid_data("unexpected input from proxy")),
+        }
+    }
+
+    fn new(target: Target, command: u8) -> Self {
+        Self {
+            target,
+            command,
+            state: State::Initial,
+            buffer: Vec::new(),
+            bound: None,
+        }
+    }
+
+    fn request(&self) -> io::Result<Vec<u8>> {
+        let mut req = vec![VERSION, self.command, 0x00];
+
+        let port = match &self.target {
+            Target::Addr(net::SocketAddr::V4(addr)) => {
+                req.push(ATYP_IPV4);
+                req.extend_from_slice(&addr.ip().octets());
+                addr.port()
+            }
+            Target::Addr(net::SocketAddr::V6(addr)) => {
+                req.push(ATYP_IPV6);
+                req.extend_from_slice(&addr.ip().octets());
+                addr.port()
+            }
+            Target::Domain(host, port) => {
+                let len = u8::try_from(host.len())
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "host too long"))?;
+
+                req.push(ATYP_DOMAIN);
+                req.push(len);
+                req.extend_from_slice(host.as_bytes());
+                *port
+            }
+        };
+        req.extend_from_slice(&port.to_be_bytes());
+
+        Ok(req)
+    }
+}
+
+/// Resolve a host name through the proxy, without any local DNS lookup.
+/// This is a blocking operation. Only supported by Tor.
+pub fn resolve(
+    proxy: &net::SocketAddr,
+    host: &str,
+    timeout: time::Duration,
+) -> io::Result<net::IpAddr> {
+    let mut stream = net::TcpStream::connect_timeout(proxy, timeout)?;
+    let mut handshake = Handshake::resolve(host);
+    let mut buf = [0; 512];
+    let mut step = Step::Write(handshake.start());
+
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    loop {
+        match step {
+            Step::Write(bytes) => stream.write_all(&bytes)?,
+            Step::Done(_) => {
+                return handshake
+                    .bound()
+                    .map(|addr| addr.ip())
+                    .ok_or_else(|| invalid_data("no address returned by proxy"));
+            }
+            Step::Pending => {}
+        }
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        step = handshake.input(&buf[..n])?;
+    }
+}
+
+/// Parse a proxy reply. Returns the bound address and the length of the reply, or `None`
+/// if the reply is incomplete.
+fn parse_reply(buf: &[u8]) -> io::Result<Option<(net::SocketAddr, usize)>> {
+    if buf.len() < 5 {
+        return Ok(None);
+    }
+    if buf[0] != VERSION {
+        return Err(invalid_data("invalid protocol version"));
+    }
+    if buf[1] != 0x00 {
+        return Err(reply_error(buf[1]));
+    }
+    let len = match buf[3] {
+        ATYP_IPV4 => 4 + 4 + 2,
+        ATYP_IPV6 => 4 + 16 + 2,
+        ATYP_DOMAIN => 4 + 1 + buf[4] as usize + 2,
+        _ => return Err(invalid_data("invalid address type")),
+    };
+    if buf.len() < len {
+        return Ok(None);
+    }
+    let port = u16::from_be_bytes([buf[len - 2], buf[len - 1]]);
+    let ip = match buf[3] {
+        ATYP_IPV4 => {
+            let octets: [u8; 4] = buf[4..8].try_into().unwrap();
+            net::IpAddr::from(octets)
+        }
+        ATYP_IPV6 => {
+            let octets: [u8; 16] = buf[4..20].try_into().unwrap();
+            net::IpAddr::from(octets)
+        }
+        // Bound domain names are not useful to us.
+        _ => net::IpAddr::from([0, 0, 0, 0]),
+    };
+
+    Ok(Some((net::SocketAddr::new(ip, port), len)))
+}
+
+fn reply_error(code: u8) -> io::Error {
+    let (kind, msg) = match code {
+        0x02 => (io::ErrorKind::PermissionDenied, "connection not allowed"),
+        0x03 => (io::ErrorKind::Other, "network unreachable"),
+        0x04 => (io::ErrorKind::Other, "host unreachable"),
+        0x05 => (io::ErrorKind::ConnectionRefused, "connection refused"),
+        0x06 => (io::ErrorKind::TimedOut, "TTL expired"),
+        0x07 => (io::ErrorKind::Unsupported, "command not supported"),
+        0x08 => (io::ErrorKind::Unsupported, "address type not supported"),
+        _ => (io::ErrorKind::Other, "general proxy failure"),
+    };
+    io::Error::new(kind, format!("socks5: {}", msg))
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("socks5: {}", msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connect_handshake() {
+        let addr: net::SocketAddr = ([1, 2, 3, 4], 8333).into();
+        let mut hs = Handshake::connect(addr);
+
+        assert_eq!(hs.start(), vec![0x05, 0x01, 0x00]);
+        assert_eq!(hs.input(&[0x05]).unwrap(), Step::Pending);
+        assert_eq!(
+            hs.input(&[0x00]).unwrap(),
+            Step::Write(vec![0x05, 0x01, 0x00, 0x01, 1, 2, 3, 4, 0x20, 0x8d])
+        );
+        assert_eq!(
+            hs.input(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0]).unwrap(),
+            Step::Pending
+        );
+        assert_eq!(hs.input(&[0, 0, 0xff]).unwrap(), Step::Done(vec![0xff]));
+    }
+
+    #[test]
+    fn test_reply_error() {
+        let mut hs = Handshake::connect(net::SocketAddr::from(([1, 2, 3, 4], 8333)));
+
+        hs.start();
+        hs.input(&[0x05, 0x00]).unwrap();
+
+        let err = hs
+            .input(&[0x05, 0x05, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    }
+}
@@ -21,15 +21,18 @@ mod tests;
 
 use addrmgr::AddressManager;
 use cbfmgr::FilterManager;
+use fees::FeeRate;
 use invmgr::InventoryManager;
 use output::Outbox;
 use peermgr::PeerManager;
 use pingmgr::PingManager;
 use syncmgr::SyncManager;
 
+pub use addrmgr::AddressFilter;
 pub use addrmgr::Event as AddressEvent;
 pub use cbfmgr::Event as FilterEvent;
 pub use invmgr::Event as InventoryEvent;
+pub use peermgr::Backoff;
 pub use peermgr::Event as PeerEvent;
 pub use pingmgr::Event as PingEvent;
 pub use syncmgr::Event as ChainEvent;
@@ -43,6 +46,7 @@ pub use output::Io;
 use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
 use std::fmt::{self, Debug};
+use std::io;
 use std::net;
 use std::ops::{Bound, RangeInclusive};
 use std::sync::Arc;
@@ -56,13 +60,13 @@ use nakamoto_common::bitcoin::network::message_blockdata::{GetHeadersMessage, In
 use nakamoto_common::bitcoin::network::message_filter::GetCFilters;
 use nakamoto_common::bitcoin::network::message_network::VersionMessage;
 use nakamoto_common::bitcoin::network::Address;
-use nakamoto_common::bitcoin::Script;
-use nakamoto_common::block::filter::Filters;
+use nakamoto_common::bitcoin::{Script, Txid};
+use nakamoto_common::block::filter::{self, BlockFilter, Filters};
 use nakamoto_common::block::time::AdjustedClock;
 use nakamoto_common::block::time::{LocalDuration, LocalTime};
-use nakamoto_common::block::tree::{self, BlockReader, BlockTree, ImportResult};
+use nakamoto_common::block::tree::{self, BlockReader, BlockTree, ImportResult, ImportSummary};
+use nakamoto_common::block::{Block, BlockTime, Transaction};
 use nakamoto_common::block::{BlockHash, Height};
-use nakamoto_common::block::{BlockTime, Transaction};
 use nakamoto_common::network;
 use nakamoto_common::nonempty::NonEmpty;
 use nakamoto_common::p2p::peer::AddressSource;
@@ -136,6 +140,8 @@ pub enum DisconnectReason {
     PeerTimeout(&'static str),
     /// Peer was dropped by all sub-protocols.
     PeerDropped,
+    /// Peer address is banned for misbehaving.
+    PeerBanned,
     /// Connection to self was detected.
     SelfConnection,
     /// Inbound connection limit reached.
@@ -175,6 +181,7 @@ impl fmt::Display for DisconnectReason {
             Self::PeerMagic(magic) => write!(f, "received message with invalid magic: {}", magic),
             Self::PeerTimeout(s) => write!(f, "peer timed out: {:?}", s),
             Self::PeerDropped => write!(f, "peer dropped"),
+            Self::PeerBanned => write!(f, "peer is banned"),
             Self::SelfConnection => write!(f, "detected self-connection"),
             Self::ConnectionLimit => write!(f, "inbound connection limit reached"),
             Self::DecodeError(err) => write!(f, "message decode error: {}", err),
@@ -199,6 +206,8 @@ pub struct Peer {
     pub height: Height,
     /// The peer's services.
     pub services: ServiceFlags,
+    /// The protocol version negotiated with this peer.
+    pub version: u32,
     /// Peer user agent string.
     pub user_agent: String,
     /// Whether this peer relays transactions.
@@ -212,6 +221,155 @@ impl Peer {
     }
 }
 
+/// Detailed information about a remote peer.
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    /// Peer.
+    pub peer: Peer,
+    /// The protocol version negotiated with this peer.
+    pub version: u32,
+    /// Average round-trip ping latency, if measured.
+    pub latency: Option<LocalDuration>,
+    /// Number of bytes sent to this peer.
+    pub bytes_sent: u64,
+    /// Number of bytes received from this peer.
+    pub bytes_received: u64,
+    /// Time at which the last message was received from this peer.
+    pub last_message: Option<LocalTime>,
+}
+
+/// Number of bytes transferred, broken down by message type.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Traffic {
+    /// Bytes of block header messages, eg. `headers` and `getheaders`.
+    pub headers: u64,
+    /// Bytes of compact filter messages, eg. `cfilter` and `cfheaders`.
+    pub filters: u64,
+    /// Bytes of `block` messages.
+    pub blocks: u64,
+    /// Bytes of `tx` messages.
+    pub transactions: u64,
+    /// Bytes of all other messages.
+    pub other: u64,
+}
+
+impl Traffic {
+    /// Record a message of the given size, in bytes.
+    pub fn record(&mut self, msg: &NetworkMessage, bytes: u64) {
+        let counter = match msg {
+            NetworkMessage::Headers(_) | NetworkMessage::GetHeaders(_) => &mut self.headers,
+            NetworkMessage::CFilter(_)
+            | NetworkMessage::GetCFilters(_)
+            | NetworkMessage::CFHeaders(_)
+            | NetworkMessage::GetCFHeaders(_)
+            | NetworkMessage::CFCheckpt(_)
+            | NetworkMessage::GetCFCheckpt(_) => &mut self.filters,
+            NetworkMessage::Block(_)
+            | NetworkMessage::CmpctBlock(_)
+            | NetworkMessage::BlockTxn(_) => &mut self.blocks,
+            NetworkMessage::Tx(_) => &mut self.transactions,
+            _ => &mut self.other,
+        };
+        *counter += bytes;
+    }
+
+    /// Total number of bytes transferred.
+    pub fn total(&self) -> u64 {
+        self.headers + self.filters + self.blocks + self.transactions + self.other
+    }
+}
+
+/// Cumulative bandwidth usage, since startup.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Bandwidth {
+    /// Traffic sent to peers.
+    pub sent: Traffic,
+    /// Traffic received from peers.
+    pub received: Traffic,
+}
+
+/// A data transfer rate, in bytes per second.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ByteRate(pub u64);
+
+impl ByteRate {
+    /// Create a rate from a number of kilobytes per second.
+    pub const fn from_kilobytes(kb: u64) -> Self {
+        Self(kb * 1024)
+    }
+
+    /// Number of bytes per second.
+    pub const fn bytes_per_sec(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Number of peer connections, by direction.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionCounts {
+    /// Connected inbound peers.
+    pub inbound: usize,
+    /// Connected outbound peers.
+    pub outbound: usize,
+    /// Outbound connection attempts in progress.
+    pub connecting: usize,
+    /// Maximum number of inbound peers. Inbound connections past this limit are rejected.
+    pub max_inbound: usize,
+    /// Target number of outbound peers.
+    pub target_outbound: usize,
+}
+
+/// Protocol versions advertised by us and negotiated with our peers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionInfo {
+    /// Protocol version advertised in our `version` messages.
+    pub protocol_version: u32,
+    /// User agent advertised in our `version` messages.
+    pub user_agent: String,
+    /// Protocol version negotiated with each connected peer, ie. the highest version
+    /// supported by both sides.
+    pub peers: HashMap<net::SocketAddr, u32>,
+}
+
+/// Tips of the block header and filter header chains.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tips {
+    /// Height and hash of the best block header.
+    pub header: (Height, BlockHash),
+    /// Height and block hash of the best filter header.
+    pub filter: (Height, BlockHash),
+}
+
+/// Synchronization status of the node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncStatus {
+    /// Whether the node is in initial block download, ie. still catching up with the
+    /// best known peer height, as opposed to following the tip.
+    pub in_ibd: bool,
+    /// Height of the block header chain.
+    pub headers_height: Height,
+    /// Height of the filter header chain.
+    pub filters_height: Height,
+    /// Heights of the first and last filters processed since the current or last rescan
+    /// was started, if any.
+    pub filters_processed: Option<(Height, Height)>,
+    /// Best height known amongst connected peers, if any.
+    pub best_peer_height: Option<Height>,
+}
+
+/// Summary of the node's health, eg. for liveness and readiness probes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Health {
+    /// Number of negotiated peers.
+    pub peers: usize,
+    /// Whether the node is in initial block download.
+    pub in_ibd: bool,
+    /// Time elapsed since the tip's timestamp, according to the network-adjusted clock.
+    pub last_block_age: LocalDuration,
+    /// Whether the last write to the stores succeeded.
+    pub store_ok: bool,
+}
+
 impl From<(&peermgr::PeerInfo, &peermgr::Connection)> for Peer {
     fn from((peer, conn): (&peermgr::PeerInfo, &peermgr::Connection)) -> Self {
         Self {
@@ -221,6 +379,7 @@ impl From<(&peermgr::PeerInfo, &peermgr::Connection)> for Peer {
             since: conn.since,
             height: peer.height,
             services: peer.services,
+            version: peer.version,
             user_agent: peer.user_agent.clone(),
             relay: peer.relay,
         }
@@ -232,17 +391,63 @@ impl From<(&peermgr::PeerInfo, &peermgr::Connection)> for Peer {
 pub enum Command {
     /// Get block header at height.
     GetBlockByHeight(Height, chan::Sender<Option<BlockHeader>>),
+    /// Get the filter header at the given height, if any.
+    GetFilterHeader(
+        Height,
+        chan::Sender<Option<(filter::FilterHash, filter::FilterHeader)>>,
+    ),
+    /// Get the filter header checkpoints, at every [`FILTER_CHECKPOINT_INTERVAL`] blocks.
+    GetFilterCheckpoints(chan::Sender<Vec<(Height, filter::FilterHeader)>>),
+    /// Get the compact filter of a block in the active chain, if it is in the filter cache.
+    GetCachedFilter(BlockHash, chan::Sender<Option<BlockFilter>>),
+    /// Get block headers in the given height range. Heights above the tip are skipped.
+    GetBlockByHeightRange(
+        RangeInclusive<Height>,
+        chan::Sender<Vec<(Height, BlockHeader)>>,
+    ),
+    /// Get known peer addresses matching a filter, along with their source and services.
+    GetAddresses(
+        AddressFilter,
+        chan::Sender<Vec<(net::SocketAddr, peer::Source, ServiceFlags)>>,
+    ),
     /// Get connected peers.
     GetPeers(ServiceFlags, chan::Sender<Vec<Peer>>),
+    /// Get connected peers matching the predicate.
+    GetPeersWhere(
+        Arc<dyn Fn(&Peer) -> bool + Send + Sync>,
+        chan::Sender<Vec<Peer>>,
+    ),
+    /// Get detailed information about a connected peer.
+    GetPeerInfo(net::SocketAddr, chan::Sender<Option<PeerInfo>>),
+    /// Get the cumulative bandwidth usage.
+    GetBandwidth(chan::Sender<Bandwidth>),
+    /// Get the number of peer connections, by direction.
+    GetConnectionCounts(chan::Sender<ConnectionCounts>),
+    /// Get our protocol version and the versions negotiated with connected peers.
+    GetVersionInfo(chan::Sender<VersionInfo>),
     /// Get the tip of the active chain.
     GetTip(chan::Sender<(Height, BlockHeader)>),
+    /// Get the synchronization status of the node.
+    GetSyncStatus(chan::Sender<SyncStatus>),
+    /// Get the tips of the block header and filter header chains.
+    GetTips(chan::Sender<Tips>),
+    /// Get a summary of the node's health.
+    GetHealth(chan::Sender<Health>),
+    /// Get the network-adjusted time, in seconds since Epoch.
+    GetNetworkTime(chan::Sender<BlockTime>),
     /// Get a block from the active chain.
     GetBlock(BlockHash),
-    /// Get block filters.
+    /// Cancel a block request made with [`Command::GetBlock`], if the block wasn't
+    /// received yet.
+    CancelBlock(BlockHash),
+    /// Get block filters. Replies with an identifier for the request, which can be used
+    /// to cancel it.
     GetFilters(
         RangeInclusive<Height>,
-        chan::Sender<Result<(), GetFiltersError>>,
+        chan::Sender<Result<RequestId, GetFiltersError>>,
     ),
+    /// Cancel a block filter request.
+    CancelFilterRequest(RequestId),
     /// Rescan the chain for matching scripts and addresses.
     Rescan {
         /// Start scan from this height. If unbounded, start at the current height.
@@ -251,14 +456,49 @@ pub enum Command {
         to: Bound<Height>,
         /// Scripts to match on.
         watch: Vec<Script>,
+        /// Reply channel. Fails if the rescan would start below the pruned filter height.
+        reply: chan::Sender<Result<(), GetFiltersError>>,
     },
+    /// Abort the active rescan, if any.
+    AbortRescan,
+    /// Pause filter and block syncing, keeping peer connections alive.
+    PauseSync,
+    /// Resume filter and block syncing.
+    ResumeSync,
     /// Update the watchlist with the provided scripts.
     Watch {
         /// Scripts to watch.
         watch: Vec<Script>,
     },
+    /// Watch a single script, optionally rescanning from the given height.
+    WatchAddress {
+        /// Script to watch.
+        script: Script,
+        /// Rescan blocks from this height.
+        rescan_from: Option<Height>,
+        /// Reply channel. Fails if the rescan would start below the pruned filter height.
+        reply: chan::Sender<Result<(), GetFiltersError>>,
+    },
+    /// Stop watching a script. Replies with whether the script was being watched.
+    RemoveWatch {
+        /// Script to stop watching.
+        script: Script,
+        /// Reply channel.
+        reply: chan::Sender<bool>,
+    },
+    /// Prune filter headers below the given height.
+    PruneFilters {
+        /// Prune filter headers below this height.
+        before: Height,
+        /// Reply channel.
+        reply: chan::Sender<Result<(), filter::Error>>,
+    },
     /// Broadcast to peers matching the predicate.
-    Broadcast(NetworkMessage, fn(Peer) -> bool, chan::Sender<Vec<PeerId>>),
+    Broadcast(
+        NetworkMessage,
+        Arc<dyn Fn(&Peer) -> bool + Send + Sync>,
+        chan::Sender<Vec<PeerId>>,
+    ),
     /// Send a message to a random peer.
     Query(NetworkMessage, chan::Sender<Option<net::SocketAddr>>),
     /// Query the block tree.
@@ -267,42 +507,100 @@ pub enum Command {
     Connect(net::SocketAddr),
     /// Disconnect from a peer.
     Disconnect(net::SocketAddr),
+    /// Ping a connected peer. Replies with the nonce of the `ping` to expect a `pong` for,
+    /// which is reported via [`PingEvent::PongReceived`].
+    Ping(net::SocketAddr, chan::Sender<Result<u64, CommandError>>),
     /// Import headers directly into the block store.
     ImportHeaders(
         Vec<BlockHeader>,
-        chan::Sender<Result<ImportResult, tree::Error>>,
+        chan::Sender<Result<ImportSummary, tree::Error>>,
     ),
     /// Import addresses into the address book.
     ImportAddresses(Vec<Address>),
+    /// Clear the address book and re-seed it with the given addresses, eg. resolved from
+    /// DNS seeds. Replies with the number of addresses in the address book after seeding.
+    ResetPeers(Vec<net::SocketAddr>, chan::Sender<io::Result<usize>>),
     /// Submit a transaction to the network.
     SubmitTransaction(
         Transaction,
         chan::Sender<Result<NonEmpty<PeerId>, CommandError>>,
     ),
+    /// Submit a batch of transactions to the network. Replies with a result for each
+    /// transaction, in the order they were given.
+    SubmitTransactions(
+        Vec<Transaction>,
+        chan::Sender<Vec<Result<NonEmpty<PeerId>, CommandError>>>,
+    ),
+    /// Get the unconfirmed transactions we submitted, along with the first peer
+    /// to have acknowledged each of them, if any.
+    GetMempool(chan::Sender<Vec<(Txid, Option<PeerId>)>>),
+    /// Estimate the fee rate required for a transaction to confirm within the given
+    /// number of blocks.
+    EstimateFee(u16, chan::Sender<Option<FeeRate>>),
+    /// Flush the block header store, filter header store and peer store to disk.
+    Flush(chan::Sender<Result<(), FlushError>>),
 }
 
 impl fmt::Debug for Command {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::GetBlockByHeight(height, _) => write!(f, "GetBlockByHeight({})", height),
+            Self::GetBlockByHeightRange(range, _) => {
+                write!(f, "GetBlockByHeightRange({:?})", range)
+            }
+            Self::GetFilterHeader(height, _) => write!(f, "GetFilterHeader({})", height),
+            Self::GetFilterCheckpoints(_) => write!(f, "GetFilterCheckpoints"),
+            Self::GetCachedFilter(hash, _) => write!(f, "GetCachedFilter({})", hash),
+            Self::GetAddresses(filter, _) => write!(f, "GetAddresses({:?})", filter),
             Self::GetPeers(flags, _) => write!(f, "GetPeers({})", flags),
+            Self::GetPeersWhere(_, _) => write!(f, "GetPeersWhere"),
+            Self::GetPeerInfo(addr, _) => write!(f, "GetPeerInfo({})", addr),
+            Self::GetBandwidth(_) => write!(f, "GetBandwidth"),
+            Self::GetConnectionCounts(_) => write!(f, "GetConnectionCounts"),
+            Self::GetVersionInfo(_) => write!(f, "GetVersionInfo"),
             Self::GetTip(_) => write!(f, "GetTip"),
+            Self::GetSyncStatus(_) => write!(f, "GetSyncStatus"),
+            Self::GetTips(_) => write!(f, "GetTips"),
+            Self::GetHealth(_) => write!(f, "GetHealth"),
+            Self::GetNetworkTime(_) => write!(f, "GetNetworkTime"),
             Self::GetBlock(hash) => write!(f, "GetBlock({})", hash),
+            Self::CancelBlock(hash) => write!(f, "CancelBlock({})", hash),
             Self::GetFilters(range, _) => write!(f, "GetFilters({:?})", range),
-            Self::Rescan { from, to, watch } => {
+            Self::CancelFilterRequest(id) => write!(f, "CancelFilterRequest({})", id),
+            Self::Rescan {
+                from, to, watch, ..
+            } => {
                 write!(f, "Rescan({:?}, {:?}, {:?})", from, to, watch)
             }
+            Self::AbortRescan => write!(f, "AbortRescan"),
+            Self::PauseSync => write!(f, "PauseSync"),
+            Self::ResumeSync => write!(f, "ResumeSync"),
             Self::Watch { watch } => {
                 write!(f, "Watch({:?})", watch)
             }
+            Self::WatchAddress {
+                script,
+                rescan_from,
+                ..
+            } => {
+                write!(f, "WatchAddress({:?}, {:?})", script, rescan_from)
+            }
+            Self::RemoveWatch { script, .. } => write!(f, "RemoveWatch({:?})", script),
+            Self::PruneFilters { before, .. } => write!(f, "PruneFilters({})", before),
             Self::Broadcast(msg, _, _) => write!(f, "Broadcast({})", msg.cmd()),
             Self::Query(msg, _) => write!(f, "Query({})", msg.cmd()),
             Self::QueryTree(_) => write!(f, "QueryTree"),
             Self::Connect(addr) => write!(f, "Connect({})", addr),
             Self::Disconnect(addr) => write!(f, "Disconnect({})", addr),
+            Self::Ping(addr, _) => write!(f, "Ping({})", addr),
             Self::ImportHeaders(_headers, _) => write!(f, "ImportHeaders(..)"),
             Self::ImportAddresses(addrs) => write!(f, "ImportAddresses({:?})", addrs),
+            Self::ResetPeers(addrs, _) => write!(f, "ResetPeers({:?})", addrs),
             Self::SubmitTransaction(tx, _) => write!(f, "SubmitTransaction({:?})", tx),
+            Self::SubmitTransactions(txs, _) => write!(f, "SubmitTransactions({:?})", txs),
+            Self::GetMempool(_) => write!(f, "GetMempool"),
+            Self::EstimateFee(target, _) => write!(f, "EstimateFee({})", target),
+            Self::Flush(_) => write!(f, "Flush"),
         }
     }
 }
@@ -313,9 +611,32 @@ pub enum CommandError {
     /// Not connected to any peer with the required services.
     #[error("not connected to any peer with the required services")]
     NotConnected,
+    /// Not connected to the given peer.
+    #[error("not connected to peer {0}")]
+    PeerNotConnected(PeerId),
 }
 
-pub use cbfmgr::GetFiltersError;
+/// An error resulting from flushing the stores to disk.
+#[derive(Error, Debug)]
+pub enum FlushError {
+    /// The block header store could not be flushed.
+    #[error("error flushing block headers: {0}")]
+    Headers(#[from] tree::Error),
+    /// The filter header store could not be flushed.
+    #[error("error flushing filter headers: {0}")]
+    Filters(#[from] filter::Error),
+    /// The peer store could not be flushed.
+    #[error("error flushing peers: {0}")]
+    Peers(#[from] io::Error),
+}
+
+pub use cbfmgr::{GetFiltersError, RequestId, FILTER_CHECKPOINT_INTERVAL};
+pub use invmgr::{
+    DEFAULT_BLOCK_DOWNLOAD_CONCURRENCY, DEFAULT_BLOCK_REQUEST_RETRIES, DEFAULT_DROP_WINDOW,
+    TRANSACTION_PRUNE_DEPTH,
+};
+pub use peermgr::{CONNECTION_TIMEOUT, HANDSHAKE_TIMEOUT};
+pub use syncmgr::DEFAULT_STALE_TIP_TIMEOUT;
 
 /// Holds functions that are used to hook into or alter protocol behavior.
 #[derive(Clone)]
@@ -331,6 +652,15 @@ pub struct Hooks {
     pub on_getcfilters: Arc<dyn Fn(PeerId, GetCFilters, &Outbox) + Send + Sync>,
     /// Called when a `getdata` message is received.
     pub on_getdata: Arc<dyn Fn(PeerId, Vec<Inventory>, &Outbox) + Send + Sync>,
+    /// Called when choosing the next outbound peer to connect to, with the eligible addresses.
+    /// If not set, or if no address is selected, a random address is chosen.
+    pub select_peer:
+        Option<Arc<dyn Fn(&[peer::KnownAddress]) -> Option<net::SocketAddr> + Send + Sync>>,
+    /// Called with each block matching the watched scripts, before it is reported as matched.
+    /// Useful for matching outputs that can't be expressed as scripts, eg. with descriptors.
+    ///
+    /// The hook runs on the client's event mapper thread, and must not block.
+    pub on_block_matched: Option<Arc<dyn Fn(&Block, Height) + Send + Sync>>,
 }
 
 impl Default for Hooks {
@@ -340,6 +670,8 @@ impl Default for Hooks {
             on_version: Arc::new(|_, _| Ok(())),
             on_getcfilters: Arc::new(|_, _, _| {}),
             on_getdata: Arc::new(|_, _, _| {}),
+            select_peer: None,
+            on_block_matched: None,
         }
     }
 }
@@ -385,6 +717,12 @@ pub struct StateMachine<T, F, P, C> {
     outbox: Outbox,
     /// State machine event hooks.
     hooks: Hooks,
+    /// Whether compact block filters are synced.
+    sync_filters: bool,
+    /// Whether the last write to the block header store succeeded.
+    store_ok: bool,
+    /// Fee filter sent to peers after the handshake, if any.
+    fee_filter: Option<FeeRate>,
 }
 
 /// Configured limits.
@@ -428,9 +766,45 @@ pub struct Config {
     /// Our protocol version.
     pub protocol_version: u32,
     /// Our user agent.
-    pub user_agent: &'static str,
+    pub user_agent: Cow<'static, str>,
+    /// Whether we want peers to announce their transactions to us. This sets the `relay`
+    /// field of our `version` messages.
+    pub relay_transactions: bool,
+    /// Minimum fee rate of the transactions we want peers to announce to us. If set, a
+    /// `feefilter` message is sent to peers after the handshake. Only used when
+    /// [`Config::relay_transactions`] is enabled, since peers don't announce transactions
+    /// to us otherwise.
+    pub min_relay_feerate: Option<FeeRate>,
+    /// Whether to ask peers to announce new blocks with `headers` messages, by sending
+    /// `sendheaders` during the handshake. This saves a `getheaders` round-trip per block,
+    /// compared to `inv` announcements.
+    pub prefer_headers_announcements: bool,
     /// Ping timeout, after which remotes are disconnected.
     pub ping_timeout: LocalDuration,
+    /// How long without a new block header before the tip is reported as stale.
+    pub stale_tip_timeout: LocalDuration,
+    /// Backoff policy for reconnecting to the peers we were told to connect to.
+    pub reconnect_backoff: Backoff,
+    /// Time to wait for an outbound connection to be established, before giving up.
+    pub connect_timeout: LocalDuration,
+    /// Maximum number of block requests in flight at the same time, across all peers.
+    pub block_download_concurrency: usize,
+    /// Number of times a block request is retried with another peer before giving up.
+    pub block_request_retries: u8,
+    /// Whether to request blocks as `BIP152` compact blocks, which are reconstructed from
+    /// the mempool and, if [`Config::relay_transactions`] is enabled, the transactions
+    /// relayed by peers. Saves bandwidth when the block transactions are already known.
+    pub compact_blocks: bool,
+    /// Number of blocks after which an acknowledged transaction that hasn't been confirmed
+    /// or acknowledged again is dropped from the mempool.
+    pub drop_window: Height,
+    /// Whether to sync compact block filters. If not, only block headers are synced.
+    pub sync_filters: bool,
+    /// Height from which compact block filters are fetched and matched. Filter headers
+    /// are synced in full regardless.
+    pub filter_sync_start: Option<Height>,
+    /// Maximum rate at which compact block filters are downloaded. Unlimited if not set.
+    pub filter_download_rate: Option<ByteRate>,
     /// State machine event hooks.
     pub hooks: Hooks,
     /// Configured limits.
@@ -449,7 +823,20 @@ impl Default for Config {
             whitelist: Whitelist::default(),
             protocol_version: PROTOCOL_VERSION,
             ping_timeout: pingmgr::PING_TIMEOUT,
-            user_agent: USER_AGENT,
+            stale_tip_timeout: syncmgr::DEFAULT_STALE_TIP_TIMEOUT,
+            reconnect_backoff: Backoff::default(),
+            connect_timeout: peermgr::CONNECTION_TIMEOUT,
+            block_download_concurrency: invmgr::DEFAULT_BLOCK_DOWNLOAD_CONCURRENCY,
+            block_request_retries: invmgr::DEFAULT_BLOCK_REQUEST_RETRIES,
+            compact_blocks: false,
+            drop_window: invmgr::DEFAULT_DROP_WINDOW,
+            sync_filters: true,
+            filter_sync_start: None,
+            filter_download_rate: None,
+            user_agent: Cow::Borrowed(USER_AGENT),
+            relay_transactions: false,
+            min_relay_feerate: None,
+            prefer_headers_announcements: true,
             hooks: Hooks::default(),
             limits: Limits::default(),
         }
@@ -508,7 +895,20 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> StateMa
             whitelist,
             protocol_version,
             ping_timeout,
+            stale_tip_timeout,
+            reconnect_backoff,
+            connect_timeout,
+            block_download_concurrency,
+            block_request_retries,
+            compact_blocks,
+            drop_window,
+            sync_filters,
+            filter_sync_start,
+            filter_download_rate,
             user_agent,
+            relay_transactions,
+            min_relay_feerate,
+            prefer_headers_announcements,
             required_services,
             params,
             hooks,
@@ -521,6 +921,7 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> StateMa
             syncmgr::Config {
                 max_message_headers: syncmgr::MAX_MESSAGE_HEADERS,
                 request_timeout: syncmgr::REQUEST_TIMEOUT,
+                stale_tip_timeout,
                 params,
             },
             rng.clone(),
@@ -531,6 +932,8 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> StateMa
         let cbfmgr = FilterManager::new(
             cbfmgr::Config {
                 filter_cache_size: limits.filter_cache_size,
+                filter_sync_start,
+                download_rate: filter_download_rate,
                 ..cbfmgr::Config::default()
             },
             rng.clone(),
@@ -546,12 +949,19 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> StateMa
                 domains: domains.clone(),
                 target_outbound_peers: limits.max_outbound_peers,
                 max_inbound_peers: limits.max_inbound_peers,
-                retry_max_wait: LocalDuration::from_mins(60),
-                retry_min_wait: LocalDuration::from_secs(1),
+                retry_backoff: reconnect_backoff,
+                connect_timeout,
                 required_services,
-                preferred_services: syncmgr::REQUIRED_SERVICES | cbfmgr::REQUIRED_SERVICES,
+                preferred_services: if sync_filters {
+                    syncmgr::REQUIRED_SERVICES | cbfmgr::REQUIRED_SERVICES
+                } else {
+                    syncmgr::REQUIRED_SERVICES
+                },
                 services,
                 user_agent,
+                relay: relay_transactions,
+                send_headers: prefer_headers_announcements,
+                compact_blocks,
             },
             rng.clone(),
             hooks.clone(),
@@ -568,7 +978,18 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> StateMa
             outbox.clone(),
             clock.clone(),
         );
-        let invmgr = InventoryManager::new(rng.clone(), outbox.clone(), clock.clone());
+        let invmgr = InventoryManager::new(
+            invmgr::Config {
+                block_download_concurrency,
+                block_request_retries,
+                compact_blocks,
+                relay_transactions,
+                drop_window,
+            },
+            rng.clone(),
+            outbox.clone(),
+            clock.clone(),
+        );
 
         Self {
             tree,
@@ -585,9 +1006,56 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> StateMa
             rng,
             outbox,
             hooks,
+            sync_filters,
+            store_ok: true,
+            fee_filter: min_relay_feerate.filter(|_| relay_transactions),
         }
     }
 
+    /// Get the filter header height. If filters aren't synced, this is the block header
+    /// height.
+    pub fn filter_height(&self) -> Height {
+        if self.sync_filters {
+            self.cbfmgr.filters.height()
+        } else {
+            self.tree.height()
+        }
+    }
+
+    /// Get detailed information about a negotiated peer.
+    ///
+    /// Byte counters and message times aren't tracked by the state machine, and are
+    /// left empty. It's up to the caller to fill them in.
+    pub fn peer_info(&self, addr: &PeerId) -> Option<PeerInfo> {
+        self.peermgr
+            .peers()
+            .find(|(p, c)| c.socket.addr == *addr && p.is_negotiated())
+            .map(|(p, c)| PeerInfo {
+                peer: Peer::from((p, c)),
+                version: p.version,
+                latency: self.pingmgr.latency(addr),
+                bytes_sent: 0,
+                bytes_received: 0,
+                last_message: None,
+            })
+    }
+
+    /// Submit a transaction to the network. Returns the peers it was announced to.
+    fn submit_transaction(&mut self, tx: Transaction) -> Result<NonEmpty<PeerId>, CommandError> {
+        // Update local watchlist to track submitted transactions.
+        //
+        // Nb. This is currently non-optimal, as the cfilter matching is based on the
+        // output scripts. This may trigger false-positives, since the same
+        // invoice (address) can be re-used by multiple transactions, ie. outputs
+        // can figure in more than one block.
+        self.cbfmgr.watch_transaction(&tx);
+
+        // TODO: For BIP 339 support, we can send a `WTx` inventory here.
+        let peers = self.invmgr.announce(tx);
+
+        NonEmpty::from_vec(peers).ok_or(CommandError::NotConnected)
+    }
+
     /// Disconnect a peer.
     pub fn disconnect(&mut self, addr: PeerId, reason: DisconnectReason) {
         // TODO: Trigger disconnection everywhere, as if peer disconnected. This
@@ -666,6 +1134,35 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> StateMa
 
                 reply.send(header).ok();
             }
+            Command::GetFilterHeader(height, reply) => {
+                reply.send(self.cbfmgr.filters.get_header(height)).ok();
+            }
+            Command::GetFilterCheckpoints(reply) => {
+                reply.send(self.cbfmgr.checkpoints()).ok();
+            }
+            Command::GetCachedFilter(hash, reply) => {
+                let filter = self
+                    .tree
+                    .get_block(&hash)
+                    .and_then(|(height, _)| self.cbfmgr.cached_filter(height));
+
+                reply.send(filter).ok();
+            }
+            Command::GetBlockByHeightRange(range, reply) => {
+                let (start, end) = (*range.start(), *range.end());
+                let headers = (start..=end.min(self.tree.height()))
+                    .filter_map(|height| {
+                        self.tree
+                            .get_block_by_height(height)
+                            .map(|h| (height, h.to_owned()))
+                    })
+                    .collect();
+
+                reply.send(headers).ok();
+            }
+            Command::GetAddresses(filter, reply) => {
+                reply.send(self.addrmgr.known_addresses(&filter)).ok();
+            }
             Command::GetPeers(services, reply) => {
                 let peers = self
                     .peermgr
@@ -677,6 +1174,60 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> StateMa
 
                 reply.send(peers).ok();
             }
+            Command::GetPeersWhere(predicate, reply) => {
+                let peers = self
+                    .peermgr
+                    .peers()
+                    .filter(|(p, _)| p.is_negotiated())
+                    .map(Peer::from)
+                    .filter(|p| predicate(p))
+                    .collect::<Vec<Peer>>();
+
+                reply.send(peers).ok();
+            }
+            Command::GetPeerInfo(addr, reply) => {
+                reply.send(self.peer_info(&addr)).ok();
+            }
+            Command::GetBandwidth(reply) => {
+                // Traffic isn't visible at this layer; it is accounted for by the service
+                // that encodes and decodes messages.
+                reply.send(Bandwidth::default()).ok();
+            }
+            Command::GetConnectionCounts(reply) => {
+                let (inbound, outbound) =
+                    self.peermgr
+                        .connected()
+                        .fold((0, 0), |(inbound, outbound), conn| match conn.link {
+                            Link::Inbound => (inbound + 1, outbound),
+                            Link::Outbound => (inbound, outbound + 1),
+                        });
+
+                reply
+                    .send(ConnectionCounts {
+                        inbound,
+                        outbound,
+                        connecting: self.peermgr.connecting().count(),
+                        max_inbound: self.peermgr.config.max_inbound_peers,
+                        target_outbound: self.peermgr.config.target_outbound_peers,
+                    })
+                    .ok();
+            }
+            Command::GetVersionInfo(reply) => {
+                let peers = self
+                    .peermgr
+                    .peers()
+                    .filter(|(p, _)| p.is_negotiated())
+                    .map(|(p, c)| (c.socket.addr, p.version))
+                    .collect();
+
+                reply
+                    .send(VersionInfo {
+                        protocol_version: self.peermgr.config.protocol_version,
+                        user_agent: self.peermgr.config.user_agent.to_string(),
+                        peers,
+                    })
+                    .ok();
+            }
             Command::Connect(addr) => {
                 self.peermgr.whitelist(addr);
                 self.peermgr.connect(&addr);
@@ -684,21 +1235,27 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> StateMa
             Command::Disconnect(addr) => {
                 self.disconnect(addr, DisconnectReason::Command);
             }
+            Command::Ping(addr, reply) => {
+                let result = self
+                    .pingmgr
+                    .ping(&addr)
+                    .ok_or(CommandError::PeerNotConnected(addr));
+
+                reply.send(result).ok();
+            }
             Command::Query(msg, reply) => {
                 reply.send(self.query(msg, |_| true)).ok();
             }
             Command::Broadcast(msg, predicate, reply) => {
-                let peers = self.broadcast(msg, |p| predicate(p.clone()));
+                let peers = self.broadcast(msg, |p| predicate(p));
                 reply.send(peers).ok();
             }
             Command::ImportHeaders(headers, reply) => {
-                let result = self
-                    .syncmgr
-                    .import_blocks(headers.into_iter(), &mut self.tree);
+                let result = self.syncmgr.import_headers(headers, &mut self.tree);
 
                 match result {
-                    Ok(import_result) => {
-                        reply.send(Ok(import_result)).ok();
+                    Ok(summary) => {
+                        reply.send(Ok(summary)).ok();
                     }
                     Err(err) => {
                         reply.send(Err(err)).ok();
@@ -712,46 +1269,172 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> StateMa
                     peer::Source::Imported,
                 );
             }
+            Command::ResetPeers(addrs, reply) => {
+                reply.send(self.addrmgr.reset(addrs.into_iter())).ok();
+            }
             Command::GetTip(reply) => {
                 let (_, header) = self.tree.tip();
                 let height = self.tree.height();
 
                 reply.send((height, header)).ok();
             }
+            Command::GetSyncStatus(reply) => {
+                reply
+                    .send(SyncStatus {
+                        in_ibd: self.syncmgr.is_ibd(&self.tree),
+                        headers_height: self.tree.height(),
+                        filters_height: self.filter_height(),
+                        filters_processed: self
+                            .cbfmgr
+                            .rescan
+                            .processed()
+                            .map(|r| (*r.start(), *r.end())),
+                        best_peer_height: self.syncmgr.best_height(),
+                    })
+                    .ok();
+            }
+            Command::GetHealth(reply) => {
+                let (_, tip) = self.tree.tip();
+                let now = LocalTime::from_block_time(self.clock.block_time());
+                let peers = self
+                    .peermgr
+                    .peers()
+                    .filter(|(p, _)| p.is_negotiated())
+                    .count();
+
+                reply
+                    .send(Health {
+                        peers,
+                        in_ibd: self.syncmgr.is_ibd(&self.tree),
+                        last_block_age: now - LocalTime::from_block_time(tip.time),
+                        store_ok: self.store_ok,
+                    })
+                    .ok();
+            }
+            Command::GetNetworkTime(reply) => {
+                reply.send(self.clock.block_time()).ok();
+            }
+            Command::GetTips(reply) => {
+                let (hash, _) = self.tree.tip();
+                let height = self.tree.height();
+                // Filter headers are never ahead of block headers on the active chain.
+                let filter_height = self.filter_height().min(height);
+                let filter_hash = self
+                    .tree
+                    .get_block_by_height(filter_height)
+                    .map(|h| h.block_hash())
+                    .unwrap_or(hash);
+
+                reply
+                    .send(Tips {
+                        header: (height, hash),
+                        filter: (filter_height, filter_hash),
+                    })
+                    .ok();
+            }
             Command::GetFilters(range, reply) => {
-                let result = self.cbfmgr.get_cfilters(range, &self.tree);
+                let result = self.cbfmgr.request_filters(range, &self.tree);
                 reply.send(result).ok();
             }
+            Command::CancelFilterRequest(id) => {
+                self.cbfmgr.cancel_request(&id);
+            }
             Command::GetBlock(hash) => {
+                // Blocks requested by the user aren't cancelled when a rescan is aborted.
+                self.cbfmgr.retain_rescan_blocks(|h| h != &hash);
                 self.invmgr.get_block(hash);
             }
+            Command::CancelBlock(hash) => {
+                self.invmgr.cancel_block(&hash);
+            }
             Command::SubmitTransaction(tx, reply) => {
-                // Update local watchlist to track submitted transactions.
-                //
-                // Nb. This is currently non-optimal, as the cfilter matching is based on the
-                // output scripts. This may trigger false-positives, since the same
-                // invoice (address) can be re-used by multiple transactions, ie. outputs
-                // can figure in more than one block.
-                self.cbfmgr.watch_transaction(&tx);
-
-                // TODO: For BIP 339 support, we can send a `WTx` inventory here.
-                let peers = self.invmgr.announce(tx);
-
-                if let Some(peers) = NonEmpty::from_vec(peers) {
-                    reply.send(Ok(peers)).ok();
-                } else {
-                    reply.send(Err(CommandError::NotConnected)).ok();
-                }
+                reply.send(self.submit_transaction(tx)).ok();
             }
-            Command::Rescan { from, to, watch } => {
+            Command::SubmitTransactions(txs, reply) => {
+                let results = txs
+                    .into_iter()
+                    .map(|tx| self.submit_transaction(tx))
+                    .collect();
+
+                reply.send(results).ok();
+            }
+            Command::GetMempool(reply) => {
+                reply.send(self.invmgr.unconfirmed()).ok();
+            }
+            Command::EstimateFee(target, reply) => {
+                reply.send(self.invmgr.estimate_fee(target)).ok();
+            }
+            Command::Flush(reply) => {
+                let result = self
+                    .tree
+                    .flush()
+                    .map_err(FlushError::from)
+                    .and_then(|()| self.cbfmgr.filters.flush().map_err(FlushError::from))
+                    .and_then(|()| self.addrmgr.flush().map_err(FlushError::from));
+
+                self.store_ok = result.is_ok();
+                reply.send(result).ok();
+            }
+            Command::Rescan {
+                from,
+                to,
+                watch,
+                reply,
+            } => {
                 // A rescan with a new watch list may return matches on cached filters.
-                for (_, hash) in self.cbfmgr.rescan(from, to, watch, &self.tree) {
-                    self.invmgr.get_block(hash);
+                let result = self
+                    .cbfmgr
+                    .rescan(from, to, watch, &self.tree)
+                    .map(|matches| {
+                        for (_, hash) in matches {
+                            self.invmgr.get_block(hash);
+                        }
+                    });
+                reply.send(result).ok();
+            }
+            Command::AbortRescan => {
+                for hash in self.cbfmgr.abort_rescan() {
+                    self.invmgr.cancel_block(&hash);
                 }
             }
+            Command::PauseSync => {
+                if self.sync_filters {
+                    self.cbfmgr.pause();
+                }
+                self.invmgr.pause();
+                self.outbox.event(Event::SyncPaused);
+            }
+            Command::ResumeSync => {
+                if self.sync_filters {
+                    self.cbfmgr.resume(&self.tree);
+                }
+                self.invmgr.resume();
+                self.outbox.event(Event::SyncResumed);
+            }
             Command::Watch { watch } => {
                 self.cbfmgr.watch(watch);
             }
+            Command::WatchAddress {
+                script,
+                rescan_from,
+                reply,
+            } => {
+                let result = self
+                    .cbfmgr
+                    .watch_address(script, rescan_from, &self.tree)
+                    .map(|matches| {
+                        for (_, hash) in matches {
+                            self.invmgr.get_block(hash);
+                        }
+                    });
+                reply.send(result).ok();
+            }
+            Command::RemoveWatch { script, reply } => {
+                reply.send(self.cbfmgr.unwatch(&script)).ok();
+            }
+            Command::PruneFilters { before, reply } => {
+                reply.send(self.cbfmgr.prune(before)).ok();
+            }
         }
     }
 }
@@ -768,11 +1451,14 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
         self.outbox.event(Event::Initializing);
         self.addrmgr.initialize();
         self.syncmgr.initialize(&self.tree);
+        self.invmgr.initialize(&self.tree);
         self.peermgr.initialize(&mut self.addrmgr);
-        self.cbfmgr.initialize(&self.tree);
+        if self.sync_filters {
+            self.cbfmgr.initialize(&self.tree);
+        }
         self.outbox.event(Event::Ready {
             height: self.tree.height(),
-            filter_height: self.cbfmgr.filters.height(),
+            filter_height: self.filter_height(),
             time,
         });
     }
@@ -816,19 +1502,23 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
                     self.addrmgr
                         .peer_negotiated(&addr, peer.services, conn.link);
                     self.pingmgr.peer_negotiated(conn.socket.addr);
-                    self.cbfmgr.peer_negotiated(
-                        conn.socket.clone(),
-                        peer.height,
-                        peer.services,
-                        conn.link,
-                        peer.persistent,
-                        &self.tree,
-                    );
+                    if self.sync_filters {
+                        self.cbfmgr.peer_negotiated(
+                            conn.socket.clone(),
+                            peer.height,
+                            peer.services,
+                            conn.link,
+                            peer.persistent,
+                            &self.tree,
+                        );
+                    }
                     self.syncmgr.peer_negotiated(
                         conn.socket.clone(),
                         peer.height,
                         peer.services,
-                        !peer.services.has(cbfmgr::REQUIRED_SERVICES),
+                        // When syncing filters, prefer syncing headers from peers that can't
+                        // serve filters, to keep filter peers available for filter sync.
+                        !self.sync_filters || !peer.services.has(cbfmgr::REQUIRED_SERVICES),
                         conn.link,
                         &self.tree,
                     );
@@ -838,6 +1528,13 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
                         peer.relay,
                         peer.wtxidrelay,
                     );
+                    // Ask the peer not to announce transactions below our fee rate (BIP 133).
+                    if let Some(rate) = self.fee_filter {
+                        self.outbox.message(
+                            addr,
+                            NetworkMessage::FeeFilter(rate.as_sat_per_kvb() as i64),
+                        );
+                    }
                 }
             }
             NetworkMessage::Ping(nonce) => {
@@ -851,10 +1548,12 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
                 }
             }
             NetworkMessage::Headers(headers) => {
-                match self
-                    .syncmgr
-                    .received_headers(&addr, headers, &self.clock, &mut self.tree)
-                {
+                let result =
+                    self.syncmgr
+                        .received_headers(&addr, headers, &self.clock, &mut self.tree);
+                self.store_ok = result.is_ok();
+
+                match result {
                     Err(e) => log::error!("Error receiving headers: {}", e),
                     Ok(ImportResult::TipChanged(_, _, _, reverted, _)) => {
                         // Nb. the reverted blocks are ordered from the tip down to
@@ -893,8 +1592,27 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
                 for confirmed in self.invmgr.received_block(&addr, block, &self.tree) {
                     self.cbfmgr.unwatch_transaction(&confirmed);
                 }
+                self.cbfmgr
+                    .retain_rescan_blocks(|h| self.invmgr.is_requested(h));
+            }
+            NetworkMessage::Tx(tx) => {
+                self.invmgr.received_tx(&addr, tx);
+            }
+            NetworkMessage::SendCmpct(msg) => {
+                self.invmgr.received_sendcmpct(&addr, &msg);
+            }
+            NetworkMessage::CmpctBlock(msg) => {
+                for confirmed in
+                    self.invmgr
+                        .received_cmpctblock(&addr, msg.compact_block, &self.tree)
+                {
+                    self.cbfmgr.unwatch_transaction(&confirmed);
+                }
+                self.cbfmgr
+                    .retain_rescan_blocks(|h| self.invmgr.is_requested(h));
             }
             NetworkMessage::Inv(inventory) => {
+                self.invmgr.received_inv(addr, &inventory);
                 self.syncmgr.received_inv(addr, inventory, &self.tree);
                 // TODO: invmgr: Update block availability for this peer.
             }
@@ -978,7 +1696,7 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
         addr: &net::SocketAddr,
         reason: nakamoto_net::DisconnectReason<DisconnectReason>,
     ) {
-        self.cbfmgr.peer_disconnected(addr);
+        self.cbfmgr.peer_disconnected(addr, &self.tree);
         self.syncmgr.peer_disconnected(addr);
         self.addrmgr.peer_disconnected(addr, reason.clone());
         self.pingmgr.peer_disconnected(addr);
@@ -996,12 +1714,19 @@ impl<T: BlockTree, F: Filters, P: peer::Store, C: AdjustedClock<PeerId>> traits:
     fn wake(&mut self) {
         trace!("Received wake");
 
-        self.invmgr.received_wake(&self.tree);
+        for confirmed in self.invmgr.received_wake(&self.tree) {
+            self.cbfmgr.unwatch_transaction(&confirmed);
+        }
+        // Blocks that failed to download are no longer requested.
+        self.cbfmgr
+            .retain_rescan_blocks(|h| self.invmgr.is_requested(h));
         self.syncmgr.received_wake(&self.tree);
         self.pingmgr.received_wake();
         self.addrmgr.received_wake();
         self.peermgr.received_wake(&mut self.addrmgr);
-        self.cbfmgr.received_wake(&self.tree);
+        if self.sync_filters {
+            self.cbfmgr.received_wake(&self.tree);
+        }
 
         #[cfg(not(test))]
         let local_time = self.clock.local_time();
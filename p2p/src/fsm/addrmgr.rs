@@ -2,7 +2,7 @@
 //! The peer-to-peer address manager.
 //!
 #![warn(missing_docs)]
-use std::net;
+use std::{io, net};
 
 use nakamoto_common::bitcoin::network::address::Address;
 use nakamoto_common::bitcoin::network::constants::ServiceFlags;
@@ -29,9 +29,59 @@ pub const SAMPLE_TIMEOUT: LocalDuration = LocalDuration::from_mins(3);
 
 /// Maximum number of addresses expected in a `addr` message.
 const MAX_ADDR_ADDRESSES: usize = 1000;
+
 /// Maximum number of addresses we store for a given address range.
 const MAX_RANGE_SIZE: usize = 256;
 
+/// Default maximum number of addresses returned by [`AddressManager::known_addresses`].
+pub const DEFAULT_ADDRESS_LIMIT: usize = 1000;
+
+/// Restricts the known addresses returned by [`AddressManager::known_addresses`].
+#[derive(Debug, Clone)]
+pub struct AddressFilter {
+    /// Only return addresses offering all of these services.
+    pub services: ServiceFlags,
+    /// Only return addresses from this source.
+    pub source: Option<Source>,
+    /// Maximum number of addresses to return.
+    pub limit: usize,
+}
+
+impl Default for AddressFilter {
+    fn default() -> Self {
+        Self {
+            services: ServiceFlags::NONE,
+            source: None,
+            limit: DEFAULT_ADDRESS_LIMIT,
+        }
+    }
+}
+
+impl AddressFilter {
+    /// Only return addresses offering all of the given services.
+    pub fn with_services(mut self, services: ServiceFlags) -> Self {
+        self.services = services;
+        self
+    }
+
+    /// Only return addresses from the given source.
+    pub fn with_source(mut self, source: Source) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    /// Return at most the given number of addresses.
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    /// Check whether a known address matches the filter.
+    pub fn matches(&self, ka: &KnownAddress) -> bool {
+        ka.addr.services.has(self.services) && self.source.map_or(true, |s| s == ka.source)
+    }
+}
+
 /// An event emitted by the address manager.
 #[derive(Debug, Clone)]
 pub enum Event {
@@ -46,6 +96,11 @@ pub enum Event {
     AddressDiscovered(Address, Source),
     /// Address book exhausted.
     AddressBookExhausted,
+    /// Address book was cleared and re-seeded.
+    AddressBookReset {
+        /// Number of addresses in the address book after seeding.
+        count: usize,
+    },
     /// An error was encountered.
     Error(String),
 }
@@ -69,6 +124,9 @@ impl std::fmt::Display for Event {
                     "Address book exhausted.. fetching new addresses from peers"
                 )
             }
+            Event::AddressBookReset { count } => {
+                write!(fmt, "Address book reset with {} seed address(es)", count)
+            }
             Event::Error(msg) => {
                 write!(fmt, "error: {}", msg)
             }
@@ -332,6 +390,62 @@ impl<P: Store, U: Wire<Event>, C: Clock> AddressManager<P, U, C> {
         self.peers.is_empty() || self.address_ranges.is_empty()
     }
 
+    /// Get the known addresses matching the given filter, along with their source and
+    /// services. Returns at most [`AddressFilter::limit`] addresses.
+    pub fn known_addresses(
+        &self,
+        filter: &AddressFilter,
+    ) -> Vec<(net::SocketAddr, Source, ServiceFlags)> {
+        self.peers
+            .iter()
+            .filter(|(_, ka)| filter.matches(ka))
+            .filter_map(|(_, ka)| {
+                let addr = ka.addr.socket_addr().ok()?;
+                Some((addr, ka.source, ka.addr.services))
+            })
+            .take(filter.limit)
+            .collect()
+    }
+
+    /// Clear the address book and re-seed it with the given addresses, resolved from
+    /// DNS seeds. Returns the number of addresses in the address book after seeding.
+    ///
+    /// Fails without clearing the address book if there are no addresses to seed it with.
+    pub fn reset(&mut self, addrs: impl Iterator<Item = net::SocketAddr>) -> io::Result<usize> {
+        let addrs = addrs
+            .filter(|addr| !self.bans.contains(&addr.ip()))
+            .collect::<Vec<_>>();
+
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no addresses to seed the address book with",
+            ));
+        }
+        self.peers.clear();
+        self.address_ranges.clear();
+
+        for addr in addrs {
+            let ip = addr.ip();
+            let ka = KnownAddress::new(Address::new(&addr, ServiceFlags::NONE), Source::Dns, None);
+
+            if self.peers.insert(ip, ka) {
+                self.populate_address_ranges(&ip);
+            }
+        }
+        self.peers.flush()?;
+
+        let count = self.peers.len();
+        self.upstream.event(Event::AddressBookReset { count });
+
+        Ok(count)
+    }
+
+    /// Flush the peer store to disk.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.peers.flush()
+    }
+
     #[cfg(test)]
     /// Clear the address manager of all peers.
     pub fn clear(&mut self) {
@@ -491,22 +605,7 @@ impl<P: Store, U: Wire<Event>, C: Clock> AddressManager<P, U, C> {
             for ip in ips.drain(..) {
                 let ka = self.peers.get_mut(ip).expect("address must exist");
 
-                // If the address domain is unsupported, skip it.
-                // Nb. this currently skips Tor addresses too.
-                if !ka
-                    .addr
-                    .socket_addr()
-                    .map_or(false, |a| domains.contains(&Domain::for_address(&a)))
-                {
-                    continue;
-                }
-
-                // If the address was already attempted unsuccessfully, skip it.
-                if ka.last_attempt.is_some() && ka.last_success.is_none() {
-                    continue;
-                }
-                // If we recently sampled this address, don't return it again.
-                if time - ka.last_sampled.unwrap_or_default() < SAMPLE_TIMEOUT {
+                if !is_sampleable(ka, domains, time) {
                     continue;
                 }
                 // If we're already connected to this address, skip it.
@@ -527,6 +626,43 @@ impl<P: Store, U: Wire<Event>, C: Clock> AddressManager<P, U, C> {
         None
     }
 
+    /// Select an address with the provided function, out of the addresses that could be
+    /// sampled with the given services. Only an address out of these is returned.
+    pub fn select(
+        &mut self,
+        services: ServiceFlags,
+        select: &dyn Fn(&[KnownAddress]) -> Option<net::SocketAddr>,
+    ) -> Option<(Address, Source)> {
+        if self.is_empty() {
+            return None;
+        }
+        let time = self
+            .last_idle
+            .expect("AddressManager::select: manager must be initialized before selecting");
+        let candidates = self
+            .address_ranges
+            .values()
+            .flatten()
+            .filter(|ip| !self.connected.contains(ip))
+            .filter_map(|ip| self.peers.get(ip))
+            .filter(|ka| ka.addr.services.has(services))
+            .filter(|ka| is_sampleable(ka, &self.cfg.domains, time))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let selected = select(&candidates)?;
+        if !candidates
+            .iter()
+            .any(|ka| ka.addr.socket_addr().ok() == Some(selected))
+        {
+            return None;
+        }
+        let ka = self.peers.get_mut(&selected.ip())?;
+        ka.last_sampled = Some(time);
+
+        Some((ka.addr.clone(), ka.source))
+    }
+
     ////////////////////////////////////////////////////////////////////////////
 
     /// Populate address ranges with an IP. This may remove an existing IP if
@@ -584,6 +720,14 @@ impl<P: Store, U: Wire<Event> + Wakeup, C: Clock> AddressSource for AddressManag
         AddressManager::sample(self, services)
     }
 
+    fn select(
+        &mut self,
+        services: ServiceFlags,
+        select: &dyn Fn(&[KnownAddress]) -> Option<net::SocketAddr>,
+    ) -> Option<(Address, Source)> {
+        AddressManager::select(self, services, select)
+    }
+
     fn record_local_address(&mut self, addr: net::SocketAddr) {
         self.local_addrs.insert(addr);
     }
@@ -593,6 +737,28 @@ impl<P: Store, U: Wire<Event> + Wakeup, C: Clock> AddressSource for AddressManag
     }
 }
 
+/// Check whether a known address can be sampled at the given time.
+fn is_sampleable(ka: &KnownAddress, domains: &[Domain], time: LocalTime) -> bool {
+    // If the address domain is unsupported, skip it.
+    // Nb. this currently skips Tor addresses too.
+    if !ka
+        .addr
+        .socket_addr()
+        .map_or(false, |a| domains.contains(&Domain::for_address(&a)))
+    {
+        return false;
+    }
+    // If the address was already attempted unsuccessfully, skip it.
+    if ka.last_attempt.is_some() && ka.last_success.is_none() {
+        return false;
+    }
+    // If we recently sampled this address, don't return it again.
+    if time - ka.last_sampled.unwrap_or_default() < SAMPLE_TIMEOUT {
+        return false;
+    }
+    true
+}
+
 /// Check whether an IP address is globally routable.
 pub fn is_routable(addr: &net::IpAddr) -> bool {
     match addr {
@@ -689,6 +855,168 @@ mod tests {
         assert!(addrmgr.sample(ServiceFlags::NONE).is_none());
     }
 
+    #[test]
+    fn test_select() {
+        let clock = RefClock::from(LocalTime::now());
+        let mut addrmgr = AddressManager::new(
+            Config::default(),
+            fastrand::Rng::new(),
+            HashMap::new(),
+            (),
+            clock.clone(),
+        );
+        addrmgr.initialize();
+
+        let addr1: net::SocketAddr = ([183, 8, 55, 2], 8333).into();
+        let addr2: net::SocketAddr = ([211, 48, 99, 4], 8333).into();
+        let unknown: net::SocketAddr = ([241, 44, 12, 5], 8333).into();
+
+        addrmgr.insert(
+            [addr1, addr2]
+                .iter()
+                .map(|a| (clock.block_time(), Address::new(a, ServiceFlags::NETWORK))),
+            Source::Dns,
+        );
+
+        // Addresses that aren't candidates are never returned.
+        assert!(addrmgr
+            .select(ServiceFlags::NONE, &|_| Some(unknown))
+            .is_none());
+        assert!(addrmgr
+            .select(ServiceFlags::COMPACT_FILTERS, &|_| Some(addr1))
+            .is_none());
+
+        let (addr, _) = addrmgr
+            .select(ServiceFlags::NETWORK, &|candidates| {
+                assert_eq!(candidates.len(), 2);
+                Some(addr2)
+            })
+            .unwrap();
+        assert_eq!(addr.socket_addr().unwrap(), addr2);
+
+        // The selected address was sampled, and is no longer a candidate.
+        addrmgr.select(ServiceFlags::NETWORK, &|candidates| {
+            assert_eq!(candidates.len(), 1);
+            None
+        });
+    }
+
+    #[test]
+    fn test_known_addresses_filter() {
+        let time = LocalTime::now();
+        let mut addrmgr = AddressManager::new(
+            Config::default(),
+            fastrand::Rng::new(),
+            HashMap::new(),
+            (),
+            time,
+        );
+        let peer: net::SocketAddr = ([99, 99, 99, 99], 8333).into();
+
+        addrmgr.initialize();
+        addrmgr.insert(
+            [
+                (
+                    time.block_time(),
+                    Address::new(&([33, 33, 33, 33], 8333).into(), ServiceFlags::NETWORK),
+                ),
+                (
+                    time.block_time(),
+                    Address::new(
+                        &([44, 44, 44, 44], 8333).into(),
+                        ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS,
+                    ),
+                ),
+            ],
+            Source::Dns,
+        );
+        addrmgr.insert(
+            [(
+                time.block_time(),
+                Address::new(
+                    &([55, 55, 55, 55], 8333).into(),
+                    ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS,
+                ),
+            )],
+            Source::Peer(peer),
+        );
+
+        assert_eq!(addrmgr.known_addresses(&AddressFilter::default()).len(), 3);
+        assert_eq!(
+            addrmgr
+                .known_addresses(&AddressFilter::default().with_limit(2))
+                .len(),
+            2
+        );
+
+        let filter = AddressFilter::default().with_services(ServiceFlags::COMPACT_FILTERS);
+        let mut addrs = addrmgr.known_addresses(&filter);
+        addrs.sort_by_key(|(addr, _, _)| *addr);
+
+        assert_eq!(
+            addrs,
+            vec![
+                (
+                    ([44, 44, 44, 44], 8333).into(),
+                    Source::Dns,
+                    ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS
+                ),
+                (
+                    ([55, 55, 55, 55], 8333).into(),
+                    Source::Peer(peer),
+                    ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS
+                ),
+            ]
+        );
+
+        let filter = filter.with_source(Source::Dns);
+        let addrs = addrmgr.known_addresses(&filter);
+
+        assert_eq!(addrs.len(), 1);
+        assert_eq!(addrs[0].0, ([44, 44, 44, 44], 8333).into());
+    }
+
+    #[test]
+    fn test_reset() {
+        let time = LocalTime::now();
+        let mut addrmgr = AddressManager::new(
+            Config::default(),
+            fastrand::Rng::new(),
+            HashMap::new(),
+            (),
+            time,
+        );
+        let seed: net::SocketAddr = ([55, 55, 55, 55], 8333).into();
+
+        addrmgr.initialize();
+        addrmgr.insert(
+            [(
+                time.block_time(),
+                Address::new(&([33, 33, 33, 33], 8333).into(), ServiceFlags::NETWORK),
+            )],
+            Source::Imported,
+        );
+        assert_eq!(addrmgr.len(), 1);
+
+        assert_eq!(addrmgr.reset([seed].into_iter()).unwrap(), 1);
+        assert_eq!(
+            addrmgr.known_addresses(&AddressFilter::default()),
+            vec![(seed, Source::Dns, ServiceFlags::NONE)]
+        );
+        assert_eq!(
+            addrmgr
+                .sample(ServiceFlags::NONE)
+                .map(|(a, _)| a.socket_addr().unwrap()),
+            Some(seed)
+        );
+
+        // The address book is left untouched when there is nothing to seed it with.
+        assert!(addrmgr
+            .reset(std::iter::empty::<net::SocketAddr>())
+            .is_err());
+        assert_eq!(addrmgr.len(), 1);
+    }
+
     #[test]
     fn test_known_addresses() {
         let time = LocalTime::now();
@@ -4,6 +4,7 @@
 //!
 mod rescan;
 
+use std::collections::{BTreeSet, VecDeque};
 use std::ops::{Bound, RangeInclusive};
 
 use thiserror::Error;
@@ -22,7 +23,7 @@ use nakamoto_common::source;
 
 use super::filter_cache::FilterCache;
 use super::output::{Disconnect, Wakeup, Wire};
-use super::{DisconnectReason, Link, PeerId, Socket};
+use super::{ByteRate, DisconnectReason, Link, PeerId, Socket};
 
 use rescan::Rescan;
 
@@ -38,12 +39,23 @@ pub const MAX_MESSAGE_CFHEADERS: usize = 2000;
 /// Maximum filters to be expected in a message.
 pub const MAX_MESSAGE_CFILTERS: usize = 1000;
 
+/// Maximum filters of a single filter request to have in flight at any given time.
+/// The remaining filters are requested as the inflight ones are received.
+pub const MAX_INFLIGHT_REQUEST_FILTERS: usize = MAX_MESSAGE_CFILTERS * 4;
+
 /// Filter cache capacity in bytes.
 pub const DEFAULT_FILTER_CACHE_SIZE: usize = 1024 * 1024; // 1 MB.
 
 /// How long to wait to receive a reply from a peer.
 pub const DEFAULT_REQUEST_TIMEOUT: LocalDuration = LocalDuration::from_secs(6);
 
+/// Expected size of a filter, in bytes, used to throttle filter requests before the
+/// filters are received.
+pub const FILTER_SIZE_ESTIMATE: usize = 20 * 1024;
+
+/// Interval between filter header checkpoints, as sent in `cfcheckpt` messages (BIP 157).
+pub const FILTER_CHECKPOINT_INTERVAL: Height = 1000;
+
 /// An error originating in the CBF manager.
 #[derive(Error, Debug)]
 pub enum Error {
@@ -90,6 +102,8 @@ pub enum Event {
         height: Height,
         /// Whether or not this filter matched something in the watchlist.
         matched: bool,
+        /// Watched scripts matched by this filter.
+        matched_scripts: Vec<Script>,
         /// Whether or not this filter was valid.
         valid: bool,
         /// Filter was cached.
@@ -134,11 +148,27 @@ pub enum Event {
         /// End height.
         end: Option<Height>,
     },
+    /// Scripts were added to the watchlist.
+    Watching {
+        /// The scripts added.
+        scripts: Vec<Script>,
+    },
+    /// A script was removed from the watchlist.
+    Unwatched {
+        /// The script removed.
+        script: Script,
+    },
     /// An active rescan has completed.
     RescanCompleted {
         /// Last height processed by rescan.
         height: Height,
     },
+    /// An active rescan was aborted.
+    RescanAborted {
+        /// Height at which the rescan was aborted. Filters from this height onward
+        /// were not processed.
+        height: Height,
+    },
     /// Finished syncing filter headers up to the specified height.
     Synced(Height),
     /// A peer has timed out responding to a filter request.
@@ -220,9 +250,18 @@ impl std::fmt::Display for Event {
             Event::RescanStarted { start, end: None } => {
                 write!(fmt, "Rescan started from height {} to ..", start)
             }
+            Event::Watching { scripts } => {
+                write!(fmt, "Watching {} new script(s)", scripts.len())
+            }
+            Event::Unwatched { script } => {
+                write!(fmt, "Script {} removed from watchlist", script)
+            }
             Event::RescanCompleted { height } => {
                 write!(fmt, "Rescan completed at height {}", height)
             }
+            Event::RescanAborted { height } => {
+                write!(fmt, "Rescan aborted at height {}", height)
+            }
             Event::RequestCanceled { reason } => {
                 write!(fmt, "Request canceled: {}", reason)
             }
@@ -252,6 +291,42 @@ pub enum GetFiltersError {
     /// Not connected to any compact filter peer.
     #[error("not connected to any peer with compact filters support")]
     NotConnected,
+    /// The filters in the specified range were pruned. Only filters at or above
+    /// the given height are available.
+    #[error("filters below height {0} were pruned")]
+    Pruned(Height),
+}
+
+/// Identifies a filter request made via [`FilterManager::request_filters`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RequestId(u64);
+
+impl From<u64> for RequestId {
+    fn from(id: u64) -> Self {
+        Self(id)
+    }
+}
+
+impl std::fmt::Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// A pending filter request.
+#[derive(Debug)]
+struct FilterRequest {
+    /// Heights requested from peers that weren't received yet.
+    inflight: BTreeSet<Height>,
+    /// Heights that remain to be requested.
+    remaining: RangeInclusive<Height>,
+}
+
+impl FilterRequest {
+    /// Check whether all filters of this request were received.
+    fn is_complete(&self) -> bool {
+        self.inflight.is_empty() && self.remaining.is_empty()
+    }
 }
 
 /// CBF manager configuration.
@@ -261,6 +336,11 @@ pub struct Config {
     pub request_timeout: LocalDuration,
     /// Filter cache size, in bytes.
     pub filter_cache_size: usize,
+    /// Height from which filters are fetched and matched. Filters of blocks below this
+    /// height are never requested, though their filter headers are still synced.
+    pub filter_sync_start: Option<Height>,
+    /// Maximum rate at which filters are downloaded, if any.
+    pub download_rate: Option<ByteRate>,
 }
 
 impl Default for Config {
@@ -268,7 +348,76 @@ impl Default for Config {
         Self {
             request_timeout: DEFAULT_REQUEST_TIMEOUT,
             filter_cache_size: DEFAULT_FILTER_CACHE_SIZE,
+            filter_sync_start: None,
+            download_rate: None,
+        }
+    }
+}
+
+/// Token bucket limiting the rate at which filters are requested.
+///
+/// Requests are paid for upfront with an estimate of the filter sizes, and the difference
+/// with the actual size is settled as filters are received. The bucket holds at most one
+/// second worth of data, and requests are queued while it is empty.
+#[derive(Debug)]
+struct Throttle {
+    /// Maximum download rate.
+    rate: ByteRate,
+    /// Bytes that can be downloaded before hitting the rate limit. Negative if we're
+    /// over the limit.
+    tokens: i64,
+    /// Last time tokens were added to the bucket.
+    last_refill: LocalTime,
+    /// Filter ranges waiting to be requested.
+    queue: VecDeque<RangeInclusive<Height>>,
+}
+
+impl Throttle {
+    fn new(rate: ByteRate, now: LocalTime) -> Self {
+        Self {
+            rate,
+            tokens: rate.bytes_per_sec() as i64,
+            last_refill: now,
+            queue: VecDeque::new(),
+        }
+    }
+
+    /// Add the tokens accumulated since the last refill.
+    fn refill(&mut self, now: LocalTime) {
+        let rate = self.rate.bytes_per_sec() as i64;
+        let tokens = (rate as u128 * (now - self.last_refill).as_millis() / 1000) as i64;
+
+        if tokens > 0 {
+            self.tokens = i64::min(self.tokens.saturating_add(tokens), rate);
+            self.last_refill = now;
+        }
+    }
+
+    /// Pop the next range to request, if there are tokens available, paying for it with
+    /// an estimate of its size.
+    fn next(&mut self) -> Option<RangeInclusive<Height>> {
+        if self.tokens <= 0 {
+            return None;
         }
+        let range = self.queue.pop_front()?;
+        let filters = range.end() - range.start() + 1;
+
+        self.tokens -= (filters * FILTER_SIZE_ESTIMATE as Height) as i64;
+
+        Some(range)
+    }
+
+    /// Settle the cost of a received filter of the given size.
+    fn received(&mut self, size: usize) {
+        self.tokens += FILTER_SIZE_ESTIMATE as i64 - size as i64;
+    }
+
+    /// Time until tokens are available again.
+    fn delay(&self) -> LocalDuration {
+        let missing = 1 - self.tokens.min(0);
+        let rate = self.rate.bytes_per_sec().max(1);
+
+        LocalDuration::from_millis((missing as u128 * 1000 / rate as u128).max(1))
     }
 }
 
@@ -303,6 +452,17 @@ pub struct FilterManager<F, U, C> {
     last_processed: Option<LocalTime>,
     /// Inflight requests.
     inflight: HashMap<BlockHash, (Height, PeerId, LocalTime)>,
+    /// Pending filter requests.
+    requests: HashMap<RequestId, FilterRequest>,
+    /// Inflight `getcfilters` requests for pending filter requests, by start height,
+    /// along with the stop height, the peer they were sent to and their expiry.
+    requests_inflight: HashMap<Height, (Height, PeerId, LocalTime)>,
+    /// Last filter request identifier issued.
+    last_request_id: u64,
+    /// Download rate limiter, if a rate is configured.
+    throttle: Option<Throttle>,
+    /// Whether syncing is paused.
+    paused: bool,
 }
 
 impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F, U, C> {
@@ -310,6 +470,9 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
     pub fn new(config: Config, rng: fastrand::Rng, filters: F, upstream: U, clock: C) -> Self {
         let peers = AddressBook::new(rng.clone());
         let rescan = Rescan::new(config.filter_cache_size);
+        let throttle = config
+            .download_rate
+            .map(|rate| Throttle::new(rate, clock.local_time()));
 
         Self {
             config,
@@ -318,9 +481,14 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
             upstream,
             clock,
             filters,
-            inflight: HashMap::with_hasher(rng.into()),
+            inflight: HashMap::with_hasher(rng.clone().into()),
+            requests: HashMap::with_hasher(rng.clone().into()),
+            requests_inflight: HashMap::with_hasher(rng.into()),
+            last_request_id: 0,
             last_idle: None,
             last_processed: None,
+            throttle,
+            paused: false,
         }
     }
 
@@ -329,7 +497,24 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
         self.idle(tree);
     }
 
-    /// A tick was received.
+    /// Pause filter syncing. Filter header and rescan requests are no longer issued until
+    /// [`FilterManager::resume`] is called. Requests already in flight are still processed,
+    /// as are explicit filter requests.
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    /// Resume filter syncing, if it was paused.
+    pub fn resume<T: BlockReader>(&mut self, tree: &T) {
+        if !self.paused {
+            return;
+        }
+        self.paused = false;
+        self.sync(tree);
+        self.send_throttled(tree).ok();
+    }
+
+    /// A tick was received. Requests in flight are retried even while paused.
     pub fn received_wake<T: BlockReader>(&mut self, tree: &T) {
         self.idle(tree);
 
@@ -362,11 +547,24 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
             }
         }
 
+        // Re-request the filters of pending filter requests from peers that timed out.
+        self.retry_requests(|_, expiry| now >= expiry, tree);
+
+        if self.paused {
+            return;
+        }
+
+        // Send the filter requests that were held back by the rate limit, if any.
+        self.send_throttled(tree).ok();
+
         // If we've waited too long since the last processed filter, re-issue requests
         // for missing filters.
         if now - self.last_processed.unwrap_or_default() >= DEFAULT_REQUEST_TIMEOUT {
             if self.rescan.active {
                 self.rescan.reset(); // Clear pending request queue.
+                if let Some(throttle) = &mut self.throttle {
+                    throttle.queue.clear();
+                }
                 self.get_cfilters(self.rescan.current..=self.filters.height(), tree)
                     .ok();
             }
@@ -428,7 +626,60 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
 
     /// Add scripts to the list of scripts to watch.
     pub fn watch(&mut self, scripts: Vec<Script>) {
-        self.rescan.watch.extend(scripts);
+        self.rescan.watch.extend(scripts.iter().cloned());
+        self.upstream.event(Event::Watching { scripts });
+    }
+
+    /// Add a script to the list of scripts to watch, and make sure new blocks are matched
+    /// against it. If a height is given, blocks are rescanned starting from that height.
+    ///
+    /// Returns matching blocks found in cached filters, if any. Fails with
+    /// [`GetFiltersError::Pruned`] if the rescan would start below the pruned height.
+    pub fn watch_address<T: BlockReader>(
+        &mut self,
+        script: Script,
+        rescan_from: Option<Height>,
+        tree: &T,
+    ) -> Result<Vec<(Height, BlockHash)>, GetFiltersError> {
+        // If we're already scanning new blocks, there's nothing to restart.
+        if rescan_from.is_none() && self.rescan.active && self.rescan.end.is_none() {
+            self.watch(vec![script]);
+            return Ok(vec![]);
+        }
+        let start = match rescan_from {
+            // Don't skip blocks that an ongoing rescan hasn't reached yet.
+            Some(height) if self.rescan.active => {
+                Bound::Included(Height::min(height, self.rescan.current))
+            }
+            Some(height) => Bound::Included(height),
+            None => Bound::Unbounded,
+        };
+        let mut watch = self.rescan.watch.iter().cloned().collect::<Vec<_>>();
+        watch.push(script);
+
+        self.rescan(start, Bound::Unbounded, watch, tree)
+    }
+
+    /// Remove a script from the list of scripts to watch. Filters processed from now on
+    /// are no longer matched against it.
+    ///
+    /// Returns whether the script was being watched.
+    pub fn unwatch(&mut self, script: &Script) -> bool {
+        if self.rescan.watch.remove(script) {
+            self.upstream.event(Event::Unwatched {
+                script: script.clone(),
+            });
+            return true;
+        }
+        false
+    }
+
+    /// Prune filter headers below the given height.
+    ///
+    /// Filters below this height can no longer be fetched, and rescans starting below it
+    /// will fail with [`GetFiltersError::Pruned`].
+    pub fn prune(&mut self, before: Height) -> Result<(), filter::Error> {
+        self.filters.prune(before)
     }
 
     /// Add transaction outputs to list of transactions to watch.
@@ -444,27 +695,67 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
         self.rescan.transactions.remove(txid).is_some()
     }
 
+    /// Abort the active rescan, if any. Pending filter requests are dropped, and filters
+    /// received for them are ignored.
+    ///
+    /// Returns the blocks matched by the rescan which are still outstanding, so that
+    /// their requests can be cancelled.
+    pub fn abort_rescan(&mut self) -> Vec<BlockHash> {
+        if !self.rescan.active {
+            return vec![];
+        }
+        let height = self.rescan.current;
+        let matched = self.rescan.abort();
+
+        self.upstream.event(Event::RescanAborted { height });
+
+        matched
+    }
+
+    /// Stop tracking the matched blocks for which the predicate doesn't hold, eg. because
+    /// they were received, or requested separately by the user. Aborting the rescan
+    /// doesn't cancel these.
+    pub fn retain_rescan_blocks(&mut self, f: impl FnMut(&BlockHash) -> bool) {
+        self.rescan.retain_blocks(f);
+    }
+
     /// Rescan compact block filters.
+    ///
+    /// Fails with [`GetFiltersError::Pruned`] if the rescan would start below the pruned
+    /// height, and with [`GetFiltersError::InvalidRange`] if a bound is out of range. In
+    /// both cases, the current rescan is left untouched.
     pub fn rescan<T: BlockReader>(
         &mut self,
         start: Bound<Height>,
         end: Bound<Height>,
         watch: Vec<Script>,
         tree: &T,
-    ) -> Vec<(Height, BlockHash)> {
-        self.rescan.restart(
-            match start {
-                Bound::Unbounded => tree.height() + 1,
-                Bound::Included(h) => h,
-                Bound::Excluded(h) => h + 1,
-            },
-            match end {
-                Bound::Unbounded => None,
-                Bound::Included(h) => Some(h),
-                Bound::Excluded(h) => Some(h - 1),
-            },
-            watch,
-        );
+    ) -> Result<Vec<(Height, BlockHash)>, GetFiltersError> {
+        let start = match start {
+            Bound::Unbounded => tree.height() + 1,
+            Bound::Included(h) => h,
+            Bound::Excluded(h) => h.checked_add(1).ok_or(GetFiltersError::InvalidRange)?,
+        };
+        let end = match end {
+            Bound::Unbounded => None,
+            Bound::Included(h) => Some(h),
+            Bound::Excluded(h) => Some(h.checked_sub(1).ok_or(GetFiltersError::InvalidRange)?),
+        };
+        // Never scan below the configured filter sync start height.
+        let start = Height::max(start, self.config.filter_sync_start.unwrap_or_default());
+
+        if end.map_or(false, |end| start > end) {
+            return Err(GetFiltersError::InvalidRange);
+        }
+        if start < self.filters.floor() {
+            return Err(GetFiltersError::Pruned(self.filters.floor()));
+        }
+        if !watch.is_empty() {
+            self.upstream.event(Event::Watching {
+                scripts: watch.clone(),
+            });
+        }
+        self.rescan.restart(start, end, watch);
 
         self.upstream.event(Event::RescanStarted {
             start: self.rescan.start,
@@ -472,7 +763,7 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
         });
 
         if self.rescan.watch.is_empty() {
-            return vec![];
+            return Ok(vec![]);
         }
 
         let height = self.filters.height();
@@ -486,7 +777,7 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
         let range = start..=stop;
 
         if range.is_empty() {
-            return vec![];
+            return Ok(vec![]);
         }
 
         // Start fetching the filters we can.
@@ -501,7 +792,7 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
         for event in events {
             self.upstream.event(event);
         }
-        matches
+        Ok(matches)
     }
 
     /// Send one or more `getcfilters` messages to random peers.
@@ -519,22 +810,178 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
         if range.is_empty() {
             return Err(GetFiltersError::InvalidRange);
         }
+        if *range.start() < self.filters.floor() {
+            return Err(GetFiltersError::Pruned(self.filters.floor()));
+        }
         assert!(*range.end() <= self.filters.height());
 
+        let ranges = self.rescan.requests(range, tree);
+
+        self.send_getcfilters(ranges, tree)
+    }
+
+    /// Get a filter from the filter cache, if present. This doesn't make any network request.
+    pub fn cached_filter(&self, height: Height) -> Option<BlockFilter> {
+        self.rescan.cache.get(&height).map(|f| f.as_ref().clone())
+    }
+
+    /// Get the filter header checkpoints, ie. the filter headers at every
+    /// [`FILTER_CHECKPOINT_INTERVAL`] blocks, as a peer would send them in a `cfcheckpt`
+    /// message. Pruned checkpoints are skipped.
+    pub fn checkpoints(&self) -> Vec<(Height, filter::FilterHeader)> {
+        (1..=self.filters.height() / FILTER_CHECKPOINT_INTERVAL)
+            .map(|i| i * FILTER_CHECKPOINT_INTERVAL)
+            .filter_map(|height| {
+                self.filters
+                    .get_header(height)
+                    .map(|(_, header)| (height, header))
+            })
+            .collect()
+    }
+
+    /// Request filters in the given range, returning an identifier for the request.
+    ///
+    /// Unlike [`FilterManager::get_cfilters`], filters are requested gradually, keeping at
+    /// most [`MAX_INFLIGHT_REQUEST_FILTERS`] in flight, so that the request can be canceled
+    /// with [`FilterManager::cancel_request`] before the whole range is fetched.
+    pub fn request_filters<T: BlockReader>(
+        &mut self,
+        range: RangeInclusive<Height>,
+        tree: &T,
+    ) -> Result<RequestId, GetFiltersError> {
+        if self.peers.is_empty() {
+            return Err(GetFiltersError::NotConnected);
+        }
+        if range.is_empty() || *range.end() > self.filters.height() {
+            return Err(GetFiltersError::InvalidRange);
+        }
+        if *range.start() < self.filters.floor() {
+            return Err(GetFiltersError::Pruned(self.filters.floor()));
+        }
+        self.last_request_id += 1;
+
+        let id = RequestId(self.last_request_id);
+
+        self.requests.insert(
+            id,
+            FilterRequest {
+                inflight: BTreeSet::new(),
+                remaining: range,
+            },
+        );
+        if let Err(err) = self.request_next(id, tree) {
+            self.requests.remove(&id);
+            return Err(err);
+        }
+        Ok(id)
+    }
+
+    /// Cancel a filter request. Filters that were already requested from peers may still
+    /// be received, but the remaining filters in the range will not be requested.
+    ///
+    /// Returns `false` if the request was not found, eg. because it already completed.
+    pub fn cancel_request(&mut self, id: &RequestId) -> bool {
+        if self.requests.remove(id).is_some() {
+            log::debug!("Filter request {} canceled", id);
+            return true;
+        }
+        false
+    }
+
+    /// Request the next filters of a filter request, if there is room for them.
+    fn request_next<T: BlockReader>(
+        &mut self,
+        id: RequestId,
+        tree: &T,
+    ) -> Result<(), GetFiltersError> {
+        let mut ranges = Vec::new();
+
+        if let Some(req) = self.requests.get_mut(&id) {
+            // Only request whole batches, to avoid requesting filters one by one as they
+            // are received.
+            while !req.remaining.is_empty()
+                && req.inflight.len() + MAX_MESSAGE_CFILTERS <= MAX_INFLIGHT_REQUEST_FILTERS
+            {
+                let (start, end) = (*req.remaining.start(), *req.remaining.end());
+                let stop = Height::min(end, start + MAX_MESSAGE_CFILTERS as Height - 1);
+
+                // Heights that are cached or already requested are not returned here, so
+                // we don't wait for them.
+                for range in self.rescan.requests(start..=stop, tree) {
+                    req.inflight.extend(range.clone());
+                    ranges.push(range);
+                }
+                req.remaining = stop + 1..=end;
+            }
+            if req.is_complete() {
+                self.requests.remove(&id);
+            }
+        }
+        self.send_getcfilters(ranges, tree)
+    }
+
+    /// Send `getcfilters` messages for the given ranges, each to a different peer.
+    /// If a download rate is configured, requests over the rate are queued.
+    fn send_getcfilters<T: BlockReader>(
+        &mut self,
+        ranges: Vec<RangeInclusive<Height>>,
+        tree: &T,
+    ) -> Result<(), GetFiltersError> {
+        if let Some(throttle) = &mut self.throttle {
+            throttle.queue.extend(ranges);
+
+            return self.send_throttled(tree);
+        }
+        self.send_ranges(ranges, tree)
+    }
+
+    /// Send the queued filter requests the rate limit allows for, and schedule a wake up
+    /// to send the rest.
+    fn send_throttled<T: BlockReader>(&mut self, tree: &T) -> Result<(), GetFiltersError> {
+        let now = self.clock.local_time();
+        let throttle = if let Some(throttle) = &mut self.throttle {
+            throttle
+        } else {
+            return Ok(());
+        };
+        throttle.refill(now);
+
+        let mut ranges = Vec::new();
+        while let Some(range) = throttle.next() {
+            ranges.push(range);
+        }
+        if !throttle.queue.is_empty() {
+            self.upstream.wakeup(throttle.delay());
+        }
+        self.send_ranges(ranges, tree)
+    }
+
+    /// Send `getcfilters` messages for the given ranges, each to a different peer.
+    fn send_ranges<T: BlockReader>(
+        &mut self,
+        ranges: Vec<RangeInclusive<Height>>,
+        tree: &T,
+    ) -> Result<(), GetFiltersError> {
         // TODO: Only ask peers synced to a certain height.
         // Choose a different peer for each requested range.
-        for (range, peer) in self
-            .rescan
-            .requests(range, tree)
-            .into_iter()
-            .zip(self.peers.cycle())
-        {
+        for (range, peer) in ranges.into_iter().zip(self.peers.cycle()) {
             let stop_hash = tree
                 .get_block_by_height(*range.end())
                 .ok_or(GetFiltersError::InvalidRange)?
                 .block_hash();
             let timeout = self.config.request_timeout;
 
+            if self
+                .requests
+                .values()
+                .any(|req| req.inflight.range(range.clone()).next().is_some())
+            {
+                self.requests_inflight.insert(
+                    *range.start(),
+                    (*range.end(), *peer, self.clock.local_time() + timeout),
+                );
+            }
+
             log::debug!(
                 "Requested filter(s) in range {} to {} from {} (stop = {})",
                 range.start(),
@@ -702,6 +1149,15 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
             });
         };
 
+        // We can't serve filter headers that were pruned.
+        let floor = self.filters.floor();
+        if floor > 0 && start_height <= floor {
+            return Err(Error::Ignored {
+                msg: "getcfheaders",
+                from,
+            });
+        }
+
         let headers = self.filters.get_headers(start_height..=stop_height);
         if !headers.is_empty() {
             let hashes = headers.iter().map(|(hash, _)| *hash);
@@ -745,6 +1201,9 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
                 from,
             });
         }
+        if let Some(throttle) = &mut self.throttle {
+            throttle.received(msg.filter.len());
+        }
 
         let height = if let Some((height, _)) = tree.get_block(&msg.block_hash) {
             height
@@ -789,6 +1248,7 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
             height,
             filter: filter.clone(),
         });
+        self.filter_request_received(height, tree);
 
         if self.rescan.received(height, filter, block_hash) {
             let (matches, events, processed) = self.rescan.process();
@@ -807,9 +1267,99 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
         Ok(Vec::default())
     }
 
+    /// Update the pending filter requests with a received filter, and request more filters
+    /// if needed.
+    fn filter_request_received<T: BlockReader>(&mut self, height: Height, tree: &T) {
+        let ids = self
+            .requests
+            .iter_mut()
+            .filter_map(|(id, req)| req.inflight.remove(&height).then(|| *id))
+            .collect::<Vec<_>>();
+
+        // Forget about the `getcfilters` requests that have nothing left to receive.
+        let requests = &self.requests;
+        self.requests_inflight.retain(|start, (stop, _, _)| {
+            requests
+                .values()
+                .any(|req| req.inflight.range(*start..=*stop).next().is_some())
+        });
+
+        for id in ids {
+            if let Err(err) = self.request_next(id, tree) {
+                log::warn!("Error requesting filters for request {}: {}", id, err);
+                self.requests.remove(&id);
+            }
+        }
+    }
+
+    /// Re-request the filters of pending filter requests that were requested from peers
+    /// matching the given predicate, eg. because they timed out or disconnected.
+    ///
+    /// Requests that can't be completed for lack of peers are removed.
+    fn retry_requests<T: BlockReader>(
+        &mut self,
+        stale: impl Fn(&PeerId, LocalTime) -> bool,
+        tree: &T,
+    ) {
+        let ranges = self
+            .requests_inflight
+            .iter()
+            .filter(|(_, (_, peer, expiry))| stale(peer, *expiry))
+            .map(|(start, (stop, _, _))| *start..=*stop)
+            .collect::<Vec<_>>();
+
+        if ranges.is_empty() {
+            return;
+        }
+        for range in &ranges {
+            self.requests_inflight.remove(range.start());
+        }
+        let pending = |req: &FilterRequest| {
+            ranges
+                .iter()
+                .any(|r| req.inflight.range(r.clone()).next().is_some())
+        };
+
+        let result = if self.peers.is_empty() {
+            Err(GetFiltersError::NotConnected)
+        } else {
+            let mut retries = Vec::new();
+            for range in &ranges {
+                let heights = self
+                    .requests
+                    .values()
+                    .flat_map(|req| req.inflight.range(range.clone()))
+                    .copied()
+                    .collect::<BTreeSet<_>>();
+
+                if let (Some(start), Some(stop)) =
+                    (heights.iter().next(), heights.iter().next_back())
+                {
+                    log::debug!("Re-requesting filter(s) in range {} to {}", start, stop);
+
+                    self.rescan.forget(heights.iter().copied());
+                    retries.extend(self.rescan.requests(*start..=*stop, tree));
+                }
+            }
+            self.send_getcfilters(retries, tree)
+        };
+
+        if let Err(err) = result {
+            self.requests.retain(|id, req| {
+                if pending(req) {
+                    log::warn!("Filter request {} failed: {}", id, err);
+                    return false;
+                }
+                true
+            });
+        }
+    }
+
     /// Called when a peer disconnected.
-    pub fn peer_disconnected(&mut self, id: &PeerId) {
+    pub fn peer_disconnected<T: BlockReader>(&mut self, id: &PeerId, tree: &T) {
         self.peers.remove(id);
+        // Re-request the filters that were requested from this peer.
+        self.retry_requests(|peer, _| peer == id, tree);
     }
 
     /// Called when a new peer was negotiated.
@@ -844,6 +1394,9 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
 
     /// Attempt to sync the filter header chain.
     pub fn sync<T: BlockReader>(&mut self, tree: &T) {
+        if self.paused {
+            return;
+        }
         let filter_height = self.filters.height();
         let block_height = tree.height();
 
@@ -978,7 +1531,7 @@ impl<F: Filters, U: Wire<Event> + Wakeup + Disconnect, C: Clock> FilterManager<F
         stop: Height,
         tree: &T,
     ) -> Result<(), GetFiltersError> {
-        if !self.rescan.active {
+        if !self.rescan.active || self.paused {
             return Ok(());
         }
 
@@ -1292,12 +1845,14 @@ mod tests {
         let (mut cbfmgr, tree, _) = util::setup(network, best, 0, RefClock::from(time));
 
         // Start rescan with no peers.
-        cbfmgr.rescan(
-            Bound::Included(0),
-            Bound::Unbounded,
-            vec![gen::script(&mut rng)],
-            &tree,
-        );
+        cbfmgr
+            .rescan(
+                Bound::Included(0),
+                Bound::Unbounded,
+                vec![gen::script(&mut rng)],
+                &tree,
+            )
+            .unwrap();
 
         cbfmgr.peer_negotiated(
             Socket::new(remote),
@@ -1387,12 +1942,14 @@ mod tests {
             .unwrap();
 
         // Start rescan.
-        cbfmgr.rescan(
-            Bound::Included(birth),
-            Bound::Unbounded,
-            vec![gen::script(&mut rng)],
-            &tree,
-        );
+        cbfmgr
+            .rescan(
+                Bound::Included(birth),
+                Bound::Unbounded,
+                vec![gen::script(&mut rng)],
+                &tree,
+            )
+            .unwrap();
 
         let expected = GetCFilters {
             filter_type,
@@ -1404,23 +1961,44 @@ mod tests {
             .expect("Rescanning should trigger filters to be fetched");
     }
 
-    /// Test that `getcfilters` request is retried.
+    /// Test that filter header checkpoints are computed at the standard interval.
     #[test]
-    fn test_rescan_getcfilters_retry() {
-        let birth = 11;
+    fn test_checkpoints() {
+        let best = FILTER_CHECKPOINT_INTERVAL * 2 + 42;
+        let time = LocalTime::now();
+        let network = Network::Regtest;
+        let (mut cbfmgr, _, _) = util::setup(network, best, 0, RefClock::from(time));
+
+        let heights = |cbfmgr: &FilterManager<_, _, _>| {
+            cbfmgr
+                .checkpoints()
+                .into_iter()
+                .map(|(height, _)| height)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(heights(&cbfmgr), vec![1000, 2000]);
+        assert_eq!(
+            cbfmgr.checkpoints()[0].1,
+            cbfmgr.filters.get_header(1000).unwrap().1
+        );
+
+        // Pruned checkpoints are skipped.
+        cbfmgr.filters.prune(1500).unwrap();
+        assert_eq!(heights(&cbfmgr), vec![2000]);
+    }
+
+    /// Test that filter requests are held back when over the download rate.
+    #[test]
+    fn test_getcfilters_throttled() {
         let best = 42;
-        let mut rng = fastrand::Rng::new();
         let time = LocalTime::now();
+        let clock = RefClock::from(time);
         let network = Network::Regtest;
-        let filter_type = 0x0;
-        let (mut cbfmgr, tree, chain) = util::setup(network, best, 0, RefClock::from(time));
+        let (mut cbfmgr, tree, _) = util::setup(network, best, 0, clock.clone());
         let remote: PeerId = ([88, 88, 88, 88], 8333).into();
-        let previous_filter_header = FilterHeader::genesis(network);
-        let cfheaders = util::cfheaders(previous_filter_header, &chain.tail);
-        let cfilters = util::cfilters(chain.iter()).collect::<Vec<_>>();
 
-        cbfmgr.filters.clear().unwrap();
-        cbfmgr.initialize(&tree);
+        // Allow for one filter per second.
+        cbfmgr.throttle = Some(Throttle::new(ByteRate(FILTER_SIZE_ESTIMATE as u64), time));
         cbfmgr.peer_negotiated(
             Socket::new(remote),
             best,
@@ -1429,24 +2007,262 @@ mod tests {
             false,
             &tree,
         );
+        output::test::messages_from(&mut cbfmgr.upstream, &remote).for_each(drop);
+
+        let requested = |cbfmgr: &mut FilterManager<_, _, _>| {
+            output::test::messages_from(&mut cbfmgr.upstream, &remote)
+                .filter_map(|m| match m {
+                    NetworkMessage::GetCFilters(msg) => Some(msg.start_height as Height),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
         cbfmgr
-            .received_cfheaders(&remote, cfheaders, &tree)
+            .send_getcfilters(vec![1..=1, 2..=2, 3..=3], &tree)
             .unwrap();
-        assert_eq!(cbfmgr.filters.height(), best);
+        assert_eq!(requested(&mut cbfmgr), vec![1]);
 
-        // Start rescan.
-        cbfmgr.rescan(
-            Bound::Included(birth),
-            Bound::Unbounded,
-            vec![gen::script(&mut rng)],
-            &tree,
-        );
-        assert!(cbfmgr.last_processed.is_none());
-        assert_eq!(cbfmgr.rescan.current, birth);
+        // Nothing is sent until enough time has passed.
+        clock.elapse(LocalDuration::from_millis(500));
+        cbfmgr.received_wake(&tree);
+        assert!(requested(&mut cbfmgr).is_empty());
 
-        output::test::messages_from(&mut cbfmgr.upstream, &remote)
-            .find(|m| matches!(m, NetworkMessage::GetCFilters(_)))
-            .expect("`getcfilters` sent");
+        clock.elapse(LocalDuration::from_millis(500));
+        cbfmgr.received_wake(&tree);
+        assert_eq!(requested(&mut cbfmgr), vec![2]);
+
+        clock.elapse(LocalDuration::from_secs(1));
+        cbfmgr.received_wake(&tree);
+        assert_eq!(requested(&mut cbfmgr), vec![3]);
+        assert!(cbfmgr.throttle.as_ref().unwrap().queue.is_empty());
+    }
+
+    /// Test that watching an address keeps the existing watch list and matches new blocks.
+    #[test]
+    fn test_watch_address() {
+        let best = 42;
+        let mut rng = fastrand::Rng::new();
+        let time = LocalTime::now();
+        let network = Network::Regtest;
+        let (mut cbfmgr, tree, _) = util::setup(network, best, 0, RefClock::from(time));
+        let (a, b, c) = (
+            gen::script(&mut rng),
+            gen::script(&mut rng),
+            gen::script(&mut rng),
+        );
+
+        cbfmgr.watch(vec![a.clone()]);
+        assert!(!cbfmgr.rescan.active);
+
+        // Without a rescan height, we only start scanning new blocks.
+        cbfmgr.watch_address(b.clone(), None, &tree).unwrap();
+        assert!(cbfmgr.rescan.active);
+        assert_eq!(cbfmgr.rescan.start, best + 1);
+        assert_eq!(cbfmgr.rescan.end, None);
+        assert!(cbfmgr.rescan.watch.contains(&a));
+        assert!(cbfmgr.rescan.watch.contains(&b));
+
+        // With a rescan height, the scan is restarted from that height.
+        cbfmgr.watch_address(c.clone(), Some(11), &tree).unwrap();
+        assert_eq!(cbfmgr.rescan.start, 11);
+        assert_eq!(cbfmgr.rescan.end, None);
+        assert_eq!(cbfmgr.rescan.watch.len(), 3);
+        assert!(cbfmgr.rescan.watch.contains(&c));
+    }
+
+    /// Test that scripts can be removed from the watch list.
+    #[test]
+    fn test_unwatch() {
+        let best = 42;
+        let mut rng = fastrand::Rng::new();
+        let time = LocalTime::now();
+        let network = Network::Regtest;
+        let (mut cbfmgr, _, _) = util::setup(network, best, 0, RefClock::from(time));
+        let (a, b) = (gen::script(&mut rng), gen::script(&mut rng));
+
+        cbfmgr.watch(vec![a.clone(), b.clone()]);
+        assert!(cbfmgr.unwatch(&a));
+        assert!(!cbfmgr.unwatch(&a), "The script is no longer watched");
+        assert!(!cbfmgr.rescan.watch.contains(&a));
+        assert!(cbfmgr.rescan.watch.contains(&b));
+    }
+
+    /// Test that rescans never start below the filter sync start height.
+    #[test]
+    fn test_rescan_filter_sync_start() {
+        let best = 42;
+        let sync_start = 30;
+        let mut rng = fastrand::Rng::new();
+        let time = LocalTime::now();
+        let network = Network::Regtest;
+        let (mut cbfmgr, tree, _) = util::setup(network, best, 0, RefClock::from(time));
+
+        cbfmgr.config.filter_sync_start = Some(sync_start);
+        cbfmgr
+            .rescan(
+                Bound::Included(11),
+                Bound::Unbounded,
+                vec![gen::script(&mut rng)],
+                &tree,
+            )
+            .unwrap();
+        assert_eq!(cbfmgr.rescan.start, sync_start);
+        assert_eq!(cbfmgr.rescan.current, sync_start);
+
+        // Rescans ending below the sync start height are rejected.
+        assert!(matches!(
+            cbfmgr.rescan(
+                Bound::Included(11),
+                Bound::Included(20),
+                vec![gen::script(&mut rng)],
+                &tree,
+            ),
+            Err(GetFiltersError::InvalidRange)
+        ));
+        assert_eq!(cbfmgr.rescan.start, sync_start);
+
+        // Rescans starting above the sync start height are unaffected.
+        cbfmgr
+            .watch_address(gen::script(&mut rng), Some(35), &tree)
+            .unwrap();
+        assert_eq!(cbfmgr.rescan.start, 35);
+    }
+
+    /// Test that rescans starting below the pruned height are rejected.
+    #[test]
+    fn test_rescan_pruned() {
+        let best = 42;
+        let mut rng = fastrand::Rng::new();
+        let time = LocalTime::now();
+        let network = Network::Regtest;
+        let (mut cbfmgr, tree, _) = util::setup(network, best, 0, RefClock::from(time));
+
+        cbfmgr.prune(20).unwrap();
+
+        assert!(matches!(
+            cbfmgr.rescan(
+                Bound::Included(11),
+                Bound::Unbounded,
+                vec![gen::script(&mut rng)],
+                &tree,
+            ),
+            Err(GetFiltersError::Pruned(20))
+        ));
+        assert!(!cbfmgr.rescan.active, "The rescan isn't started");
+
+        assert!(matches!(
+            cbfmgr.watch_address(gen::script(&mut rng), Some(19), &tree),
+            Err(GetFiltersError::Pruned(20))
+        ));
+        cbfmgr
+            .watch_address(gen::script(&mut rng), Some(20), &tree)
+            .unwrap();
+        assert_eq!(cbfmgr.rescan.start, 20);
+    }
+
+    #[test]
+    fn test_rescan_bounds_overflow() {
+        let best = 42;
+        let mut rng = fastrand::Rng::new();
+        let time = LocalTime::now();
+        let network = Network::Regtest;
+        let (mut cbfmgr, tree, _) = util::setup(network, best, 0, RefClock::from(time));
+
+        assert!(matches!(
+            cbfmgr.rescan(
+                Bound::Excluded(Height::MAX),
+                Bound::Unbounded,
+                vec![gen::script(&mut rng)],
+                &tree,
+            ),
+            Err(GetFiltersError::InvalidRange)
+        ));
+        assert!(matches!(
+            cbfmgr.rescan(
+                Bound::Included(0),
+                Bound::Excluded(0),
+                vec![gen::script(&mut rng)],
+                &tree,
+            ),
+            Err(GetFiltersError::InvalidRange)
+        ));
+        assert!(!cbfmgr.rescan.active, "The rescan isn't started");
+    }
+
+    /// Test that an active rescan can be aborted.
+    #[test]
+    fn test_abort_rescan() {
+        let best = 42;
+        let mut rng = fastrand::Rng::new();
+        let time = LocalTime::now();
+        let network = Network::Regtest;
+        let (mut cbfmgr, tree, _) = util::setup(network, best, 0, RefClock::from(time));
+
+        assert!(cbfmgr.abort_rescan().is_empty());
+
+        cbfmgr
+            .rescan(
+                Bound::Included(11),
+                Bound::Unbounded,
+                vec![gen::script(&mut rng)],
+                &tree,
+            )
+            .unwrap();
+        assert!(cbfmgr.rescan.active);
+
+        cbfmgr.abort_rescan();
+        assert!(!cbfmgr.rescan.active);
+        util::events(cbfmgr.upstream.drain())
+            .find(|e| matches!(e, Event::RescanAborted { height: 11 }))
+            .expect("An event is emitted when the rescan is aborted");
+    }
+
+    /// Test that `getcfilters` request is retried.
+    #[test]
+    fn test_rescan_getcfilters_retry() {
+        let birth = 11;
+        let best = 42;
+        let mut rng = fastrand::Rng::new();
+        let time = LocalTime::now();
+        let network = Network::Regtest;
+        let filter_type = 0x0;
+        let (mut cbfmgr, tree, chain) = util::setup(network, best, 0, RefClock::from(time));
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+        let previous_filter_header = FilterHeader::genesis(network);
+        let cfheaders = util::cfheaders(previous_filter_header, &chain.tail);
+        let cfilters = util::cfilters(chain.iter()).collect::<Vec<_>>();
+
+        cbfmgr.filters.clear().unwrap();
+        cbfmgr.initialize(&tree);
+        cbfmgr.peer_negotiated(
+            Socket::new(remote),
+            best,
+            REQUIRED_SERVICES,
+            Link::Outbound,
+            false,
+            &tree,
+        );
+        cbfmgr
+            .received_cfheaders(&remote, cfheaders, &tree)
+            .unwrap();
+        assert_eq!(cbfmgr.filters.height(), best);
+
+        // Start rescan.
+        cbfmgr
+            .rescan(
+                Bound::Included(birth),
+                Bound::Unbounded,
+                vec![gen::script(&mut rng)],
+                &tree,
+            )
+            .unwrap();
+        assert!(cbfmgr.last_processed.is_none());
+        assert_eq!(cbfmgr.rescan.current, birth);
+
+        output::test::messages_from(&mut cbfmgr.upstream, &remote)
+            .find(|m| matches!(m, NetworkMessage::GetCFilters(_)))
+            .expect("`getcfilters` sent");
 
         // Receive a filter, but not one that we can process immediately.
         cbfmgr
@@ -1587,12 +2403,14 @@ mod tests {
         );
 
         // 1. Populate the cache from heights 5 to 8.
-        cbfmgr.rescan(
-            Bound::Included(birth),
-            Bound::Included(best),
-            watch.clone(),
-            &tree,
-        );
+        cbfmgr
+            .rescan(
+                Bound::Included(birth),
+                Bound::Included(best),
+                watch.clone(),
+                &tree,
+            )
+            .unwrap();
 
         for msg in util::cfilters(chain.iter().take(best as usize + 1)) {
             cbfmgr.received_cfilter(&remote, msg, &tree).unwrap();
@@ -1620,12 +2438,14 @@ mod tests {
         cbfmgr.upstream.drain().for_each(drop);
 
         // 5. Trigger a rescan for the new range 7 to 9
-        let matched = cbfmgr.rescan(
-            rescan_range.start_bound().cloned(),
-            rescan_range.end_bound().cloned(),
-            watch,
-            &tree,
-        );
+        let matched = cbfmgr
+            .rescan(
+                rescan_range.start_bound().cloned(),
+                rescan_range.end_bound().cloned(),
+                watch,
+                &tree,
+            )
+            .unwrap();
 
         // 6. Expect that 7 and 8 are cache hits, and 9 is requested.
         assert_eq!(
@@ -1679,12 +2499,14 @@ mod tests {
         );
 
         // 1. Populate the cache from heights 7 to 9.
-        cbfmgr.rescan(
-            Bound::Included(birth),
-            Bound::Included(best),
-            watch.clone(),
-            &tree,
-        );
+        cbfmgr
+            .rescan(
+                Bound::Included(birth),
+                Bound::Included(best),
+                watch.clone(),
+                &tree,
+            )
+            .unwrap();
 
         for msg in util::cfilters(chain.iter().take(best as usize + 1)) {
             cbfmgr.received_cfilter(&remote, msg, &tree).unwrap();
@@ -1699,12 +2521,14 @@ mod tests {
 
         // 5. Trigger a rescan for the new range 6 to 8.
         // Nothing should be matched yet, since we don't have filter #6.
-        let matched = cbfmgr.rescan(
-            rescan_range.start_bound().cloned(),
-            rescan_range.end_bound().cloned(),
-            watch,
-            &tree,
-        );
+        let matched = cbfmgr
+            .rescan(
+                rescan_range.start_bound().cloned(),
+                rescan_range.end_bound().cloned(),
+                watch,
+                &tree,
+            )
+            .unwrap();
         assert_eq!(matched, vec![]);
 
         // 6. Expect that #6 is requested.
@@ -1793,12 +2617,14 @@ mod tests {
         );
 
         // 1. Populate the cache from heights 5 to 8.
-        cbfmgr.rescan(
-            Bound::Included(birth),
-            Bound::Included(best),
-            watch.clone(),
-            &tree,
-        );
+        cbfmgr
+            .rescan(
+                Bound::Included(birth),
+                Bound::Included(best),
+                watch.clone(),
+                &tree,
+            )
+            .unwrap();
 
         for msg in util::cfilters(chain.iter().take(best as usize + 1)) {
             cbfmgr.received_cfilter(&remote, msg, &tree).unwrap();
@@ -1826,12 +2652,14 @@ mod tests {
         cbfmgr.upstream.drain().for_each(drop);
 
         // 5. Trigger a rescan for the new range 7 to 9
-        let matched = cbfmgr.rescan(
-            rescan_range.start_bound().cloned(),
-            rescan_range.end_bound().cloned(),
-            watch,
-            &tree,
-        );
+        let matched = cbfmgr
+            .rescan(
+                rescan_range.start_bound().cloned(),
+                rescan_range.end_bound().cloned(),
+                watch,
+                &tree,
+            )
+            .unwrap();
         assert_eq!(matched, vec![]);
 
         let mut msgs = output::test::messages_from(&mut cbfmgr.upstream, &remote);
@@ -1884,12 +2712,14 @@ mod tests {
 
         // 1. Populate the cache with height 6 and 8.
         for height in [6, 8] {
-            cbfmgr.rescan(
-                Bound::Included(height),
-                Bound::Included(height),
-                watch.clone(),
-                &tree,
-            );
+            cbfmgr
+                .rescan(
+                    Bound::Included(height),
+                    Bound::Included(height),
+                    watch.clone(),
+                    &tree,
+                )
+                .unwrap();
             let msg = util::cfilters(iter::once(&chain[height as usize]))
                 .next()
                 .unwrap();
@@ -1899,7 +2729,9 @@ mod tests {
         cbfmgr.upstream.drain().for_each(drop);
 
         // 2. Request range 5 to 9.
-        let matched = cbfmgr.rescan(Bound::Included(5), Bound::Included(9), watch, &tree);
+        let matched = cbfmgr
+            .rescan(Bound::Included(5), Bound::Included(9), watch, &tree)
+            .unwrap();
         assert!(matched.is_empty());
 
         // 3. Check for requests only on the heights not in the cache.
@@ -1935,6 +2767,206 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_request_filters_cancel() {
+        let network = Network::Regtest;
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+        let batch = MAX_MESSAGE_CFILTERS as Height;
+        let best = MAX_INFLIGHT_REQUEST_FILTERS as Height + batch * 2;
+        let time = LocalTime::now();
+        let (mut cbfmgr, tree, chain) = util::setup(network, best, 0, time);
+
+        cbfmgr.initialize(&tree);
+        cbfmgr.peer_negotiated(
+            Socket::new(remote),
+            best,
+            REQUIRED_SERVICES,
+            Link::Outbound,
+            false,
+            &tree,
+        );
+        output::test::messages(&mut cbfmgr.upstream).for_each(drop);
+
+        let getcfilters = |cbfmgr: &mut FilterManager<_, _, _>| {
+            output::test::messages_from(&mut cbfmgr.upstream, &remote)
+                .filter_map(|m| match m {
+                    NetworkMessage::GetCFilters(msg) => Some(msg.start_height as Height),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let id = cbfmgr.request_filters(1..=best, &tree).unwrap();
+
+        // Only a limited number of filters are requested at first.
+        assert_eq!(getcfilters(&mut cbfmgr), vec![1, 1001, 2001, 3001]);
+
+        // Once a batch is received, the next one is requested.
+        for msg in util::cfilters(chain.iter().skip(1).take(batch as usize)) {
+            cbfmgr.received_cfilter(&remote, msg, &tree).unwrap();
+        }
+        assert_eq!(getcfilters(&mut cbfmgr), vec![4001]);
+
+        // After canceling, the remaining filters are no longer requested.
+        assert!(cbfmgr.cancel_request(&id));
+        assert!(!cbfmgr.cancel_request(&id));
+
+        for msg in util::cfilters(chain.iter().skip(batch as usize + 1).take(batch as usize)) {
+            cbfmgr.received_cfilter(&remote, msg, &tree).unwrap();
+        }
+        assert!(getcfilters(&mut cbfmgr).is_empty());
+    }
+
+    /// Test that the filters requested from a peer that timed out are requested again.
+    #[test]
+    fn test_request_filters_timeout() {
+        let network = Network::Regtest;
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+        let best = 42;
+        let time = LocalTime::now();
+        let (mut cbfmgr, tree, chain) = util::setup(network, best, 0, RefClock::from(time));
+
+        cbfmgr.initialize(&tree);
+        cbfmgr.peer_negotiated(
+            Socket::new(remote),
+            best,
+            REQUIRED_SERVICES,
+            Link::Outbound,
+            false,
+            &tree,
+        );
+        output::test::messages(&mut cbfmgr.upstream).for_each(drop);
+
+        let getcfilters = |cbfmgr: &mut FilterManager<_, _, _>| {
+            output::test::messages_from(&mut cbfmgr.upstream, &remote)
+                .filter_map(|m| match m {
+                    NetworkMessage::GetCFilters(msg) => Some(msg.start_height as Height),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        cbfmgr.request_filters(1..=best, &tree).unwrap();
+        assert_eq!(getcfilters(&mut cbfmgr), vec![1]);
+
+        // Only some of the filters are received before the request times out.
+        for msg in util::cfilters(chain.iter().skip(1).take(10)) {
+            cbfmgr.received_cfilter(&remote, msg, &tree).unwrap();
+        }
+        cbfmgr.clock.elapse(DEFAULT_REQUEST_TIMEOUT);
+        cbfmgr.received_wake(&tree);
+
+        // The missing filters are requested again.
+        assert_eq!(getcfilters(&mut cbfmgr), vec![11]);
+
+        for msg in util::cfilters(chain.iter().skip(11)) {
+            cbfmgr.received_cfilter(&remote, msg, &tree).unwrap();
+        }
+        assert!(
+            cbfmgr.requests.is_empty(),
+            "The finished request is removed"
+        );
+        assert!(cbfmgr.requests_inflight.is_empty());
+
+        // Nothing is requested once the request is finished.
+        cbfmgr.clock.elapse(DEFAULT_REQUEST_TIMEOUT);
+        cbfmgr.received_wake(&tree);
+        assert!(getcfilters(&mut cbfmgr).is_empty());
+    }
+
+    #[test]
+    fn test_pause_resume() {
+        let network = Network::Regtest;
+        let remote: PeerId = ([88, 88, 88, 88], 8333).into();
+        let best = 42;
+        let time = LocalTime::now();
+        let (mut cbfmgr, tree, _) = util::setup(network, best, 0, RefClock::from(time));
+        let getcfheaders = |cbfmgr: &mut FilterManager<_, _, _>| {
+            output::test::messages_from(&mut cbfmgr.upstream, &remote)
+                .filter(|m| matches!(m, NetworkMessage::GetCFHeaders(_)))
+                .count()
+        };
+
+        cbfmgr.filters.clear().unwrap();
+        cbfmgr.initialize(&tree);
+        cbfmgr.pause();
+        cbfmgr.peer_negotiated(
+            Socket::new(remote),
+            best,
+            REQUIRED_SERVICES,
+            Link::Outbound,
+            false,
+            &tree,
+        );
+        assert_eq!(
+            getcfheaders(&mut cbfmgr),
+            0,
+            "Filter headers aren't requested while paused"
+        );
+
+        cbfmgr.resume(&tree);
+        assert_eq!(
+            getcfheaders(&mut cbfmgr),
+            1,
+            "Filter headers are requested once resumed"
+        );
+
+        // Requests in flight are still retried when they time out while paused.
+        cbfmgr.pause();
+        cbfmgr.clock.elapse(DEFAULT_REQUEST_TIMEOUT);
+        cbfmgr.received_wake(&tree);
+        assert_eq!(getcfheaders(&mut cbfmgr), 1);
+    }
+
+    /// Test that the filters requested from a peer that disconnected are requested from
+    /// another peer, and that the request fails when there are no peers left.
+    #[test]
+    fn test_request_filters_disconnect() {
+        let network = Network::Regtest;
+        let peers: [PeerId; 2] = [
+            ([88, 88, 88, 88], 8333).into(),
+            ([99, 99, 99, 99], 8333).into(),
+        ];
+        let best = 42;
+        let time = LocalTime::now();
+        let (mut cbfmgr, tree, _) = util::setup(network, best, 0, time);
+
+        cbfmgr.initialize(&tree);
+        for peer in peers {
+            cbfmgr.peer_negotiated(
+                Socket::new(peer),
+                best,
+                REQUIRED_SERVICES,
+                Link::Outbound,
+                false,
+                &tree,
+            );
+        }
+        output::test::messages(&mut cbfmgr.upstream).for_each(drop);
+
+        let getcfilters = |cbfmgr: &mut FilterManager<_, _, _>| {
+            output::test::messages(&mut cbfmgr.upstream)
+                .filter_map(|(peer, m)| match m {
+                    NetworkMessage::GetCFilters(msg) => Some((peer, msg.start_height as Height)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        cbfmgr.request_filters(1..=best, &tree).unwrap();
+
+        let (first, height) = getcfilters(&mut cbfmgr).pop().unwrap();
+        let second = peers.into_iter().find(|p| *p != first).unwrap();
+        assert_eq!(height, 1);
+
+        // The filters are requested from the remaining peer.
+        cbfmgr.peer_disconnected(&first, &tree);
+        assert_eq!(getcfilters(&mut cbfmgr), vec![(second, 1)]);
+
+        // Without peers, the request fails and is removed.
+        cbfmgr.peer_disconnected(&second, &tree);
+        assert!(getcfilters(&mut cbfmgr).is_empty());
+        assert!(cbfmgr.requests.is_empty(), "The failed request is removed");
+        assert!(cbfmgr.requests_inflight.is_empty());
+    }
+
     // TODO: Test that we panic if we get filters beyond the allowed range
     // TODO: Test rescan when the filter header chain is not caught up to the start of the range.
 
@@ -1961,12 +2993,14 @@ mod tests {
             false,
             &tree,
         );
-        let matched = cbfmgr.rescan(
-            Bound::Included(birth),
-            Bound::Unbounded,
-            watch.clone(),
-            &tree,
-        );
+        let matched = cbfmgr
+            .rescan(
+                Bound::Included(birth),
+                Bound::Unbounded,
+                watch.clone(),
+                &tree,
+            )
+            .unwrap();
         assert!(matched.is_empty());
 
         for msg in util::cfilters(chain.iter().take(best as usize + 1)) {
@@ -1976,15 +3010,22 @@ mod tests {
         assert_eq!(cbfmgr.rescan.cache.start(), Some(birth));
         assert_eq!(cbfmgr.rescan.cache.end(), Some(best));
         assert_eq!(cbfmgr.rescan.current, best + 1);
+        assert_eq!(
+            cbfmgr.cached_filter(best).map(|f| f.content),
+            Some(gen::cfilter(&chain[best as usize]).content)
+        );
+        assert!(cbfmgr.cached_filter(birth - 1).is_none());
 
         // After a new rescan with a non-empty watchlist, the scripts are checked against the
         // cached filters.
-        let matched = cbfmgr.rescan(
-            Bound::Included(birth),
-            Bound::Unbounded,
-            watch.clone(),
-            &tree,
-        );
+        let matched = cbfmgr
+            .rescan(
+                Bound::Included(birth),
+                Bound::Unbounded,
+                watch.clone(),
+                &tree,
+            )
+            .unwrap();
 
         assert_eq!(matched.len(), matches.len());
         assert_eq!(matched.iter().map(|(h, _)| *h).collect::<Vec<_>>(), matches);
@@ -2057,7 +3098,9 @@ mod tests {
                 false,
                 &tree,
             );
-            cbfmgr.rescan(Bound::Included(birth), Bound::Unbounded, watch, &tree);
+            cbfmgr
+                .rescan(Bound::Included(birth), Bound::Unbounded, watch, &tree)
+                .unwrap();
 
             log::debug!(target: "test",
                 "Chain {:?}",
@@ -2181,7 +3224,9 @@ mod tests {
             false,
             &tree,
         );
-        cbfmgr.rescan(Bound::Included(birth), Bound::Unbounded, watch, &tree);
+        cbfmgr
+            .rescan(Bound::Included(birth), Bound::Unbounded, watch, &tree)
+            .unwrap();
 
         let mut msgs = output::test::messages_from(&mut cbfmgr.upstream, &remote);
         let mut events = util::events(cbfmgr.upstream.drain());
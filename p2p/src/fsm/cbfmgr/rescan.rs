@@ -1,5 +1,7 @@
 //! Blockchain (re-)scanning for matching scripts.
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
+use std::iter;
+use std::mem;
 use std::ops::RangeInclusive;
 use std::rc::Rc;
 
@@ -12,6 +14,10 @@ use nakamoto_common::collections::{HashMap, HashSet};
 
 use super::{Event, FilterCache, HeightIterator, MAX_MESSAGE_CFILTERS};
 
+/// Maximum number of matched block requests tracked by a rescan. When exceeded, the
+/// lowest blocks are no longer tracked, and aren't cancelled if the rescan is aborted.
+pub const MAX_RESCAN_BLOCKS: usize = 1024;
+
 /// Filter (re)scan state.
 #[derive(Debug, Default)]
 pub struct Rescan {
@@ -35,6 +41,8 @@ pub struct Rescan {
     requested: BTreeSet<Height>,
     /// Received filters waiting to be matched.
     received: HashMap<Height, (Rc<BlockFilter>, BlockHash, bool)>,
+    /// Blocks matched and requested by the rescan, which weren't received yet.
+    blocks: BTreeMap<Height, BlockHash>,
 }
 
 impl Rescan {
@@ -61,6 +69,22 @@ impl Rescan {
         self.end = end;
         self.watch = watch.into_iter().collect();
         self.requested.clear();
+        self.blocks.clear();
+    }
+
+    /// Abort the rescan. Resets the request state, and returns the blocks matched by the
+    /// rescan which are still outstanding.
+    pub fn abort(&mut self) -> Vec<BlockHash> {
+        self.active = false;
+        self.requested.clear();
+        self.received.clear();
+        mem::take(&mut self.blocks).into_values().collect()
+    }
+
+    /// Keep tracking only the matched blocks for which the predicate holds, eg. blocks
+    /// that are still being downloaded.
+    pub fn retain_blocks(&mut self, mut f: impl FnMut(&BlockHash) -> bool) {
+        self.blocks.retain(|_, hash| f(hash));
     }
 
     /// Return info string on rescan state.
@@ -76,11 +100,23 @@ impl Rescan {
         )
     }
 
+    /// Heights of the filters processed since the rescan was started, if any.
+    pub fn processed(&self) -> Option<RangeInclusive<Height>> {
+        (self.current > self.start).then(|| self.start..=self.current - 1)
+    }
+
     /// Reset requested heights. This allows for requests to be re-issued.
     pub fn reset(&mut self) {
         self.requested.clear();
     }
 
+    /// Forget that the given heights were requested, so that they can be requested again.
+    pub fn forget(&mut self, heights: impl IntoIterator<Item = Height>) {
+        for height in heights {
+            self.requested.remove(&height);
+        }
+    }
+
     /// Rollback state to height.
     pub fn rollback(&mut self, to: Height) {
         self.cache.rollback(to)
@@ -111,20 +147,28 @@ impl Rescan {
         let old = current;
 
         while let Some((filter, block_hash, cached)) = self.received.remove(&current) {
-            let (matched, valid) = if let Ok(matched) = self.match_filter(&filter, &block_hash) {
-                (matched, true)
-            } else {
-                (false, false)
+            let (matched_scripts, valid) = match self.match_filter(&filter, &block_hash) {
+                Ok(scripts) => (scripts, true),
+                Err(_) => (Vec::new(), false),
             };
+            let matched = !matched_scripts.is_empty();
 
             if matched {
                 matches.push((current, block_hash));
+                self.blocks.insert(current, block_hash);
+
+                if self.blocks.len() > MAX_RESCAN_BLOCKS {
+                    if let Some(lowest) = self.blocks.keys().next().copied() {
+                        self.blocks.remove(&lowest);
+                    }
+                }
             }
             events.push(Event::FilterProcessed {
                 block: block_hash,
                 height: current,
                 valid,
                 matched,
+                matched_scripts,
                 cached,
             });
             current += 1;
@@ -141,24 +185,41 @@ impl Rescan {
         (matches, events, current - old)
     }
 
-    /// Check whether a filter matches one of our scripts.
+    /// Check whether a filter matches any of our scripts. Returns the matched scripts,
+    /// which is empty if the filter didn't match.
     pub fn match_filter(
         &self,
         filter: &BlockFilter,
         block_hash: &BlockHash,
-    ) -> Result<bool, bip158::Error> {
-        let mut matched = false;
+    ) -> Result<Vec<Script>, bip158::Error> {
+        let mut matched = Vec::new();
 
         // Match scripts first, then match transactions. All outputs of a transaction must
         // match to consider the transaction matched.
-        if !self.watch.is_empty() {
-            matched = filter.match_any(block_hash, &mut self.watch.iter().map(|k| k.as_bytes()))?;
+        if !self.watch.is_empty()
+            && filter.match_any(block_hash, &mut self.watch.iter().map(|k| k.as_bytes()))?
+        {
+            // Matching scripts one by one is more costly, so we only find out which
+            // scripts matched once we know the filter matched.
+            for script in self.watch.iter() {
+                if filter.match_any(block_hash, &mut iter::once(script.as_bytes()))? {
+                    matched.push(script.clone());
+                }
+            }
         }
-        if !matched && !self.transactions.is_empty() {
-            matched = self.transactions.values().any(|outs| {
-                let mut outs = outs.iter().map(|k| k.as_bytes());
-                filter.match_all(block_hash, &mut outs).unwrap_or(false)
-            })
+        if matched.is_empty() && !self.transactions.is_empty() {
+            for outs in self.transactions.values() {
+                let mut scripts = outs.iter().map(|k| k.as_bytes());
+
+                if !filter.match_all(block_hash, &mut scripts).unwrap_or(false) {
+                    continue;
+                }
+                for script in outs {
+                    if !matched.contains(script) {
+                        matched.push(script.clone());
+                    }
+                }
+            }
         }
         Ok(matched)
     }
@@ -234,6 +295,59 @@ mod tests {
     use super::*;
     use nakamoto_common::network::Network;
     use nakamoto_test::block::cache::model;
+    use nakamoto_test::block::gen;
+
+    #[test]
+    fn test_rescan_processed() {
+        let mut rng = fastrand::Rng::new();
+        let chain = gen::blockchain(Network::Regtest.genesis_block(), 8, &mut rng);
+        let mut rescan = Rescan::default();
+
+        rescan.restart(3, None, vec![]);
+        assert_eq!(rescan.processed(), None);
+
+        // Filters are processed in order, so the filter at height 5 is held back.
+        for height in [3, 4, 6] {
+            let filter = BlockFilter::new(&[0]);
+            rescan.requested.insert(height);
+            rescan.received(height, filter, chain[height as usize].block_hash());
+        }
+        rescan.process();
+        assert_eq!(rescan.processed(), Some(3..=4));
+    }
+
+    #[test]
+    fn test_rescan_blocks() {
+        let mut rng = fastrand::Rng::new();
+        let chain = gen::blockchain(Network::Regtest.genesis_block(), 4, &mut rng);
+        let watch = chain
+            .iter()
+            .map(|b| b.txdata[0].output[0].script_pubkey.clone())
+            .collect::<Vec<_>>();
+        let mut rescan = Rescan::default();
+
+        rescan.restart(1, None, watch);
+        for (height, block) in chain.iter().enumerate().skip(1) {
+            let height = height as Height;
+            rescan.requested.insert(height);
+            rescan.received(height, gen::cfilter(block), block.block_hash());
+        }
+        let (matches, _, _) = rescan.process();
+        assert_eq!(matches.len(), 4);
+
+        // Block #2 was received, so it's no longer tracked.
+        rescan.retain_blocks(|h| h != &chain[2].block_hash());
+
+        assert_eq!(
+            rescan.abort(),
+            vec![
+                chain[1].block_hash(),
+                chain[3].block_hash(),
+                chain[4].block_hash()
+            ]
+        );
+        assert!(rescan.abort().is_empty());
+    }
 
     #[test]
     fn test_rescan_requests() {
@@ -259,4 +373,28 @@ mod tests {
             vec![0..=3, 7..=8, 10..=11, 15..=16]
         );
     }
+
+    #[test]
+    fn test_match_filter_scripts() {
+        let mut rng = fastrand::Rng::new();
+        let genesis = Network::Regtest.genesis_block();
+        let mut tx = gen::transaction(&mut rng);
+        let block = gen::block_with(
+            &genesis.header,
+            vec![gen::coinbase(&mut rng), tx.clone()],
+            &mut rng,
+        );
+        let filter = gen::cfilter(&block);
+        let hash = block.block_hash();
+        let script = tx.output.remove(0).script_pubkey;
+        let mut rescan = Rescan::default();
+
+        assert_eq!(rescan.match_filter(&filter, &hash).unwrap(), vec![]);
+
+        rescan.watch.insert(gen::script(&mut rng));
+        assert_eq!(rescan.match_filter(&filter, &hash).unwrap(), vec![]);
+
+        rescan.watch.insert(script.clone());
+        assert_eq!(rescan.match_filter(&filter, &hash).unwrap(), vec![script]);
+    }
 }
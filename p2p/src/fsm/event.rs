@@ -17,8 +17,15 @@ pub enum Event {
         /// Local time.
         time: LocalTime,
     },
+    /// Filter and block syncing was paused. No new filter or block requests are issued
+    /// until syncing is resumed.
+    SyncPaused,
+    /// Filter and block syncing was resumed.
+    SyncResumed,
     /// Received a message from a peer.
     Received(PeerId, NetworkMessage),
+    /// Sent a message to a peer.
+    Sent(PeerId, NetworkMessage),
     /// An address manager event.
     Address(fsm::AddressEvent),
     /// A sync manager event.
@@ -1,5 +1,6 @@
 //! Types and utilities related to transaction fees and fee rates.
 use std::collections::VecDeque;
+use std::fmt;
 
 use nakamoto_common::bitcoin::blockdata::constants::WITNESS_SCALE_FACTOR;
 use nakamoto_common::bitcoin::{Block, OutPoint, Transaction, TxOut};
@@ -14,8 +15,67 @@ use super::Height;
 /// Maximum depth of a re-org that we are able to handle.
 pub const MAX_UTXO_SNAPSHOTS: usize = 12;
 
-/// Transaction fee rate in satoshis/vByte.
-pub type FeeRate = u64;
+/// Number of processed blocks from which fee rates are kept for estimation.
+pub const MAX_FEE_HISTORY: usize = 24;
+
+/// Transaction fee rate, in satoshis/vByte.
+///
+/// ```
+/// use nakamoto_p2p::fsm::fees::FeeRate;
+///
+/// let rate = FeeRate::from_sat_per_vb(12);
+///
+/// assert_eq!(rate.as_sat_per_vb(), 12);
+/// assert_eq!(rate.as_sat_per_kvb(), 12_000);
+/// assert_eq!(rate.as_sat_per_kwu(), 3_000);
+/// assert_eq!(FeeRate::from_sat_per_kvb(12_400), rate);
+/// assert_eq!(rate.fee(141), 1692);
+/// assert_eq!(rate.to_string(), "12 sat/vB");
+/// ```
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FeeRate(u64);
+
+impl FeeRate {
+    /// Zero fee rate.
+    pub const ZERO: Self = Self(0);
+
+    /// Create a fee rate from satoshis per virtual byte.
+    pub const fn from_sat_per_vb(sat: u64) -> Self {
+        Self(sat)
+    }
+
+    /// Create a fee rate from satoshis per thousand virtual bytes.
+    /// The rate is rounded to the nearest satoshi per virtual byte.
+    pub const fn from_sat_per_kvb(sat: u64) -> Self {
+        Self((sat + 500) / 1000)
+    }
+
+    /// Get the fee rate in satoshis per virtual byte.
+    pub const fn as_sat_per_vb(&self) -> u64 {
+        self.0
+    }
+
+    /// Get the fee rate in satoshis per thousand virtual bytes.
+    pub const fn as_sat_per_kvb(&self) -> u64 {
+        self.0 * 1000
+    }
+
+    /// Get the fee rate in satoshis per thousand weight units.
+    pub const fn as_sat_per_kwu(&self) -> u64 {
+        self.0 * 1000 / WITNESS_SCALE_FACTOR as u64
+    }
+
+    /// Get the fee, in satoshis, of a transaction of the given virtual size.
+    pub const fn fee(&self, vsize: u64) -> u64 {
+        self.0 * vsize
+    }
+}
+
+impl fmt::Display for FeeRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} sat/vB", self.0)
+    }
+}
 
 /// Fee rate estimate for a single block.
 /// Measured in satoshis/vByte.
@@ -34,28 +94,24 @@ impl FeeEstimate {
     /// Returns [`None`] if the list is empty.
     ///
     /// ```
-    /// use nakamoto_p2p::fsm::fees::FeeEstimate;
-    ///
-    /// assert_eq!(
-    ///     FeeEstimate::from(vec![3, 9, 2]),
-    ///     Some(FeeEstimate { low: 2, median: 3, high: 9 }),
-    /// );
-    ///
-    /// assert_eq!(
-    ///     FeeEstimate::from(vec![4, 6]),
-    ///     Some(FeeEstimate { low: 4, median: 5, high: 6 }),
-    /// );
+    /// use nakamoto_p2p::fsm::fees::{FeeEstimate, FeeRate};
     ///
-    /// assert_eq!(
-    ///     FeeEstimate::from(vec![9, 2, 1, 7]),
-    ///     Some(FeeEstimate { low: 1, median: 5, high: 9 }),
-    /// );
+    /// fn rates(rates: &[u64]) -> Vec<FeeRate> {
+    ///     rates.iter().copied().map(FeeRate::from_sat_per_vb).collect()
+    /// }
     ///
-    /// assert_eq!(
-    ///     FeeEstimate::from(vec![3]),
-    ///     Some(FeeEstimate { low: 3, median: 3, high: 3 }),
-    /// );
+    /// fn estimate(low: u64, median: u64, high: u64) -> FeeEstimate {
+    ///     FeeEstimate {
+    ///         low: FeeRate::from_sat_per_vb(low),
+    ///         median: FeeRate::from_sat_per_vb(median),
+    ///         high: FeeRate::from_sat_per_vb(high),
+    ///     }
+    /// }
     ///
+    /// assert_eq!(FeeEstimate::from(rates(&[3, 9, 2])), Some(estimate(2, 3, 9)));
+    /// assert_eq!(FeeEstimate::from(rates(&[4, 6])), Some(estimate(4, 5, 6)));
+    /// assert_eq!(FeeEstimate::from(rates(&[9, 2, 1, 7])), Some(estimate(1, 5, 9)));
+    /// assert_eq!(FeeEstimate::from(rates(&[3])), Some(estimate(3, 3, 3)));
     /// assert_eq!(FeeEstimate::from(vec![]), None);
     /// ```
     pub fn from(mut fees: Vec<FeeRate>) -> Option<Self> {
@@ -66,10 +122,10 @@ impl FeeEstimate {
             let median = if count % 2 == 1 {
                 fees[count / 2]
             } else {
-                let left = fees[count / 2 - 1] as f64;
-                let right = fees[count / 2] as f64;
+                let left = fees[count / 2 - 1].as_sat_per_vb() as f64;
+                let right = fees[count / 2].as_sat_per_vb() as f64;
 
-                ((left + right) / 2.).round() as FeeRate
+                FeeRate::from_sat_per_vb(((left + right) / 2.).round() as u64)
             };
 
             Self {
@@ -94,6 +150,8 @@ pub struct FeeEstimator {
     /// UTXO set snapshots.
     /// These are used to return to a previous state in the case of a re-org.
     snapshots: VecDeque<(Height, UtxoSet)>,
+    /// Fee rates of the most recently processed blocks, sorted in ascending order.
+    history: VecDeque<(Height, Vec<FeeRate>)>,
 }
 
 impl FeeEstimator {
@@ -120,12 +178,59 @@ impl FeeEstimator {
         }
         self.height = height;
 
+        fees.sort_unstable();
+        self.history.push_back((height, fees.clone()));
+        if self.history.len() > MAX_FEE_HISTORY {
+            self.history.pop_front();
+        }
+
         FeeEstimate::from(fees)
     }
 
+    /// Estimate the fee rate required for a transaction to be confirmed within the
+    /// given number of blocks.
+    ///
+    /// The fee rates of the last `target_blocks` processed blocks with fee data are
+    /// aggregated, skipping blocks without any, eg. coinbase-only blocks. The
+    /// `50 / target_blocks` percentile is returned, ie. the median for a target of one block,
+    /// the 25th percentile for a target of two blocks, etc.
+    ///
+    /// Returns [`None`] if fewer than `target_blocks` blocks with fee data were processed,
+    /// or if the target is zero or greater than [`MAX_FEE_HISTORY`].
+    pub fn estimate(&self, target_blocks: u16) -> Option<FeeRate> {
+        let target = target_blocks as usize;
+
+        if target == 0 || target > MAX_FEE_HISTORY {
+            return None;
+        }
+        let blocks = self
+            .history
+            .iter()
+            .rev()
+            .filter(|(_, fees)| !fees.is_empty())
+            .take(target)
+            .collect::<Vec<_>>();
+
+        if blocks.len() < target {
+            return None;
+        }
+        let mut fees = blocks
+            .into_iter()
+            .flat_map(|(_, fees)| fees.iter().copied())
+            .collect::<Vec<_>>();
+
+        fees.sort_unstable();
+
+        let percentile = 0.5 / target as f64;
+        let ix = ((fees.len() - 1) as f64 * percentile).round() as usize;
+
+        Some(fees[ix])
+    }
+
     /// Rollback to a certain height.
     pub fn rollback(&mut self, height: Height) {
         self.snapshots.retain(|(h, _)| h <= &height);
+        self.history.retain(|(h, _)| h <= &height);
 
         if let Some((h, snapshot)) = self.snapshots.pop_back() {
             assert!(h <= height);
@@ -172,7 +277,7 @@ impl FeeEstimator {
         let weight = tx.weight();
         let rate = fee as f64 / (weight as f64 / WITNESS_SCALE_FACTOR as f64);
 
-        Some(rate.round() as FeeRate)
+        Some(FeeRate::from_sat_per_vb(rate.round() as u64))
     }
 }
 
@@ -213,6 +318,35 @@ mod tests {
         assert_matches!(fe.snapshots.back(), Some((18, _)));
     }
 
+    #[test]
+    fn test_estimate() {
+        let mut fe = FeeEstimator::default();
+
+        let rate = FeeRate::from_sat_per_vb;
+
+        assert_eq!(fe.estimate(1), None);
+
+        fe.history
+            .push_back((1, [1, 2, 3, 4, 5].into_iter().map(rate).collect()));
+        fe.history
+            .push_back((2, [6, 7, 8, 9, 10].into_iter().map(rate).collect()));
+
+        assert_eq!(fe.estimate(0), None);
+        assert_eq!(fe.estimate(1), Some(rate(8)));
+        assert_eq!(fe.estimate(2), Some(rate(3)));
+        assert_eq!(fe.estimate(3), None);
+        assert_eq!(fe.estimate(MAX_FEE_HISTORY as u16 + 1), None);
+
+        // Blocks without fee data aren't counted.
+        fe.history.push_back((3, vec![]));
+        assert_eq!(fe.estimate(1), Some(rate(8)));
+        assert_eq!(fe.estimate(3), None);
+
+        fe.rollback(1);
+        assert_eq!(fe.estimate(1), Some(rate(3)));
+        assert_eq!(fe.estimate(2), None);
+    }
+
     #[test]
     fn test_rollback_missing_height() {
         let mut fe = FeeEstimator::default();
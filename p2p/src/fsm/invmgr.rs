@@ -19,9 +19,21 @@
 //! the [`InventoryManager::received_wake`] function is called. Confirmed transactions are removed
 //! after they are burried at a certain depth.
 //!
-use std::collections::BTreeMap;
+//! ## Compact blocks
+//!
+//! When enabled, blocks are requested as `BIP152` compact blocks from the peers that support
+//! them, and reconstructed from the transactions in the mempool. If any transaction is missing,
+//! the full block is requested from the same peer instead.
+//!
+//! Since our own mempool rarely covers a block, transactions relayed by peers are downloaded
+//! and kept until they are confirmed, when transaction relay is enabled. Up to
+//! [`MAX_RELAYED_TRANSACTIONS`] are kept, the oldest being evicted first.
+//!
+use std::collections::{BTreeMap, VecDeque};
 
+use nakamoto_common::bitcoin::network::message_compact_blocks::SendCmpct;
 use nakamoto_common::bitcoin::network::{constants::ServiceFlags, message_blockdata::Inventory};
+use nakamoto_common::bitcoin::util::bip152::{HeaderAndShortIds, ShortId};
 use nakamoto_common::bitcoin::{Block, BlockHash, Transaction, Txid, Wtxid};
 
 // TODO: Timeout should be configurable
@@ -29,9 +41,9 @@ use nakamoto_common::bitcoin::{Block, BlockHash, Transaction, Txid, Wtxid};
 
 use nakamoto_common::block::time::{Clock, LocalDuration, LocalTime};
 use nakamoto_common::block::tree::BlockReader;
-use nakamoto_common::collections::{AddressBook, HashMap};
+use nakamoto_common::collections::{AddressBook, HashMap, HashSet};
 
-use super::fees::{FeeEstimate, FeeEstimator};
+use super::fees::{FeeEstimate, FeeEstimator, FeeRate};
 use super::output::{Wakeup, Wire};
 use super::{Height, PeerId, Socket};
 
@@ -50,9 +62,79 @@ pub const IDLE_TIMEOUT: LocalDuration = LocalDuration::from_secs(30);
 /// Block depth at which confirmed transactions are pruned and no longer reverted after a re-org.
 pub const TRANSACTION_PRUNE_DEPTH: Height = 12;
 
+/// Default maximum number of blocks requested at the same time.
+pub const DEFAULT_BLOCK_DOWNLOAD_CONCURRENCY: usize = 8;
+
+/// Default number of times a block request is retried before giving up on the block.
+pub const DEFAULT_BLOCK_REQUEST_RETRIES: u8 = 3;
+
+/// Default number of blocks after which an acknowledged transaction that hasn't been
+/// confirmed or acknowledged again is dropped from the mempool.
+pub const DEFAULT_DROP_WINDOW: Height = 144;
+
+/// `BIP152` compact blocks versions we support, in order of preference. Version 2 computes
+/// short transaction identifiers from witness transaction identifiers, version 1 from
+/// non-witness identifiers.
+pub const COMPACT_BLOCKS_VERSIONS: [u64; 2] = [2, 1];
+
+/// Maximum number of transactions relayed by peers that are kept to reconstruct compact blocks.
+pub const MAX_RELAYED_TRANSACTIONS: usize = 20_000;
+
+/// Inventory manager configuration.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Maximum number of block requests in flight at the same time, across all peers.
+    pub block_download_concurrency: usize,
+    /// Number of times a block request is retried, when the peer doesn't deliver the block
+    /// in time or disconnects, before giving up on the block.
+    pub block_request_retries: u8,
+    /// Whether to request blocks as `BIP152` compact blocks from peers that support them.
+    pub compact_blocks: bool,
+    /// Whether peers relay transactions to us. Together with compact blocks, relayed
+    /// transactions are downloaded and kept to reconstruct blocks.
+    pub relay_transactions: bool,
+    /// Number of blocks after which an acknowledged transaction that hasn't been confirmed
+    /// or acknowledged again is dropped from the mempool.
+    pub drop_window: Height,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            block_download_concurrency: DEFAULT_BLOCK_DOWNLOAD_CONCURRENCY,
+            block_request_retries: DEFAULT_BLOCK_REQUEST_RETRIES,
+            compact_blocks: false,
+            relay_transactions: false,
+            drop_window: DEFAULT_DROP_WINDOW,
+        }
+    }
+}
+
 /// An event emitted by the inventory manager.
 #[derive(Debug, Clone)]
 pub enum Event {
+    /// Block download started, ie. the block was requested from a peer.
+    /// This event is emitted again if the request is retried.
+    BlockDownloadStarted {
+        /// Block hash.
+        hash: BlockHash,
+        /// Peer the block was requested from.
+        peer: PeerId,
+    },
+    /// A block request was retried, because the peer didn't deliver the block in time
+    /// or disconnected. Blocks are requested from a different peer when possible.
+    BlockDownloadRetried {
+        /// Block hash.
+        hash: BlockHash,
+        /// Retry attempt, starting at one.
+        attempt: u8,
+    },
+    /// A block couldn't be downloaded after [`Config::block_request_retries`] retries,
+    /// and is no longer requested.
+    BlockDownloadFailed {
+        /// Block hash.
+        hash: BlockHash,
+    },
     /// Block received.
     BlockReceived {
         /// Sender.
@@ -76,6 +158,14 @@ pub enum Event {
         /// The acknowledging peer.
         peer: PeerId,
     },
+    /// A peer announced one of our transactions via an `inv` message, which means
+    /// the transaction is in its mempool. Emitted at most once per transaction and peer.
+    TxAnnounced {
+        /// The announced transaction ID.
+        txid: Txid,
+        /// The announcing peer.
+        peer: PeerId,
+    },
     /// A transaction was confirmed.
     Confirmed {
         /// The confirmed transaction.
@@ -85,6 +175,24 @@ pub enum Event {
         /// The block in which it was confirmed.
         block: BlockHash,
     },
+    /// A transaction wasn't confirmed or acknowledged again by any peer within
+    /// [`Config::drop_window`] blocks, and was removed from the mempool.
+    Dropped {
+        /// The dropped transaction ID.
+        txid: Txid,
+        /// Number of blocks since the transaction was last acknowledged.
+        blocks: Height,
+    },
+    /// A transaction was removed from the mempool, because a conflicting transaction
+    /// spending one of its inputs was included in a block.
+    Replaced {
+        /// The replaced transaction ID.
+        txid: Txid,
+        /// The conflicting transaction ID.
+        replaced_by: Txid,
+        /// The block in which the conflicting transaction was included.
+        block: BlockHash,
+    },
     /// A transaction was reverted.
     Reverted {
         /// The reverted transaction.
@@ -100,6 +208,19 @@ pub enum Event {
 impl std::fmt::Display for Event {
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            Event::BlockDownloadStarted { hash, peer } => {
+                write!(fmt, "{}: Downloading block {}", peer, hash)
+            }
+            Event::BlockDownloadRetried { hash, attempt } => {
+                write!(
+                    fmt,
+                    "Retrying download of block {} (attempt {})",
+                    hash, attempt
+                )
+            }
+            Event::BlockDownloadFailed { hash } => {
+                write!(fmt, "Failed to download block {}", hash)
+            }
             Event::BlockReceived { from, height, .. } => {
                 write!(fmt, "{}: Received block #{}", from, height)
             }
@@ -113,6 +234,9 @@ impl std::fmt::Display for Event {
                     txid, peer
                 )
             }
+            Event::TxAnnounced { txid, peer } => {
+                write!(fmt, "Transaction {} was announced by peer {}", txid, peer)
+            }
             Event::Confirmed {
                 transaction,
                 height,
@@ -124,6 +248,20 @@ impl std::fmt::Display for Event {
                 height,
                 block,
             ),
+            Event::Dropped { txid, blocks } => write!(
+                fmt,
+                "Transaction {} was dropped after {} block(s) without being acknowledged",
+                txid, blocks
+            ),
+            Event::Replaced {
+                txid,
+                replaced_by,
+                block,
+            } => write!(
+                fmt,
+                "Transaction {} was replaced by {} in block {}",
+                txid, replaced_by, block
+            ),
             Event::Reverted { transaction, .. } => {
                 write!(fmt, "Transaction {} was reverted", transaction.txid(),)
             }
@@ -141,6 +279,8 @@ pub struct Peer {
     pub services: ServiceFlags,
     /// Does this peer use BIP-339?
     pub wtxidrelay: bool,
+    /// Highest compact blocks version supported by this peer, if any.
+    pub compact_blocks: Option<u64>,
 
     /// Inventories we are attempting to send to this peer.
     outbox: HashMap<Wtxid, Txid>,
@@ -150,7 +290,6 @@ pub struct Peer {
     last_attempt: Option<LocalTime>,
 
     /// Number of times a certain block was requested.
-    #[allow(dead_code)]
     requests: HashMap<BlockHash, usize>,
 
     /// Peer socket.
@@ -163,7 +302,6 @@ impl Peer {
         self.attempts += 1;
     }
 
-    #[allow(dead_code)]
     fn requested(&mut self, hash: BlockHash) {
         *self.requests.entry(hash).or_default() += 1;
     }
@@ -177,6 +315,8 @@ impl Peer {
 /// Inventory manager state.
 #[derive(Debug)]
 pub struct InventoryManager<U, C> {
+    /// Manager configuration.
+    config: Config,
     /// Peer map.
     peers: AddressBook<PeerId, Peer>,
     /// Timeout used for retrying broadcasts.
@@ -190,12 +330,33 @@ pub struct InventoryManager<U, C> {
 
     /// Transaction mempool. Stores unconfirmed transactions sent to the network.
     pub mempool: BTreeMap<Wtxid, Transaction>,
+    /// Unconfirmed transactions relayed by peers, kept to reconstruct compact blocks.
+    relayed: HashMap<Wtxid, Transaction>,
+    /// Identifiers of the relayed transactions.
+    relayed_txids: HashSet<Txid>,
+    /// Relayed transactions, oldest first. May contain transactions that were since removed.
+    relayed_order: VecDeque<Wtxid>,
+    /// Relayed transactions requested, and the time at which they were requested.
+    tx_requests: HashMap<Inventory, LocalTime>,
+    /// First peer to have acknowledged each of our unconfirmed transactions.
+    acknowledged: HashMap<Txid, PeerId>,
+    /// Peers that announced each of our unconfirmed transactions.
+    announced: HashMap<Txid, HashSet<PeerId>>,
+    /// Height of the active chain when each of our unconfirmed transactions was last
+    /// acknowledged or announced by a peer.
+    last_seen: HashMap<Txid, Height>,
+    /// Height of the active chain, as of the last wake.
+    height: Height,
     /// Blocks requested and the time at which they were last requested.
     pub remaining: HashMap<BlockHash, Option<LocalTime>>,
+    /// Peer each requested block was last requested from, and the number of retries.
+    requested: HashMap<BlockHash, (PeerId, u8)>,
     /// Blocks received, waiting to be processed.
     pub received: HashMap<Height, Block>,
 
     last_tick: Option<LocalTime>,
+    /// Whether block requests are paused.
+    paused: bool,
     rng: fastrand::Rng,
     upstream: U,
     clock: C,
@@ -203,22 +364,38 @@ pub struct InventoryManager<U, C> {
 
 impl<U: Wire<Event> + Wakeup, C: Clock> InventoryManager<U, C> {
     /// Create a new inventory manager.
-    pub fn new(rng: fastrand::Rng, upstream: U, clock: C) -> Self {
+    pub fn new(config: Config, rng: fastrand::Rng, upstream: U, clock: C) -> Self {
         Self {
+            config,
             peers: AddressBook::new(rng.clone()),
             mempool: BTreeMap::new(),
+            relayed: HashMap::with_hasher(rng.clone().into()),
+            relayed_txids: HashSet::with_hasher(rng.clone().into()),
+            relayed_order: VecDeque::new(),
+            tx_requests: HashMap::with_hasher(rng.clone().into()),
+            acknowledged: HashMap::with_hasher(rng.clone().into()),
+            announced: HashMap::with_hasher(rng.clone().into()),
+            last_seen: HashMap::with_hasher(rng.clone().into()),
+            height: 0,
             estimator: FeeEstimator::default(),
             confirmed: HashMap::with_hasher(rng.clone().into()),
             remaining: HashMap::with_hasher(rng.clone().into()),
+            requested: HashMap::with_hasher(rng.clone().into()),
             received: HashMap::with_hasher(rng.clone().into()),
             timeout: REBROADCAST_TIMEOUT,
             last_tick: None,
+            paused: false,
             rng,
             upstream,
             clock,
         }
     }
 
+    /// Initialize the inventory manager. Should only be called once.
+    pub fn initialize<T: BlockReader>(&mut self, tree: &T) {
+        self.height = tree.height();
+    }
+
     #[cfg(test)]
     /// Check whether the inventory is empty.
     pub fn is_empty(&self) -> bool {
@@ -231,6 +408,24 @@ impl<U: Wire<Event> + Wakeup, C: Clock> InventoryManager<U, C> {
         self.mempool.contains_key(wtxid)
     }
 
+    /// Get the unconfirmed transactions in the mempool, along with the first peer
+    /// to have acknowledged each of them, if any.
+    pub fn unconfirmed(&self) -> Vec<(Txid, Option<PeerId>)> {
+        self.mempool
+            .values()
+            .map(|tx| {
+                let txid = tx.txid();
+                (txid, self.acknowledged.get(&txid).copied())
+            })
+            .collect()
+    }
+
+    /// Estimate the fee rate required for a transaction to confirm within the given
+    /// number of blocks. See [`FeeEstimator::estimate`].
+    pub fn estimate_fee(&self, target_blocks: u16) -> Option<FeeRate> {
+        self.estimator.estimate(target_blocks)
+    }
+
     /// Called when a peer is negotiated.
     pub fn peer_negotiated(
         &mut self,
@@ -252,6 +447,7 @@ impl<U: Wire<Event> + Wakeup, C: Clock> InventoryManager<U, C> {
                 attempts: 0,
                 relay,
                 wtxidrelay,
+                compact_blocks: None,
                 outbox,
                 last_attempt: None,
                 requests: HashMap::with_hasher(self.rng.clone().into()),
@@ -263,6 +459,27 @@ impl<U: Wire<Event> + Wakeup, C: Clock> InventoryManager<U, C> {
     /// Called when a peer disconnected.
     pub fn peer_disconnected(&mut self, id: &PeerId) {
         self.peers.remove(id);
+
+        // Forget about the transactions announced by this peer. A transaction that is
+        // announced again after the peer reconnects is then reported again.
+        self.announced.retain(|_, peers| {
+            peers.remove(id);
+            !peers.is_empty()
+        });
+
+        // Blocks in flight with this peer won't be delivered: retry them right away.
+        let mut retry = false;
+        for (hash, (peer, _)) in &self.requested {
+            if peer == id {
+                if let Some(last_request) = self.remaining.get_mut(hash) {
+                    *last_request = Some(LocalTime::default());
+                    retry = true;
+                }
+            }
+        }
+        if retry {
+            self.schedule_tick();
+        }
     }
 
     /// Called when a block is reverted.
@@ -283,7 +500,8 @@ impl<U: Wire<Event> + Wakeup, C: Clock> InventoryManager<U, C> {
     }
 
     /// Called when we receive a tick.
-    pub fn received_wake<T: BlockReader>(&mut self, tree: &T) {
+    /// Returns the list of confirmed [`Txid`], if blocks were processed.
+    pub fn received_wake<T: BlockReader>(&mut self, tree: &T) -> Vec<Txid> {
         let now = self.clock.local_time();
         if now - self.last_tick.unwrap_or_default() >= IDLE_TIMEOUT {
             self.last_tick = Some(now);
@@ -296,6 +514,14 @@ impl<U: Wire<Event> + Wakeup, C: Clock> InventoryManager<U, C> {
             self.confirmed
                 .retain(|h, _| height - h <= TRANSACTION_PRUNE_DEPTH);
         }
+        // Drop transactions that peers seem to have forgotten about.
+        self.height = tree.height();
+        self.drop_expired();
+
+        // Forget relayed transaction requests that weren't answered, so that they can be
+        // requested from other peers.
+        self.tx_requests
+            .retain(|_, requested| now - *requested < REQUEST_TIMEOUT);
 
         // Handle retries annd disconnects.
         let mut disconnect = Vec::new();
@@ -344,28 +570,43 @@ impl<U: Wire<Event> + Wakeup, C: Clock> InventoryManager<U, C> {
         }
 
         // Handle block request queue.
-        let queue = self
-            .remaining
-            .iter_mut()
-            .filter(|(_, t)| now - t.unwrap_or_default() >= REQUEST_TIMEOUT);
+        self.request_blocks(now);
 
-        for (block_hash, last_request) in queue {
-            if let Some((addr, _)) = self
-                .peers
-                .sample_with(|_, p| p.services.has(ServiceFlags::NETWORK))
-            {
-                log::debug!("Requesting block {} from {}", block_hash, addr);
+        // Blocks we gave up on may have been holding back the processing of received blocks.
+        if !self.remaining.is_empty() {
+            return vec![];
+        }
+        self.process_received()
+    }
 
-                self.upstream
-                    .get_data(*addr, vec![Inventory::Block(*block_hash)]);
-                self.upstream.wakeup(REQUEST_TIMEOUT);
+    /// Called when an `inv` is received from a peer.
+    ///
+    /// If the peer announces one of our unconfirmed transactions, the transaction made it
+    /// into the peer's mempool.
+    pub fn received_inv(&mut self, addr: PeerId, invs: &[Inventory]) {
+        self.request_relayed(addr, invs);
 
-                *last_request = Some(now);
-            } else {
-                log::debug!(
-                    "No peers with required services to request block {} from",
-                    block_hash
-                );
+        for inv in invs {
+            let tx = match inv {
+                Inventory::Transaction(txid) | Inventory::WitnessTransaction(txid) => {
+                    self.mempool.values().find(|tx| tx.txid() == *txid)
+                }
+                Inventory::WTx(wtxid) => self.mempool.get(wtxid),
+                _ => None,
+            };
+
+            if let Some(tx) = tx {
+                let txid = tx.txid();
+                let rng = self.rng.clone();
+                let peers = self
+                    .announced
+                    .entry(txid)
+                    .or_insert_with(|| HashSet::with_hasher(rng.into()));
+
+                if peers.insert(addr) {
+                    self.last_seen.insert(txid, self.height);
+                    self.upstream.event(Event::TxAnnounced { txid, peer: addr });
+                }
             }
         }
     }
@@ -393,6 +634,8 @@ impl<U: Wire<Event> + Wakeup, C: Clock> InventoryManager<U, C> {
                                 if peer.outbox.is_empty() {
                                     log::debug!("Peer {} transaction outbox is empty", &addr);
                                 }
+                                self.acknowledged.entry(*txid).or_insert(addr);
+                                self.last_seen.insert(*txid, self.height);
                                 self.upstream.event(Event::Acknowledged {
                                     peer: addr,
                                     txid: *txid,
@@ -416,6 +659,8 @@ impl<U: Wire<Event> + Wakeup, C: Clock> InventoryManager<U, C> {
                             if peer.outbox.is_empty() {
                                 log::debug!("Peer {} transaction outbox is empty", &addr);
                             }
+                            self.acknowledged.entry(txid).or_insert(addr);
+                            self.last_seen.insert(txid, self.height);
                             self.upstream
                                 .event(Event::Acknowledged { peer: addr, txid });
                         }
@@ -450,6 +695,9 @@ impl<U: Wire<Event> + Wakeup, C: Clock> InventoryManager<U, C> {
         for peer in self.peers.values_mut() {
             peer.requests.remove(&hash);
         }
+        self.requested.remove(&hash);
+        // Make room for the next block in the queue.
+        self.request_blocks(self.clock.local_time());
 
         // Find the block height, otherwise we've somehow requested a block which
         // isn't part of the active chain. This could happen in the case of a re-org
@@ -470,8 +718,278 @@ impl<U: Wire<Event> + Wakeup, C: Clock> InventoryManager<U, C> {
             return vec![];
         }
 
-        // Now that all blocks to be processed are downloaded, we can start
-        // processing them in order.
+        self.process_received()
+    }
+
+    /// Called when a transaction is received from a peer.
+    ///
+    /// The transaction is kept to reconstruct compact blocks, if we requested it.
+    pub fn received_tx(&mut self, addr: &PeerId, tx: Transaction) {
+        let (txid, wtxid) = (tx.txid(), tx.wtxid());
+        let requested = self.tx_requests.remove(&Inventory::WTx(wtxid)).is_some()
+            | self
+                .tx_requests
+                .remove(&Inventory::Transaction(txid))
+                .is_some();
+
+        if !requested || self.mempool.contains_key(&wtxid) || self.relayed.contains_key(&wtxid) {
+            return;
+        }
+        log::trace!("Received transaction {} from {}", txid, addr);
+
+        self.relayed.insert(wtxid, tx);
+        self.relayed_txids.insert(txid);
+        self.relayed_order.push_back(wtxid);
+
+        // Evict the oldest transactions. Entries of transactions that were already removed
+        // are skipped.
+        while self.relayed.len() > MAX_RELAYED_TRANSACTIONS {
+            if let Some(wtxid) = self.relayed_order.pop_front() {
+                self.remove_relayed(&wtxid);
+            }
+        }
+        if self.relayed_order.len() > MAX_RELAYED_TRANSACTIONS * 2 {
+            let relayed = &self.relayed;
+            self.relayed_order
+                .retain(|wtxid| relayed.contains_key(wtxid));
+        }
+    }
+
+    /// Called when a peer sends us a `sendcmpct` message, signaling support for compact blocks.
+    pub fn received_sendcmpct(&mut self, addr: &PeerId, msg: &SendCmpct) {
+        if let Some(peer) = self.peers.get_mut(addr) {
+            // Nb. Peers send one message per version they support, in order of preference.
+            if COMPACT_BLOCKS_VERSIONS.contains(&msg.version) {
+                peer.compact_blocks = peer.compact_blocks.max(Some(msg.version));
+            }
+        }
+    }
+
+    /// Called when a compact block is received from a peer.
+    /// Returns the list of confirmed [`Txid`], if the block could be reconstructed.
+    ///
+    /// If the block can't be reconstructed from our mempool, the full block is requested instead.
+    pub fn received_cmpctblock<T: BlockReader>(
+        &mut self,
+        from: &PeerId,
+        cmpct: HeaderAndShortIds,
+        tree: &T,
+    ) -> Vec<Txid> {
+        let hash = cmpct.header.block_hash();
+
+        if !self.remaining.contains_key(&hash) {
+            return vec![];
+        }
+        let version = self
+            .peers
+            .get(from)
+            .and_then(|p| p.compact_blocks)
+            .unwrap_or(1);
+
+        if let Some(block) = self.reconstruct(&cmpct, version) {
+            log::debug!("Reconstructed compact block {} from {}", hash, from);
+
+            return self.received_block(from, block, tree);
+        }
+        log::debug!(
+            "Unable to reconstruct compact block {} from {}, requesting full block",
+            hash,
+            from
+        );
+        self.remaining.insert(hash, Some(self.clock.local_time()));
+        self.upstream.get_data(*from, vec![Inventory::Block(hash)]);
+        self.upstream.wakeup(REQUEST_TIMEOUT);
+
+        vec![]
+    }
+
+    /// Announce inventories to all matching peers. Retries if necessary.
+    pub fn announce(&mut self, tx: Transaction) -> Vec<PeerId> {
+        // All peers we are sending inventories to.
+        let mut addrs = Vec::new();
+
+        let txid = tx.txid();
+        let wtxid = tx.wtxid();
+
+        // Insert transaction into the peer outboxes and keep a local copy for re-broadcasting later.
+        self.mempool.insert(wtxid, tx);
+
+        for (addr, peer) in self.peers.iter_mut().filter(|(_, p)| p.relay) {
+            peer.outbox.insert(wtxid, txid);
+            addrs.push(*addr);
+        }
+        self.schedule_tick();
+
+        addrs
+    }
+
+    /// Attempt to get a block from the network. Retries if necessary.
+    pub fn get_block(&mut self, hash: BlockHash) {
+        log::debug!("Queueing block {hash} to be requested");
+
+        self.remaining.entry(hash).or_insert(None);
+        self.schedule_tick();
+    }
+
+    /// Pause block downloads. Queued blocks are no longer requested, and timed out
+    /// requests aren't retried, until [`InventoryManager::resume`] is called.
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    /// Resume block downloads, if they were paused.
+    pub fn resume(&mut self) {
+        if self.paused {
+            self.paused = false;
+            self.schedule_tick();
+        }
+    }
+
+    /// Check whether a block is queued or being downloaded.
+    pub fn is_requested(&self, hash: &BlockHash) -> bool {
+        self.remaining.contains_key(hash)
+    }
+
+    /// Cancel a block request. Returns `true` if the block was still queued or downloading.
+    pub fn cancel_block(&mut self, hash: &BlockHash) -> bool {
+        for peer in self.peers.values_mut() {
+            peer.requests.remove(hash);
+        }
+        self.requested.remove(hash);
+        self.remaining.remove(hash).is_some()
+    }
+
+    ////////////////////////////////////////////////////////////////////////////
+
+    /// Request queued blocks, keeping at most [`Config::block_download_concurrency`] requests
+    /// in flight. Requests are distributed round-robin across peers serving blocks, and
+    /// retried requests go to the peers least asked for the block.
+    fn request_blocks(&mut self, now: LocalTime) {
+        if self.paused {
+            return;
+        }
+        let inflight = self
+            .remaining
+            .values()
+            .filter(|t| matches!(t, Some(t) if now - *t < REQUEST_TIMEOUT))
+            .count();
+        let available = self
+            .config
+            .block_download_concurrency
+            .saturating_sub(inflight);
+
+        if available == 0 {
+            return;
+        }
+        let peers = self
+            .peers
+            .shuffled()
+            .filter(|(_, p)| p.services.has(ServiceFlags::NETWORK))
+            .map(|(addr, _)| *addr)
+            .collect::<Vec<_>>();
+
+        if peers.is_empty() {
+            if !self.remaining.is_empty() {
+                log::debug!("No peers with required services to request blocks from");
+            }
+            return;
+        }
+        let queue = self
+            .remaining
+            .iter_mut()
+            .filter(|(_, t)| now - t.unwrap_or_default() >= REQUEST_TIMEOUT)
+            .take(available);
+        let mut failed = Vec::new();
+
+        for (i, (block_hash, last_request)) in queue.enumerate() {
+            let attempt = match self.requested.get(block_hash) {
+                Some((_, retries)) if *retries >= self.config.block_request_retries => {
+                    failed.push(*block_hash);
+                    continue;
+                }
+                Some((_, retries)) => retries + 1,
+                None => 0,
+            };
+            // Starting from the next peer in the rotation, pick the first peer we've
+            // asked the least for this block.
+            let addr = (0..peers.len())
+                .map(|j| peers[(i + j) % peers.len()])
+                .min_by_key(|addr| {
+                    self.peers
+                        .get(addr)
+                        .and_then(|p| p.requests.get(block_hash).copied())
+                        .unwrap_or_default()
+                })
+                .expect("InventoryManager::request_blocks: there is at least one peer");
+
+            log::debug!("Requesting block {} from {}", block_hash, addr);
+
+            let compact = self.config.compact_blocks
+                && self
+                    .peers
+                    .get(&addr)
+                    .map_or(false, |p| p.compact_blocks.is_some());
+            let inv = if compact {
+                Inventory::CompactBlock(*block_hash)
+            } else {
+                Inventory::Block(*block_hash)
+            };
+            self.upstream.get_data(addr, vec![inv]);
+            self.upstream.wakeup(REQUEST_TIMEOUT);
+
+            if let Some(peer) = self.peers.get_mut(&addr) {
+                peer.requested(*block_hash);
+            }
+            self.requested.insert(*block_hash, (addr, attempt));
+            *last_request = Some(now);
+
+            self.upstream.event(Event::BlockDownloadStarted {
+                hash: *block_hash,
+                peer: addr,
+            });
+            if attempt > 0 {
+                self.upstream.event(Event::BlockDownloadRetried {
+                    hash: *block_hash,
+                    attempt,
+                });
+            }
+        }
+
+        for hash in failed {
+            log::debug!("Giving up on block {}", hash);
+
+            self.cancel_block(&hash);
+            self.upstream.event(Event::BlockDownloadFailed { hash });
+        }
+    }
+
+    /// Remove the acknowledged transactions that weren't confirmed or acknowledged again
+    /// within [`Config::drop_window`] blocks from the mempool.
+    fn drop_expired(&mut self) {
+        let (height, window) = (self.height, self.config.drop_window);
+        let expired = self
+            .last_seen
+            .iter()
+            .filter(|(_, seen)| height.saturating_sub(**seen) >= window)
+            .map(|(txid, seen)| (*txid, height - seen))
+            .collect::<Vec<_>>();
+
+        for (txid, blocks) in expired {
+            self.last_seen.remove(&txid);
+            self.acknowledged.remove(&txid);
+            self.announced.remove(&txid);
+            self.mempool.retain(|_, tx| tx.txid() != txid);
+
+            for peer in self.peers.values_mut() {
+                peer.outbox.retain(|_, t| *t != txid);
+            }
+            self.upstream.event(Event::Dropped { txid, blocks });
+        }
+    }
+
+    /// Process the received blocks in height order, once all requested blocks are
+    /// downloaded. Returns the list of confirmed [`Txid`].
+    fn process_received(&mut self) -> Vec<Txid> {
         let mut confirmed = Vec::new();
 
         while let Some((height, block)) = self
@@ -486,9 +1004,14 @@ impl<U: Wire<Event> + Wakeup, C: Clock> InventoryManager<U, C> {
             for tx in &block.txdata {
                 let wtxid = tx.wtxid();
 
+                self.remove_relayed(&wtxid);
+
                 // Attempt to remove confirmed transaction from mempool.
                 if let Some(transaction) = self.mempool.remove(&wtxid) {
                     confirmed.push(tx.txid());
+                    self.acknowledged.remove(&tx.txid());
+                    self.announced.remove(&tx.txid());
+                    self.last_seen.remove(&tx.txid());
 
                     // Transactions that have been confirmed no longer need to be announced.
                     for peer in self.peers.values_mut() {
@@ -506,6 +1029,38 @@ impl<U: Wire<Event> + Wakeup, C: Clock> InventoryManager<U, C> {
                         height,
                     });
                 }
+
+                // Remove transactions from the mempool that spend any of the same inputs,
+                // since they can no longer be confirmed.
+                let replaced = self
+                    .mempool
+                    .iter()
+                    .filter(|(_, t)| {
+                        t.txid() != tx.txid()
+                            && t.input.iter().any(|i| {
+                                tx.input
+                                    .iter()
+                                    .any(|j| i.previous_output == j.previous_output)
+                            })
+                    })
+                    .map(|(wtxid, t)| (*wtxid, t.txid()))
+                    .collect::<Vec<_>>();
+
+                for (wtxid, txid) in replaced {
+                    self.mempool.remove(&wtxid);
+                    self.acknowledged.remove(&txid);
+                    self.announced.remove(&txid);
+                    self.last_seen.remove(&txid);
+
+                    for peer in self.peers.values_mut() {
+                        peer.outbox.remove(&wtxid);
+                    }
+                    self.upstream.event(Event::Replaced {
+                        txid,
+                        replaced_by: tx.txid(),
+                        block: hash,
+                    });
+                }
             }
             // Process block through fee estimator.
             let fees = self.estimator.process(block.clone(), height);
@@ -519,36 +1074,90 @@ impl<U: Wire<Event> + Wakeup, C: Clock> InventoryManager<U, C> {
         confirmed
     }
 
-    /// Announce inventories to all matching peers. Retries if necessary.
-    pub fn announce(&mut self, tx: Transaction) -> Vec<PeerId> {
-        // All peers we are sending inventories to.
-        let mut addrs = Vec::new();
+    /// Request the transactions announced by a peer that we don't know of, if they are kept
+    /// to reconstruct compact blocks.
+    fn request_relayed(&mut self, addr: PeerId, invs: &[Inventory]) {
+        if !(self.config.compact_blocks && self.config.relay_transactions) {
+            return;
+        }
+        let now = self.clock.local_time();
+        let mut request = Vec::new();
 
-        let txid = tx.txid();
-        let wtxid = tx.wtxid();
+        for inv in invs {
+            let known = match inv {
+                Inventory::Transaction(txid) => {
+                    self.relayed_txids.contains(txid)
+                        || self.mempool.values().any(|tx| tx.txid() == *txid)
+                }
+                Inventory::WTx(wtxid) => {
+                    self.relayed.contains_key(wtxid) || self.mempool.contains_key(wtxid)
+                }
+                _ => continue,
+            };
+            if known || self.tx_requests.contains_key(inv) {
+                continue;
+            }
+            self.tx_requests.insert(*inv, now);
+            request.push(*inv);
+        }
+        if !request.is_empty() {
+            self.upstream.get_data(addr, request);
+        }
+    }
 
-        // Insert transaction into the peer outboxes and keep a local copy for re-broadcasting later.
-        self.mempool.insert(wtxid, tx);
+    /// Remove a relayed transaction, eg. once it is confirmed.
+    fn remove_relayed(&mut self, wtxid: &Wtxid) {
+        if let Some(tx) = self.relayed.remove(wtxid) {
+            self.relayed_txids.remove(&tx.txid());
+        }
+    }
 
-        for (addr, peer) in self.peers.iter_mut().filter(|(_, p)| p.relay) {
-            peer.outbox.insert(wtxid, txid);
-            addrs.push(*addr);
+    /// Reconstruct a block from a compact block of the given version, and the transactions
+    /// in the mempool or relayed by peers. Returns `None` if any of the block transactions
+    /// are missing.
+    fn reconstruct(&self, cmpct: &HeaderAndShortIds, version: u64) -> Option<Block> {
+        let keys = ShortId::calculate_siphash_keys(&cmpct.header, cmpct.nonce);
+        let known = self
+            .mempool
+            .values()
+            .chain(self.relayed.values())
+            .map(|tx| {
+                let short_id = if version == 2 {
+                    ShortId::with_siphash_keys(&tx.wtxid(), keys)
+                } else {
+                    ShortId::with_siphash_keys(&tx.txid(), keys)
+                };
+                (short_id, tx)
+            })
+            .collect::<std::collections::HashMap<_, _>>();
+        let mut txdata: Vec<Option<Transaction>> =
+            vec![None; cmpct.short_ids.len() + cmpct.prefilled_txs.len()];
+
+        // Prefilled transaction indexes are differentially encoded.
+        let mut index = 0;
+        for (i, prefilled) in cmpct.prefilled_txs.iter().enumerate() {
+            index += prefilled.idx as usize + if i > 0 { 1 } else { 0 };
+            *txdata.get_mut(index)? = Some(prefilled.tx.clone());
         }
-        self.schedule_tick();
 
-        addrs
-    }
+        let mut short_ids = cmpct.short_ids.iter();
+        for slot in txdata.iter_mut().filter(|tx| tx.is_none()) {
+            let tx = known.get(short_ids.next()?)?;
 
-    /// Attempt to get a block from the network. Retries if necessary.
-    pub fn get_block(&mut self, hash: BlockHash) {
-        log::debug!("Queueing block {hash} to be requested");
+            *slot = Some((*tx).clone());
+        }
 
-        self.remaining.entry(hash).or_insert(None);
-        self.schedule_tick();
+        let block = Block {
+            header: cmpct.header,
+            txdata: txdata.into_iter().collect::<Option<Vec<_>>>()?,
+        };
+        // Guard against short id collisions.
+        if !block.check_merkle_root() {
+            return None;
+        }
+        Some(block)
     }
 
-    ////////////////////////////////////////////////////////////////////////////
-
     fn schedule_tick(&mut self) {
         self.last_tick = None; // Disable rate-limiting for the next tick.
         self.upstream.wakeup(LocalDuration::from_secs(1));
@@ -601,7 +1210,12 @@ mod tests {
         let inv = vec![Inventory::Block(hash)];
         let block = chain.iter().find(|b| b.block_hash() == hash).unwrap();
 
-        let mut invmgr = InventoryManager::new(rng.clone(), upstream.clone(), clock.clone());
+        let mut invmgr = InventoryManager::new(
+            Config::default(),
+            rng.clone(),
+            upstream.clone(),
+            clock.clone(),
+        );
 
         invmgr.peer_negotiated(
             Socket::new(([66, 66, 66, 66], 8333)),
@@ -674,6 +1288,367 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_block_download_started() {
+        let network = Network::Regtest;
+        let mut upstream = Outbox::new(network, PROTOCOL_VERSION);
+        let mut rng = fastrand::Rng::new();
+        let clock = RefClock::from(LocalTime::now());
+
+        let genesis = network.genesis_block();
+        let chain = gen::blockchain(genesis, 8, &mut rng);
+        let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+        let tree = model::Cache::from(headers);
+        let hash = tree.get_block_by_height(4).unwrap().block_hash();
+        let remote: PeerId = ([66, 66, 66, 66], 8333).into();
+
+        let mut invmgr = InventoryManager::new(
+            Config::default(),
+            rng.clone(),
+            upstream.clone(),
+            clock.clone(),
+        );
+        invmgr.peer_negotiated(Socket::new(remote), ServiceFlags::NETWORK, true, true);
+        invmgr.get_block(hash);
+        invmgr.received_wake(&tree);
+
+        events(upstream.drain())
+            .find(|e| {
+                matches!(
+                    e,
+                    Event::BlockDownloadStarted { hash: h, peer } if *h == hash && *peer == remote
+                )
+            })
+            .expect("An event is emitted when a block is requested");
+    }
+
+    #[test]
+    fn test_pause_resume() {
+        let network = Network::Regtest;
+        let mut upstream = Outbox::new(network, PROTOCOL_VERSION);
+        let mut rng = fastrand::Rng::new();
+        let clock = RefClock::from(LocalTime::now());
+
+        let genesis = network.genesis_block();
+        let chain = gen::blockchain(genesis, 8, &mut rng);
+        let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+        let tree = model::Cache::from(headers);
+        let hash = tree.get_block_by_height(4).unwrap().block_hash();
+        let remote: PeerId = ([66, 66, 66, 66], 8333).into();
+
+        let mut invmgr = InventoryManager::new(
+            Config::default(),
+            rng.clone(),
+            upstream.clone(),
+            clock.clone(),
+        );
+        invmgr.peer_negotiated(Socket::new(remote), ServiceFlags::NETWORK, true, true);
+        invmgr.pause();
+        invmgr.get_block(hash);
+        invmgr.received_wake(&tree);
+
+        assert!(
+            !output::test::messages(&mut upstream)
+                .any(|(_, m)| matches!(m, NetworkMessage::GetData(_))),
+            "Blocks aren't requested while paused"
+        );
+        assert!(invmgr.remaining.contains_key(&hash));
+
+        invmgr.resume();
+        invmgr.received_wake(&tree);
+
+        output::test::messages(&mut upstream)
+            .find(|(addr, m)| {
+                *addr == remote
+                    && matches!(m, NetworkMessage::GetData(invs) if invs == &[Inventory::Block(hash)])
+            })
+            .expect("The block is requested once resumed");
+    }
+
+    #[test]
+    fn test_block_download_concurrency() {
+        let network = Network::Regtest;
+        let mut upstream = Outbox::new(network, PROTOCOL_VERSION);
+        let mut rng = fastrand::Rng::new();
+        let clock = RefClock::from(LocalTime::now());
+
+        let genesis = network.genesis_block();
+        let chain = gen::blockchain(genesis, 16, &mut rng);
+        let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+        let tree = model::Cache::from(headers);
+        let config = Config {
+            block_download_concurrency: 3,
+            ..Config::default()
+        };
+        let mut invmgr = InventoryManager::new(config, rng.clone(), upstream.clone(), clock);
+
+        for ip in [66u8, 77, 88] {
+            invmgr.peer_negotiated(
+                Socket::new(([ip, ip, ip, ip], 8333)),
+                ServiceFlags::NETWORK,
+                true,
+                true,
+            );
+        }
+        for block in chain.tail.iter() {
+            invmgr.get_block(block.block_hash());
+        }
+        invmgr.received_wake(&tree);
+
+        let requests = output::test::messages(&mut upstream)
+            .filter_map(|(addr, m)| match m {
+                NetworkMessage::GetData(invs) => Some((addr, invs)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let peers = requests
+            .iter()
+            .map(|(addr, _)| *addr)
+            .collect::<std::collections::HashSet<_>>();
+
+        assert_eq!(
+            requests.len(),
+            3,
+            "Only three blocks are requested at a time"
+        );
+        assert_eq!(peers.len(), 3, "Requests are spread across peers");
+
+        // Once a block is received, the next one is requested.
+        let (addr, invs) = &requests[0];
+        let block = chain
+            .iter()
+            .find(|b| invs == &vec![Inventory::Block(b.block_hash())])
+            .unwrap();
+        invmgr.received_block(addr, block.clone(), &tree);
+
+        assert_eq!(
+            output::test::messages(&mut upstream)
+                .filter(|(_, m)| matches!(m, NetworkMessage::GetData(_)))
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_block_download_retries() {
+        let network = Network::Regtest;
+        let mut upstream = Outbox::new(network, PROTOCOL_VERSION);
+        let mut rng = fastrand::Rng::new();
+        let clock = RefClock::from(LocalTime::now());
+
+        let genesis = network.genesis_block();
+        let chain = gen::blockchain(genesis, 8, &mut rng);
+        let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+        let tree = model::Cache::from(headers);
+        let config = Config {
+            block_request_retries: 1,
+            ..Config::default()
+        };
+        let mut invmgr =
+            InventoryManager::new(config, rng.clone(), upstream.clone(), clock.clone());
+
+        for ip in [66u8, 77, 88] {
+            invmgr.peer_negotiated(
+                Socket::new(([ip, ip, ip, ip], 8333)),
+                ServiceFlags::NETWORK,
+                true,
+                true,
+            );
+        }
+        let requested = |upstream: &mut Outbox| {
+            output::test::messages(upstream)
+                .filter_map(|(addr, m)| match m {
+                    NetworkMessage::GetData(_) => Some(addr),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // The block times out, and is requested from another peer.
+        let hash = tree.get_block_by_height(4).unwrap().block_hash();
+        invmgr.get_block(hash);
+        invmgr.received_wake(&tree);
+        let first = requested(&mut upstream);
+        assert_eq!(first.len(), 1);
+
+        clock.elapse(REQUEST_TIMEOUT);
+        invmgr.received_wake(&tree);
+        let second = requested(&mut upstream);
+        assert_eq!(second.len(), 1);
+        assert_ne!(first, second, "The block is requested from another peer");
+        events(upstream.drain())
+            .find(
+                |e| matches!(e, Event::BlockDownloadRetried { hash: h, attempt: 1 } if *h == hash),
+            )
+            .expect("An event is emitted when a block request is retried");
+
+        // Once the retries are exhausted, the block is dropped.
+        clock.elapse(REQUEST_TIMEOUT);
+        invmgr.received_wake(&tree);
+        assert!(requested(&mut upstream).is_empty());
+        assert!(invmgr.remaining.is_empty());
+        events(upstream.drain())
+            .find(|e| matches!(e, Event::BlockDownloadFailed { hash: h } if *h == hash))
+            .expect("An event is emitted when a block download fails");
+
+        // If the peer disconnects, the block is requested again right away.
+        let hash = tree.get_block_by_height(5).unwrap().block_hash();
+        invmgr.get_block(hash);
+        invmgr.received_wake(&tree);
+        let first = requested(&mut upstream);
+        assert_eq!(first.len(), 1);
+
+        invmgr.peer_disconnected(&first[0]);
+        invmgr.received_wake(&tree);
+        let second = requested(&mut upstream);
+        assert_eq!(second.len(), 1);
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn test_tx_announced() {
+        let network = Network::Mainnet;
+        let mut upstream = Outbox::new(network, PROTOCOL_VERSION);
+        let remote1: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
+        let remote2: net::SocketAddr = ([99, 99, 99, 99], 8333).into();
+        let mut rng = fastrand::Rng::new();
+        let tx = gen::transaction(&mut rng);
+        let other = gen::transaction(&mut rng);
+
+        let mut invmgr =
+            InventoryManager::new(Config::default(), rng, upstream.clone(), LocalTime::now());
+
+        invmgr.peer_negotiated(remote1.into(), ServiceFlags::NETWORK, true, false);
+        invmgr.peer_negotiated(remote2.into(), ServiceFlags::NETWORK, true, true);
+        invmgr.announce(tx.clone());
+        upstream.drain().for_each(drop);
+
+        invmgr.received_inv(remote1, &[Inventory::Transaction(other.txid())]);
+        assert_eq!(
+            events(upstream.drain()).count(),
+            0,
+            "Unknown txs are ignored"
+        );
+
+        invmgr.received_inv(remote1, &[Inventory::Transaction(tx.txid())]);
+        invmgr.received_inv(remote1, &[Inventory::Transaction(tx.txid())]);
+        invmgr.received_inv(remote2, &[Inventory::WTx(tx.wtxid())]);
+
+        let announced = events(upstream.drain())
+            .filter_map(|e| match e {
+                Event::TxAnnounced { txid, peer } => Some((txid, peer)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            announced,
+            vec![(tx.txid(), remote1), (tx.txid(), remote2)],
+            "An event is emitted once per peer"
+        );
+
+        // Announcements are forgotten when peers disconnect.
+        invmgr.peer_disconnected(&remote1);
+        assert_eq!(invmgr.announced[&tx.txid()].len(), 1);
+
+        invmgr.peer_disconnected(&remote2);
+        assert!(invmgr.announced.is_empty());
+    }
+
+    #[test]
+    fn test_tx_dropped() {
+        let network = Network::Mainnet;
+        let mut upstream = Outbox::new(network, PROTOCOL_VERSION);
+        let remote: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
+        let mut rng = fastrand::Rng::new();
+        let tx = gen::transaction(&mut rng);
+        let chain = gen::blockchain(network.genesis_block(), 8, &mut rng);
+        let tree = |height: usize| {
+            model::Cache::from(
+                NonEmpty::from_vec(chain[..=height].iter().map(|b| b.header).collect()).unwrap(),
+            )
+        };
+
+        let mut invmgr = InventoryManager::new(
+            Config {
+                drop_window: 3,
+                ..Config::default()
+            },
+            rng,
+            upstream.clone(),
+            LocalTime::now(),
+        );
+        invmgr.initialize(&tree(3));
+        invmgr.peer_negotiated(remote.into(), ServiceFlags::NETWORK, true, true);
+        invmgr.announce(tx.clone());
+        invmgr.received_inv(remote, &[Inventory::WTx(tx.wtxid())]);
+        upstream.drain().for_each(drop);
+
+        invmgr.received_wake(&tree(5));
+        assert!(!invmgr.is_empty());
+        assert!(!events(upstream.drain()).any(|e| matches!(e, Event::Dropped { .. })));
+
+        invmgr.received_wake(&tree(6));
+        assert!(
+            invmgr.is_empty(),
+            "The transaction is removed from the mempool"
+        );
+        assert_eq!(
+            events(upstream.drain())
+                .filter_map(|e| match e {
+                    Event::Dropped { txid, blocks } => Some((txid, blocks)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>(),
+            vec![(tx.txid(), 3)]
+        );
+    }
+
+    #[test]
+    fn test_tx_replaced() {
+        let network = Network::Regtest;
+        let remote: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
+        let mut rng = fastrand::Rng::new();
+        let mut upstream = Outbox::new(network, PROTOCOL_VERSION);
+
+        let mut chain = gen::blockchain(network.genesis_block(), 4, &mut rng);
+        let tx = gen::transaction(&mut rng);
+        let mut conflict = gen::transaction(&mut rng);
+        conflict.input[0].previous_output = tx.input[0].previous_output;
+
+        let block = gen::block_with(&chain.last().header, vec![conflict.clone()], &mut rng);
+        chain.push(block.clone());
+
+        let tree = model::Cache::from(
+            NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap(),
+        );
+        let mut invmgr =
+            InventoryManager::new(Config::default(), rng, upstream.clone(), LocalTime::now());
+
+        invmgr.peer_negotiated(remote.into(), ServiceFlags::NETWORK, true, false);
+        invmgr.announce(tx.clone());
+        invmgr.get_block(block.block_hash());
+        invmgr.received_block(&remote, block.clone(), &tree);
+
+        assert!(
+            !invmgr.contains(&tx.wtxid()),
+            "The replaced transaction is removed from the mempool"
+        );
+        assert_eq!(
+            events(upstream.drain())
+                .filter_map(|e| match e {
+                    Event::Replaced {
+                        txid,
+                        replaced_by,
+                        block,
+                    } => Some((txid, replaced_by, block)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>(),
+            vec![(tx.txid(), conflict.txid(), block.block_hash())]
+        );
+    }
+
     #[test]
     fn test_rebroadcast_timeout() {
         let network = Network::Mainnet;
@@ -685,7 +1660,8 @@ mod tests {
         let clock = RefClock::from(LocalTime::now());
         let tx = gen::transaction(&mut rng);
 
-        let mut invmgr = InventoryManager::new(rng, upstream.clone(), clock.clone());
+        let mut invmgr =
+            InventoryManager::new(Config::default(), rng, upstream.clone(), clock.clone());
 
         invmgr.peer_negotiated(remote.into(), ServiceFlags::NETWORK, true, false);
         invmgr.announce(tx);
@@ -726,7 +1702,8 @@ mod tests {
         let remote: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
         let tx = gen::transaction(&mut rng);
 
-        let mut invmgr = InventoryManager::new(rng, upstream.clone(), clock.clone());
+        let mut invmgr =
+            InventoryManager::new(Config::default(), rng, upstream.clone(), clock.clone());
 
         invmgr.peer_negotiated(remote.into(), ServiceFlags::NETWORK, true, false);
         invmgr.announce(tx.clone());
@@ -751,6 +1728,159 @@ mod tests {
         assert!(invmgr.peers.is_empty());
     }
 
+    #[test]
+    fn test_compact_blocks() {
+        let network = Network::Regtest;
+        let mut upstream = Outbox::new(network, PROTOCOL_VERSION);
+        let mut rng = fastrand::Rng::new();
+        let clock = RefClock::from(LocalTime::now());
+        let remote: PeerId = ([66, 66, 66, 66], 8333).into();
+
+        let mut chain = gen::blockchain(network.genesis_block(), 8, &mut rng);
+        let tip = chain.last().header;
+        let tx = gen::transaction(&mut rng);
+        let unknown = gen::transaction(&mut rng);
+        let known = gen::block_with(&tip, vec![tx.clone()], &mut rng);
+        let missing = gen::block_with(&known.header, vec![unknown], &mut rng);
+
+        chain.push(known.clone());
+        chain.push(missing.clone());
+
+        let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+        let tree = model::Cache::from(headers);
+
+        let mut invmgr = InventoryManager::new(
+            Config {
+                compact_blocks: true,
+                ..Config::default()
+            },
+            rng.clone(),
+            upstream.clone(),
+            clock.clone(),
+        );
+        invmgr.peer_negotiated(Socket::new(remote), ServiceFlags::NETWORK, true, true);
+        invmgr.received_sendcmpct(
+            &remote,
+            &SendCmpct {
+                send_compact: false,
+                version: 1,
+            },
+        );
+        invmgr.announce(tx.clone());
+        invmgr.get_block(known.block_hash());
+        invmgr.get_block(missing.block_hash());
+        invmgr.received_wake(&tree);
+
+        let requested = output::test::messages_from(&mut upstream, &remote)
+            .filter_map(|m| match m {
+                NetworkMessage::GetData(invs) => Some(invs),
+                _ => None,
+            })
+            .flatten()
+            .collect::<Vec<_>>();
+        assert!(requested.contains(&Inventory::CompactBlock(known.block_hash())));
+        assert!(requested.contains(&Inventory::CompactBlock(missing.block_hash())));
+
+        // The block containing an unknown transaction can't be reconstructed.
+        let cmpct = HeaderAndShortIds::from_block(&missing, 42, 1, &[]).unwrap();
+        invmgr.received_cmpctblock(&remote, cmpct, &tree);
+
+        output::test::messages_from(&mut upstream, &remote)
+            .find(|m| {
+                matches!(
+                    m,
+                    NetworkMessage::GetData(invs)
+                    if invs == &vec![Inventory::Block(missing.block_hash())]
+                )
+            })
+            .expect("The full block is requested as a fallback");
+
+        // The block containing our transaction is reconstructed from the mempool.
+        let cmpct = HeaderAndShortIds::from_block(&known, 42, 1, &[]).unwrap();
+        invmgr.received_cmpctblock(&remote, cmpct, &tree);
+        invmgr.received_block(&remote, missing, &tree);
+
+        assert!(invmgr.remaining.is_empty());
+        assert!(!invmgr.contains(&tx.wtxid()));
+    }
+
+    #[test]
+    fn test_compact_blocks_relayed() {
+        let network = Network::Regtest;
+        let mut upstream = Outbox::new(network, PROTOCOL_VERSION);
+        let mut rng = fastrand::Rng::new();
+        let clock = RefClock::from(LocalTime::now());
+        let remote1: PeerId = ([66, 66, 66, 66], 8333).into();
+        let remote2: PeerId = ([77, 77, 77, 77], 8333).into();
+
+        let mut chain = gen::blockchain(network.genesis_block(), 8, &mut rng);
+        let tip = chain.last().header;
+        let tx = gen::transaction(&mut rng);
+        let block = gen::block_with(&tip, vec![tx.clone()], &mut rng);
+
+        chain.push(block.clone());
+
+        let headers = NonEmpty::from_vec(chain.iter().map(|b| b.header).collect()).unwrap();
+        let tree = model::Cache::from(headers);
+
+        let mut invmgr = InventoryManager::new(
+            Config {
+                compact_blocks: true,
+                relay_transactions: true,
+                ..Config::default()
+            },
+            rng.clone(),
+            upstream.clone(),
+            clock.clone(),
+        );
+        for remote in [remote1, remote2] {
+            invmgr.peer_negotiated(Socket::new(remote), ServiceFlags::NETWORK, true, true);
+        }
+        for version in COMPACT_BLOCKS_VERSIONS {
+            invmgr.received_sendcmpct(
+                &remote1,
+                &SendCmpct {
+                    send_compact: false,
+                    version,
+                },
+            );
+        }
+        assert_eq!(invmgr.peers.get(&remote1).unwrap().compact_blocks, Some(2));
+
+        // Announced transactions are only requested once.
+        invmgr.received_inv(remote1, &[Inventory::WTx(tx.wtxid())]);
+        invmgr.received_inv(remote2, &[Inventory::WTx(tx.wtxid())]);
+
+        output::test::messages_from(&mut upstream, &remote1)
+            .find(|m| {
+                matches!(
+                    m,
+                    NetworkMessage::GetData(invs) if invs == &vec![Inventory::WTx(tx.wtxid())]
+                )
+            })
+            .expect("The announced transaction is requested");
+        assert_eq!(
+            output::test::messages_from(&mut upstream, &remote2)
+                .filter(|m| matches!(m, NetworkMessage::GetData(_)))
+                .count(),
+            0
+        );
+
+        invmgr.received_tx(&remote1, tx.clone());
+        assert!(invmgr.relayed.contains_key(&tx.wtxid()));
+
+        // The block is reconstructed from the relayed transaction, using witness short ids.
+        invmgr.get_block(block.block_hash());
+        invmgr.received_wake(&tree);
+
+        let cmpct = HeaderAndShortIds::from_block(&block, 42, 2, &[]).unwrap();
+        invmgr.received_cmpctblock(&remote1, cmpct, &tree);
+
+        assert!(invmgr.remaining.is_empty());
+        assert!(invmgr.relayed.is_empty());
+        assert!(invmgr.relayed_txids.is_empty());
+    }
+
     #[test]
     fn test_block_reverted() {
         let network = Network::Regtest;
@@ -774,7 +1904,7 @@ mod tests {
         let time = LocalTime::now();
 
         let mut tree = model::Cache::from(headers);
-        let mut invmgr = InventoryManager::new(rng, upstream.clone(), time);
+        let mut invmgr = InventoryManager::new(Config::default(), rng, upstream.clone(), time);
 
         invmgr.peer_negotiated(remote.into(), ServiceFlags::NETWORK, true, false);
         invmgr.announce(tx.clone());
@@ -838,7 +1968,7 @@ mod tests {
         let remote2: net::SocketAddr = ([88, 88, 88, 89], 8333).into();
         let tx = gen::transaction(&mut rng);
 
-        let mut invmgr = InventoryManager::new(rng, upstream.clone(), time);
+        let mut invmgr = InventoryManager::new(Config::default(), rng, upstream.clone(), time);
 
         invmgr.peer_negotiated(remote.into(), ServiceFlags::NETWORK, true, true);
         invmgr.announce(tx);
@@ -878,7 +2008,8 @@ mod tests {
         let remote: net::SocketAddr = ([88, 88, 88, 88], 8333).into();
         let tx = gen::transaction(&mut rng);
 
-        let mut invmgr = InventoryManager::new(rng, upstream.clone(), LocalTime::now());
+        let mut invmgr =
+            InventoryManager::new(Config::default(), rng, upstream.clone(), LocalTime::now());
 
         invmgr.peer_negotiated(remote.into(), ServiceFlags::NETWORK, true, true);
         invmgr.announce(tx.clone());
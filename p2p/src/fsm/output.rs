@@ -16,6 +16,7 @@ pub use crossbeam_channel as chan;
 use nakamoto_common::bitcoin::network::address::Address;
 use nakamoto_common::bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
 use nakamoto_common::bitcoin::network::message_blockdata::{GetHeadersMessage, Inventory};
+use nakamoto_common::bitcoin::network::message_compact_blocks::SendCmpct;
 use nakamoto_common::bitcoin::network::message_filter::{
     CFHeaders, CFilter, GetCFHeaders, GetCFilters,
 };
@@ -75,6 +76,9 @@ pub trait Wire<E> {
     /// Send a `sendheaders` message.
     fn send_headers(&mut self, addr: PeerId) -> &mut Self;
 
+    /// Send a BIP-152 `sendcmpct` message, in low-bandwidth mode.
+    fn send_cmpct(&mut self, addr: PeerId, version: u64) -> &mut Self;
+
     // Ping/pong ///////////////////////////////////////////////////////////////
 
     /// Send a `ping` message.
@@ -267,6 +271,17 @@ impl<E: Into<Event> + std::fmt::Display> Wire<E> for Outbox {
         self
     }
 
+    fn send_cmpct(&mut self, addr: PeerId, version: u64) -> &mut Self {
+        self.message(
+            addr,
+            NetworkMessage::SendCmpct(SendCmpct {
+                send_compact: false,
+                version,
+            }),
+        );
+        self
+    }
+
     fn ping(&mut self, addr: net::SocketAddr, nonce: u64) -> &Self {
         self.message(addr, NetworkMessage::Ping(nonce));
         self
@@ -389,6 +404,9 @@ impl<E> Wire<E> for () {
     fn send_headers(&mut self, addr: PeerId) -> &mut Self {
         self
     }
+    fn send_cmpct(&mut self, addr: PeerId, version: u64) -> &mut Self {
+        self
+    }
     fn get_cfilters(
         &mut self,
         addr: PeerId,
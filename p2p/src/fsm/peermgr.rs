@@ -14,6 +14,7 @@
 //!   3. Send `verack` message.
 //!   4. Expect `verack` message from remote.
 //!
+use std::borrow::Cow;
 use std::net;
 use std::sync::Arc;
 
@@ -31,6 +32,7 @@ use nakamoto_common::source;
 use nakamoto_net as network;
 
 use crate::fsm::addrmgr;
+use crate::fsm::invmgr;
 use crate::fsm::DisconnectReason;
 
 use super::output::{Connect, Disconnect, Wakeup, Wire};
@@ -38,8 +40,7 @@ use super::{Hooks, Link, PeerId, Socket, Whitelist};
 
 /// Time to wait for response during peer handshake before disconnecting the peer.
 pub const HANDSHAKE_TIMEOUT: LocalDuration = LocalDuration::from_secs(12);
-/// Time to wait for a new connection.
-/// TODO: Should be in config.
+/// Default time to wait for a new connection.
 pub const CONNECTION_TIMEOUT: LocalDuration = LocalDuration::from_secs(6);
 /// Time to wait until idle.
 pub const IDLE_TIMEOUT: LocalDuration = LocalDuration::from_mins(1);
@@ -47,6 +48,12 @@ pub const IDLE_TIMEOUT: LocalDuration = LocalDuration::from_mins(1);
 pub const TARGET_OUTBOUND_PEERS: usize = 8;
 /// Maximum number of inbound peer connections.
 pub const MAX_INBOUND_PEERS: usize = 16;
+/// Misbehavior score at which a peer's IP address is banned.
+pub const MISBEHAVIOR_BAN_THRESHOLD: u32 = 3;
+
+/// Maximum fraction of a reconnection delay that is randomly shaved off, to avoid
+/// reconnecting to all peers at the same time.
+const RETRY_JITTER: f64 = 0.2;
 
 /// Maximum height difference for a stale peer, to maintain the connection (2 weeks).
 const MAX_STALE_HEIGHT_DIFFERENCE: Height = 2016;
@@ -89,6 +96,18 @@ pub enum Event {
     Connected(PeerId, Link),
     /// A peer has been disconnected.
     Disconnected(PeerId, network::DisconnectReason<DisconnectReason>),
+    /// A peer was disconnected for misbehaving. Once the misbehavior score of its address
+    /// reaches [`MISBEHAVIOR_BAN_THRESHOLD`], the address is banned.
+    Misbehaved {
+        /// The peer's id.
+        addr: PeerId,
+        /// Accumulated misbehavior score of the peer's address.
+        score: u32,
+        /// Reason for the misbehavior.
+        reason: &'static str,
+        /// Whether the peer's address is banned.
+        banned: bool,
+    },
 }
 
 impl std::fmt::Display for Event {
@@ -123,10 +142,61 @@ impl std::fmt::Display for Event {
             Self::Disconnected(addr, reason) => {
                 write!(fmt, "Disconnected from {} ({})", &addr, reason)
             }
+            Self::Misbehaved {
+                addr,
+                score,
+                reason,
+                banned,
+            } => {
+                write!(
+                    fmt,
+                    "{}: Peer misbehaved (score = {}): {}",
+                    &addr, score, reason
+                )?;
+                if *banned {
+                    write!(fmt, ", banning")?;
+                }
+                Ok(())
+            }
         }
     }
 }
 
+/// Exponential backoff policy, used when reconnecting to peers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    /// Delay before the first reconnection attempt.
+    pub initial: LocalDuration,
+    /// Maximum delay between reconnection attempts.
+    pub max: LocalDuration,
+    /// Factor by which the delay grows after each failed attempt.
+    pub multiplier: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: LocalDuration::from_secs(1),
+            max: LocalDuration::from_mins(60),
+            multiplier: 2.,
+        }
+    }
+}
+
+impl Backoff {
+    /// Get the delay before the next attempt, given the number of failed attempts so far.
+    /// The delay is never less than the initial delay, or more than the maximum delay.
+    pub fn delay(&self, attempts: u32) -> LocalDuration {
+        let initial = self.initial.as_millis() as f64;
+        let max = self.max.as_millis() as f64;
+        let delay = initial * self.multiplier.powi(attempts.min(i32::MAX as u32) as i32);
+
+        LocalDuration::from_millis(delay.min(max) as u128)
+            .max(self.initial)
+            .min(self.max)
+    }
+}
+
 /// Peer manager configuration.
 #[derive(Debug, Clone)]
 pub struct Config {
@@ -147,12 +217,18 @@ pub struct Config {
     pub target_outbound_peers: usize,
     /// Maximum number of inbound peer connections.
     pub max_inbound_peers: usize,
-    /// Maximum time to wait between reconnection attempts.
-    pub retry_max_wait: LocalDuration,
-    /// Minimum time to wait between reconnection attempts.
-    pub retry_min_wait: LocalDuration,
+    /// Backoff policy for reconnection attempts to persistent peers.
+    pub retry_backoff: Backoff,
+    /// Time to wait for an outbound connection to be established.
+    pub connect_timeout: LocalDuration,
     /// Our user agent.
-    pub user_agent: &'static str,
+    pub user_agent: Cow<'static, str>,
+    /// Whether we want peers to announce transactions to us.
+    pub relay: bool,
+    /// Whether we want peers to announce new blocks with `headers` messages.
+    pub send_headers: bool,
+    /// Whether we want to receive `BIP152` compact blocks.
+    pub compact_blocks: bool,
     /// Supported communication domains.
     pub domains: Vec<Domain>,
 }
@@ -241,11 +317,17 @@ pub struct PeerManager<U, C> {
 
     retry_at: HashMap<net::SocketAddr, LocalTime>,
     retry_attempts: HashMap<net::SocketAddr, u32>,
+    /// Misbehavior scores, by peer IP.
+    misbehavior: HashMap<net::IpAddr, u32>,
+    /// IPs banned for misbehaving. Connections to and from these are refused.
+    banned: HashSet<net::IpAddr>,
 
     /// Last time we were idle.
     last_idle: Option<LocalTime>,
     /// Connection states.
     peers: HashMap<net::SocketAddr, Peer>,
+    /// Peers being disconnected because the connection attempt timed out.
+    timed_out: HashSet<net::SocketAddr>,
     upstream: U,
     rng: fastrand::Rng,
     hooks: Hooks,
@@ -261,8 +343,11 @@ impl<U: Wire<Event> + Wakeup + Connect + Disconnect, C: Clock> PeerManager<U, C>
             config,
             retry_at: HashMap::with_hasher(rng.clone().into()),
             retry_attempts: HashMap::with_hasher(rng.clone().into()),
+            misbehavior: HashMap::with_hasher(rng.clone().into()),
+            banned: HashSet::with_hasher(rng.clone().into()),
             last_idle: None,
             peers,
+            timed_out: HashSet::with_hasher(rng.clone().into()),
             upstream,
             rng,
             hooks,
@@ -295,8 +380,12 @@ impl<U: Wire<Event> + Wakeup + Connect + Disconnect, C: Clock> PeerManager<U, C>
 
     fn retrier_add_peer(&mut self, addr: &net::SocketAddr, local_time: LocalTime) {
         let attempts = self.retry_attempts.entry(*addr).or_default();
-        let delay = LocalDuration::from_secs(2_u64.saturating_pow(*attempts))
-            .clamp(self.config.retry_min_wait, self.config.retry_max_wait);
+        let delay = self.config.retry_backoff.delay(*attempts);
+        // Shave a random fraction off the delay, so that peers disconnected at the same
+        // time aren't all retried at the same time.
+        let jitter = (delay.as_millis() as f64 * RETRY_JITTER * self.rng.f64()) as u128;
+        let delay = LocalDuration::from_millis(delay.as_millis() - jitter);
+
         self.retry_at.insert(*addr, local_time + delay);
         self.upstream.wakeup(delay);
         *attempts += 1;
@@ -361,10 +450,12 @@ impl<U: Wire<Event> + Wakeup + Connect + Disconnect, C: Clock> PeerManager<U, C>
 
         match link {
             Link::Inbound => {
-                if self.connected().filter(|c| c.link.is_inbound()).count()
-                    >= self.config.max_inbound_peers
+                // Nb. The newly connected peer is included in the count.
+                if self.banned.contains(&addr.ip()) {
+                    self._disconnect(addr, DisconnectReason::PeerBanned);
+                } else if self.connected().filter(|c| c.link.is_inbound()).count()
+                    > self.config.max_inbound_peers
                 {
-                    // TODO: Test this branch.
                     // Don't allow inbound connections beyond the configured limit.
                     self._disconnect(addr, DisconnectReason::ConnectionLimit);
                 } else {
@@ -396,7 +487,34 @@ impl<U: Wire<Event> + Wakeup + Connect + Disconnect, C: Clock> PeerManager<U, C>
         debug_assert!(self.peers.contains_key(addr));
         debug_assert!(!self.is_disconnected(addr));
 
-        if self.is_disconnecting(addr) || self.is_connected(addr) {
+        if let network::DisconnectReason::StateMachine(DisconnectReason::PeerMisbehaving(r)) =
+            reason
+        {
+            let score = self.misbehavior.entry(addr.ip()).or_default();
+            *score += 1;
+
+            let banned = *score >= MISBEHAVIOR_BAN_THRESHOLD;
+            if banned {
+                self.banned.insert(addr.ip());
+            }
+            self.upstream.event(Event::Misbehaved {
+                addr: *addr,
+                score: *score,
+                reason: r,
+                banned,
+            });
+        }
+
+        if self.timed_out.remove(addr) {
+            // The connection was never established, so this is a failed connection attempt.
+            self.upstream.event(Event::ConnectionFailed(
+                *addr,
+                Arc::new(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "connection attempt timed out",
+                )),
+            ));
+        } else if self.is_disconnecting(addr) || self.is_connected(addr) {
             self.upstream.event(Event::Disconnected(*addr, reason));
         } else if self.is_connecting(addr) {
             // If we haven't yet established a connection, the disconnect reason
@@ -408,7 +526,7 @@ impl<U: Wire<Event> + Wakeup + Connect + Disconnect, C: Clock> PeerManager<U, C>
 
         self.peers.remove(addr);
 
-        if self.config.persistent.contains(addr) {
+        if self.config.persistent.contains(addr) && !self.is_banned(addr) {
             self.retrier_add_peer(addr, local_time);
         } else {
             // If an outbound peer disconnected, we should make sure to maintain
@@ -543,18 +661,26 @@ impl<U: Wire<Event> + Wakeup + Connect + Disconnect, C: Clock> PeerManager<U, C>
                             self.version(conn.socket.addr, conn.local_addr, nonce, height, now),
                         )
                         .wtxid_relay(conn.socket.addr)
-                        .verack(conn.socket.addr)
-                        .send_headers(conn.socket.addr)
-                        .wakeup(HANDSHAKE_TIMEOUT);
+                        .verack(conn.socket.addr);
                 }
                 Link::Outbound => {
                     self.upstream
                         .wtxid_relay(conn.socket.addr)
-                        .verack(conn.socket.addr)
-                        .send_headers(conn.socket.addr)
-                        .wakeup(HANDSHAKE_TIMEOUT);
+                        .verack(conn.socket.addr);
+                }
+            }
+            // Ask the peer to announce new blocks with `headers` instead of `inv`.
+            if self.config.send_headers {
+                self.upstream.send_headers(conn.socket.addr);
+            }
+            // Let the peer know we can receive compact blocks. We only ever request them
+            // explicitly, so the peer shouldn't push them to us.
+            if self.config.compact_blocks {
+                for version in invmgr::COMPACT_BLOCKS_VERSIONS {
+                    self.upstream.send_cmpct(conn.socket.addr, version);
                 }
             }
+            self.upstream.wakeup(HANDSHAKE_TIMEOUT);
             let conn = conn.clone();
             let persistent = self.config.persistent.contains(&conn.socket.addr);
 
@@ -622,6 +748,7 @@ impl<U: Wire<Event> + Wakeup + Connect + Disconnect, C: Clock> PeerManager<U, C>
 
         // Time out all peers that have been idle in a "connecting" state for too long.
         for addr in self.idle_peers(local_time).collect::<Vec<_>>() {
+            self.timed_out.insert(addr);
             timed_out.push((addr, "connection"));
         }
         // Time out peers that haven't sent a `verack` quickly enough.
@@ -702,11 +829,11 @@ impl<U: Wire<Event> + Wakeup + Connect + Disconnect, C: Clock> PeerManager<U, C>
             // A nonce to detect connections to self.
             nonce,
             // Our user agent string.
-            user_agent: self.config.user_agent.to_owned(),
+            user_agent: self.config.user_agent.to_string(),
             // Our best height.
             start_height,
             // Whether we want to receive transaction `inv` messages.
-            relay: false,
+            relay: self.config.relay,
         }
     }
 }
@@ -789,6 +916,11 @@ impl<U: Connect + Disconnect + Wakeup + Wire<Event>, C: Clock> PeerManager<U, C>
             .filter(move |(p, c)| p.is_negotiated() && c.link == link)
     }
 
+    /// Check whether a peer's address is banned for misbehaving.
+    pub fn is_banned(&self, addr: &PeerId) -> bool {
+        self.banned.contains(&addr.ip())
+    }
+
     /// Connect to a peer.
     pub fn connect(&mut self, addr: &PeerId) -> bool {
         let time = self.clock.local_time();
@@ -796,12 +928,15 @@ impl<U: Connect + Disconnect + Wakeup + Wire<Event>, C: Clock> PeerManager<U, C>
         if !self.is_disconnected(addr) && !self.is_disconnecting(addr) {
             return false;
         }
+        if self.is_banned(addr) {
+            return false;
+        }
         // Don't allow connections to unsupported domains.
         if !self.config.domains.contains(&Domain::for_address(addr)) {
             return false;
         }
         self.peers.insert(*addr, Peer::Connecting { time });
-        self.upstream.connect(*addr, CONNECTION_TIMEOUT);
+        self.upstream.connect(*addr, self.config.connect_timeout);
 
         true
     }
@@ -868,20 +1003,28 @@ impl<U: Connect + Disconnect + Wakeup + Wire<Event>, C: Clock> PeerManager<U, C>
         // Keep track of new addresses we're connecting to, and loop until
         // we've connected to enough addresses.
         let mut connecting = HashSet::with_hasher(self.rng.clone().into());
+        // Use the address selection hook if one was provided, and sample randomly if it
+        // wasn't, or if it didn't select any address.
+        let select_peer = self.hooks.select_peer.clone();
+        let sample = |addrs: &mut A, services: ServiceFlags| match &select_peer {
+            Some(select) => addrs
+                .select(services, select.as_ref())
+                .or_else(|| addrs.sample(services)),
+            None => addrs.sample(services),
+        };
 
         while connecting.len() < delta {
             if let Some((addr, source)) =
-                addrs.sample(self.config.preferred_services).or_else(|| {
+                sample(addrs, self.config.preferred_services).or_else(|| {
                     // Only try to connect to non-preferred peers if we are below our target.
                     if negotiated < target {
-                        addrs
-                            .sample(self.config.required_services)
+                        sample(addrs, self.config.required_services)
                             // If we can't find peers with any kind of useful services, then
                             // perhaps we should connect to peers that may know of such peers. This
                             // is especially important when doing an initial DNS sync, since DNS
                             // addresses don't come with service information. This will draw from
                             // that pool.
-                            .or_else(|| addrs.sample(ServiceFlags::NONE))
+                            .or_else(|| sample(addrs, ServiceFlags::NONE))
                     } else {
                         None
                     }
@@ -908,11 +1051,11 @@ impl<U: Connect + Disconnect + Wakeup + Wire<Event>, C: Clock> PeerManager<U, C>
         }
     }
 
-    /// Peers that have been idle longer than [`CONNECTION_TIMEOUT`].
+    /// Peers that have been idle longer than [`Config::connect_timeout`].
     fn idle_peers(&self, now: LocalTime) -> impl Iterator<Item = PeerId> + '_ {
         self.peers.iter().filter_map(move |(addr, c)| {
             if let Peer::Connecting { time } = c {
-                if now - *time >= CONNECTION_TIMEOUT {
+                if now - *time >= self.config.connect_timeout {
                     return Some(*addr);
                 }
             }
@@ -928,6 +1071,7 @@ mod tests {
 
     use nakamoto_common::bitcoin::network::address::Address;
     use nakamoto_common::block::time::RefClock;
+    use nakamoto_common::p2p::peer::KnownAddress;
     use nakamoto_test::assert_matches;
 
     mod util {
@@ -939,10 +1083,13 @@ mod tests {
                 target_outbound_peers: TARGET_OUTBOUND_PEERS,
                 max_inbound_peers: MAX_INBOUND_PEERS,
                 domains: Domain::all(),
-                user_agent: crate::fsm::USER_AGENT,
+                user_agent: Cow::Borrowed(crate::fsm::USER_AGENT),
+                relay: false,
+                send_headers: true,
+                compact_blocks: false,
                 persistent: vec![],
-                retry_max_wait: LocalDuration::from_mins(60),
-                retry_min_wait: LocalDuration::from_secs(1),
+                retry_backoff: Backoff::default(),
+                connect_timeout: CONNECTION_TIMEOUT,
                 services: ServiceFlags::NONE,
                 preferred_services: ServiceFlags::COMPACT_FILTERS | ServiceFlags::NETWORK,
                 required_services: ServiceFlags::NETWORK,
@@ -1007,6 +1154,107 @@ mod tests {
         assert_eq!(peermgr.connecting().next(), Some(&remote));
     }
 
+    #[test]
+    fn test_version_user_agent() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = LocalTime::now();
+        let local = ([99, 99, 99, 99], 9999).into();
+        let remote = ([124, 43, 110, 1], 8333).into();
+        let user_agent = format!("/nakamoto:0.4.0/app:{}/", 42);
+
+        let cfg = Config {
+            user_agent: user_agent.clone().into(),
+            ..util::config()
+        };
+        let peermgr = PeerManager::new(cfg, rng, Hooks::default(), (), time);
+        let version = peermgr.version(remote, local, 1, 144, time);
+
+        assert_eq!(version.user_agent, user_agent);
+    }
+
+    #[test]
+    fn test_version_relay() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = LocalTime::now();
+        let local = ([99, 99, 99, 99], 9999).into();
+        let remote = ([124, 43, 110, 1], 8333).into();
+
+        let peermgr = PeerManager::new(util::config(), rng.clone(), Hooks::default(), (), time);
+        assert!(!peermgr.version(remote, local, 1, 144, time).relay);
+
+        let cfg = Config {
+            relay: true,
+            ..util::config()
+        };
+        let peermgr = PeerManager::new(cfg, rng, Hooks::default(), (), time);
+        assert!(peermgr.version(remote, local, 1, 144, time).relay);
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        let backoff = Backoff {
+            initial: LocalDuration::from_secs(2),
+            max: LocalDuration::from_secs(60),
+            multiplier: 3.,
+        };
+
+        assert_eq!(backoff.delay(0), LocalDuration::from_secs(2));
+        assert_eq!(backoff.delay(1), LocalDuration::from_secs(6));
+        assert_eq!(backoff.delay(2), LocalDuration::from_secs(18));
+        assert_eq!(backoff.delay(3), LocalDuration::from_secs(54));
+        assert_eq!(backoff.delay(4), LocalDuration::from_secs(60));
+        assert_eq!(backoff.delay(u32::MAX), LocalDuration::from_secs(60));
+
+        let backoff = Backoff {
+            multiplier: 0.5,
+            ..backoff
+        };
+        assert_eq!(backoff.delay(3), LocalDuration::from_secs(2));
+    }
+
+    #[test]
+    fn test_persistent_client_reconnect_backoff() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = RefClock::from(LocalTime::now());
+        let height = 144;
+
+        let local = ([99, 99, 99, 99], 9999).into();
+        let remote = ([124, 43, 110, 1], 8333).into();
+
+        let mut addrs = VecDeque::new();
+        let cfg = Config {
+            persistent: vec![remote],
+            retry_backoff: Backoff {
+                initial: LocalDuration::from_secs(10),
+                max: LocalDuration::from_secs(100),
+                multiplier: 10.,
+            },
+            ..util::config()
+        };
+        let mut peermgr = PeerManager::new(cfg, rng, Hooks::default(), (), time.clone());
+
+        peermgr.initialize(&mut addrs);
+        peermgr.peer_connected(remote, local, Link::Outbound, height);
+
+        for delay in [10, 100, 100] {
+            peermgr.peer_disconnected(
+                &remote,
+                &mut addrs,
+                DisconnectReason::PeerTimeout("").into(),
+            );
+            assert_eq!(peermgr.connecting().next(), None);
+
+            // Some jitter is applied, but never more than a fraction of the delay.
+            time.elapse(LocalDuration::from_secs(delay) * 3 / 4);
+            peermgr.received_wake(&mut addrs);
+            assert_eq!(peermgr.connecting().next(), None);
+
+            time.elapse(LocalDuration::from_secs(delay) / 4);
+            peermgr.received_wake(&mut addrs);
+            assert_eq!(peermgr.connecting().next(), Some(&remote));
+        }
+    }
+
     #[test]
     fn test_wtxidrelay_outbound() {
         let rng = fastrand::Rng::with_seed(1);
@@ -1044,28 +1292,72 @@ mod tests {
 
     #[test]
     fn test_wtxidrelay_misbehavior() {
+        use crate::fsm::network::Network;
+        use crate::fsm::output::{self, Outbox};
+
         let rng = fastrand::Rng::with_seed(1);
         let time = LocalTime::now();
+        let mut upstream = Outbox::new(Network::Mainnet, crate::fsm::PROTOCOL_VERSION);
 
         let mut addrs = VecDeque::new();
-        let mut peermgr = PeerManager::new(util::config(), rng.clone(), Hooks::default(), (), time);
+        let mut peermgr = PeerManager::new(
+            util::config(),
+            rng.clone(),
+            Hooks::default(),
+            upstream.clone(),
+            time,
+        );
 
         let height = 144;
         let local = ([99, 99, 99, 99], 9999).into();
-        let remote = ([124, 43, 110, 1], 8333).into();
+        let remote: PeerId = ([124, 43, 110, 1], 8333).into();
         let version = VersionMessage {
             services: ServiceFlags::NETWORK,
             ..peermgr.version(local, remote, rng.u64(..), height, time)
         };
 
         peermgr.initialize(&mut addrs);
-        peermgr.connect(&remote);
-        peermgr.peer_connected(remote, local, Link::Outbound, height);
-        peermgr.received_version(&remote, version, height, &mut addrs);
-        peermgr.received_verack(&remote, time);
-        peermgr.received_wtxidrelay(&remote);
 
-        assert_matches!(peermgr.peers.get(&remote), Some(Peer::Disconnecting));
+        for score in 1..=MISBEHAVIOR_BAN_THRESHOLD {
+            assert!(peermgr.connect(&remote));
+            peermgr.peer_connected(remote, local, Link::Outbound, height);
+            peermgr.received_version(&remote, version.clone(), height, &mut addrs);
+            peermgr.received_verack(&remote, time);
+            peermgr.received_wtxidrelay(&remote);
+
+            assert_matches!(peermgr.peers.get(&remote), Some(Peer::Disconnecting));
+
+            peermgr.peer_disconnected(
+                &remote,
+                &mut addrs,
+                DisconnectReason::PeerMisbehaving("unexpected `wtxidrelay` message").into(),
+            );
+            assert!(peermgr.peers.get(&remote).is_none());
+
+            let events = output::test::events(&mut upstream)
+                .filter_map(|e| match e {
+                    crate::fsm::Event::Peer(Event::Misbehaved {
+                        addr,
+                        score,
+                        banned,
+                        ..
+                    }) => Some((addr, score, banned)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(
+                events,
+                vec![(remote, score, score == MISBEHAVIOR_BAN_THRESHOLD)]
+            );
+        }
+
+        // The peer's address is banned: we don't connect to it, and it can't connect to us.
+        assert!(peermgr.is_banned(&remote));
+        assert!(!peermgr.connect(&remote));
+
+        let inbound = ([124, 43, 110, 1], 18333).into();
+        peermgr.peer_connected(inbound, local, Link::Inbound, height);
+        assert_matches!(peermgr.peers.get(&inbound), Some(Peer::Disconnecting));
     }
 
     #[test]
@@ -1100,6 +1392,52 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_connect_timeout_config() {
+        use crate::fsm::network::Network;
+        use crate::fsm::output::{self, Outbox};
+
+        let rng = fastrand::Rng::with_seed(1);
+        let time = RefClock::from(LocalTime::now());
+        let remote = ([124, 43, 110, 1], 8333).into();
+        let cfg = Config {
+            connect_timeout: LocalDuration::from_secs(2),
+            ..util::config()
+        };
+        let mut upstream = Outbox::new(Network::Mainnet, crate::fsm::PROTOCOL_VERSION);
+
+        let mut addrs = VecDeque::new();
+        let mut peermgr =
+            PeerManager::new(cfg, rng, Hooks::default(), upstream.clone(), time.clone());
+
+        peermgr.initialize(&mut addrs);
+        peermgr.connect(&remote);
+
+        time.elapse(LocalDuration::from_secs(1));
+        peermgr.received_wake(&mut addrs);
+        assert_eq!(peermgr.connecting().next(), Some(&remote));
+
+        time.elapse(LocalDuration::from_secs(1));
+        peermgr.received_wake(&mut addrs);
+        assert_eq!(peermgr.connecting().next(), None);
+
+        peermgr.peer_disconnected(
+            &remote,
+            &mut addrs,
+            DisconnectReason::PeerTimeout("connection").into(),
+        );
+        let events = output::test::events(&mut upstream).collect::<Vec<_>>();
+
+        assert!(events.iter().any(|e| matches!(
+            e,
+            crate::fsm::Event::Peer(Event::ConnectionFailed(addr, err))
+            if *addr == remote && err.kind() == std::io::ErrorKind::TimedOut
+        )));
+        assert!(!events
+            .iter()
+            .any(|e| matches!(e, crate::fsm::Event::Peer(Event::Disconnected(..)))));
+    }
+
     #[test]
     fn test_peer_dropped() {
         let rng = fastrand::Rng::with_seed(1);
@@ -1209,6 +1547,103 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_max_inbound_peers() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = LocalTime::now();
+        let height = 144;
+
+        let local = ([99, 99, 99, 99], 9999).into();
+        let remote1 = ([124, 43, 110, 1], 8333).into();
+        let remote2 = ([124, 43, 110, 2], 8333).into();
+        let remote3 = ([124, 43, 110, 3], 8333).into();
+
+        let mut addrs = VecDeque::new();
+        let cfg = Config {
+            max_inbound_peers: 2,
+            ..util::config()
+        };
+        let mut peermgr = PeerManager::new(cfg, rng, Hooks::default(), (), time);
+
+        peermgr.initialize(&mut addrs);
+        peermgr.peer_connected(remote1, local, Link::Inbound, height);
+        peermgr.peer_connected(remote2, local, Link::Inbound, height);
+        assert_eq!(peermgr.connected().count(), 2);
+
+        peermgr.peer_connected(remote3, local, Link::Inbound, height);
+        assert!(!peermgr.is_disconnecting(&remote1));
+        assert!(!peermgr.is_disconnecting(&remote2));
+        assert!(
+            peermgr.is_disconnecting(&remote3),
+            "Inbound connections past the limit are rejected"
+        );
+    }
+
+    #[test]
+    fn test_select_peer_hook() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = LocalTime::now();
+
+        let services = ServiceFlags::NETWORK;
+        let remote1: net::SocketAddr = ([124, 43, 110, 1], 8333).into();
+        let remote2: net::SocketAddr = ([124, 43, 110, 2], 8333).into();
+        let remote3: net::SocketAddr = ([124, 43, 110, 3], 8333).into();
+
+        let mut addrs = VecDeque::new();
+        for remote in [remote1, remote2, remote3] {
+            addrs.push_back((Address::new(&remote, services), Source::Dns));
+        }
+        let hooks = Hooks {
+            select_peer: Some(Arc::new(move |candidates: &[KnownAddress]| {
+                candidates
+                    .iter()
+                    .filter_map(|ka| ka.addr.socket_addr().ok())
+                    .find(|addr| *addr == remote3)
+            })),
+            ..Hooks::default()
+        };
+        let cfg = Config {
+            target_outbound_peers: 1,
+            ..util::config()
+        };
+        let mut peermgr = PeerManager::new(cfg, rng, hooks, (), time);
+
+        peermgr.initialize(&mut addrs);
+
+        assert_eq!(peermgr.connecting().collect::<Vec<_>>(), vec![&remote3]);
+        assert_eq!(addrs.len(), 2, "Only the selected address is taken");
+    }
+
+    #[test]
+    fn test_select_peer_hook_fallback() {
+        let rng = fastrand::Rng::with_seed(1);
+        let time = LocalTime::now();
+
+        let services = ServiceFlags::NETWORK;
+        let remote: net::SocketAddr = ([124, 43, 110, 1], 8333).into();
+
+        let mut addrs = VecDeque::new();
+        addrs.push_back((Address::new(&remote, services), Source::Dns));
+
+        let hooks = Hooks {
+            select_peer: Some(Arc::new(|_: &[KnownAddress]| None)),
+            ..Hooks::default()
+        };
+        let cfg = Config {
+            target_outbound_peers: 1,
+            ..util::config()
+        };
+        let mut peermgr = PeerManager::new(cfg, rng, hooks, (), time);
+
+        peermgr.initialize(&mut addrs);
+
+        assert_eq!(
+            peermgr.connecting().collect::<Vec<_>>(),
+            vec![&remote],
+            "An address is sampled when the hook doesn't select one"
+        );
+    }
+
     #[test]
     fn test_connection_delta() {
         let target_outbound_peers = 4;
@@ -27,11 +27,25 @@ const MAX_RECORDED_LATENCIES: usize = 64;
 
 /// A ping-related event.
 #[derive(Clone, Debug)]
-pub enum Event {}
+pub enum Event {
+    /// A `pong` matching our last `ping` was received from a peer.
+    PongReceived {
+        /// The peer.
+        addr: PeerId,
+        /// Nonce of the `ping` being answered.
+        nonce: u64,
+        /// Round-trip latency of the `ping`.
+        latency: LocalDuration,
+    },
+}
 
 impl std::fmt::Display for Event {
-    fn fmt(&self, _f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        Ok(())
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::PongReceived { addr, latency, .. } => {
+                write!(f, "{}: Received pong after {}", addr, latency)
+            }
+        }
     }
 }
 
@@ -51,11 +65,14 @@ struct Peer {
 
 impl Peer {
     /// Calculate the average latency of this peer.
-    #[allow(dead_code)]
-    fn latency(&self) -> LocalDuration {
+    /// Returns [`None`] if no latency was recorded yet.
+    fn latency(&self) -> Option<LocalDuration> {
+        if self.latencies.is_empty() {
+            return None;
+        }
         let sum: LocalDuration = self.latencies.iter().sum();
 
-        sum / self.latencies.len() as u32
+        Some(sum / self.latencies.len() as u32)
     }
 
     fn record_latency(&mut self, sample: LocalDuration) {
@@ -105,6 +122,30 @@ impl<U: Wire<Event> + Wakeup + Disconnect, C: Clock> PingManager<U, C> {
         );
     }
 
+    /// Ping a peer, unless a `ping` is already awaiting a `pong`. Returns the nonce of the
+    /// `ping` to wait for, or `None` if the peer isn't known.
+    pub fn ping(&mut self, addr: &PeerId) -> Option<u64> {
+        let now = self.clock.local_time();
+        let peer = self.peers.get_mut(addr)?;
+
+        match peer.state {
+            State::AwaitingPong { nonce, .. } => Some(nonce),
+            State::Idle { .. } => {
+                let nonce = self.rng.u64(..);
+
+                self.upstream.ping(*addr, nonce).wakeup(self.ping_timeout);
+                peer.state = State::AwaitingPong { nonce, since: now };
+
+                Some(nonce)
+            }
+        }
+    }
+
+    /// Get the average round-trip latency of a peer, if known.
+    pub fn latency(&self, addr: &PeerId) -> Option<LocalDuration> {
+        self.peers.get(addr).and_then(|p| p.latency())
+    }
+
     /// Called when a peer is disconnected.
     pub fn peer_disconnected(&mut self, addr: &PeerId) {
         self.peers.remove(addr);
@@ -166,9 +207,17 @@ impl<U: Wire<Event> + Wakeup + Disconnect, C: Clock> PingManager<U, C> {
                     since,
                 } => {
                     if nonce == last_nonce {
-                        peer.record_latency(now - since);
+                        let latency = now - since;
+
+                        peer.record_latency(latency);
                         peer.state = State::Idle { since: now };
 
+                        self.upstream.event(Event::PongReceived {
+                            addr,
+                            nonce,
+                            latency,
+                        });
+
                         return true;
                     }
                 }
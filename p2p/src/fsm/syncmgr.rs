@@ -8,7 +8,7 @@ use nakamoto_common::bitcoin::network::message_blockdata::Inventory;
 use nakamoto_common::bitcoin_hashes::Hash;
 use nakamoto_common::block::store;
 use nakamoto_common::block::time::{Clock, LocalDuration, LocalTime};
-use nakamoto_common::block::tree::{BlockReader, BlockTree, Error, ImportResult};
+use nakamoto_common::block::tree::{BlockReader, BlockTree, Error, ImportResult, ImportSummary};
 use nakamoto_common::block::{BlockHash, BlockHeader, Height};
 use nakamoto_common::collections::{AddressBook, HashMap};
 use nakamoto_common::nonempty::NonEmpty;
@@ -21,6 +21,8 @@ pub const REQUEST_TIMEOUT: LocalDuration = LocalDuration::from_secs(30);
 /// How long before the tip of the chain is considered stale. This takes into account
 /// that the block timestamp may have been set sometime in the future.
 pub const TIP_STALE_DURATION: LocalDuration = LocalDuration::from_mins(60 * 2);
+/// How long without a new block header before [`Event::TipStale`] is emitted, by default.
+pub const DEFAULT_STALE_TIP_TIMEOUT: LocalDuration = LocalDuration::from_mins(30);
 /// Maximum number of headers sent in a `headers` message.
 pub const MAX_MESSAGE_HEADERS: usize = 2000;
 /// Maximum number of inventories sent in an `inv` message.
@@ -30,10 +32,16 @@ pub const IDLE_TIMEOUT: LocalDuration = LocalDuration::BLOCK_INTERVAL;
 /// Services required from peers for header sync.
 pub const REQUIRED_SERVICES: ServiceFlags = ServiceFlags::NETWORK;
 
+/// Maximum number of blocks our tip can be behind the best peer height, for us to be
+/// considered done with initial block download.
+pub const IBD_MAX_BLOCKS_BEHIND: Height = 6;
+
 /// Maximum headers announced in a `headers` message, when unsolicited.
 const MAX_UNSOLICITED_HEADERS: usize = 24;
 /// How long to wait between checks for longer chains from peers.
 const PEER_SAMPLE_INTERVAL: LocalDuration = LocalDuration::from_mins(60);
+/// Minimum time between two [`Event::SyncProgress`] events.
+const PROGRESS_INTERVAL: LocalDuration = LocalDuration::from_secs(1);
 
 /// What to do if a timeout for a peer is received.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -66,6 +74,8 @@ pub struct Config {
     pub max_message_headers: usize,
     /// How long to wait for a response from a peer.
     pub request_timeout: LocalDuration,
+    /// How long without a new block header before the tip is reported as stale.
+    pub stale_tip_timeout: LocalDuration,
     /// Consensus parameters.
     pub params: Params,
 }
@@ -80,10 +90,16 @@ pub struct SyncManager<U, C> {
     peers: AddressBook<PeerId, Peer>,
     /// Last time our tip was updated.
     last_tip_update: Option<LocalTime>,
+    /// Time at which the sync manager was initialized.
+    initialized_at: Option<LocalTime>,
+    /// Whether we reported our tip as stale, and haven't received a new header since.
+    tip_stale: bool,
     /// Last time we sampled our peers for their active chain.
     last_peer_sample: Option<LocalTime>,
     /// Last time we idled.
     last_idle: Option<LocalTime>,
+    /// Last time we reported sync progress.
+    last_progress: Option<LocalTime>,
     /// In-flight requests to peers.
     inflight: HashMap<PeerId, GetHeaders>,
     /// Upstream protocol channel.
@@ -108,6 +124,8 @@ pub enum Event {
         height: Height,
         /// Block header.
         header: BlockHeader,
+        /// Total number of blocks disconnected by the re-org this block is part of.
+        reorg_depth: usize,
     },
     /// A new block was discovered via a peer.
     BlockDiscovered(PeerId, BlockHash),
@@ -120,8 +138,35 @@ pub enum Event {
     },
     /// Synced up to the specified hash and height.
     Synced(BlockHash, Height),
+    /// Header sync progress. Emitted at most once per second as the chain is extended.
+    SyncProgress {
+        /// Current block header height.
+        height: Height,
+        /// Best known block header height, out of all our peers.
+        target: Height,
+    },
     /// Potential stale tip detected on the active chain.
     StaleTip(LocalTime),
+    /// No new block header was received within the configured stale tip timeout.
+    TipStale {
+        /// Height of our tip.
+        height: Height,
+        /// Last time our tip was updated.
+        last_update: LocalTime,
+    },
+    /// A new block header was received after the tip was reported stale.
+    TipFresh {
+        /// Height of our new tip.
+        height: Height,
+    },
+    /// A re-org was rejected for exceeding the configured maximum depth.
+    /// The active chain was kept.
+    ReorgRejected {
+        /// Number of blocks that would have been disconnected.
+        depth: usize,
+        /// Height and hash of the block the rejected branch forks from.
+        fork_point: (Height, BlockHash),
+    },
     /// Peer misbehaved.
     PeerMisbehaved(PeerId),
     /// Peer height updated.
@@ -148,6 +193,9 @@ impl std::fmt::Display for Event {
                 )
             }
             Event::Syncing { current, best } => write!(fmt, "Syncing headers {}/{}", current, best),
+            Event::SyncProgress { height, target } => {
+                write!(fmt, "Sync progress at height {}/{}", height, target)
+            }
             Event::BlockConnected { height, header } => {
                 write!(
                     fmt,
@@ -156,7 +204,7 @@ impl std::fmt::Display for Event {
                     height
                 )
             }
-            Event::BlockDisconnected { height, header } => {
+            Event::BlockDisconnected { height, header, .. } => {
                 write!(
                     fmt,
                     "Block {} disconnected at height {}",
@@ -174,6 +222,26 @@ impl std::fmt::Display for Event {
                     last_update
                 )
             }
+            Event::TipStale {
+                height,
+                last_update,
+            } => {
+                write!(
+                    fmt,
+                    "Tip at height {} is stale (last update was {})",
+                    height, last_update
+                )
+            }
+            Event::TipFresh { height } => {
+                write!(fmt, "Tip is fresh again at height {}", height)
+            }
+            Event::ReorgRejected { depth, fork_point } => {
+                write!(
+                    fmt,
+                    "Re-org of depth {} from block {} at height {} rejected",
+                    depth, fork_point.1, fork_point.0
+                )
+            }
         }
     }
 }
@@ -194,16 +262,22 @@ impl<U: Wakeup + Disconnect + Wire<Event>, C: Clock> SyncManager<U, C> {
     pub fn new(config: Config, rng: fastrand::Rng, upstream: U, clock: C) -> Self {
         let peers = AddressBook::new(rng.clone());
         let last_tip_update = None;
+        let initialized_at = None;
+        let tip_stale = false;
         let last_peer_sample = None;
         let last_idle = None;
+        let last_progress = None;
         let inflight = HashMap::with_hasher(rng.into());
 
         Self {
             peers,
             config,
             last_tip_update,
+            initialized_at,
+            tip_stale,
             last_peer_sample,
             last_idle,
+            last_progress,
             inflight,
             upstream,
             clock,
@@ -216,6 +290,9 @@ impl<U: Wakeup + Disconnect + Wire<Event>, C: Clock> SyncManager<U, C> {
         let (hash, _) = tree.tip();
         let height = tree.height();
 
+        self.initialized_at = Some(self.clock.local_time());
+        self.upstream.wakeup(self.config.stale_tip_timeout);
+
         self.idle(tree);
         self.upstream.event(Event::Synced(hash, height));
     }
@@ -287,8 +364,71 @@ impl<U: Wakeup + Disconnect + Wire<Event>, C: Clock> SyncManager<U, C> {
         blocks: I,
         tree: &mut T,
     ) -> Result<ImportResult, Error> {
-        match tree.import_blocks(blocks, &self.clock) {
-            Ok(ImportResult::TipChanged(header, tip, height, reverted, connected)) => {
+        let result = tree.import_blocks(blocks, &self.clock)?;
+
+        Ok(self.imported(result, tree))
+    }
+
+    /// Import blocks into our block tree, and return a summary of the import.
+    ///
+    /// Since the headers may come from an untrusted source, their proof-of-work is checked
+    /// before anything is imported, and the whole batch is rejected if any header fails.
+    pub fn import_headers<T: BlockTree>(
+        &mut self,
+        headers: Vec<BlockHeader>,
+        tree: &mut T,
+    ) -> Result<ImportSummary, Error> {
+        self.validate_pow(&headers, tree)?;
+
+        let summary = tree.import_blocks_with_summary(headers.into_iter(), &self.clock)?;
+        let result = self.imported(summary.result, tree);
+
+        Ok(ImportSummary { result, ..summary })
+    }
+
+    /// Check the proof-of-work of a batch of headers against their expected target.
+    ///
+    /// Headers that don't connect to the tree or to the previous header in the batch can't
+    /// be placed, and are left for the tree to handle. Targets at difficulty adjustment
+    /// heights are left to the full header validation done by the tree.
+    fn validate_pow<T: BlockReader>(&self, headers: &[BlockHeader], tree: &T) -> Result<(), Error> {
+        let params = &self.config.params;
+        let mut last: Option<(Height, &BlockHeader)> = None;
+
+        for header in headers {
+            let parent = match last {
+                Some((height, prev)) if prev.block_hash() == header.prev_blockhash => {
+                    Some((height, prev))
+                }
+                _ => tree.get_block(&header.prev_blockhash),
+            };
+            let (height, parent) = if let Some((height, parent)) = parent {
+                (height + 1, parent)
+            } else {
+                last = None;
+                continue;
+            };
+            let target = header.target();
+
+            if target > params.pow_limit || header.validate_pow(&target).is_err() {
+                return Err(Error::InvalidProofOfWork { height });
+            }
+            // Outside of adjustment heights, the target is inherited from the parent.
+            if !params.allow_min_difficulty_blocks
+                && height % params.difficulty_adjustment_interval() != 0
+                && header.bits != parent.bits
+            {
+                return Err(Error::InvalidProofOfWork { height });
+            }
+            last = Some((height, header));
+        }
+        Ok(())
+    }
+
+    /// Called after blocks were imported into the block tree.
+    fn imported<T: BlockTree>(&mut self, result: ImportResult, tree: &T) -> ImportResult {
+        match result {
+            ImportResult::TipChanged(header, tip, height, reverted, connected) => {
                 let result = ImportResult::TipChanged(
                     header,
                     tip,
@@ -297,22 +437,40 @@ impl<U: Wakeup + Disconnect + Wire<Event>, C: Clock> SyncManager<U, C> {
                     connected.clone(),
                 );
 
+                let reorg_depth = reverted.len();
+
                 for (height, header) in reverted {
-                    self.upstream
-                        .event(Event::BlockDisconnected { height, header });
+                    self.upstream.event(Event::BlockDisconnected {
+                        height,
+                        header,
+                        reorg_depth,
+                    });
                 }
                 for (height, header) in connected {
                     self.upstream
                         .event(Event::BlockConnected { height, header });
                 }
 
+                self.progress(height);
                 self.upstream.event(Event::Synced(tip, height));
                 self.broadcast_tip(&tip, tree);
 
-                Ok(result)
+                result
+            }
+            // The branch has more work, but we're not willing to re-org that deeply.
+            // Keep our active chain and let the user know.
+            ImportResult::ReorgRejected { depth, fork_point } => {
+                log::warn!(
+                    "[sync] Rejected re-org of depth {} from height {}",
+                    depth,
+                    fork_point.0
+                );
+                self.upstream
+                    .event(Event::ReorgRejected { depth, fork_point });
+
+                ImportResult::ReorgRejected { depth, fork_point }
             }
-            Ok(result @ ImportResult::TipUnchanged) => Ok(result),
-            Err(err) => Err(err),
+            result @ ImportResult::TipUnchanged => result,
         }
     }
 
@@ -385,6 +543,12 @@ impl<U: Wakeup + Disconnect + Wire<Event>, C: Clock> SyncManager<U, C> {
                 // Keep track of when we last updated our tip. This is useful to check
                 // whether our tip is stale.
                 self.last_tip_update = Some(clock.local_time());
+                self.upstream.wakeup(self.config.stale_tip_timeout);
+
+                if self.tip_stale {
+                    self.tip_stale = false;
+                    self.upstream.event(Event::TipFresh { height });
+                }
 
                 // If we received less than the maximum number of headers, we must be in sync.
                 // Otherwise, ask for the next batch of headers.
@@ -404,6 +568,7 @@ impl<U: Wakeup + Disconnect + Wire<Event>, C: Clock> SyncManager<U, C> {
                     header, tip, height, reverted, connected,
                 ))
             }
+            Ok(result @ ImportResult::ReorgRejected { .. }) => Ok(result),
             Err(err) => self
                 .handle_error(from, err)
                 .map(|()| ImportResult::TipUnchanged),
@@ -485,6 +650,8 @@ impl<U: Wakeup + Disconnect + Wire<Event>, C: Clock> SyncManager<U, C> {
 
     /// Called when we received a tick.
     pub fn received_wake<T: BlockReader>(&mut self, tree: &T) {
+        self.check_stale_tip(tree);
+
         let local_time = self.clock.local_time();
         let timeout = self.config.request_timeout;
         let timed_out = self
@@ -541,6 +708,16 @@ impl<U: Wakeup + Disconnect + Wire<Event>, C: Clock> SyncManager<U, C> {
         !self.inflight.is_empty()
     }
 
+    /// Are we in initial block download? This is the case until our tip is within
+    /// [`IBD_MAX_BLOCKS_BEHIND`] blocks of the best known peer height, or when no
+    /// peer height is known.
+    pub fn is_ibd<T: BlockReader>(&self, tree: &T) -> bool {
+        match self.best_height() {
+            Some(best) => tree.height() + IBD_MAX_BLOCKS_BEHIND < best,
+            None => true,
+        }
+    }
+
     ///////////////////////////////////////////////////////////////////////////
 
     fn handle_error(&mut self, from: &PeerId, err: Error) -> Result<(), store::Error> {
@@ -551,6 +728,7 @@ impl<U: Wakeup + Disconnect + Wire<Event>, C: Clock> SyncManager<U, C> {
 
             // If we got a bad block from the peer, we can handle it here.
             Error::InvalidBlockPoW
+            | Error::InvalidProofOfWork { .. }
             | Error::InvalidBlockTarget(_, _)
             | Error::InvalidBlockHash(_, _)
             | Error::InvalidBlockHeight(_)
@@ -576,10 +754,45 @@ impl<U: Wakeup + Disconnect + Wire<Event>, C: Clock> SyncManager<U, C> {
         }
     }
 
+    /// Report sync progress, if we haven't done so recently. Progress is always reported
+    /// once the target is reached, so that the final update isn't dropped.
+    fn progress(&mut self, height: Height) {
+        let now = self.clock.local_time();
+        let target = self.best_height().map_or(height, |best| best.max(height));
+
+        if let Some(last) = self.last_progress {
+            if now - last < PROGRESS_INTERVAL && height < target {
+                return;
+            }
+        }
+
+        self.last_progress = Some(now);
+        self.upstream.event(Event::SyncProgress { height, target });
+    }
+
     fn record_misbehavior(&mut self, peer: &PeerId) {
         self.upstream.event(Event::PeerMisbehaved(*peer));
     }
 
+    /// Report our tip as stale if no new header was received within the configured timeout.
+    /// The tip is only reported once, until a new header is received.
+    fn check_stale_tip<T: BlockReader>(&mut self, tree: &T) {
+        if self.tip_stale {
+            return;
+        }
+        let last_update = match self.last_tip_update.or(self.initialized_at) {
+            Some(t) => t,
+            None => return,
+        };
+        if self.clock.local_time() - last_update >= self.config.stale_tip_timeout {
+            self.tip_stale = true;
+            self.upstream.event(Event::TipStale {
+                height: tree.height(),
+                last_update,
+            });
+        }
+    }
+
     /// Check whether our current tip is stale.
     ///
     /// *Nb. This doesn't check whether we've already requested new blocks.*
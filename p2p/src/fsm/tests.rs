@@ -12,11 +12,12 @@ use std::sync::Arc;
 use log::*;
 use nakamoto_common::bitcoin::network::message_blockdata::GetHeadersMessage;
 
+use super::fees::FeeRate;
 use super::{addrmgr, cbfmgr, invmgr, peermgr, pingmgr, syncmgr};
 use super::{
-    chan, network::Network, BlockHash, BlockHeader, Command, Config, DisconnectReason, Event,
-    HashSet, Height, Io, Limits, NetworkMessage, PeerId, RawNetworkMessage, ServiceFlags,
-    VersionMessage,
+    chan, network::Network, BlockHash, BlockHeader, Command, CommandError, Config,
+    DisconnectReason, Event, HashSet, Height, Io, Limits, NetworkMessage, PeerId,
+    RawNetworkMessage, ServiceFlags, SyncStatus, Tips, VersionMessage,
 };
 use super::{PROTOCOL_VERSION, USER_AGENT};
 
@@ -39,7 +40,7 @@ use nakamoto_chain::store::Genesis;
 
 use nakamoto_common::block::filter::FilterHeader;
 use nakamoto_common::block::time::{AdjustedTime, RefClock};
-use nakamoto_common::block::tree::BlockReader as _;
+use nakamoto_common::block::tree::{self, BlockReader as _};
 use nakamoto_common::collections::HashMap;
 use nakamoto_common::nonempty::NonEmpty;
 use nakamoto_common::p2p::peer::KnownAddress;
@@ -161,6 +162,56 @@ fn test_idle_disconnect() {
         .expect("peer disconnects remote");
 }
 
+#[test]
+fn test_ping_command() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut peer = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let remote = ([241, 19, 44, 18], 8333).into();
+
+    peer.connect_addr(&remote, Link::Outbound);
+
+    let nonce = peer
+        .messages(&remote)
+        .find_map(|m| match m {
+            NetworkMessage::Ping(nonce) => Some(nonce),
+            _ => None,
+        })
+        .expect("`ping` is sent on connect");
+
+    // A `ping` is already awaiting a `pong`, so its nonce is returned.
+    let (transmit, receive) = chan::bounded(1);
+    peer.command(Command::Ping(remote, transmit));
+    assert_eq!(receive.recv().unwrap().unwrap(), nonce);
+    assert!(!peer
+        .messages(&remote)
+        .any(|m| matches!(m, NetworkMessage::Ping(_))));
+
+    peer.elapse(LocalDuration::from_millis(120));
+    peer.received(&remote, NetworkMessage::Pong(nonce));
+    assert!(peer.events().any(|e| matches!(
+        e,
+        Event::Ping(pingmgr::Event::PongReceived { addr, nonce: n, latency })
+        if addr == remote && n == nonce && latency == LocalDuration::from_millis(120)
+    )));
+
+    // Without a `ping` in flight, a new one is sent.
+    let (transmit, receive) = chan::bounded(1);
+    peer.command(Command::Ping(remote, transmit));
+    let nonce = receive.recv().unwrap().unwrap();
+    assert!(peer
+        .messages(&remote)
+        .any(|m| matches!(m, NetworkMessage::Ping(n) if n == nonce)));
+
+    let unknown = ([88, 88, 88, 88], 8333).into();
+    let (transmit, receive) = chan::bounded(1);
+    peer.command(Command::Ping(unknown, transmit));
+    assert!(matches!(
+        receive.recv().unwrap(),
+        Err(CommandError::PeerNotConnected(addr)) if addr == unknown
+    ));
+}
+
 #[test]
 fn test_inv_getheaders() {
     let rng = fastrand::Rng::new();
@@ -482,6 +533,71 @@ fn test_handshake_initial_messages() {
         .any(|msg| matches!(msg, NetworkMessage::Ping(_))));
 }
 
+#[test]
+fn test_handshake_without_sendheaders() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let cfg = Config {
+        network,
+        prefer_headers_announcements: false,
+        ..Config::default()
+    };
+    let mut peer = Peer::config("alice", [48, 48, 48, 48], vec![], vec![], vec![], cfg, rng);
+    let remote = PeerDummy::new([131, 31, 11, 33], network, 144, ServiceFlags::NETWORK);
+    let local = ([0, 0, 0, 0], 0).into();
+
+    peer.init();
+    peer.protocol.peermgr.connect(&remote.addr);
+    peer.connected(remote.addr, &local, Link::Outbound);
+    peer.received(
+        &remote.addr,
+        NetworkMessage::Version(remote.version(local, 0)),
+    );
+    peer.received(&remote.addr, NetworkMessage::Verack);
+
+    let msgs = peer.messages(&remote.addr).collect::<Vec<_>>();
+
+    assert!(msgs.contains(&NetworkMessage::Verack));
+    assert!(!msgs.contains(&NetworkMessage::SendHeaders));
+}
+
+#[test]
+fn test_handshake_feefilter() {
+    let network = Network::Mainnet;
+    let local = ([0, 0, 0, 0], 0).into();
+    let rate = FeeRate::from_sat_per_vb(3);
+
+    for (relay, expected) in [(true, true), (false, false)] {
+        let cfg = Config {
+            network,
+            relay_transactions: relay,
+            min_relay_feerate: Some(rate),
+            ..Config::default()
+        };
+        let rng = fastrand::Rng::new();
+        let mut peer = Peer::config("alice", [48, 48, 48, 48], vec![], vec![], vec![], cfg, rng);
+        let remote = PeerDummy::new([131, 31, 11, 33], network, 144, ServiceFlags::NETWORK);
+
+        peer.init();
+        peer.protocol.peermgr.connect(&remote.addr);
+        peer.connected(remote.addr, &local, Link::Outbound);
+        peer.received(
+            &remote.addr,
+            NetworkMessage::Version(remote.version(local, 0)),
+        );
+        peer.received(&remote.addr, NetworkMessage::Verack);
+
+        let msgs = peer.messages(&remote.addr).collect::<Vec<_>>();
+
+        assert_eq!(
+            msgs.contains(&NetworkMessage::FeeFilter(3000)),
+            expected,
+            "relay = {}",
+            relay
+        );
+    }
+}
+
 #[test]
 fn test_connection_error() {
     let network = Network::Mainnet;
@@ -603,6 +719,118 @@ fn test_stale_tip() {
         .expect("Alice emits a `StaleTip` event");
 }
 
+#[test]
+fn test_tip_stale_and_fresh() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let remote: PeerId = ([33, 33, 33, 33], network.port()).into();
+    let headers = &BITCOIN_HEADERS;
+
+    alice.connect_addr(&remote, Link::Outbound);
+    alice.elapse(syncmgr::DEFAULT_STALE_TIP_TIMEOUT);
+    alice
+        .events()
+        .find(|e| matches!(e, Event::Chain(syncmgr::Event::TipStale { height: 0, .. })))
+        .expect("Alice emits a `TipStale` event");
+
+    // The stale tip is only reported once.
+    alice.elapse(syncmgr::DEFAULT_STALE_TIP_TIMEOUT);
+    assert!(!alice
+        .events()
+        .any(|e| matches!(e, Event::Chain(syncmgr::Event::TipStale { .. }))));
+
+    alice.received(
+        &remote,
+        NetworkMessage::Headers(vec![*headers.get(1).unwrap()]),
+    );
+    alice
+        .events()
+        .find(|e| matches!(e, Event::Chain(syncmgr::Event::TipFresh { height: 1 })))
+        .expect("Alice emits a `TipFresh` event");
+}
+
+#[test]
+fn test_sync_progress() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let remote: PeerId = ([33, 33, 33, 33], network.port()).into();
+    let headers = &BITCOIN_HEADERS;
+
+    alice.connect_addr(&remote, Link::Outbound);
+    alice.received(
+        &remote,
+        NetworkMessage::Headers(vec![*headers.get(1).unwrap()]),
+    );
+    alice
+        .events()
+        .find(|e| {
+            matches!(
+                e,
+                Event::Chain(syncmgr::Event::SyncProgress {
+                    height: 1,
+                    target: 144
+                })
+            )
+        })
+        .expect("Alice emits a `SyncProgress` event");
+
+    // Progress is not reported more than once per second.
+    alice.received(
+        &remote,
+        NetworkMessage::Headers(vec![*headers.get(2).unwrap()]),
+    );
+    assert!(!alice
+        .events()
+        .any(|e| matches!(e, Event::Chain(syncmgr::Event::SyncProgress { .. }))));
+
+    alice.elapse(LocalDuration::from_secs(1));
+    alice.received(
+        &remote,
+        NetworkMessage::Headers(vec![*headers.get(3).unwrap()]),
+    );
+    alice
+        .events()
+        .find(|e| {
+            matches!(
+                e,
+                Event::Chain(syncmgr::Event::SyncProgress {
+                    height: 3,
+                    target: 144
+                })
+            )
+        })
+        .expect("Alice emits a `SyncProgress` event");
+
+    // Reaching the target is always reported, even within a second of the last update.
+    let remaining = (4..=144)
+        .map(|h| *headers.get(h).unwrap())
+        .collect::<Vec<_>>();
+    let chunks = remaining.chunks(24).collect::<Vec<_>>();
+    let (last, rest) = chunks.split_last().unwrap();
+
+    for chunk in rest {
+        alice.received(&remote, NetworkMessage::Headers(chunk.to_vec()));
+        assert!(!alice
+            .events()
+            .any(|e| matches!(e, Event::Chain(syncmgr::Event::SyncProgress { .. }))));
+    }
+    alice.received(&remote, NetworkMessage::Headers(last.to_vec()));
+    alice
+        .events()
+        .find(|e| {
+            matches!(
+                e,
+                Event::Chain(syncmgr::Event::SyncProgress {
+                    height: 144,
+                    target: 144
+                })
+            )
+        })
+        .expect("Alice emits a final `SyncProgress` event");
+}
+
 #[quickcheck]
 fn prop_addrs(seed: u64) {
     let rng = fastrand::Rng::with_seed(seed);
@@ -859,6 +1087,44 @@ fn test_submit_transactions() {
         .expect("Alice responds to `getdata` with a `tx` message");
 }
 
+#[test]
+fn test_submit_transactions_batch() {
+    let network = Network::Mainnet;
+    let mut rng = fastrand::Rng::new();
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng.clone());
+    let remote = PeerDummy {
+        addr: ([88, 88, 88, 88], 8333).into(),
+        height: 144,
+        protocol_version: PROTOCOL_VERSION,
+        services: ServiceFlags::NETWORK,
+        relay: true,
+        time: LocalTime::now(),
+    };
+    let txs = vec![gen::transaction(&mut rng), gen::transaction(&mut rng)];
+
+    // Without peers, every transaction fails to be submitted.
+    let (transmit, receive) = chan::bounded(1);
+    alice.command(Command::SubmitTransactions(txs.clone(), transmit));
+
+    let results = receive.recv().unwrap();
+    assert_eq!(results.len(), txs.len());
+    assert!(results
+        .iter()
+        .all(|r| matches!(r, Err(CommandError::NotConnected))));
+
+    alice.connect(&remote, Link::Outbound);
+
+    let (transmit, receive) = chan::bounded(1);
+    alice.command(Command::SubmitTransactions(txs.clone(), transmit));
+
+    let results = receive.recv().unwrap();
+    assert_eq!(results.len(), txs.len());
+    for (tx, result) in txs.iter().zip(results) {
+        assert_eq!(Vec::from(result.unwrap()), vec![remote.addr]);
+        assert!(alice.protocol.invmgr.contains(&tx.wtxid()));
+    }
+}
+
 /// Should rebroadcast `inv` when no `getdata` is received.
 /// Should rebroadcast when a new peer connects.
 #[test]
@@ -1178,6 +1444,7 @@ fn test_submitted_transaction_filtering() {
         from: Bound::Unbounded, // Start scanning from the current height.
         to: Bound::Unbounded,   // Keep scanning forever.
         watch: vec![],          // Submitted transactions are tracked automatically.
+        reply: chan::bounded(1).0,
     });
     alice.command(Command::SubmitTransaction(tx.clone(), transmit));
     alice.tock();
@@ -1293,6 +1560,7 @@ fn test_transaction_reverted_reconfirm() {
         from: Bound::Unbounded, // Start scanning from the current height.
         to: Bound::Unbounded,   // Keep scanning forever.
         watch: vec![],          // Submitted transactions are tracked automatically.
+        reply: chan::bounded(1).0,
     });
     alice.command(Command::SubmitTransaction(tx.clone(), submit_reply));
     alice.tock();
@@ -1444,6 +1712,30 @@ fn test_transaction_reverted_reconfirm() {
     }
 }
 
+/// Test that imported headers with invalid proof-of-work are rejected as a batch.
+#[test]
+fn test_import_headers_invalid_pow() {
+    let mut rng = fastrand::Rng::new();
+    let network = Network::Regtest;
+    let genesis = network.genesis();
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng.clone());
+    let (transmit, import) = chan::unbounded();
+
+    let mut headers = gen::headers(genesis, 16, &mut rng);
+    // Mangle the difficulty target of one of the headers. Its hash no longer meets it.
+    headers.tail[7].bits = 0x1d00ffff;
+
+    alice.tick(LocalTime::from_block_time(headers.last().time));
+    alice.init();
+    alice.command(Command::ImportHeaders(headers.tail.clone(), transmit));
+
+    assert_matches!(
+        import.recv().unwrap(),
+        Err(tree::Error::InvalidProofOfWork { height: 8 })
+    );
+    assert_eq!(alice.protocol.tree.height(), 0, "No header was imported");
+}
+
 /// Test that blocks being imported and going stale generates the right events.
 #[test]
 fn test_block_events() {
@@ -1534,15 +1826,17 @@ fn test_block_events() {
     // Disconnected events.
     assert_matches!(
         events.next().unwrap(),
-        syncmgr::Event::BlockDisconnected { height, header }
-        if height == best + 1 && header.block_hash() == extra.block_hash()
+        syncmgr::Event::BlockDisconnected { height, header, reorg_depth }
+        if height == best + 1
+            && header.block_hash() == extra.block_hash()
+            && reorg_depth == (best + 1 - fork_height) as usize
     );
     for height_ in (fork_height + 1..=best).rev() {
         let hash_ = headers[height_ as usize].block_hash();
 
         assert_matches!(
             events.next().unwrap(),
-            syncmgr::Event::BlockDisconnected { height, header }
+            syncmgr::Event::BlockDisconnected { height, header, .. }
             if height == height_ as Height && header.block_hash() == hash_
         );
     }
@@ -1566,6 +1860,167 @@ fn test_block_events() {
     assert!(events.next().is_none());
 }
 
+#[test]
+fn test_get_block_by_height_range() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let headers = BITCOIN_HEADERS.tail[0..16].to_vec();
+    let mut alice = Peer::new(
+        "alice",
+        [48, 48, 48, 48],
+        network,
+        headers.clone(),
+        vec![],
+        vec![],
+        rng,
+    );
+    let (transmit, receive) = chan::bounded(1);
+
+    alice.command(Command::GetBlockByHeightRange(8..=32, transmit));
+
+    let result = receive.recv().unwrap();
+    let expected = (8..=16)
+        .map(|h| (h as Height, headers[h - 1]))
+        .collect::<Vec<_>>();
+
+    assert_eq!(result, expected);
+}
+
+#[test]
+fn test_get_filter_header() {
+    let mut rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let headers = BITCOIN_HEADERS.tail[0..16].to_vec();
+    let cfheaders = gen::cfheaders(FilterHeader::genesis(network), &mut rng)
+        .take(8)
+        .collect::<Vec<_>>();
+    let mut alice = Peer::new(
+        "alice",
+        [48, 48, 48, 48],
+        network,
+        headers,
+        cfheaders.clone(),
+        vec![],
+        rng,
+    );
+    let (transmit, receive) = chan::bounded(1);
+
+    alice.command(Command::GetFilterHeader(4, transmit.clone()));
+    assert_eq!(receive.recv().unwrap(), Some(cfheaders[3]));
+
+    alice.command(Command::GetFilterHeader(8, transmit.clone()));
+    assert_eq!(receive.recv().unwrap(), Some(cfheaders[7]));
+
+    alice.command(Command::GetFilterHeader(9, transmit));
+    assert_eq!(receive.recv().unwrap(), None);
+}
+
+#[test]
+fn test_get_peer_info() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let bob = PeerDummy::new([131, 31, 11, 33], network, 144, ServiceFlags::NETWORK);
+    let (transmit, receive) = chan::bounded(1);
+
+    alice.command(Command::GetPeerInfo(bob.addr, transmit.clone()));
+    assert!(receive.recv().unwrap().is_none());
+
+    alice.connect(&bob, Link::Outbound);
+    alice.command(Command::GetPeerInfo(bob.addr, transmit));
+
+    let info = receive.recv().unwrap().expect("bob is connected");
+    assert_eq!(info.peer.addr, bob.addr);
+    assert_eq!(info.peer.height, 144);
+    assert_eq!(info.version, bob.protocol_version);
+    assert_eq!(info.latency, None);
+}
+
+#[test]
+fn test_get_tips() {
+    let mut rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let headers = BITCOIN_HEADERS.tail[0..16].to_vec();
+    let cfheaders = gen::cfheaders(FilterHeader::genesis(network), &mut rng)
+        .take(8)
+        .collect::<Vec<_>>();
+    let mut alice = Peer::new(
+        "alice",
+        [48, 48, 48, 48],
+        network,
+        headers.clone(),
+        cfheaders,
+        vec![],
+        rng,
+    );
+    let (transmit, receive) = chan::bounded(1);
+
+    alice.command(Command::GetTips(transmit));
+    assert_eq!(
+        receive.recv().unwrap(),
+        Tips {
+            header: (16, headers[15].block_hash()),
+            filter: (8, headers[7].block_hash()),
+        }
+    );
+}
+
+#[test]
+fn test_get_sync_status() {
+    let mut rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let headers = BITCOIN_HEADERS.tail[0..16].to_vec();
+    let cfheaders = gen::cfheaders(FilterHeader::genesis(network), &mut rng)
+        .take(8)
+        .collect::<Vec<_>>();
+    let mut alice = Peer::new(
+        "alice",
+        [48, 48, 48, 48],
+        network,
+        headers,
+        cfheaders,
+        vec![],
+        rng,
+    );
+    let (transmit, receive) = chan::bounded(1);
+
+    // Without peers, we can't know whether we're caught up.
+    alice.command(Command::GetSyncStatus(transmit.clone()));
+    assert_eq!(
+        receive.recv().unwrap(),
+        SyncStatus {
+            in_ibd: true,
+            headers_height: 16,
+            filters_height: 8,
+            filters_processed: None,
+            best_peer_height: None,
+        }
+    );
+
+    let bob = PeerDummy::new([131, 31, 11, 33], network, 144, ServiceFlags::NETWORK);
+    alice.connect(&bob, Link::Outbound);
+    alice.command(Command::GetSyncStatus(transmit.clone()));
+    assert_matches!(
+        receive.recv().unwrap(),
+        SyncStatus {
+            in_ibd: true,
+            best_peer_height: Some(144),
+            ..
+        }
+    );
+
+    let carol = PeerDummy::new(
+        [131, 31, 11, 66],
+        network,
+        16 + syncmgr::IBD_MAX_BLOCKS_BEHIND,
+        ServiceFlags::NETWORK,
+    );
+    alice.disconnected(&bob.addr, DisconnectReason::Command.into());
+    alice.connect(&carol, Link::Outbound);
+    alice.command(Command::GetSyncStatus(transmit));
+    assert_matches!(receive.recv().unwrap(), SyncStatus { in_ibd: false, .. });
+}
+
 #[test]
 fn test_transaction_mempool_rebroadcast() {
     // TODO: Should check mempool to rebroadcast.
@@ -1575,3 +2030,151 @@ fn test_transaction_mempool_rebroadcast() {
 fn test_getdata_retry() {
     // TODO: Should retry getting blocks
 }
+
+#[test]
+fn test_headers_only() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let headers = BITCOIN_HEADERS.tail[0..16].to_vec();
+    let cfg = Config {
+        network,
+        sync_filters: false,
+        ..Config::default()
+    };
+    let mut alice = Peer::config("alice", [48, 48, 48, 48], headers, vec![], vec![], cfg, rng);
+
+    alice.init();
+    assert!(alice.events().any(|e| matches!(
+        e,
+        Event::Ready {
+            height: 16,
+            filter_height: 16,
+            ..
+        }
+    )));
+
+    let bob = PeerDummy::new(
+        [131, 31, 11, 33],
+        network,
+        144,
+        ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS,
+    );
+    alice.connect(&bob, Link::Outbound);
+    alice.tock();
+
+    assert!(
+        !alice.messages(&bob.addr).any(|m| matches!(
+            m,
+            NetworkMessage::GetCFHeaders(_) | NetworkMessage::GetCFilters(_)
+        )),
+        "Alice doesn't request filters"
+    );
+}
+
+#[test]
+fn test_flush_command() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut peer = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+
+    peer.init();
+
+    let (transmit, receive) = chan::bounded(1);
+    peer.command(Command::Flush(transmit));
+    assert!(receive.recv().unwrap().is_ok());
+}
+
+#[test]
+fn test_get_peers_where_command() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let bob = PeerDummy::new([131, 31, 11, 33], network, 144, ServiceFlags::NETWORK);
+    let eve = PeerDummy::new([131, 31, 11, 66], network, 12, ServiceFlags::NETWORK);
+
+    alice.init();
+    alice.connect(&bob, Link::Outbound);
+    alice.connect(&eve, Link::Outbound);
+
+    let (transmit, receive) = chan::bounded(1);
+    alice.command(Command::GetPeersWhere(
+        Arc::new(|p: &super::Peer| p.height >= 100 && p.version >= super::MIN_PROTOCOL_VERSION),
+        transmit,
+    ));
+    let peers = receive.recv().unwrap();
+
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0].addr, bob.addr);
+}
+
+#[test]
+fn test_get_version_info_command() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let bob = PeerDummy::new([131, 31, 11, 33], network, 144, ServiceFlags::NETWORK);
+    let mut eve = PeerDummy::new([131, 31, 11, 66], network, 12, ServiceFlags::NETWORK);
+
+    eve.protocol_version = super::MIN_PROTOCOL_VERSION;
+
+    alice.init();
+    alice.connect(&bob, Link::Outbound);
+    alice.connect(&eve, Link::Outbound);
+
+    let (transmit, receive) = chan::bounded(1);
+    alice.command(Command::GetVersionInfo(transmit));
+    let info = receive.recv().unwrap();
+
+    assert_eq!(info.protocol_version, PROTOCOL_VERSION);
+    assert_eq!(info.user_agent, USER_AGENT);
+    assert_eq!(info.peers.len(), 2);
+    assert_eq!(info.peers[&bob.addr], PROTOCOL_VERSION);
+    assert_eq!(info.peers[&eve.addr], super::MIN_PROTOCOL_VERSION);
+}
+
+#[test]
+fn test_get_network_time_command() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let local_time = alice.local_time();
+
+    alice.init();
+
+    let (transmit, receive) = chan::bounded(1);
+    alice.command(Command::GetNetworkTime(transmit.clone()));
+    assert_eq!(receive.recv().unwrap(), local_time.block_time());
+
+    // Once enough peers report a clock ahead of ours, the network time is adjusted.
+    for i in 1..=4 {
+        let mut peer = PeerDummy::new([131, 31, 11, i], network, 144, ServiceFlags::NETWORK);
+        peer.time = local_time + LocalDuration::from_secs(60);
+        alice.connect(&peer, Link::Outbound);
+    }
+    alice.command(Command::GetNetworkTime(transmit));
+    assert_eq!(receive.recv().unwrap(), local_time.block_time() + 60);
+}
+
+#[test]
+fn test_get_health_command() {
+    let rng = fastrand::Rng::new();
+    let network = Network::Mainnet;
+    let mut alice = Peer::genesis("alice", [48, 48, 48, 48], network, vec![], rng);
+    let bob = PeerDummy::new([131, 31, 11, 33], network, 144, ServiceFlags::NETWORK);
+
+    alice.init();
+    alice.connect(&bob, Link::Outbound);
+    alice.elapse(LocalDuration::from_mins(10));
+
+    let (transmit, receive) = chan::bounded(1);
+    alice.command(Command::GetHealth(transmit));
+    let health = receive.recv().unwrap();
+
+    assert_eq!(health.peers, 1);
+    assert!(health.in_ibd, "Bob is ahead of us");
+    assert!(health.store_ok);
+    assert_eq!(
+        health.last_block_age,
+        alice.local_time() - LocalTime::from_block_time(network.genesis().time)
+    );
+}
@@ -208,7 +208,7 @@ impl Peer<Protocol> {
 
     pub fn elapse(&mut self, duration: LocalDuration) {
         let time = self.clock.local_time();
-        self.clock.borrow_mut().set_local_time(time + duration);
+        self.clock.lock().unwrap().set_local_time(time + duration);
         self.protocol.wake();
     }
 
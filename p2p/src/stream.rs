@@ -24,6 +24,11 @@ impl Decoder {
         self.unparsed.extend_from_slice(bytes);
     }
 
+    /// Number of bytes input that weren't decoded yet.
+    pub fn buffered(&self) -> usize {
+        self.unparsed.len()
+    }
+
     /// Decode and return the next message. Returns [`None`] if nothing was decoded.
     pub fn decode_next<D: Decodable>(&mut self) -> Result<Option<D>, encode::Error> {
         match encode::deserialize_partial::<D>(&self.unparsed) {
@@ -170,6 +170,10 @@ impl BlockTree for Cache {
             Ok(ImportResult::TipUnchanged)
         }
     }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
 }
 
 impl BlockReader for Cache {
@@ -237,6 +241,7 @@ impl BlockReader for Cache {
 pub struct FilterCache {
     headers: NonEmpty<(FilterHash, FilterHeader)>,
     filters: BTreeMap<Height, BlockFilter>,
+    floor: Height,
 }
 
 impl FilterCache {
@@ -244,6 +249,7 @@ impl FilterCache {
         Self {
             headers: NonEmpty::new((FilterHash::all_zeros(), genesis)),
             filters: BTreeMap::new(),
+            floor: 0,
         }
     }
 
@@ -251,12 +257,16 @@ impl FilterCache {
         Self {
             headers,
             filters: BTreeMap::new(),
+            floor: 0,
         }
     }
 }
 
 impl Filters for FilterCache {
     fn get_header(&self, height: Height) -> Option<(FilterHash, FilterHeader)> {
+        if height < self.floor {
+            return None;
+        }
         self.headers.get(height as usize).copied()
     }
 
@@ -265,6 +275,11 @@ impl Filters for FilterCache {
 
         assert!(start <= end);
 
+        let start = Height::max(start, self.floor);
+        if start > end {
+            return vec![];
+        }
+
         self.headers
             .iter()
             .cloned()
@@ -292,6 +307,9 @@ impl Filters for FilterCache {
     }
 
     fn rollback(&mut self, height: Height) -> Result<(), filter::Error> {
+        if height < self.floor {
+            return Err(filter::Error::Pruned(height));
+        }
         self.headers.tail.truncate(height as usize);
 
         let heights = self
@@ -309,7 +327,26 @@ impl Filters for FilterCache {
     fn clear(&mut self) -> Result<(), filter::Error> {
         self.headers.tail.clear();
         self.filters.clear();
+        self.floor = 0;
+
+        Ok(())
+    }
+
+    fn prune(&mut self, before: Height) -> Result<(), filter::Error> {
+        if before > self.height() {
+            return Err(filter::Error::NotFound(before));
+        }
+        self.floor = Height::max(self.floor, before);
+        self.filters = self.filters.split_off(&self.floor);
+
+        Ok(())
+    }
+
+    fn floor(&self) -> Height {
+        self.floor
+    }
 
+    fn flush(&mut self) -> Result<(), filter::Error> {
         Ok(())
     }
 }
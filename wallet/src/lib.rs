@@ -66,7 +66,7 @@ impl<H: Handle> Wallet<H> {
                     ..
                 } => {
                     for t in &transactions {
-                        self.utxos.apply(t, &addresses);
+                        self.utxos.apply(t, height, &addresses);
                     }
                     log::info!(
                         "Processed block at height #{} (balance = {})",